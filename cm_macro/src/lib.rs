@@ -1,7 +1,11 @@
 use proc_macro2::{Span, TokenStream};
 use quote::{quote, quote_spanned, ToTokens};
 use syn::spanned::Spanned;
-use syn::{parse_macro_input, parse_quote, Field, Ident, ItemFn, ItemStruct};
+use syn::visit::Visit;
+use syn::{
+    parse_macro_input, parse_quote, Field, Fields, FnArg, Ident, ItemFn, ItemStruct, Lifetime,
+    LitStr, ReturnType, Type, TypeImplTrait, TypeReference,
+};
 
 fn root() -> TokenStream {
     use std::env::{var as env_var, VarError};
@@ -28,6 +32,38 @@ fn root() -> TokenStream {
     }
 }
 
+/// Visitor which collects non-`'static` lifetimes, including elided reference lifetimes.
+#[derive(Default)]
+struct NonStaticLifetimes(Vec<Span>);
+impl<'ast> Visit<'ast> for NonStaticLifetimes {
+    fn visit_type_reference(&mut self, ty: &'ast TypeReference) {
+        if ty.lifetime.is_none() {
+            self.0.push(ty.and_token.span());
+        }
+        syn::visit::visit_type_reference(self, ty);
+    }
+
+    fn visit_lifetime(&mut self, lifetime: &'ast Lifetime) {
+        if lifetime.ident != "static" {
+            self.0.push(lifetime.span());
+        }
+    }
+}
+
+/// Visitor which checks for any `impl Trait` types.
+#[derive(Default)]
+struct ContainsImplTrait(bool);
+impl<'ast> Visit<'ast> for ContainsImplTrait {
+    fn visit_type_impl_trait(&mut self, _ty: &'ast TypeImplTrait) {
+        self.0 = true;
+    }
+}
+
+/// Runs the body of an `async fn` via [`local_future!`], making the returned future `Send`.
+///
+/// The body is spawned on the local executor, so all arguments must be `'static`. If the return
+/// type is not `impl Trait`, it is used to annotate the body so that `?` and `return` infer
+/// correctly.
 #[proc_macro_attribute]
 pub fn local_async(
     _attr: proc_macro::TokenStream,
@@ -40,27 +76,80 @@ pub fn local_async(
         }
         .into();
     }
+
+    let mut non_static = NonStaticLifetimes::default();
+    for input in f.sig.inputs.iter() {
+        match input {
+            FnArg::Receiver(receiver) => {
+                if receiver.reference.is_some() {
+                    non_static.0.push(receiver.span());
+                } else {
+                    non_static.visit_type(&receiver.ty);
+                }
+            }
+            FnArg::Typed(pat_type) => non_static.visit_type(&pat_type.ty),
+        }
+    }
+    if !non_static.0.is_empty() {
+        return non_static
+            .0
+            .into_iter()
+            .map(|span| {
+                quote_spanned! {span=>
+                    ::std::compile_error!("`#[local_async]` arguments must be `'static`, as they are captured by the spawned body. Take ownership or use a `&'static` reference instead.");
+                }
+            })
+            .collect::<TokenStream>()
+            .into();
+    }
+
     let root = root();
     let block = &f.block;
+    let ret_ty: Option<Type> = match &f.sig.output {
+        ReturnType::Default => Some(parse_quote! { () }),
+        ReturnType::Type(_, ty) => {
+            // `impl Trait` cannot be used to annotate a `let`, rely on inference instead.
+            let mut contains_impl_trait = ContainsImplTrait::default();
+            contains_impl_trait.visit_type(ty);
+            (!contains_impl_trait.0).then(|| (**ty).clone())
+        }
+    };
+    let body = match ret_ty {
+        Some(ty) => quote! {
+            {
+                let out: #ty = #block;
+                out
+            }
+        },
+        None => block.to_token_stream(),
+    };
     f.block = parse_quote! {
         {
-            #root::local_future!(async #block).await
+            #root::local_future!(async #body).await
         }
     };
     f.to_token_stream().into()
 }
 
+/// Derives `FromRef<&'static Struct>` for `&'static Field` for each field of the struct.
+///
+/// Generic structs are supported, but field types must not overlap (e.g. a `T` field and a `u32`
+/// field), otherwise the generated impls conflict.
 #[proc_macro_derive(FromRefStatic)]
 pub fn derive_from_ref_static(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let st = parse_macro_input!(item as ItemStruct);
     let root = root();
     let item_ident = &st.ident;
+    let (impl_generics, ty_generics, where_clause) = st.generics.split_for_impl();
     st.fields
         .iter()
         .map(|Field { ident, ty, .. }| {
             quote! {
-                impl #root::axum::extract::FromRef<&'static #item_ident> for &'static #ty {
-                    fn from_ref(input: &&'static #item_ident) -> Self {
+                impl #impl_generics #root::axum::extract::FromRef<&'static #item_ident #ty_generics>
+                    for &'static #ty
+                #where_clause
+                {
+                    fn from_ref(input: &&'static #item_ident #ty_generics) -> Self {
                         &input.#ident
                     }
                 }
@@ -69,3 +158,84 @@ pub fn derive_from_ref_static(item: proc_macro::TokenStream) -> proc_macro::Toke
         .collect::<TokenStream>()
         .into()
 }
+
+/// Derives positional deserialization of a D1 row into a struct with named fields, via
+/// `cm_worker::with::IgnoreKeys`. Also implements `cm_worker::checked_query::ColumnCount` and
+/// `cm_worker::query_row::QueryRow`.
+///
+/// Columns are assigned to fields in declaration order, column names are ignored. Each field is
+/// deserialized with the `serde_with` adapter given by `#[query_row(with = "...")]`, or `Same`
+/// if none is given.
+#[proc_macro_derive(QueryRow, attributes(query_row))]
+pub fn derive_query_row(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let st = parse_macro_input!(item as ItemStruct);
+    let root = root();
+    let private = quote! { #root::query_row::__private };
+
+    let Fields::Named(fields) = &st.fields else {
+        return quote_spanned! {st.span()=>
+            ::std::compile_error!("`#[derive(QueryRow)]` requires a struct with named fields.");
+        }
+        .into();
+    };
+    if fields.named.is_empty() {
+        return quote_spanned! {st.span()=>
+            ::std::compile_error!("`#[derive(QueryRow)]` requires at least one field.");
+        }
+        .into();
+    }
+    if !st.generics.params.is_empty() {
+        return quote_spanned! {st.generics.span()=>
+            ::std::compile_error!("`#[derive(QueryRow)]` does not support generics.");
+        }
+        .into();
+    }
+
+    let mut idents = Vec::new();
+    let mut tys = Vec::new();
+    let mut withs = Vec::new();
+    for field in fields.named.iter() {
+        let mut with: Type = parse_quote! { #private::serde_with::Same };
+        for attr in field.attrs.iter() {
+            if !attr.path().is_ident("query_row") {
+                continue;
+            }
+            let parsed = attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("with") {
+                    with = meta.value()?.parse::<LitStr>()?.parse()?;
+                    Ok(())
+                } else {
+                    Err(meta.error("Unknown `query_row` attribute, expected `with`."))
+                }
+            });
+            if let Err(err) = parsed {
+                return err.to_compile_error().into();
+            }
+        }
+        idents.push(&field.ident);
+        tys.push(&field.ty);
+        withs.push(with);
+    }
+
+    let item_ident = &st.ident;
+    let columns = idents.len();
+    quote! {
+        impl<'de> #private::serde::Deserialize<'de> for #item_ident {
+            fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+            where
+                D: #private::serde::Deserializer<'de>,
+            {
+                let ( #( #idents, )* ) = <
+                    #root::with::IgnoreKeys<( #( #withs, )* )>
+                        as #private::serde_with::DeserializeAs<'de, ( #( #tys, )* )>
+                >::deserialize_as(deserializer)?;
+                ::std::result::Result::Ok(Self { #( #idents, )* })
+            }
+        }
+        impl #root::checked_query::ColumnCount for #item_ident {
+            const COLUMNS: usize = #columns;
+        }
+        impl #root::query_row::QueryRow for #item_ident {}
+    }
+    .into()
+}
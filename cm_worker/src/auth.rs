@@ -1,24 +1,35 @@
 //! Authentication-related stuff (oauth2 and utilities).
 
-use std::num::NonZeroU64;
+use std::str::FromStr;
 
 use axum::extract::{FromRef, FromRequestParts};
 use axum::response::{IntoResponse, Response};
-use axum::{async_trait, Json, RequestPartsExt};
+use axum::{async_trait, Json};
 use axum_extra::headers::authorization::Bearer;
-use axum_extra::headers::Authorization;
-use axum_extra::TypedHeader;
-use hmac::Hmac;
+use axum_extra::headers::{Authorization, Cookie, HeaderMapExt};
+use hmac::{Hmac, Mac};
+use http::header::{AUTHORIZATION, ORIGIN};
 use http::request::Parts;
-use http::StatusCode;
-use jwt::{SignWithKey, VerifyWithKey};
-use rand::{thread_rng, RngCore};
+use http::{HeaderMap, HeaderValue, Method, StatusCode};
+use jwt::{AlgorithmType, Header, SignWithKey, Token, VerifyWithKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
 use riven::reqwest::Client;
 use secrecy::{ExposeSecret, SecretString};
-use serde_with::serde_as;
+use serde::de::IgnoredAny;
+use serde_with::base64::{Base64, UrlSafe};
+use serde_with::ser::SerializeAsWrap;
+use serde_with::{serde_as, TimestampMilliSeconds};
 use sha2::Sha512;
 use url::Url;
 use web_time::{Duration, SystemTime};
+use worker::{query, D1Database, Error};
+
+use crate::crypt::TokenCipher;
+use crate::ids::UserId;
+use crate::init::{AdminToken, CmPagesOrigin, JwtAudience, JwtClockSkew};
+use crate::outbound::TimedOut;
+use crate::with::WebSystemTime;
 
 /// Query `?a=b` data returned to the callback url by the provider after the user authorizes login.
 #[derive(Debug, serde::Deserialize)]
@@ -42,9 +53,43 @@ pub struct OauthTokenRequest<'a> {
     pub redirect_uri: &'a str,
 }
 
+/// Form body data posted to the provider's token endpoint to refresh an access token.
+#[derive(Debug, serde::Serialize)]
+pub struct OauthRefreshRequest<'a> {
+    /// `"refresh_token"`.
+    pub grant_type: &'static str,
+    /// Refresh token from a previous token response.
+    pub refresh_token: &'a str,
+}
+
+/// Formats a secret for logging, showing at most a short prefix and the length.
+pub struct Redacted<'a>(pub &'a str);
+impl Redacted<'_> {
+    /// Number of leading characters shown, only if the secret is long enough.
+    const PREFIX_LEN: usize = 4;
+}
+impl std::fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let len = self.0.chars().count();
+        if 4 * Self::PREFIX_LEN <= len {
+            let prefix = self.0.chars().take(Self::PREFIX_LEN).collect::<String>();
+            write!(f, "{}... (len {})", prefix, len)
+        } else {
+            write!(f, "... (len {})", len)
+        }
+    }
+}
+impl std::fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
 /// JSON body data returned by the provider's token endpoint.
+///
+/// The [`Debug`] impl redacts tokens.
 #[serde_as]
-#[derive(Debug, serde::Deserialize)]
+#[derive(serde::Deserialize)]
 pub struct OauthTokenResponse {
     /// The access token.
     pub access_token: String,
@@ -63,6 +108,114 @@ pub struct OauthTokenResponse {
     #[serde_as(as = "serde_with::DurationSeconds<u64>")]
     pub expires_in: Duration,
 }
+impl std::fmt::Debug for OauthTokenResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OauthTokenResponse")
+            .field("access_token", &Redacted(&self.access_token))
+            .field(
+                "refresh_token",
+                &self.refresh_token.as_deref().map(Redacted),
+            )
+            .field("scope", &self.scope)
+            .field("id_token", &self.id_token.as_deref().map(Redacted))
+            .field("token_type", &self.token_type)
+            .field("expires_in", &self.expires_in)
+            .finish()
+    }
+}
+
+/// Identity claims from an RSO `id_token`.
+#[serde_as]
+#[derive(Debug, serde::Deserialize)]
+pub struct RsoIdentity {
+    /// Subject, the Riot PUUID.
+    pub sub: String,
+    /// Issuer, see [`RSO_ISSUER`].
+    pub iss: String,
+    /// Audience, the client IDs the token was issued to. A single string or an array.
+    #[serde_as(as = "serde_with::OneOrMany<_>")]
+    pub aud: Vec<String>,
+    /// Issued-at time.
+    #[serde_as(as = "crate::with::WebSystemTime<serde_with::TimestampSeconds<i64>>")]
+    pub iat: SystemTime,
+    /// Expiration time.
+    #[serde_as(as = "crate::with::WebSystemTime<serde_with::TimestampSeconds<i64>>")]
+    pub exp: SystemTime,
+    /// Current platform ID, e.g. `NA1`, if the `cpid` scope was requested.
+    #[serde(default)]
+    pub cpid: Option<String>,
+}
+
+/// Expected [`RsoIdentity::iss`] of RSO `id_token`s.
+pub const RSO_ISSUER: &str = "https://auth.riotgames.com";
+
+/// Parses the RSO `id_token` from [`OauthTokenResponse::id_token`] into its [`RsoIdentity`].
+///
+/// The signature is not checked, the token must come directly from the provider's token endpoint
+/// over TLS (OpenID Connect Core 1.0, section 3.1.3.7). The issuer must be [`RSO_ISSUER`], the
+/// audience must include our `client_id`, and expiration is checked.
+pub fn parse_rso_id_token(id_token: &str, client_id: &str) -> Result<RsoIdentity, AuthError> {
+    let (_header, identity): (Header, RsoIdentity) =
+        Token::<Header, RsoIdentity, _>::parse_unverified(id_token)
+            .map_err(|_| AuthError::InvalidToken)?
+            .into();
+    if RSO_ISSUER != identity.iss || !identity.aud.iter().any(|aud| client_id == aud) {
+        return Err(AuthError::InvalidToken);
+    }
+    if identity.exp < SystemTime::now() {
+        return Err(AuthError::InvalidToken);
+    }
+    Ok(identity)
+}
+
+/// Supported oauth providers, see [`crate::init::OauthHelpers`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OauthProvider {
+    /// Reddit.
+    Reddit,
+    /// Riot Sign On.
+    Rso,
+}
+impl OauthProvider {
+    /// All providers.
+    pub const ALL: [Self; 2] = [Self::Reddit, Self::Rso];
+
+    /// Name used in routes and the `user_oauth_token.provider` column.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Reddit => "reddit",
+            Self::Rso => "rso",
+        }
+    }
+
+    /// Prefix of this provider's env vars, e.g. `REDDIT` for `REDDIT_CLIENT_ID`.
+    pub fn env_prefix(self) -> &'static str {
+        match self {
+            Self::Reddit => "REDDIT",
+            Self::Rso => "RSO",
+        }
+    }
+
+    /// Provider-specific query params for the authorization URL, see
+    /// [`OauthHelper::extra_params`].
+    pub fn extra_params(self) -> Vec<(String, String)> {
+        match self {
+            Self::Reddit => vec![("duration".to_owned(), "permanent".to_owned())],
+            Self::Rso => Vec::new(),
+        }
+    }
+}
+impl FromStr for OauthProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|provider| s == provider.as_str())
+            .ok_or_else(|| format!("Unknown oauth provider: {}", s))
+    }
+}
 
 /// Helper for managing oauth authentication.
 #[derive(Debug)]
@@ -77,36 +230,43 @@ pub struct OauthHelper {
     pub provider_token_url: String,
     /// Client's callback url.
     pub callback_url: String,
+    /// Oauth scopes to request, space-separated in the authorization URL.
+    pub scopes: Vec<String>,
+    /// Additional provider-specific query params for the authorization URL.
+    pub extra_params: Vec<(String, String)>,
 }
 impl OauthHelper {
+    /// Starts an [`OauthHelperBuilder`], which validates the URLs in
+    /// [`build`](OauthHelperBuilder::build).
+    pub fn builder() -> OauthHelperBuilder {
+        OauthHelperBuilder::default()
+    }
+
     /// Creates the URL for the authorization endpoint.
     pub fn make_signin_link(&self, state: &str) -> Url {
-        Url::parse_with_params(
+        let mut url = Url::parse_with_params(
             &self.provider_authorize_url,
             [
                 ("response_type", "code"),
-                ("scope", "identity"),
+                ("scope", &self.scopes.join(" ")),
                 ("redirect_uri", &self.callback_url),
                 ("client_id", &self.client_id),
-                ("duration", "temporary"),
-                ("state", state),
             ],
         )
-        .unwrap()
+        .unwrap();
+        url.query_pairs_mut()
+            .extend_pairs(&self.extra_params)
+            .append_pair("state", state);
+        url
     }
 
-    /// Handler for the callback at [`Self::callback_url`].
+    /// Handler for the callback at [`Self::callback_url`], exchanges the code for tokens. The
+    /// `state` must already be checked with [`verify_callback_state`].
     pub async fn handle_callback(
         &self,
         reqwest_client: &Client,
-        jwt_hmac: &Hmac<Sha512>,
         callback_data: &OauthCallbackQueryResponse,
     ) -> Result<OauthTokenResponse, AuthError> {
-        let session_state = verify_session_state_token(jwt_hmac, &callback_data.state)?;
-        let SessionState::Anonymous = session_state else {
-            return Err(AuthError::MissingCredentials);
-        };
-
         let request = reqwest_client
             .post(&self.provider_token_url)
             .basic_auth(&self.client_id, Some(self.client_secret.expose_secret()))
@@ -117,24 +277,160 @@ impl OauthHelper {
             })
             .build()
             .unwrap();
-        log::info!(
-            "REQ: {:#?}\n{:#?}",
-            request,
-            request
-                .body()
-                .and_then(|b| b.as_bytes())
-                .map(|b| std::str::from_utf8(b))
-        );
+        log::debug!("Requesting oauth token: {}", request.url());
         let response = reqwest_client
             .execute(request)
             .await
             .and_then(|r| r.error_for_status())
             .map_err(|e| AuthError::TokenCreation(e.to_string()))?; // Ensure non-2xx codes error.
 
-        Ok(response
+        let tokens = response
+            .json()
+            .await
+            .map_err(|e| AuthError::TokenCreation(e.to_string()))?;
+        Ok(tokens)
+    }
+
+    /// Exchanges `refresh_token` for a new access token.
+    ///
+    /// Some providers (Reddit) do not return a new `refresh_token`, in which case the given
+    /// `refresh_token` is put into the response so it continues to be used, see
+    /// [`keep_refresh_token`].
+    ///
+    /// Returns [`AuthError::Unauthorized`] if the provider rejects the grant (`400`, e.g.
+    /// `invalid_grant` after the user revoked access), which will not succeed on retry.
+    pub async fn refresh_token(
+        &self,
+        reqwest_client: &Client,
+        refresh_token: &str,
+    ) -> Result<OauthTokenResponse, AuthError> {
+        let response = reqwest_client
+            .post(&self.provider_token_url)
+            .basic_auth(&self.client_id, Some(self.client_secret.expose_secret()))
+            .form(&OauthRefreshRequest {
+                grant_type: "refresh_token",
+                refresh_token,
+            })
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| match e.status() {
+                Some(riven::reqwest::StatusCode::BAD_REQUEST) => {
+                    AuthError::Unauthorized(e.to_string())
+                }
+                _ => AuthError::TokenCreation(e.to_string()),
+            })?; // Ensure non-2xx codes error.
+
+        let tokens = response
             .json()
             .await
-            .map_err(|e| AuthError::TokenCreation(e.to_string()))?)
+            .map_err(|e| AuthError::TokenCreation(e.to_string()))?;
+        Ok(keep_refresh_token(tokens, refresh_token))
+    }
+}
+
+/// Fills in the previous `refresh_token` if the refresh response `tokens` did not include a new
+/// one, see [`OauthHelper::refresh_token`].
+pub fn keep_refresh_token(
+    mut tokens: OauthTokenResponse,
+    refresh_token: &str,
+) -> OauthTokenResponse {
+    tokens
+        .refresh_token
+        .get_or_insert_with(|| refresh_token.to_owned());
+    tokens
+}
+
+/// Builder for [`OauthHelper`], see [`OauthHelper::builder`]. [`OauthHelper::scopes`] and
+/// [`OauthHelper::extra_params`] default to empty, all other fields are required.
+#[derive(Debug, Default)]
+pub struct OauthHelperBuilder {
+    client_id: Option<String>,
+    client_secret: Option<SecretString>,
+    provider_authorize_url: Option<String>,
+    provider_token_url: Option<String>,
+    callback_url: Option<String>,
+    scopes: Vec<String>,
+    extra_params: Vec<(String, String)>,
+}
+impl OauthHelperBuilder {
+    /// See [`OauthHelper::client_id`].
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    /// See [`OauthHelper::client_secret`].
+    pub fn client_secret(mut self, client_secret: SecretString) -> Self {
+        self.client_secret = Some(client_secret);
+        self
+    }
+
+    /// See [`OauthHelper::provider_authorize_url`].
+    pub fn provider_authorize_url(mut self, url: impl Into<String>) -> Self {
+        self.provider_authorize_url = Some(url.into());
+        self
+    }
+
+    /// See [`OauthHelper::provider_token_url`].
+    pub fn provider_token_url(mut self, url: impl Into<String>) -> Self {
+        self.provider_token_url = Some(url.into());
+        self
+    }
+
+    /// See [`OauthHelper::callback_url`].
+    pub fn callback_url(mut self, url: impl Into<String>) -> Self {
+        self.callback_url = Some(url.into());
+        self
+    }
+
+    /// See [`OauthHelper::scopes`].
+    pub fn scopes(mut self, scopes: Vec<String>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    /// See [`OauthHelper::extra_params`].
+    pub fn extra_params(mut self, extra_params: Vec<(String, String)>) -> Self {
+        self.extra_params = extra_params;
+        self
+    }
+
+    /// Builds the [`OauthHelper`]. Fails if a required field is missing or empty, or if any URL is
+    /// not an absolute `http(s)` URL.
+    pub fn build(self) -> Result<OauthHelper, String> {
+        fn required<T>(value: Option<T>, name: &str) -> Result<T, String> {
+            value.ok_or_else(|| format!("Missing `{}`.", name))
+        }
+        fn absolute_url(value: Option<String>, name: &str) -> Result<String, String> {
+            let value = required(value, name)?;
+            let url =
+                Url::parse(&value).map_err(|e| format!("Invalid `{}` {:?}: {}", name, value, e))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(format!(
+                    "Invalid `{}` {:?}: must be an absolute http(s) URL.",
+                    name, value
+                ));
+            }
+            Ok(value)
+        }
+
+        let client_id = required(self.client_id, "client_id")?;
+        if client_id.is_empty() {
+            return Err("Empty `client_id`.".to_owned());
+        }
+        Ok(OauthHelper {
+            client_id,
+            client_secret: required(self.client_secret, "client_secret")?,
+            provider_authorize_url: absolute_url(
+                self.provider_authorize_url,
+                "provider_authorize_url",
+            )?,
+            provider_token_url: absolute_url(self.provider_token_url, "provider_token_url")?,
+            callback_url: absolute_url(self.callback_url, "callback_url")?,
+            scopes: self.scopes,
+            extra_params: self.extra_params,
+        })
     }
 }
 
@@ -143,6 +439,8 @@ impl OauthHelper {
 pub enum AuthError {
     /// 401.
     Unauthorized(String),
+    /// 403.
+    Forbidden(String),
     /// 400.
     MissingCredentials,
     /// 500.
@@ -151,6 +449,8 @@ pub enum AuthError {
     InvalidToken,
     /// 503.
     UpstreamError,
+    /// 500.
+    Internal(String),
 }
 
 impl IntoResponse for AuthError {
@@ -159,6 +459,7 @@ impl IntoResponse for AuthError {
             AuthError::Unauthorized(msg) => {
                 (StatusCode::UNAUTHORIZED, &*format!("Unauthorized: {}", msg))
             }
+            AuthError::Forbidden(msg) => (StatusCode::FORBIDDEN, &*format!("Forbidden: {}", msg)),
             AuthError::MissingCredentials => (StatusCode::BAD_REQUEST, "Missing credentials"),
             AuthError::TokenCreation(msg) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -169,6 +470,10 @@ impl IntoResponse for AuthError {
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Failed to communicate with oauth provider",
             ),
+            AuthError::Internal(msg) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &*format!("Internal error: {}", msg),
+            ),
         };
         let body = Json(serde_json::json!({
             "error": error_message,
@@ -177,7 +482,15 @@ impl IntoResponse for AuthError {
     }
 }
 
+impl From<TimedOut> for AuthError {
+    fn from(value: TimedOut) -> Self {
+        log::warn!("Oauth provider request failed: {}", value);
+        AuthError::UpstreamError
+    }
+}
+
 /// Session token types.
+#[serde_as]
 #[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
 #[serde(tag = "type")]
 pub enum SessionState {
@@ -189,23 +502,51 @@ pub enum SessionState {
     #[serde(rename = "TRANSITION")]
     Transition {
         /// User ID to be signed-in.
-        user_id: NonZeroU64,
+        user_id: UserId,
+        /// Nonce of the [`Self::Anonymous`] token used as the oauth `state`, see
+        /// [`verify_callback_state`].
+        #[serde_as(as = "Base64<UrlSafe>")]
+        state_nonce: [u8; 16],
     },
 
     /// User login session token.
     #[serde(rename = "SIGNEDIN")]
     SignedIn {
         /// User ID this is signed-in.
-        user_id: NonZeroU64,
+        user_id: UserId,
     },
 }
-impl SessionState {
-    /// Time to live for each type of session.
-    pub fn ttl(self) -> Duration {
-        match self {
-            SessionState::Anonymous { .. } => Duration::from_secs(24 * 60 * 60),
-            SessionState::Transition { .. } => Duration::from_secs(60),
-            SessionState::SignedIn { .. } => Duration::from_secs(3 * 60 * 60),
+
+/// Time to live for each type of [`SessionState`], set up in [`crate::init`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionTtls {
+    /// See [`SessionState::Anonymous`]. `SESSION_TTL_ANON_SECS`, default 24 hours.
+    pub anonymous: Duration,
+    /// See [`SessionState::Transition`]. `SESSION_TTL_TRANSITION_SECS`, default 60 seconds.
+    pub transition: Duration,
+    /// See [`SessionState::SignedIn`]. `SESSION_TTL_SIGNED_IN_SECS`, default 3 hours.
+    pub signed_in: Duration,
+    /// How far before `iat` new tokens' `nbf` is set, so a token is still valid if the verifying
+    /// clock runs slightly behind the issuing one. `JWT_NBF_BACKDATE_SECS`, default zero.
+    pub nbf_backdate: Duration,
+}
+impl Default for SessionTtls {
+    fn default() -> Self {
+        Self {
+            anonymous: Duration::from_secs(24 * 60 * 60),
+            transition: Duration::from_secs(60),
+            signed_in: Duration::from_secs(3 * 60 * 60),
+            nbf_backdate: Duration::ZERO,
+        }
+    }
+}
+impl SessionTtls {
+    /// Time to live for `session_state`.
+    pub fn get(&self, session_state: SessionState) -> Duration {
+        match session_state {
+            SessionState::Anonymous { .. } => self.anonymous,
+            SessionState::Transition { .. } => self.transition,
+            SessionState::SignedIn { .. } => self.signed_in,
         }
     }
 }
@@ -213,7 +554,11 @@ impl SessionState {
 impl<S> FromRequestParts<S> for SessionState
 where
     S: Send + Sync,
-    &'static Hmac<Sha512>: FromRef<S>,
+    &'static JwtKeys: FromRef<S>,
+    &'static JwtClockSkew: FromRef<S>,
+    &'static JwtAudience: FromRef<S>,
+    &'static CmPagesOrigin: FromRef<S>,
+    &'static D1Database: FromRef<S>,
 {
     type Rejection = AuthError;
 
@@ -221,13 +566,8 @@ where
         parts: &mut Parts,
         state: &S,
     ) -> std::result::Result<Self, Self::Rejection> {
-        // Extract the token from the authorization header
-        let TypedHeader(Authorization(bearer)) = parts
-            .extract::<TypedHeader<Authorization<Bearer>>>()
-            .await
-            .map_err(|_| AuthError::InvalidToken)?;
-        // Decode the user data
-        verify_session_state_token(FromRef::from_ref(state), bearer.token())
+        let claims = JwtSessionState::from_request_parts(parts, state).await?;
+        Ok(claims.session_state)
     }
 }
 
@@ -239,7 +579,11 @@ pub struct SessionStateAnonymous;
 impl<S> FromRequestParts<S> for SessionStateAnonymous
 where
     S: Send + Sync,
-    &'static Hmac<Sha512>: FromRef<S>,
+    &'static JwtKeys: FromRef<S>,
+    &'static JwtClockSkew: FromRef<S>,
+    &'static JwtAudience: FromRef<S>,
+    &'static CmPagesOrigin: FromRef<S>,
+    &'static D1Database: FromRef<S>,
 {
     type Rejection = AuthError;
 
@@ -257,53 +601,24 @@ where
     }
 }
 
-/// [`SessionState::Transition`]
-#[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
-#[serde(transparent)]
-#[repr(transparent)]
-pub struct SessionStateTransition {
-    /// User ID to be signed-in.
-    pub user_id: NonZeroU64,
-}
-// TODO: cleanup boilerplate.
-#[async_trait]
-impl<S> FromRequestParts<S> for SessionStateTransition
-where
-    S: Send + Sync,
-    &'static Hmac<Sha512>: FromRef<S>,
-{
-    type Rejection = AuthError;
-
-    async fn from_request_parts(
-        parts: &mut Parts,
-        state: &S,
-    ) -> std::result::Result<Self, Self::Rejection> {
-        if let SessionState::Transition { user_id } =
-            SessionState::from_request_parts(parts, state).await?
-        {
-            Ok(SessionStateTransition { user_id })
-        } else {
-            Err(AuthError::Unauthorized(
-                "Session state must by transition.".to_owned(),
-            ))
-        }
-    }
-}
-
 /// [`SessionState::SignedIn`]
 #[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
 #[serde(transparent)]
 #[repr(transparent)]
 pub struct SessionStateSignedIn {
     /// User ID that is signed-in.
-    pub user_id: NonZeroU64,
+    pub user_id: UserId,
 }
 // TODO: cleanup boilerplate.
 #[async_trait]
 impl<S> FromRequestParts<S> for SessionStateSignedIn
 where
     S: Send + Sync,
-    &'static Hmac<Sha512>: FromRef<S>,
+    &'static JwtKeys: FromRef<S>,
+    &'static JwtClockSkew: FromRef<S>,
+    &'static JwtAudience: FromRef<S>,
+    &'static CmPagesOrigin: FromRef<S>,
+    &'static D1Database: FromRef<S>,
 {
     type Rejection = AuthError;
 
@@ -339,63 +654,1423 @@ pub struct JwtSessionState {
     /// Expiration time.
     #[serde_as(as = "crate::with::WebSystemTime<serde_with::TimestampSeconds<i64>>")]
     exp: SystemTime,
+    /// Audience, the service origin the token is valid for. `None` for tokens issued before
+    /// audiences were added, see [`Self::check_audience`].
+    #[serde(default)]
+    aud: Option<String>,
     /// User session state.
     #[serde_as(as = "serde_with::json::JsonString")]
     session_state: SessionState,
 }
 impl JwtSessionState {
-    /// Creates a new token expiring after [`SessionState::ttl`] from now.
-    /// Sets a random [`Self::nonce`].
-    pub fn create_now(session_state: SessionState) -> Self {
+    /// Creates a new token for `audience`, expiring after the [`SessionTtls`] for `session_state`
+    /// from now, and valid from [`SessionTtls::nbf_backdate`] ago. Sets a random [`Self::nonce`].
+    ///
+    /// Randomness comes straight from [`OsRng`], which on `wasm32` is `crypto.getRandomValues()`
+    /// (via `getrandom`'s `js` feature) and is always available on Workers.
+    pub fn create_now(
+        session_ttls: &SessionTtls,
+        audience: &str,
+        session_state: SessionState,
+    ) -> Self {
         let iat = SystemTime::now();
-        let nbf = iat - Duration::from_secs(10);
-        let exp = iat + session_state.ttl();
+        let nbf = iat - session_ttls.nbf_backdate;
+        let exp = iat + session_ttls.get(session_state);
 
         let mut nonce = [0; 16];
-        thread_rng().fill_bytes(&mut nonce);
+        OsRng.fill_bytes(&mut nonce);
 
         Self {
             nonce,
             iat,
             nbf,
             exp,
+            aud: Some(audience.to_owned()),
             session_state,
         }
     }
 
-    /// Checks that the token is valid right now.
-    pub fn check_now(&self) -> Result<(), AuthError> {
-        let now = SystemTime::now();
-        if now < self.nbf || self.exp < now {
-            return Err(AuthError::Unauthorized(
-                "Token time is invalid (expired).".to_owned(),
-            ));
+    /// Checks that the token is valid right now, allowing up to `skew` of clock drift.
+    pub fn check_now(&self, skew: Duration) -> Result<(), AuthError> {
+        self.check_at(SystemTime::now(), skew)
+    }
+
+    /// Checks that the token is valid at `now`, allowing up to `skew` of clock drift on both
+    /// [`Self::nbf`] and [`Self::exp`].
+    pub fn check_at(&self, now: SystemTime, skew: Duration) -> Result<(), AuthError> {
+        check_token_time(self.nbf, self.exp, now, skew)
+    }
+
+    /// Checks that the token's [`Self::aud`] is `audience`.
+    ///
+    /// Tokens without an audience are still accepted, so existing sessions are not all signed out
+    /// on deploy.
+    // TODO: reject missing audiences once pre-audience tokens have expired (one release).
+    pub fn check_audience(&self, audience: &str) -> Result<(), AuthError> {
+        match &self.aud {
+            Some(aud) if audience != aud => Err(AuthError::InvalidToken),
+            _ => Ok(()),
+        }
+    }
+
+    /// The user session state.
+    pub fn session_state(&self) -> SessionState {
+        self.session_state
+    }
+}
+#[async_trait]
+impl<S> FromRequestParts<S> for JwtSessionState
+where
+    S: Send + Sync,
+    &'static JwtKeys: FromRef<S>,
+    &'static JwtClockSkew: FromRef<S>,
+    &'static JwtAudience: FromRef<S>,
+    &'static CmPagesOrigin: FromRef<S>,
+    &'static D1Database: FromRef<S>,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        // Extract the token from the authorization header or cookie
+        let token = session_token(&parts.headers).ok_or(AuthError::InvalidToken)?;
+        let CmPagesOrigin(pages_origin): &'static CmPagesOrigin = FromRef::from_ref(state);
+        check_cookie_origin(&parts.method, &parts.headers, pages_origin)?;
+        // Decode the user data
+        let jwt_keys: &'static JwtKeys = FromRef::from_ref(state);
+        let JwtClockSkew(skew): &'static JwtClockSkew = FromRef::from_ref(state);
+        let JwtAudience(audience): &'static JwtAudience = FromRef::from_ref(state);
+        let db: &'static D1Database = FromRef::from_ref(state);
+        crate::local_future!(verify_session_state_token(
+            jwt_keys, *skew, audience, db, &token
+        ))
+        .await
+    }
+}
+
+/// Name of the cookie which may hold the session token, for browser frontends. See
+/// [`session_token`].
+pub const SESSION_COOKIE: &str = "cm_session";
+
+/// Gets the session token from the request headers.
+///
+/// If there is an `Authorization` header it takes precedence and the cookie is ignored, even if the
+/// header is not a valid bearer token. Otherwise the token is read from the [`SESSION_COOKIE`].
+pub fn session_token(headers: &HeaderMap) -> Option<String> {
+    if headers.contains_key(AUTHORIZATION) {
+        return headers
+            .typed_get::<Authorization<Bearer>>()
+            .map(|Authorization(bearer)| bearer.token().to_owned());
+    }
+    headers
+        .typed_get::<Cookie>()?
+        .get(SESSION_COOKIE)
+        .map(ToOwned::to_owned)
+}
+
+/// Guards against CSRF: browsers send the [`SESSION_COOKIE`] on cross-site requests too, so a
+/// state-changing (non-`GET`/`HEAD`/`OPTIONS`) request authenticated by the cookie must have an
+/// `Origin` header matching `pages_origin`. Requests with an `Authorization` header are not checked,
+/// as [`session_token`] ignores the cookie for them.
+pub fn check_cookie_origin(
+    method: &Method,
+    headers: &HeaderMap,
+    pages_origin: &Url,
+) -> Result<(), AuthError> {
+    if headers.contains_key(AUTHORIZATION) || method.is_safe() {
+        return Ok(());
+    }
+    let expected = pages_origin.origin().ascii_serialization();
+    match headers.get(ORIGIN) {
+        Some(origin) if origin.as_bytes() == expected.as_bytes() => Ok(()),
+        origin => Err(AuthError::Forbidden(format!(
+            "Cookie session requires `Origin: {}`, got: {:?}.",
+            expected, origin
+        ))),
+    }
+}
+
+/// `Set-Cookie` value storing the session `token` in the [`SESSION_COOKIE`] for `max_age`.
+pub fn session_cookie(token: &str, max_age: Duration) -> Result<HeaderValue, AuthError> {
+    set_cookie(SESSION_COOKIE, token, max_age)
+}
+
+/// Name of the cookie holding the oauth `state` of the sign-in started by this browser, see
+/// [`verify_callback_state`].
+pub const SIGNIN_STATE_COOKIE: &str = "cm_signin_state";
+
+/// `Set-Cookie` value storing the oauth `state` in the [`SIGNIN_STATE_COOKIE`] for `max_age`.
+pub fn signin_state_cookie(state: &str, max_age: Duration) -> Result<HeaderValue, AuthError> {
+    set_cookie(SIGNIN_STATE_COOKIE, state, max_age)
+}
+
+/// Gets the oauth `state` from the [`SIGNIN_STATE_COOKIE`], if any.
+pub fn signin_state(headers: &HeaderMap) -> Option<String> {
+    headers
+        .typed_get::<Cookie>()?
+        .get(SIGNIN_STATE_COOKIE)
+        .map(ToOwned::to_owned)
+}
+
+/// `HttpOnly; Secure; SameSite=Lax` cookie for the whole site. `Lax` so it is still sent on the
+/// top-level redirect back from the oauth provider.
+fn set_cookie(name: &str, value: &str, max_age: Duration) -> Result<HeaderValue, AuthError> {
+    let cookie = format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; Secure; SameSite=Lax",
+        name,
+        value,
+        max_age.as_secs()
+    );
+    HeaderValue::try_from(cookie).map_err(|e| AuthError::TokenCreation(e.to_string()))
+}
+
+/// Admin access, requires `Authorization: Bearer <ADMIN_TOKEN>`. See [`AdminToken`].
+#[derive(Clone, Copy, Debug)]
+pub struct Admin;
+#[async_trait]
+impl<S> FromRequestParts<S> for Admin
+where
+    S: Send + Sync,
+    &'static AdminToken: FromRef<S>,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let AdminToken(admin_token): &'static AdminToken = FromRef::from_ref(state);
+        let bearer = parts.headers.typed_get::<Authorization<Bearer>>();
+        check_admin_token(
+            admin_token.as_ref(),
+            bearer.as_ref().map(|Authorization(bearer)| bearer.token()),
+        )
+    }
+}
+
+/// Checks the request's bearer `token` against the configured `admin_token`, in constant time.
+fn check_admin_token(
+    admin_token: Option<&SecretString>,
+    token: Option<&str>,
+) -> Result<Admin, AuthError> {
+    let Some(admin_token) = admin_token else {
+        return Err(AuthError::Unauthorized(
+            "Admin routes are disabled.".to_owned(),
+        ));
+    };
+    let expected = admin_token.expose_secret().as_bytes();
+    let token = token.unwrap_or_default().as_bytes();
+    let diff = expected
+        .iter()
+        .zip(token)
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    if expected.len() != token.len() || 0 != diff {
+        return Err(AuthError::Unauthorized("Invalid admin token.".to_owned()));
+    }
+    Ok(Admin)
+}
+
+/// An HMAC key for signing session JWTs, see [`JwtKeys`].
+pub struct JwtKey {
+    /// Short key ID, put in the JWT `kid` header. Derived from the key so it is stable across
+    /// deployments without revealing the key.
+    pub kid: String,
+    /// The key.
+    pub hmac: Hmac<Sha512>,
+}
+impl JwtKey {
+    /// Creates a key, deriving its [`Self::kid`].
+    pub fn new(hmac: Hmac<Sha512>) -> Self {
+        let digest = hmac.clone().chain_update(b"kid").finalize().into_bytes();
+        let kid = base64::encode_config(&digest[..6], base64::URL_SAFE_NO_PAD);
+        Self { kid, hmac }
+    }
+}
+
+/// HMAC keys for session JWTs, set up in [`crate::init`] from `HMAC_SECRET` and
+/// `HMAC_SECRET_PREVIOUS`. New tokens are signed with the primary key, tokens signed by any key
+/// are accepted so rotating keys does not invalidate live sessions.
+pub struct JwtKeys {
+    /// Key used to sign new tokens.
+    pub primary: JwtKey,
+    /// Previous keys, only used to verify tokens.
+    pub previous: Vec<JwtKey>,
+}
+impl JwtKeys {
+    /// Signs `claims` with the primary key, setting the `kid` header.
+    pub fn sign<C: jwt::ToBase64>(&self, claims: C) -> Result<String, jwt::Error> {
+        let header = Header {
+            algorithm: AlgorithmType::Hs512,
+            key_id: Some(self.primary.kid.clone()),
+            ..Default::default()
+        };
+        let token = Token::new(header, claims).sign_with_key(&self.primary.hmac)?;
+        Ok(token.as_str().to_owned())
+    }
+
+    /// Verifies `token` with the key matching its `kid` header. Tokens without a `kid` are tried
+    /// against every key.
+    pub fn verify<C: jwt::FromBase64>(&self, token: &str) -> Result<C, jwt::Error> {
+        let mut keys = std::iter::once(&self.primary).chain(&self.previous);
+        let unverified = Token::<Header, IgnoredAny, _>::parse_unverified(token)?;
+        match unverified.header().key_id.as_deref() {
+            Some(kid) => {
+                let key = keys
+                    .find(|key| kid == key.kid)
+                    .ok_or_else(|| jwt::Error::NoKeyWithKeyId(kid.to_owned()))?;
+                token.verify_with_key(&key.hmac)
+            }
+            None => keys
+                .find_map(|key| token.verify_with_key(&key.hmac).ok())
+                .ok_or(jwt::Error::InvalidSignature),
         }
-        Ok(())
     }
 }
 
-/// Create a user session token for the given `user_id`, expiring in some amount of time.
+/// Create a user session token for the given `user_id` and `audience`, expiring per
+/// `session_ttls`.
 pub fn create_session_state_token(
-    jwt_hmac: &Hmac<Sha512>,
+    jwt_keys: &JwtKeys,
+    session_ttls: &SessionTtls,
+    audience: &str,
     session_state: SessionState,
 ) -> Result<String, AuthError> {
-    let claims = JwtSessionState::create_now(session_state);
-    let token = claims
-        .sign_with_key(jwt_hmac)
+    let claims = JwtSessionState::create_now(session_ttls, audience, session_state);
+    let token = jwt_keys
+        .sign(claims)
         .map_err(|e| AuthError::TokenCreation(e.to_string()))?;
     Ok(token)
 }
 
-/// Verifies that the session token is valid. Returns the [`SessionState`] if valid, otherwise
-/// returns an error.
-pub fn verify_session_state_token(
-    jwt_hmac: &Hmac<Sha512>,
+/// Decodes the session token and checks its signature, audience, and time validity, but NOT
+/// whether it has been revoked. Only use directly for tokens which cannot be revoked (i.e. not
+/// [`SessionState::SignedIn`]), otherwise use [`verify_session_state_token`].
+pub fn decode_session_state_token(
+    jwt_keys: &JwtKeys,
+    skew: Duration,
+    audience: &str,
     token: &str,
-) -> Result<SessionState, AuthError> {
-    let claims: JwtSessionState = token
-        .verify_with_key(jwt_hmac)
+) -> Result<JwtSessionState, AuthError> {
+    let claims: JwtSessionState = jwt_keys
+        .verify(token)
         .map_err(|_| AuthError::InvalidToken)?;
-    let () = claims.check_now()?;
-    Ok(claims.session_state)
+    let () = claims.check_audience(audience)?;
+    let () = claims.check_now(skew)?;
+    Ok(claims)
+}
+
+/// Checks the oauth callback's `state` and returns its nonce, to be put in the resulting
+/// [`SessionState::Transition`] token.
+///
+/// Threat model (login CSRF): an attacker starts a sign-in with their own account and tricks the
+/// victim's browser into completing the callback, signing the victim in as the attacker. The
+/// `state` must be an unexpired [`SessionState::Anonymous`] token and equal `state_cookie`, the
+/// [`SIGNIN_STATE_COOKIE`] set by the browser which started the sign-in. The attacker cannot set
+/// that cookie in the victim's browser, so a callback carrying the attacker's `state` is rejected.
+/// Each `state` is also only accepted once (recorded in `store`), so a leaked callback URL cannot
+/// be replayed.
+pub async fn verify_callback_state(
+    store: &impl NonceStore,
+    jwt_keys: &JwtKeys,
+    skew: Duration,
+    audience: &str,
+    state_cookie: Option<&str>,
+    state: &str,
+) -> Result<[u8; 16], AuthError> {
+    if Some(state) != state_cookie {
+        return Err(AuthError::Unauthorized(
+            "Sign-in was not started by this browser.".to_owned(),
+        ));
+    }
+    let claims = decode_session_state_token(jwt_keys, skew, audience, state)?;
+    let SessionState::Anonymous = claims.session_state else {
+        return Err(AuthError::MissingCredentials);
+    };
+    let first_use = store
+        .insert_nonce(&claims.nonce, claims.exp)
+        .await
+        .map_err(|e| AuthError::Internal(e.to_string()))?;
+    if !first_use {
+        return Err(AuthError::Unauthorized(
+            "Sign-in state has already been used.".to_owned(),
+        ));
+    }
+    Ok(claims.nonce)
+}
+
+/// Checks that `state` is the (unexpired) [`SessionState::Anonymous`] token whose nonce was bound
+/// into a transition token as `state_nonce`, i.e. that the upgrade belongs to the same sign-in.
+/// `state` travels in the same redirect URL as the transition token, so this is not a login CSRF
+/// defense on its own, that is [`verify_callback_state`].
+pub fn check_transition_state(
+    jwt_keys: &JwtKeys,
+    skew: Duration,
+    audience: &str,
+    state_nonce: &[u8; 16],
+    state: &str,
+) -> Result<(), AuthError> {
+    let claims = decode_session_state_token(jwt_keys, skew, audience, state)?;
+    match claims.session_state {
+        SessionState::Anonymous if state_nonce == &claims.nonce => Ok(()),
+        _ => Err(AuthError::InvalidToken),
+    }
+}
+
+/// Checks that a token with the given `nbf` and `exp` is valid at `now`, allowing up to `skew` of
+/// clock drift on both.
+fn check_token_time(
+    nbf: SystemTime,
+    exp: SystemTime,
+    now: SystemTime,
+    skew: Duration,
+) -> Result<(), AuthError> {
+    if now + skew < nbf || exp + skew < now {
+        return Err(AuthError::Unauthorized(
+            "Token time is invalid (expired).".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Short-lived token proving ownership of an RSO account, issued by `GET /signin-rso` when the
+/// account is not yet linked to any user. Exchanged at `POST /user/me/link-rso` to add the
+/// account's summoner to the signed-in user.
+#[serde_as]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct JwtRsoLink {
+    /// Nonce, so the token can only be exchanged once, see [`consume_rso_link_token`].
+    #[serde_as(as = "serde_with::base64::Base64<serde_with::base64::UrlSafe>")]
+    nonce: [u8; 16],
+    /// Issued-at time.
+    #[serde_as(as = "crate::with::WebSystemTime<serde_with::TimestampSeconds<i64>>")]
+    iat: SystemTime,
+    /// Not before time.
+    #[serde_as(as = "crate::with::WebSystemTime<serde_with::TimestampSeconds<i64>>")]
+    nbf: SystemTime,
+    /// Expiration time.
+    #[serde_as(as = "crate::with::WebSystemTime<serde_with::TimestampSeconds<i64>>")]
+    exp: SystemTime,
+    /// Audience, the service origin the token is valid for.
+    aud: String,
+    /// Riot PUUID, from the verified [`RsoIdentity::sub`].
+    pub puuid: String,
+    /// See [`RsoIdentity::cpid`].
+    pub cpid: Option<String>,
+}
+
+/// Creates a [`JwtRsoLink`] token for the RSO `identity` and `audience`, expiring after
+/// [`SessionTtls::transition`] and backdated by [`SessionTtls::nbf_backdate`].
+pub fn create_rso_link_token(
+    jwt_keys: &JwtKeys,
+    session_ttls: &SessionTtls,
+    audience: &str,
+    identity: &RsoIdentity,
+) -> Result<String, AuthError> {
+    let iat = SystemTime::now();
+    let mut nonce = [0; 16];
+    OsRng.fill_bytes(&mut nonce);
+    let claims = JwtRsoLink {
+        nonce,
+        iat,
+        nbf: iat - session_ttls.nbf_backdate,
+        exp: iat + session_ttls.transition,
+        aud: audience.to_owned(),
+        puuid: identity.sub.clone(),
+        cpid: identity.cpid.clone(),
+    };
+    jwt_keys
+        .sign(claims)
+        .map_err(|e| AuthError::TokenCreation(e.to_string()))
+}
+
+/// Decodes the [`JwtRsoLink`] token and checks its signature, audience, and time validity.
+pub fn decode_rso_link_token(
+    jwt_keys: &JwtKeys,
+    skew: Duration,
+    audience: &str,
+    token: &str,
+) -> Result<JwtRsoLink, AuthError> {
+    let claims: JwtRsoLink = jwt_keys
+        .verify(token)
+        .map_err(|_| AuthError::InvalidToken)?;
+    if audience != claims.aud {
+        return Err(AuthError::InvalidToken);
+    }
+    check_token_time(claims.nbf, claims.exp, SystemTime::now(), skew)?;
+    Ok(claims)
+}
+
+/// Verifies that the session token is valid and, if signed-in, not revoked. Returns the
+/// [`JwtSessionState`] if valid, otherwise returns an error.
+pub async fn verify_session_state_token(
+    jwt_keys: &JwtKeys,
+    skew: Duration,
+    audience: &str,
+    db: &D1Database,
+    token: &str,
+) -> Result<JwtSessionState, AuthError> {
+    let claims = decode_session_state_token(jwt_keys, skew, audience, token)?;
+    if let SessionState::SignedIn { .. } = claims.session_state {
+        // Revoked nonces are only deleted once their token would be rejected even with `skew`
+        // (see `revoked_nonce_cutoff`), and `claims` was accepted, so its row cannot be purged.
+        let revoked = query!(
+            &db,
+            "SELECT 1 FROM revoked_nonce WHERE nonce = ?",
+            <SerializeAsWrap<_, Base64<UrlSafe>>>::new(&claims.nonce),
+        )
+        .map_err(|e| AuthError::Internal(e.to_string()))?
+        .first::<IgnoredAny>(None)
+        .await
+        .map_err(|e| AuthError::Internal(e.to_string()))?
+        .is_some();
+        if revoked {
+            return Err(AuthError::Unauthorized(
+                "Token has been revoked.".to_owned(),
+            ));
+        }
+    }
+    Ok(claims)
+}
+
+/// Records token nonces until they expire, see [`consume_transition_token`].
+#[allow(async_fn_in_trait)]
+pub trait NonceStore {
+    /// Records `nonce` until its token, expiring at `exp`, can no longer be accepted (including
+    /// clock skew). Returns `false` if it was already recorded.
+    async fn insert_nonce(&self, nonce: &[u8; 16], exp: SystemTime) -> worker::Result<bool>;
+}
+/// Shares the `revoked_nonce` table with [`revoke_session_state_token`], so entries are only
+/// cleaned up by [`delete_expired_revoked_nonces`] after `exp` plus the clock skew, once the
+/// transition token could not be replayed anyway.
+impl NonceStore for D1Database {
+    async fn insert_nonce(&self, nonce: &[u8; 16], exp: SystemTime) -> worker::Result<bool> {
+        let inserted = query!(
+            self,
+            "INSERT INTO revoked_nonce(nonce, exp) VALUES (?, ?) ON CONFLICT DO NOTHING
+            RETURNING nonce",
+            <SerializeAsWrap<_, Base64<UrlSafe>>>::new(nonce),
+            <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(&exp),
+        )?
+        .first::<IgnoredAny>(None)
+        .await?
+        .is_some();
+        Ok(inserted)
+    }
+}
+
+/// Marks the [`SessionState::Transition`] token as used, so it can only be exchanged once. Fails
+/// if it was already used, e.g. replayed after leaking from the redirect URL.
+pub async fn consume_transition_token(
+    store: &impl NonceStore,
+    claims: &JwtSessionState,
+) -> Result<(), AuthError> {
+    let SessionState::Transition { .. } = claims.session_state else {
+        return Err(AuthError::InvalidToken);
+    };
+    let first_use = store
+        .insert_nonce(&claims.nonce, claims.exp)
+        .await
+        .map_err(|e| AuthError::Internal(e.to_string()))?;
+    if !first_use {
+        return Err(AuthError::Unauthorized(
+            "Token has already been used.".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Marks the [`JwtRsoLink`] token as used, so it can only be exchanged once. Fails if it was
+/// already used, e.g. replayed after leaking from the redirect URL.
+pub async fn consume_rso_link_token(
+    store: &impl NonceStore,
+    claims: &JwtRsoLink,
+) -> Result<(), AuthError> {
+    let first_use = store
+        .insert_nonce(&claims.nonce, claims.exp)
+        .await
+        .map_err(|e| AuthError::Internal(e.to_string()))?;
+    if !first_use {
+        return Err(AuthError::Unauthorized(
+            "Token has already been used.".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Revokes the session token, so it fails [`verify_session_state_token`] until it expires.
+pub async fn revoke_session_state_token(
+    db: &D1Database,
+    claims: &JwtSessionState,
+) -> worker::Result<()> {
+    let query = query!(
+        &db,
+        "INSERT INTO revoked_nonce(nonce, exp) VALUES (?, ?) ON CONFLICT DO NOTHING",
+        <SerializeAsWrap<_, Base64<UrlSafe>>>::new(&claims.nonce),
+        <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(&claims.exp),
+    )?;
+    if let Some(error) = query.run().await?.error() {
+        return Err(Error::RustError(error));
+    }
+    Ok(())
+}
+
+/// Deletes revoked nonces whose tokens have expired anyway, i.e. would fail
+/// [`JwtSessionState::check_at`] even allowing for `skew`. See [`revoked_nonce_cutoff`].
+pub async fn delete_expired_revoked_nonces(db: &D1Database, skew: Duration) -> worker::Result<()> {
+    let cutoff = revoked_nonce_cutoff(SystemTime::now(), skew);
+    let query = query!(
+        &db,
+        "DELETE FROM revoked_nonce WHERE exp < ?",
+        <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(&cutoff),
+    )?;
+    if let Some(error) = query.run().await?.error() {
+        return Err(Error::RustError(error));
+    }
+    Ok(())
+}
+
+/// Revoked nonces with `exp` before the returned time may be deleted at `now`. Tokens are accepted
+/// until `exp + skew` (see [`check_token_time`]), so a row must be kept until then or the revoked
+/// (or consumed) token would become valid again.
+pub fn revoked_nonce_cutoff(now: SystemTime, skew: Duration) -> SystemTime {
+    now - skew
+}
+
+/// Encrypts and stores the user's oauth tokens for `provider`, replacing any previous tokens.
+///
+/// If `tokens` has no `refresh_token`, any previously stored refresh token is kept. Clears any
+/// `refresh_error`, so [`crate::webjob::oauth_token_refresh`] resumes refreshing.
+pub async fn store_oauth_tokens(
+    db: &D1Database,
+    token_cipher: &TokenCipher,
+    user_id: UserId,
+    provider: &str,
+    tokens: &OauthTokenResponse,
+) -> worker::Result<()> {
+    let expires_at = SystemTime::now() + tokens.expires_in;
+    let query = query!(
+        &db,
+        "INSERT INTO user_oauth_token(user_id, provider, access_token, refresh_token, expires_at)
+        VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT DO UPDATE SET
+            access_token = EXCLUDED.access_token,
+            refresh_token = COALESCE(EXCLUDED.refresh_token, refresh_token),
+            expires_at = EXCLUDED.expires_at,
+            refresh_error = NULL",
+        user_id,
+        provider,
+        token_cipher.encrypt(&tokens.access_token),
+        tokens
+            .refresh_token
+            .as_deref()
+            .map(|refresh_token| token_cipher.encrypt(refresh_token)),
+        <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(&expires_at),
+    )?;
+    if let Some(error) = query.run().await?.error() {
+        return Err(Error::RustError(error));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    const AUDIENCE: &str = "https://example.com";
+
+    #[test]
+    fn test_oauth_provider_from_str() {
+        for provider in OauthProvider::ALL {
+            assert_eq!(Ok(provider), provider.as_str().parse());
+            assert_eq!(
+                provider,
+                serde_json::from_value(serde_json::json!(provider.as_str())).unwrap()
+            );
+        }
+        assert!("google".parse::<OauthProvider>().is_err());
+    }
+
+    #[test]
+    fn test_redacted() {
+        let secret = "abcdefghijklmnopqrstuvwxyz0123456789";
+        let redacted = Redacted(secret).to_string();
+        assert!(!redacted.contains(secret));
+        assert!(!redacted.contains("efgh"));
+        assert_eq!("abcd... (len 36)", redacted);
+        assert_eq!("... (len 8)", Redacted("abcdefgh").to_string());
+        assert_eq!("... (len 0)", Redacted("").to_string());
+
+        let tokens = OauthTokenResponse {
+            access_token: secret.to_owned(),
+            refresh_token: Some(secret.to_owned()),
+            scope: vec!["identity".to_owned()],
+            id_token: Some(secret.to_owned()),
+            token_type: "bearer".to_owned(),
+            expires_in: Duration::from_secs(3600),
+        };
+        for debug in [format!("{:?}", tokens), format!("{:#?}", tokens)] {
+            assert!(!debug.contains(secret));
+            assert!(!debug.contains("efgh"));
+        }
+    }
+
+    #[test]
+    fn test_keep_refresh_token() {
+        let response = |refresh_token: Option<&str>| {
+            serde_json::from_value::<OauthTokenResponse>(serde_json::json!({
+                "access_token": "new-access",
+                "refresh_token": refresh_token,
+                "scope": "identity",
+                "token_type": "bearer",
+                "expires_in": 3600,
+            }))
+            .unwrap()
+        };
+        // Provider omitted `refresh_token` (Reddit), the old one is kept.
+        let tokens = keep_refresh_token(response(None), "old-refresh");
+        assert_eq!("new-access", tokens.access_token);
+        assert_eq!(Some("old-refresh"), tokens.refresh_token.as_deref());
+        // Provider rotated it.
+        let tokens = keep_refresh_token(response(Some("new-refresh")), "old-refresh");
+        assert_eq!(Some("new-refresh"), tokens.refresh_token.as_deref());
+    }
+
+    #[test]
+    fn test_make_signin_link() {
+        let oauth = OauthHelper {
+            client_id: "my-client".to_owned(),
+            client_secret: "my-secret".to_owned().into(),
+            provider_authorize_url: "https://example.com/authorize".to_owned(),
+            provider_token_url: "https://example.com/token".to_owned(),
+            callback_url: "https://example.com/callback".to_owned(),
+            scopes: vec!["openid".to_owned(), "offline_access".to_owned()],
+            extra_params: vec![("duration".to_owned(), "permanent".to_owned())],
+        };
+        let url = oauth.make_signin_link("my-state");
+        let query = url.query_pairs().collect::<Vec<_>>();
+        assert!(query.contains(&("scope".into(), "openid offline_access".into())));
+        assert!(query.contains(&("duration".into(), "permanent".into())));
+        assert!(query.contains(&("state".into(), "my-state".into())));
+    }
+
+    fn oauth_builder() -> OauthHelperBuilder {
+        OauthHelper::builder()
+            .client_id("my-client")
+            .client_secret("my-secret".to_owned().into())
+            .provider_authorize_url("https://example.com/authorize")
+            .provider_token_url("https://example.com/token")
+            .callback_url("https://example.com/callback")
+    }
+
+    #[test]
+    fn test_oauth_helper_builder() {
+        let oauth = oauth_builder()
+            .scopes(vec!["identity".to_owned()])
+            .build()
+            .unwrap();
+        assert_eq!("https://example.com/token", oauth.provider_token_url);
+        assert_eq!(vec!["identity".to_owned()], oauth.scopes);
+        assert!(oauth.extra_params.is_empty());
+
+        let err = OauthHelper::builder()
+            .client_id("my-client")
+            .build()
+            .unwrap_err();
+        assert!(err.contains("`client_secret`"), "{}", err);
+    }
+
+    #[test]
+    fn test_oauth_helper_builder_bad_url() {
+        let err = oauth_builder()
+            .provider_token_url("example.com/token")
+            .build()
+            .unwrap_err();
+        assert!(err.contains("`provider_token_url`"), "{}", err);
+        assert!(err.contains("example.com/token"), "{}", err);
+
+        let err = oauth_builder()
+            .callback_url("/signin-reddit")
+            .build()
+            .unwrap_err();
+        assert!(err.contains("`callback_url`"), "{}", err);
+
+        let err = oauth_builder()
+            .provider_authorize_url("mailto:me@example.com")
+            .build()
+            .unwrap_err();
+        assert!(err.contains("`provider_authorize_url`"), "{}", err);
+    }
+
+    fn make_id_token(claims: serde_json::Value) -> String {
+        let encode = |value: serde_json::Value| {
+            base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+        };
+        format!(
+            "{}.{}.signature",
+            encode(serde_json::json!({ "alg": "RS256", "kid": "s1" })),
+            encode(claims),
+        )
+    }
+
+    #[test]
+    fn test_parse_rso_id_token() {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let id_token = make_id_token(serde_json::json!({
+            "sub": "my-puuid",
+            "iss": "https://auth.riotgames.com",
+            "aud": "championmains",
+            "iat": now,
+            "exp": now + 60,
+        }));
+        let identity = parse_rso_id_token(&id_token, "championmains").unwrap();
+        assert_eq!("my-puuid", identity.sub);
+        assert_eq!("https://auth.riotgames.com", identity.iss);
+
+        let expired = make_id_token(serde_json::json!({
+            "sub": "my-puuid",
+            "iss": "https://auth.riotgames.com",
+            "aud": "championmains",
+            "iat": now - 120,
+            "exp": now - 60,
+        }));
+        assert!(matches!(
+            parse_rso_id_token(&expired, "championmains"),
+            Err(AuthError::InvalidToken)
+        ));
+
+        let missing_sub = make_id_token(serde_json::json!({
+            "iss": "https://auth.riotgames.com",
+            "aud": "championmains",
+            "iat": now,
+            "exp": now + 60,
+        }));
+        assert!(matches!(
+            parse_rso_id_token(&missing_sub, "championmains"),
+            Err(AuthError::InvalidToken)
+        ));
+
+        assert!(matches!(
+            parse_rso_id_token("not-a-jwt", "championmains"),
+            Err(AuthError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_parse_rso_id_token_aud_iss() {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let id_token = |iss: &str, aud: serde_json::Value| {
+            make_id_token(serde_json::json!({
+                "sub": "my-puuid",
+                "iss": iss,
+                "aud": aud,
+                "iat": now,
+                "exp": now + 60,
+            }))
+        };
+
+        // Audience may be an array.
+        let identity = parse_rso_id_token(
+            &id_token(RSO_ISSUER, serde_json::json!(["other", "championmains"])),
+            "championmains",
+        )
+        .unwrap();
+        assert_eq!(vec!["other", "championmains"], identity.aud);
+
+        // Issued to another client.
+        for aud in [serde_json::json!("other"), serde_json::json!(["other"])] {
+            assert!(matches!(
+                parse_rso_id_token(&id_token(RSO_ISSUER, aud), "championmains"),
+                Err(AuthError::InvalidToken)
+            ));
+        }
+        // Missing audience.
+        let missing_aud = make_id_token(serde_json::json!({
+            "sub": "my-puuid",
+            "iss": RSO_ISSUER,
+            "iat": now,
+            "exp": now + 60,
+        }));
+        assert!(matches!(
+            parse_rso_id_token(&missing_aud, "championmains"),
+            Err(AuthError::InvalidToken)
+        ));
+        // Another issuer.
+        assert!(matches!(
+            parse_rso_id_token(
+                &id_token(
+                    "https://evil.example.com",
+                    serde_json::json!("championmains")
+                ),
+                "championmains",
+            ),
+            Err(AuthError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_create_now_ttl() {
+        let session_ttls = SessionTtls {
+            signed_in: Duration::from_secs(120),
+            ..SessionTtls::default()
+        };
+        let user_id = UserId::new(5).unwrap();
+        let claims = JwtSessionState::create_now(
+            &session_ttls,
+            AUDIENCE,
+            SessionState::SignedIn { user_id },
+        );
+        assert_eq!(
+            Duration::from_secs(120),
+            claims.exp.duration_since(claims.iat).unwrap()
+        );
+        let claims = JwtSessionState::create_now(
+            &session_ttls,
+            AUDIENCE,
+            SessionState::Transition {
+                user_id,
+                state_nonce: [0; 16],
+            },
+        );
+        assert_eq!(
+            Duration::from_secs(60),
+            claims.exp.duration_since(claims.iat).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_create_now_unique_nonces() {
+        let nonces = (0..10_000)
+            .map(|_| {
+                JwtSessionState::create_now(
+                    &SessionTtls::default(),
+                    AUDIENCE,
+                    SessionState::Anonymous,
+                )
+                .nonce
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(10_000, nonces.len());
+    }
+
+    fn jwt_key(seed: u8) -> JwtKey {
+        JwtKey::new(Hmac::new_from_slice(&[seed; 32]).unwrap())
+    }
+
+    #[test]
+    fn test_jwt_keys_rotation() {
+        let old = JwtKeys {
+            primary: jwt_key(1),
+            previous: Vec::new(),
+        };
+        let token = create_session_state_token(
+            &old,
+            &SessionTtls::default(),
+            AUDIENCE,
+            SessionState::Anonymous,
+        )
+        .unwrap();
+
+        let rotated = JwtKeys {
+            primary: jwt_key(2),
+            previous: vec![jwt_key(1)],
+        };
+        assert_ne!(rotated.primary.kid, rotated.previous[0].kid);
+        assert!(decode_session_state_token(&rotated, Duration::ZERO, AUDIENCE, &token).is_ok());
+
+        let dropped = JwtKeys {
+            primary: jwt_key(2),
+            previous: Vec::new(),
+        };
+        assert!(matches!(
+            decode_session_state_token(&dropped, Duration::ZERO, AUDIENCE, &token),
+            Err(AuthError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_jwt_keys_no_kid() {
+        // Signed without a `kid` header, as before key rotation.
+        let claims =
+            JwtSessionState::create_now(&SessionTtls::default(), AUDIENCE, SessionState::Anonymous);
+        let token = claims.sign_with_key(&jwt_key(1).hmac).unwrap();
+        let rotated = JwtKeys {
+            primary: jwt_key(2),
+            previous: vec![jwt_key(1)],
+        };
+        assert!(decode_session_state_token(&rotated, Duration::ZERO, AUDIENCE, &token).is_ok());
+    }
+
+    #[test]
+    fn test_audience() {
+        let jwt_keys = JwtKeys {
+            primary: jwt_key(1),
+            previous: Vec::new(),
+        };
+        let token = create_session_state_token(
+            &jwt_keys,
+            &SessionTtls::default(),
+            AUDIENCE,
+            SessionState::Anonymous,
+        )
+        .unwrap();
+        assert!(decode_session_state_token(&jwt_keys, Duration::ZERO, AUDIENCE, &token).is_ok());
+        assert!(matches!(
+            decode_session_state_token(&jwt_keys, Duration::ZERO, "https://evil.com", &token),
+            Err(AuthError::InvalidToken)
+        ));
+
+        // Issued before audiences were added.
+        let mut claims =
+            JwtSessionState::create_now(&SessionTtls::default(), AUDIENCE, SessionState::Anonymous);
+        claims.aud = None;
+        let token = jwt_keys.sign(claims).unwrap();
+        assert!(decode_session_state_token(&jwt_keys, Duration::ZERO, AUDIENCE, &token).is_ok());
+    }
+
+    #[test]
+    fn test_check_transition_state() {
+        let jwt_keys = JwtKeys {
+            primary: jwt_key(1),
+            previous: Vec::new(),
+        };
+        let session_ttls = SessionTtls::default();
+        let anonymous = || {
+            let claims =
+                JwtSessionState::create_now(&session_ttls, AUDIENCE, SessionState::Anonymous);
+            (claims.nonce, jwt_keys.sign(claims).unwrap())
+        };
+        let (nonce, state) = anonymous();
+        let (_other_nonce, other_state) = anonymous();
+        let check = |state: &str| {
+            check_transition_state(&jwt_keys, Duration::ZERO, AUDIENCE, &nonce, state)
+        };
+
+        assert!(check(&state).is_ok());
+        // State from a different (e.g. attacker's) browser.
+        assert!(matches!(check(&other_state), Err(AuthError::InvalidToken)));
+        // Non-anonymous token replayed as state.
+        let transition = create_session_state_token(
+            &jwt_keys,
+            &session_ttls,
+            AUDIENCE,
+            SessionState::Transition {
+                user_id: UserId::new(5).unwrap(),
+                state_nonce: nonce,
+            },
+        )
+        .unwrap();
+        assert!(matches!(check(&transition), Err(AuthError::InvalidToken)));
+    }
+
+    #[test]
+    fn test_rso_link_token() {
+        let jwt_keys = JwtKeys {
+            primary: jwt_key(1),
+            previous: Vec::new(),
+        };
+        let session_ttls = SessionTtls::default();
+        let identity = RsoIdentity {
+            sub: "my-puuid".to_owned(),
+            iss: RSO_ISSUER.to_owned(),
+            aud: vec!["championmains".to_owned()],
+            iat: SystemTime::now(),
+            exp: SystemTime::now() + Duration::from_secs(60),
+            cpid: Some("NA1".to_owned()),
+        };
+        let token = create_rso_link_token(&jwt_keys, &session_ttls, AUDIENCE, &identity).unwrap();
+        let claims = decode_rso_link_token(&jwt_keys, Duration::ZERO, AUDIENCE, &token).unwrap();
+        assert_eq!("my-puuid", claims.puuid);
+        assert_eq!(Some("NA1"), claims.cpid.as_deref());
+
+        assert!(matches!(
+            decode_rso_link_token(&jwt_keys, Duration::ZERO, "https://evil.com", &token),
+            Err(AuthError::InvalidToken)
+        ));
+        // Not interchangeable with session tokens.
+        assert!(matches!(
+            decode_session_state_token(&jwt_keys, Duration::ZERO, AUDIENCE, &token),
+            Err(AuthError::InvalidToken)
+        ));
+        let signed_in = create_session_state_token(
+            &jwt_keys,
+            &session_ttls,
+            AUDIENCE,
+            SessionState::SignedIn {
+                user_id: UserId::new(5).unwrap(),
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            decode_rso_link_token(&jwt_keys, Duration::ZERO, AUDIENCE, &signed_in),
+            Err(AuthError::InvalidToken)
+        ));
+
+        let store = SetNonceStore::default();
+        assert!(block_on(consume_rso_link_token(&store, &claims)).is_ok());
+        // Replayed, e.g. from the redirect URL.
+        assert!(matches!(
+            block_on(consume_rso_link_token(&store, &claims)),
+            Err(AuthError::Unauthorized(_))
+        ));
+        // Other link tokens are unaffected.
+        let other = create_rso_link_token(&jwt_keys, &session_ttls, AUDIENCE, &identity).unwrap();
+        let other = decode_rso_link_token(&jwt_keys, Duration::ZERO, AUDIENCE, &other).unwrap();
+        assert!(block_on(consume_rso_link_token(&store, &other)).is_ok());
+    }
+
+    /// In-memory [`NonceStore`], ignores expiration.
+    #[derive(Default)]
+    struct SetNonceStore(std::cell::RefCell<HashMap<[u8; 16], SystemTime>>);
+    impl NonceStore for SetNonceStore {
+        async fn insert_nonce(&self, nonce: &[u8; 16], exp: SystemTime) -> worker::Result<bool> {
+            Ok(self.0.borrow_mut().insert(*nonce, exp).is_none())
+        }
+    }
+    impl SetNonceStore {
+        /// Same as [`delete_expired_revoked_nonces`].
+        fn delete_expired(&self, now: SystemTime, skew: Duration) {
+            let cutoff = revoked_nonce_cutoff(now, skew);
+            self.0.borrow_mut().retain(|_nonce, exp| cutoff <= *exp);
+        }
+    }
+
+    #[test]
+    fn test_consume_transition_token() {
+        let session_ttls = SessionTtls::default();
+        let transition = || {
+            JwtSessionState::create_now(
+                &session_ttls,
+                AUDIENCE,
+                SessionState::Transition {
+                    user_id: UserId::new(5).unwrap(),
+                    state_nonce: [0; 16],
+                },
+            )
+        };
+        let store = SetNonceStore::default();
+        let claims = transition();
+        assert!(block_on(consume_transition_token(&store, &claims)).is_ok());
+        // Second exchange of the same token.
+        assert!(matches!(
+            block_on(consume_transition_token(&store, &claims)),
+            Err(AuthError::Unauthorized(_))
+        ));
+        // Other transition tokens are unaffected.
+        assert!(block_on(consume_transition_token(&store, &transition())).is_ok());
+
+        let anonymous =
+            JwtSessionState::create_now(&session_ttls, AUDIENCE, SessionState::Anonymous);
+        assert!(matches!(
+            block_on(consume_transition_token(&store, &anonymous)),
+            Err(AuthError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_consume_transition_token_skew() {
+        let skew = Duration::from_secs(10);
+        let claims = JwtSessionState::create_now(
+            &SessionTtls::default(),
+            AUDIENCE,
+            SessionState::Transition {
+                user_id: UserId::new(5).unwrap(),
+                state_nonce: [0; 16],
+            },
+        );
+        let store = SetNonceStore::default();
+        assert!(block_on(consume_transition_token(&store, &claims)).is_ok());
+
+        // Still accepted within the skew, so the used nonce must not be purged yet.
+        let now = claims.exp + skew;
+        store.delete_expired(now, skew);
+        assert!(claims.check_at(now, skew).is_ok());
+        assert!(matches!(
+            block_on(consume_transition_token(&store, &claims)),
+            Err(AuthError::Unauthorized(_))
+        ));
+
+        // Purged only once the token itself is rejected.
+        let now = claims.exp + skew + Duration::from_millis(1);
+        store.delete_expired(now, skew);
+        assert!(store.0.borrow().is_empty());
+        assert!(claims.check_at(now, skew).is_err());
+    }
+
+    #[test]
+    fn test_verify_callback_state() {
+        let jwt_keys = JwtKeys {
+            primary: jwt_key(1),
+            previous: Vec::new(),
+        };
+        let session_ttls = SessionTtls::default();
+        let anonymous = || {
+            create_session_state_token(&jwt_keys, &session_ttls, AUDIENCE, SessionState::Anonymous)
+                .unwrap()
+        };
+        let store = SetNonceStore::default();
+        let verify = |state_cookie: Option<&str>, state: &str| {
+            block_on(verify_callback_state(
+                &store,
+                &jwt_keys,
+                Duration::ZERO,
+                AUDIENCE,
+                state_cookie,
+                state,
+            ))
+        };
+
+        let state = anonymous();
+        let nonce = verify(Some(state.as_str()), &state).unwrap();
+        let claims: JwtSessionState = jwt_keys.verify(&state).unwrap();
+        assert_eq!(claims.nonce, nonce);
+        // Replayed callback.
+        assert!(matches!(
+            verify(Some(state.as_str()), &state),
+            Err(AuthError::Unauthorized(_))
+        ));
+
+        // Attacker's state completed in the victim's browser, which holds its own (or no) cookie.
+        let attacker_state = anonymous();
+        let victim_state = anonymous();
+        assert!(matches!(
+            verify(Some(victim_state.as_str()), &attacker_state),
+            Err(AuthError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            verify(None, &attacker_state),
+            Err(AuthError::Unauthorized(_))
+        ));
+        // Rejected attempts do not use up the state.
+        assert!(verify(Some(attacker_state.as_str()), &attacker_state).is_ok());
+
+        // Not an anonymous token.
+        let signed_in = create_session_state_token(
+            &jwt_keys,
+            &session_ttls,
+            AUDIENCE,
+            SessionState::SignedIn {
+                user_id: UserId::new(5).unwrap(),
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            verify(Some(signed_in.as_str()), &signed_in),
+            Err(AuthError::MissingCredentials)
+        ));
+    }
+
+    #[test]
+    fn test_signin_state_cookie() {
+        let cookie = signin_state_cookie("a.b.c", Duration::from_secs(60)).unwrap();
+        assert_eq!(
+            "cm_signin_state=a.b.c; Max-Age=60; Path=/; HttpOnly; Secure; SameSite=Lax",
+            cookie
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::COOKIE,
+            HeaderValue::from_static("cm_session=x.y.z; cm_signin_state=a.b.c"),
+        );
+        assert_eq!(Some("a.b.c".to_owned()), signin_state(&headers));
+        assert_eq!(None, signin_state(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_check_admin_token() {
+        let admin_token = SecretString::from("hunter2".to_owned());
+        assert!(check_admin_token(Some(&admin_token), Some("hunter2")).is_ok());
+        for token in [
+            None,
+            Some(""),
+            Some("hunter"),
+            Some("hunter22"),
+            Some("hunter3"),
+        ] {
+            assert!(matches!(
+                check_admin_token(Some(&admin_token), token),
+                Err(AuthError::Unauthorized(_))
+            ));
+        }
+        // Disabled if unset.
+        assert!(matches!(
+            check_admin_token(None, Some("")),
+            Err(AuthError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn test_session_token_precedence() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            pairs
+                .iter()
+                .map(|&(name, value)| {
+                    (
+                        http::HeaderName::from_static(name),
+                        HeaderValue::from_static(value),
+                    )
+                })
+                .collect::<HeaderMap>()
+        };
+        let bearer = ("authorization", "Bearer header-token");
+        let cookie = ("cookie", "other=1; cm_session=cookie-token");
+
+        assert_eq!(None, session_token(&headers(&[])));
+        assert_eq!(
+            Some("header-token".to_owned()),
+            session_token(&headers(&[bearer]))
+        );
+        assert_eq!(
+            Some("cookie-token".to_owned()),
+            session_token(&headers(&[cookie]))
+        );
+        // Header takes precedence over cookie.
+        assert_eq!(
+            Some("header-token".to_owned()),
+            session_token(&headers(&[bearer, cookie]))
+        );
+        // Even an invalid header takes precedence over cookie.
+        assert_eq!(
+            None,
+            session_token(&headers(&[("authorization", "Basic abc"), cookie]))
+        );
+    }
+
+    #[test]
+    fn test_check_cookie_origin() {
+        let pages_origin = Url::parse("http://localhost:5173/").unwrap();
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            pairs
+                .iter()
+                .map(|&(name, value)| {
+                    (
+                        http::HeaderName::from_static(name),
+                        HeaderValue::from_static(value),
+                    )
+                })
+                .collect::<HeaderMap>()
+        };
+        let cookie = ("cookie", "cm_session=cookie-token");
+        let check = |method: Method, pairs: &[(&'static str, &'static str)]| {
+            check_cookie_origin(&method, &headers(pairs), &pages_origin)
+        };
+
+        assert!(check(Method::POST, &[cookie, ("origin", "http://localhost:5173")]).is_ok());
+        assert!(matches!(
+            check(Method::POST, &[cookie, ("origin", "https://evil.example")]),
+            Err(AuthError::Forbidden(_))
+        ));
+        assert!(matches!(
+            check(Method::DELETE, &[cookie]),
+            Err(AuthError::Forbidden(_))
+        ));
+        // Safe methods and bearer tokens are not checked.
+        assert!(check(Method::GET, &[cookie]).is_ok());
+        assert!(check(
+            Method::PATCH,
+            &[("authorization", "Bearer header-token"), cookie]
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_session_cookie() {
+        let cookie = session_cookie("a.b.c", Duration::from_secs(60)).unwrap();
+        assert_eq!(
+            "cm_session=a.b.c; Max-Age=60; Path=/; HttpOnly; Secure; SameSite=Lax",
+            cookie
+        );
+    }
+
+    #[test]
+    fn test_check_at_same_instant() {
+        // Created and verified within the same millisecond, with no skew allowed.
+        let claims =
+            JwtSessionState::create_now(&SessionTtls::default(), AUDIENCE, SessionState::Anonymous);
+        let now = claims.iat;
+        // Round trip through the whole-seconds encoded form, as when verified.
+        let claims: JwtSessionState =
+            serde_json::from_value(serde_json::to_value(&claims).unwrap()).unwrap();
+        assert!(claims.check_at(now, Duration::ZERO).is_ok());
+
+        // Verifying clock behind by up to the backdate.
+        let backdate = Duration::from_secs(5);
+        let session_ttls = SessionTtls {
+            nbf_backdate: backdate,
+            ..SessionTtls::default()
+        };
+        let claims = JwtSessionState::create_now(&session_ttls, AUDIENCE, SessionState::Anonymous);
+        assert_eq!(claims.iat - backdate, claims.nbf);
+        assert!(claims
+            .check_at(claims.iat - backdate, Duration::ZERO)
+            .is_ok());
+        assert!(claims
+            .check_at(
+                claims.iat - backdate - Duration::from_secs(1),
+                Duration::ZERO
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_check_at_skew() {
+        let skew = Duration::from_secs(10);
+        let claims =
+            JwtSessionState::create_now(&SessionTtls::default(), AUDIENCE, SessionState::Anonymous);
+        let (nbf, exp) = (claims.nbf, claims.exp);
+
+        assert!(claims.check_at(nbf, skew).is_ok());
+        assert!(claims.check_at(exp, skew).is_ok());
+        assert!(claims.check_at(nbf - skew, skew).is_ok());
+        assert!(claims.check_at(exp + skew, skew).is_ok());
+        assert!(claims
+            .check_at(nbf - skew - Duration::from_secs(1), skew)
+            .is_err());
+        assert!(claims
+            .check_at(exp + skew + Duration::from_secs(1), skew)
+            .is_err());
+        assert!(claims
+            .check_at(exp + Duration::from_secs(1), Duration::ZERO)
+            .is_err());
+    }
+
+    #[test]
+    fn test_revoked_nonce_cutoff() {
+        let skew = Duration::from_secs(10);
+        let claims = JwtSessionState::create_now(
+            &SessionTtls::default(),
+            AUDIENCE,
+            SessionState::SignedIn {
+                user_id: UserId::new(1).unwrap(),
+            },
+        );
+        // Same comparison as `delete_expired_revoked_nonces`.
+        let purged = |now| claims.exp < revoked_nonce_cutoff(now, skew);
+
+        // Last instant the token is accepted, so its revocation must be kept.
+        let now = claims.exp + skew;
+        assert!(claims.check_at(now, skew).is_ok());
+        assert!(!purged(now));
+
+        // Once the token is rejected the row may go.
+        let now = claims.exp + skew + Duration::from_millis(1);
+        assert!(claims.check_at(now, skew).is_err());
+        assert!(purged(now));
+
+        // Expired without skew, but still accepted with it.
+        let now = claims.exp + Duration::from_secs(1);
+        assert!(claims.check_at(now, skew).is_ok());
+        assert!(!purged(now));
+    }
 }
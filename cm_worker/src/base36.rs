@@ -1,10 +1,33 @@
-//! Decode base36.
+//! Encode and decode base36.
+use std::fmt;
+
 use itertools::Itertools;
 
+/// Error decoding base36, see [`decode`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Base36Error {
+    /// Non-alphanumeric character.
+    InvalidChar(char),
+    /// Value does not fit in a u64.
+    Overflow,
+}
+impl fmt::Display for Base36Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidChar(c) => write!(f, "Bad char for base36: {}", c),
+            Self::Overflow => write!(f, "Base36 value overflows u64."),
+        }
+    }
+}
+
 /// Decode a base36 string (0-9aA-zZ) as a u64.
-pub fn decode(s: &str) -> Result<u64, char> {
+pub fn decode(s: &str) -> Result<u64, Base36Error> {
     s.bytes()
-        .map(|b| b.is_ascii_alphanumeric().then_some(b).ok_or(b as char))
+        .map(|b| {
+            b.is_ascii_alphanumeric()
+                .then_some(b)
+                .ok_or(Base36Error::InvalidChar(b as char))
+        })
         .map_ok(|b| b.to_ascii_uppercase())
         .map_ok(|b| {
             if b.is_ascii_digit() {
@@ -13,7 +36,25 @@ pub fn decode(s: &str) -> Result<u64, char> {
                 b - b'A' + 10
             }
         })
-        .fold_ok(0, |a, b| a * 36 + (b as u64))
+        .fold_ok(Some(0_u64), |a, b| {
+            a?.checked_mul(36)?.checked_add(b as u64)
+        })?
+        .ok_or(Base36Error::Overflow)
+}
+
+/// Encode a u64 as a lowercase base36 string (0-9a-z), without leading zeros.
+pub fn encode(mut n: u64) -> String {
+    let mut bytes = Vec::new();
+    loop {
+        let b = (n % 36) as u8;
+        bytes.push(if b < 10 { b'0' + b } else { b'a' + b - 10 });
+        n /= 36;
+        if 0 == n {
+            break;
+        }
+    }
+    bytes.reverse();
+    String::from_utf8(bytes).unwrap()
 }
 
 #[cfg(test)]
@@ -23,5 +64,33 @@ mod test {
     #[test]
     fn test_decode() {
         assert_eq!(Ok(23806698), decode("e69d6"));
+        assert_eq!(Ok(23806698), decode("E69D6"));
+        assert_eq!(Err(Base36Error::InvalidChar('!')), decode("e6!d6"));
+    }
+
+    #[test]
+    fn test_decode_overflow() {
+        assert_eq!(Ok(u64::MAX), decode("3w5e11264sgsf"));
+        assert_eq!(Err(Base36Error::Overflow), decode("3w5e11264sgsg"));
+        assert_eq!(Err(Base36Error::Overflow), decode("zzzzzzzzzzzzzz"));
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!("0", encode(0));
+        assert_eq!("z", encode(35));
+        assert_eq!("10", encode(36));
+        assert_eq!("e69d6", encode(23806698));
+        assert_eq!("3w5e11264sgsf", encode(u64::MAX));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for n in (0..10_000)
+            .chain((0..64).map(|s| 1 << s))
+            .chain((0..64).map(|s| u64::MAX >> s))
+        {
+            assert_eq!(Ok(n), decode(&encode(n)), "{}", n);
+        }
     }
 }
@@ -0,0 +1,155 @@
+//! Caching of Riot API responses in Cloudflare KV.
+
+use std::future::Future;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use web_time::Duration;
+use worker::kv::KvStore;
+
+/// Minimum TTL supported by Cloudflare KV.
+pub const MIN_TTL: Duration = Duration::from_secs(60);
+
+/// A key-value store for cached responses.
+#[allow(async_fn_in_trait)]
+pub trait Cache {
+    /// Gets the value at `key`, if present and not expired.
+    async fn get_text(&self, key: &str) -> worker::Result<Option<String>>;
+    /// Sets the value at `key`, expiring after `ttl`.
+    async fn put_text(&self, key: &str, value: String, ttl: Duration) -> worker::Result<()>;
+}
+impl Cache for KvStore {
+    async fn get_text(&self, key: &str) -> worker::Result<Option<String>> {
+        Ok(self.get(key).text().await?)
+    }
+
+    async fn put_text(&self, key: &str, value: String, ttl: Duration) -> worker::Result<()> {
+        self.put(key, value)?
+            .expiration_ttl(ttl.max(MIN_TTL).as_secs())
+            .execute()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Returns the value cached at `key`, or calls `fetch` and caches its result for `ttl`.
+///
+/// If `bypass` is set the cached value is ignored, but the fetched result is still cached. Cache
+/// failures are logged and otherwise ignored, so the cache never causes `fetch`'s caller to fail.
+pub async fn get_or_fetch<T, E, C, F, Fut>(
+    cache: Option<&C>,
+    key: &str,
+    ttl: Duration,
+    bypass: bool,
+    fetch: F,
+) -> Result<T, E>
+where
+    T: Serialize + DeserializeOwned,
+    C: Cache,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let Some(cache) = cache else {
+        return fetch().await;
+    };
+    if !bypass {
+        match cache.get_text(key).await {
+            Ok(Some(text)) => match serde_json::from_str(&text) {
+                Ok(value) => return Ok(value),
+                Err(e) => log::warn!("Failed to decode cached value for `{}`: {}", key, e),
+            },
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to get cached value for `{}`: {}", key, e),
+        }
+    }
+    let value = fetch().await?;
+    match serde_json::to_string(&value) {
+        Ok(text) => {
+            if let Err(e) = cache.put_text(key, text, ttl).await {
+                log::warn!("Failed to cache value for `{}`: {}", key, e);
+            }
+        }
+        Err(e) => log::warn!("Failed to encode value for `{}`: {}", key, e),
+    }
+    Ok(value)
+}
+
+/// In-memory [`Cache`] for tests, ignores TTLs.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MapCache(
+    pub(crate) std::cell::RefCell<std::collections::HashMap<String, String>>,
+);
+#[cfg(test)]
+impl Cache for MapCache {
+    async fn get_text(&self, key: &str) -> worker::Result<Option<String>> {
+        Ok(self.0.borrow().get(key).cloned())
+    }
+
+    async fn put_text(&self, key: &str, value: String, _ttl: Duration) -> worker::Result<()> {
+        self.0.borrow_mut().insert(key.to_owned(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::future::ready;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn get(cache: Option<&MapCache>, bypass: bool, value: u64, fetches: &Cell<u32>) -> u64 {
+        let fetch = || {
+            fetches.set(fetches.get() + 1);
+            ready(Ok::<_, ()>(value))
+        };
+        block_on(get_or_fetch(cache, "key", MIN_TTL, bypass, fetch)).unwrap()
+    }
+
+    #[test]
+    fn test_get_or_fetch_miss_then_hit() {
+        let cache = MapCache::default();
+        let fetches = Cell::new(0);
+        assert_eq!(1, get(Some(&cache), false, 1, &fetches));
+        assert_eq!(1, fetches.get());
+        assert_eq!(Some("1"), cache.0.borrow().get("key").map(String::as_str));
+
+        assert_eq!(1, get(Some(&cache), false, 2, &fetches));
+        assert_eq!(1, fetches.get(), "Cache hit should not fetch.");
+    }
+
+    #[test]
+    fn test_get_or_fetch_bypass() {
+        let cache = MapCache::default();
+        let fetches = Cell::new(0);
+        assert_eq!(1, get(Some(&cache), false, 1, &fetches));
+        assert_eq!(2, get(Some(&cache), true, 2, &fetches));
+        assert_eq!(2, fetches.get());
+        // Bypass still refreshes the cached value.
+        assert_eq!(2, get(Some(&cache), false, 3, &fetches));
+        assert_eq!(2, fetches.get());
+    }
+
+    #[test]
+    fn test_get_or_fetch_no_cache() {
+        let fetches = Cell::new(0);
+        assert_eq!(1, get(None, false, 1, &fetches));
+        assert_eq!(2, get(None, false, 2, &fetches));
+        assert_eq!(2, fetches.get());
+    }
+
+    #[test]
+    fn test_get_or_fetch_bad_cached_value() {
+        let cache = MapCache::default();
+        cache
+            .0
+            .borrow_mut()
+            .insert("key".to_owned(), "{".to_owned());
+        let fetches = Cell::new(0);
+        assert_eq!(1, get(Some(&cache), false, 1, &fetches));
+        assert_eq!(1, fetches.get());
+    }
+}
@@ -0,0 +1,178 @@
+//! [`checked_query!`], [`worker::query!`] with a compile-time check of the `SELECT` column count.
+
+/// Like [`worker::query!`], but also checks at compile time that the number of columns selected
+/// (or returned by `RETURNING`) matches the arity of the row type `$row`, which must implement
+/// [`ColumnCount`]. `$row` is typically the [`crate::with::IgnoreKeys`] tuple used to deserialize
+/// the rows.
+///
+/// Only the number of columns is checked. Column names are NOT compared against anything, so the
+/// columns must still be listed in the same order as the row type's elements.
+///
+/// ```ignore
+/// let query = checked_query!(
+///     &db,
+///     (Same, Same),
+///     "SELECT id, reddit_user_name FROM user WHERE id = ?",
+///     user_id,
+/// )?;
+/// ```
+#[macro_export]
+macro_rules! checked_query {
+    ($db:expr, $row:ty, $query:literal $(, $args:expr)* $(,)?) => {{
+        const {
+            assert!(
+                $crate::checked_query::select_column_count($query)
+                    == <$row as $crate::checked_query::ColumnCount>::COLUMNS,
+                concat!("Selected columns do not match `", stringify!($row), "`: ", $query),
+            )
+        };
+        ::worker::query!($db, $query $(, $args)*)
+    }};
+}
+
+/// Number of columns in a row type, see [`checked_query!`].
+///
+/// Implemented for tuples. Implement it manually for structs deserialized with
+/// [`crate::with::Positional`].
+pub trait ColumnCount {
+    /// Number of columns.
+    const COLUMNS: usize;
+}
+
+macro_rules! tuple_impl {
+    ($len:literal $($t:ident)+) => {
+        impl<$($t,)+> ColumnCount for ($($t,)+) {
+            const COLUMNS: usize = $len;
+        }
+    };
+}
+
+tuple_impl!(1 T0);
+tuple_impl!(2 T0 T1);
+tuple_impl!(3 T0 T1 T2);
+tuple_impl!(4 T0 T1 T2 T3);
+tuple_impl!(5 T0 T1 T2 T3 T4);
+tuple_impl!(6 T0 T1 T2 T3 T4 T5);
+tuple_impl!(7 T0 T1 T2 T3 T4 T5 T6);
+tuple_impl!(8 T0 T1 T2 T3 T4 T5 T6 T7);
+tuple_impl!(9 T0 T1 T2 T3 T4 T5 T6 T7 T8);
+tuple_impl!(10 T0 T1 T2 T3 T4 T5 T6 T7 T8 T9);
+tuple_impl!(11 T0 T1 T2 T3 T4 T5 T6 T7 T8 T9 T10);
+tuple_impl!(12 T0 T1 T2 T3 T4 T5 T6 T7 T8 T9 T10 T11);
+tuple_impl!(13 T0 T1 T2 T3 T4 T5 T6 T7 T8 T9 T10 T11 T12);
+tuple_impl!(14 T0 T1 T2 T3 T4 T5 T6 T7 T8 T9 T10 T11 T12 T13);
+tuple_impl!(15 T0 T1 T2 T3 T4 T5 T6 T7 T8 T9 T10 T11 T12 T13 T14);
+tuple_impl!(16 T0 T1 T2 T3 T4 T5 T6 T7 T8 T9 T10 T11 T12 T13 T14 T15);
+
+/// Counts the result columns of `sql`: the columns of the last top-level `RETURNING` clause if
+/// there is one, otherwise of the first top-level `SELECT`. Returns zero if there are neither.
+///
+/// Columns are counted by top-level commas, ignoring commas in parentheses, string literals, and
+/// `--` comments.
+pub const fn select_column_count(sql: &str) -> usize {
+    let bytes = sql.as_bytes();
+    let mut columns = 0;
+    // Counting commas in a `SELECT` or `RETURNING` list.
+    let mut counting = false;
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' => {
+                // Skip string literal. `''` escapes are two adjacent literals, which is equivalent.
+                i += 1;
+                while i < bytes.len() && b'\'' != bytes[i] {
+                    i += 1;
+                }
+            }
+            b'-' if i + 1 < bytes.len() && b'-' == bytes[i + 1] => {
+                while i < bytes.len() && b'\n' != bytes[i] {
+                    i += 1;
+                }
+            }
+            b'(' => depth += 1,
+            b')' => depth -= 1,
+            b',' if counting && 0 == depth => columns += 1,
+            b if is_word_byte(b) => {
+                let start = i;
+                while i + 1 < bytes.len() && is_word_byte(bytes[i + 1]) {
+                    i += 1;
+                }
+                let word = (start, i + 1);
+                if 0 == depth {
+                    if eq_keyword(bytes, word, b"RETURNING")
+                        || (0 == columns && eq_keyword(bytes, word, b"SELECT"))
+                    {
+                        columns = 1;
+                        counting = true;
+                    } else if eq_keyword(bytes, word, b"FROM") {
+                        counting = false;
+                    }
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    columns
+}
+
+/// If `b` is part of an SQL identifier or keyword.
+const fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b'_' == b
+}
+
+/// If `bytes[start..end]` is `keyword`, case-insensitive.
+const fn eq_keyword(bytes: &[u8], (start, end): (usize, usize), keyword: &[u8]) -> bool {
+    if end - start != keyword.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < keyword.len() {
+        if bytes[start + i].to_ascii_uppercase() != keyword[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_select_column_count() {
+        assert_eq!(1, select_column_count("SELECT 1"));
+        assert_eq!(
+            4,
+            select_column_count(
+                "SELECT user_id, last_update, pending_update, last_forced_update
+                FROM summoner WHERE id = ?"
+            )
+        );
+        // Nested commas, subqueries, and literals are ignored.
+        assert_eq!(
+            3,
+            select_column_count(
+                "select COALESCE(SUM(p), 0), 'a,b' AS s, -- x, y
+                (SELECT MAX(a, b) FROM t) from (SELECT a, b FROM u)"
+            )
+        );
+        assert_eq!(
+            1,
+            select_column_count("UPDATE summoner SET a = ?, b = ? WHERE id = ? RETURNING id")
+        );
+        assert_eq!(
+            2,
+            select_column_count("INSERT INTO t(a, b) SELECT a, b FROM u RETURNING a, b")
+        );
+        assert_eq!(0, select_column_count("DELETE FROM t WHERE a IN (1, 2)"));
+    }
+
+    #[test]
+    fn test_column_count() {
+        assert_eq!(1, <(u64,) as ColumnCount>::COLUMNS);
+        assert_eq!(4, <(u8, u16, u32, u64) as ColumnCount>::COLUMNS);
+    }
+}
@@ -0,0 +1,84 @@
+//! Encryption for secrets stored at rest, i.e. oauth tokens in the DB.
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use secrecy::SecretString;
+
+/// Nonce length for AES-GCM, 96 bits.
+const NONCE_LEN: usize = 12;
+
+/// AES-256-GCM cipher for encrypting tokens before storing them.
+pub struct TokenCipher(Aes256Gcm);
+impl TokenCipher {
+    /// Creates a cipher from a 256-bit key.
+    pub fn new(key: &[u8]) -> Result<Self, String> {
+        Aes256Gcm::new_from_slice(key)
+            .map(Self)
+            .map_err(|_| format!("Key must be 32 bytes, len: {}", key.len()))
+    }
+
+    /// Encrypts `plaintext` with a random nonce. Returns URL-safe base64 of
+    /// `nonce || ciphertext`.
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .expect("AES-GCM encryption should not fail");
+        let mut bytes = nonce.to_vec();
+        bytes.extend(ciphertext);
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Decrypts a value produced by [`Self::encrypt`].
+    pub fn decrypt(&self, encrypted: &str) -> Result<SecretString, String> {
+        let bytes = base64::decode_config(encrypted, base64::URL_SAFE_NO_PAD)
+            .map_err(|e| format!("Failed to decode encrypted token: {}", e))?;
+        if bytes.len() < NONCE_LEN {
+            return Err("Encrypted token is too short.".to_owned());
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plaintext = self
+            .0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Failed to decrypt token.".to_owned())?;
+        String::from_utf8(plaintext)
+            .map(Into::into)
+            .map_err(|_| "Decrypted token is not UTF-8.".to_owned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use secrecy::ExposeSecret;
+
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let cipher = TokenCipher::new(&[7; 32]).unwrap();
+        let encrypted = cipher.encrypt("hello world");
+        assert_ne!("hello world", encrypted);
+        assert_ne!(encrypted, cipher.encrypt("hello world"));
+        assert_eq!(
+            "hello world",
+            cipher.decrypt(&encrypted).unwrap().expose_secret()
+        );
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let encrypted = TokenCipher::new(&[7; 32]).unwrap().encrypt("hello world");
+        let other = TokenCipher::new(&[8; 32]).unwrap();
+        assert!(other.decrypt(&encrypted).is_err());
+        assert!(other.decrypt("abc").is_err());
+    }
+
+    #[test]
+    fn test_bad_key_len() {
+        assert!(TokenCipher::new(&[7; 16]).is_err());
+    }
+}
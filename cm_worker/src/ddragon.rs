@@ -0,0 +1,63 @@
+//! Data Dragon static asset URLs.
+
+use riven::consts::Champion;
+
+use crate::profile::validate_bgskinid;
+
+/// Data Dragon CDN base URL.
+pub const DDRAGON_CDN: &str = "https://ddragon.leagueoflegends.com/cdn";
+
+/// Square champion icon, e.g.
+/// `https://ddragon.leagueoflegends.com/cdn/14.20.1/img/champion/MonkeyKing.png` for Wukong.
+pub fn champion_square_url(champ: Champion, version: &str) -> String {
+    format!(
+        "{}/{}/img/champion/{}.png",
+        DDRAGON_CDN,
+        version,
+        champ.identifier().unwrap_or("Unknown")
+    )
+}
+
+/// Splash art of a `profile_bgskinid` (`champID * 1000 + skinIdx`), e.g.
+/// `https://ddragon.leagueoflegends.com/cdn/img/champion/splash/Lux_8.jpg` for `99008`. Splash
+/// art is not versioned. `None` if `skinid` is invalid, see [`validate_bgskinid`].
+pub fn champion_splash_url(skinid: u64) -> Option<String> {
+    let (champ, skin_idx) = validate_bgskinid(skinid).ok()?;
+    let key = champ.identifier()?;
+    Some(format!(
+        "{}/img/champion/splash/{}_{}.jpg",
+        DDRAGON_CDN, key, skin_idx
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_champion_square_url() {
+        assert_eq!(
+            "https://ddragon.leagueoflegends.com/cdn/14.20.1/img/champion/MonkeyKing.png",
+            champion_square_url(Champion::WUKONG, "14.20.1")
+        );
+    }
+
+    #[test]
+    fn test_champion_splash_url() {
+        assert_eq!(
+            Some("https://ddragon.leagueoflegends.com/cdn/img/champion/splash/Lux_8.jpg"),
+            champion_splash_url(99008).as_deref()
+        );
+        // Default skin, `skinIdx = 0`.
+        assert_eq!(
+            Some("https://ddragon.leagueoflegends.com/cdn/img/champion/splash/Annie_0.jpg"),
+            champion_splash_url(1000).as_deref()
+        );
+        assert_eq!(
+            Some("https://ddragon.leagueoflegends.com/cdn/img/champion/splash/MonkeyKing_0.jpg"),
+            champion_splash_url(62000).as_deref()
+        );
+        assert_eq!(None, champion_splash_url(9999000));
+        assert_eq!(None, champion_splash_url(99100));
+    }
+}
@@ -1,21 +1,51 @@
 //! Error helpers.
 
 use axum::response::IntoResponse;
+use http::header::RETRY_AFTER;
 use http::StatusCode;
+use web_time::Duration;
+
+use crate::auth::AuthError;
 
 /// Error helper type.
 #[derive(Debug)]
 pub enum CmError {
     /// [`worker::Error`]
     WorkerError(worker::Error),
+    /// [`AuthError`], responds the same as the [`AuthError`] itself.
+    AuthError(AuthError),
     /// Generic internal server error.
     InternalServerError(String),
+    /// 400, e.g. invalid input.
+    BadRequest(String),
+    /// 403, e.g. accessing another user's resource.
+    Forbidden(String),
+    /// 404.
+    NotFound(String),
+    /// 409, e.g. a resource already belonging to another user.
+    Conflict(String),
+    /// 429, e.g. updating a resource too frequently.
+    TooManyRequests(String),
+    /// 429 with a `Retry-After` header, see [`crate::rate_limit`].
+    RateLimited(Duration),
+    /// 500, failed to decode a D1 row or JSON value. See [`DecodeContext`].
+    Deserialize(String),
 }
 impl From<worker::Error> for CmError {
     fn from(value: worker::Error) -> Self {
         Self::WorkerError(value)
     }
 }
+impl From<AuthError> for CmError {
+    fn from(value: AuthError) -> Self {
+        Self::AuthError(value)
+    }
+}
+impl From<serde_json::Error> for CmError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Deserialize(value.to_string())
+    }
+}
 impl IntoResponse for CmError {
     fn into_response(self) -> axum::response::Response {
         match self {
@@ -24,9 +54,107 @@ impl IntoResponse for CmError {
                 format!("Worker error: {}", worker_error),
             )
                 .into_response(),
+            CmError::AuthError(auth_error) => auth_error.into_response(),
             CmError::InternalServerError(msg) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response()
             }
+            CmError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg).into_response(),
+            CmError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg).into_response(),
+            CmError::NotFound(msg) => (StatusCode::NOT_FOUND, msg).into_response(),
+            CmError::Conflict(msg) => (StatusCode::CONFLICT, msg).into_response(),
+            CmError::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg).into_response(),
+            CmError::RateLimited(retry_after) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.as_secs().to_string())],
+                "Rate limit exceeded.",
+            )
+                .into_response(),
+            CmError::Deserialize(msg) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Deserialize error: {}", msg),
+            )
+                .into_response(),
         }
     }
 }
+
+/// Adds context to D1 row-decoding errors, e.g. from [`worker::D1Result::results`].
+pub trait DecodeContext<T> {
+    /// Converts an error into [`CmError::Deserialize`], naming the `query` whose rows failed to
+    /// decode.
+    fn decode_context(self, query: &str) -> Result<T, CmError>;
+}
+impl<T> DecodeContext<T> for worker::Result<T> {
+    fn decode_context(self, query: &str) -> Result<T, CmError> {
+        self.map_err(|e| CmError::Deserialize(format!("Failed to decode `{}` rows: {}", query, e)))
+    }
+}
+
+/// Checks that a D1 `batch` returned one result per query, i.e. `N` results. D1 should never
+/// return any other count, but if it does this errors instead of panicking.
+pub fn batch_results<T, const N: usize>(results: &[T]) -> Result<&[T; N], CmError> {
+    results.try_into().map_err(|_| {
+        CmError::InternalServerError(format!(
+            "Unexpected D1 batch result count: expected {}, got {}.",
+            N,
+            results.len()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_auth_error_status() {
+        let auth_errors = || {
+            [
+                AuthError::Unauthorized("x".to_owned()),
+                AuthError::Forbidden("x".to_owned()),
+                AuthError::MissingCredentials,
+                AuthError::TokenCreation("x".to_owned()),
+                AuthError::InvalidToken,
+                AuthError::UpstreamError,
+                AuthError::Internal("x".to_owned()),
+            ]
+        };
+        for (auth_error, expected) in auth_errors().into_iter().zip(auth_errors()) {
+            assert_eq!(
+                expected.into_response().status(),
+                CmError::from(auth_error).into_response().status()
+            );
+        }
+    }
+
+    #[test]
+    fn test_rate_limited_retry_after() {
+        let response = CmError::RateLimited(Duration::from_secs(45)).into_response();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!("45", response.headers()[RETRY_AFTER]);
+    }
+
+    #[test]
+    fn test_decode_context() {
+        let result: worker::Result<()> = Err(worker::Error::RustError("missing field".to_owned()));
+        let Err(CmError::Deserialize(msg)) = result.decode_context("user") else {
+            panic!("Expected `CmError::Deserialize`.");
+        };
+        assert!(msg.contains("`user`"), "{}", msg);
+        assert!(msg.contains("missing field"), "{}", msg);
+
+        let json_error = serde_json::from_str::<u64>("{").unwrap_err();
+        assert!(matches!(CmError::from(json_error), CmError::Deserialize(_)));
+    }
+
+    #[test]
+    fn test_batch_results() {
+        let [a, b] = batch_results(&[1, 2]).unwrap();
+        assert_eq!((1, 2), (*a, *b));
+
+        let Err(CmError::InternalServerError(msg)) = batch_results::<_, 3>(&[1, 2]) else {
+            panic!("Expected InternalServerError.");
+        };
+        assert_eq!("Unexpected D1 batch result count: expected 3, got 2.", msg);
+    }
+}
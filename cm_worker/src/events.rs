@@ -0,0 +1,245 @@
+//! Summoner update completion events, for `GET /summoner/:sid/events`.
+//!
+//! The webjob records each update's outcome in D1 with [`publish`], and the handler polls for it
+//! with [`wait_for_event`]. D1 reads see prior writes, so an event is seen by the next poll. (KV is
+//! not used as it caches negative lookups for about a minute, longer than [`STREAM_TIMEOUT`].)
+
+use std::future::Future;
+
+use serde_with::de::DeserializeAsWrap;
+use serde_with::ser::SerializeAsWrap;
+use serde_with::{serde_as, Same, TimestampMilliSeconds};
+use web_time::{Duration, SystemTime};
+use worker::{query, D1Database, Error};
+
+use crate::ids::SummonerId;
+use crate::with::{IgnoreKeys, WebSystemTime};
+
+/// How often to check D1 for an event.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for an event before closing the stream.
+pub const STREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Outcome of a summoner update.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateOutcome {
+    /// The update succeeded.
+    Completed,
+    /// The update failed. It may still be retried.
+    Failed,
+}
+impl UpdateOutcome {
+    /// SSE event name.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// A summoner update outcome, as stored in the `summoner_update_event` table.
+#[serde_as]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct UpdateEvent {
+    /// Outcome.
+    pub outcome: UpdateOutcome,
+    /// When the update finished.
+    #[serde_as(as = "WebSystemTime<TimestampMilliSeconds<i64>>")]
+    pub at: SystemTime,
+}
+
+/// Storage for each summoner's latest [`UpdateEvent`], implemented by [`D1Database`]. Allows
+/// testing without D1.
+#[allow(async_fn_in_trait)]
+pub trait EventStore {
+    /// Gets the summoner's latest event, `None` if there is none.
+    async fn get_event(&self, summoner_id: SummonerId) -> worker::Result<Option<UpdateEvent>>;
+    /// Sets the summoner's latest event, replacing any previous one.
+    async fn put_event(&self, summoner_id: SummonerId, event: UpdateEvent) -> worker::Result<()>;
+}
+impl EventStore for D1Database {
+    async fn get_event(&self, summoner_id: SummonerId) -> worker::Result<Option<UpdateEvent>> {
+        type EventWith = (Same, WebSystemTime<TimestampMilliSeconds<i64>>);
+        let row = checked_query!(
+            self,
+            EventWith,
+            "SELECT outcome, at FROM summoner_update_event WHERE summoner_id = ?",
+            summoner_id,
+        )?
+        .first::<DeserializeAsWrap<(UpdateOutcome, SystemTime), IgnoreKeys<EventWith>>>(None)
+        .await?
+        .map(DeserializeAsWrap::into_inner);
+        Ok(row.map(|(outcome, at)| UpdateEvent { outcome, at }))
+    }
+
+    async fn put_event(&self, summoner_id: SummonerId, event: UpdateEvent) -> worker::Result<()> {
+        let result = query!(
+            self,
+            "INSERT INTO summoner_update_event(summoner_id, outcome, at) VALUES (?, ?, ?)
+            ON CONFLICT(summoner_id) DO UPDATE SET outcome = excluded.outcome, at = excluded.at",
+            summoner_id,
+            event.outcome.as_str(),
+            <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(&event.at),
+        )?
+        .run()
+        .await?;
+        if let Some(error) = result.error() {
+            return Err(Error::RustError(error));
+        }
+        Ok(())
+    }
+}
+
+/// Records the summoner's update `outcome`, replacing any previous event.
+pub async fn publish(
+    store: &impl EventStore,
+    summoner_id: SummonerId,
+    outcome: UpdateOutcome,
+) -> worker::Result<()> {
+    let event = UpdateEvent {
+        outcome,
+        at: SystemTime::now(),
+    };
+    store.put_event(summoner_id, event).await
+}
+
+/// Gets the summoner's latest [`UpdateEvent`], if it happened at or after `since`.
+pub async fn latest(
+    store: &impl EventStore,
+    summoner_id: SummonerId,
+    since: SystemTime,
+) -> worker::Result<Option<UpdateEvent>> {
+    let event = store.get_event(summoner_id).await?;
+    Ok(event.filter(|event| since <= event.at))
+}
+
+/// Polls [`latest`] every [`POLL_INTERVAL`], using `sleep` to wait, until an event at or after
+/// `since` is found. Returns `None` after `max_polls` polls without one.
+pub async fn wait_for_event<S, SleepFut>(
+    store: &impl EventStore,
+    summoner_id: SummonerId,
+    since: SystemTime,
+    max_polls: u32,
+    sleep: S,
+) -> Option<UpdateEvent>
+where
+    S: Fn(Duration) -> SleepFut,
+    SleepFut: Future<Output = ()>,
+{
+    for poll in 0..max_polls {
+        if 0 < poll {
+            sleep(POLL_INTERVAL).await;
+        }
+        match latest(store, summoner_id, since).await {
+            Ok(Some(event)) => return Some(event),
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to get summoner {} event: {}", summoner_id, e),
+        }
+    }
+    None
+}
+
+/// Formats a server-sent event.
+pub fn format_sse(event: &str, data: &str) -> String {
+    format!("event: {}\ndata: {}\n\n", event, data)
+}
+
+/// The SSE message for the result of [`wait_for_event`]: the event, or `timeout`.
+pub fn event_message(event: Option<UpdateEvent>) -> String {
+    match event {
+        Some(event) => format_sse(
+            event.outcome.as_str(),
+            &serde_json::to_string(&event).unwrap_or_default(),
+        ),
+        None => format_sse("timeout", "null"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::future::ready;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// In-memory [`EventStore`].
+    #[derive(Default)]
+    struct MapEvents(RefCell<HashMap<SummonerId, UpdateEvent>>);
+    impl EventStore for MapEvents {
+        async fn get_event(&self, summoner_id: SummonerId) -> worker::Result<Option<UpdateEvent>> {
+            Ok(self.0.borrow().get(&summoner_id).copied())
+        }
+
+        async fn put_event(
+            &self,
+            summoner_id: SummonerId,
+            event: UpdateEvent,
+        ) -> worker::Result<()> {
+            self.0.borrow_mut().insert(summoner_id, event);
+            Ok(())
+        }
+    }
+
+    fn sid(id: u64) -> SummonerId {
+        SummonerId::new(id).unwrap()
+    }
+
+    #[test]
+    fn test_publish_latest() {
+        let store = MapEvents::default();
+        let before = SystemTime::now() - Duration::from_secs(1);
+        assert_eq!(None, block_on(latest(&store, sid(5), before)).unwrap());
+
+        block_on(publish(&store, sid(5), UpdateOutcome::Completed)).unwrap();
+        let event = block_on(latest(&store, sid(5), before)).unwrap().unwrap();
+        assert_eq!(UpdateOutcome::Completed, event.outcome);
+        // Other summoners and stale events are ignored.
+        assert_eq!(None, block_on(latest(&store, sid(6), before)).unwrap());
+        let after = SystemTime::now() + Duration::from_secs(1);
+        assert_eq!(None, block_on(latest(&store, sid(5), after)).unwrap());
+    }
+
+    #[test]
+    fn test_wait_for_event() {
+        let store = MapEvents::default();
+        let since = SystemTime::now() - Duration::from_secs(1);
+        let sleeps = Cell::new(0);
+        // Publishes on the second sleep.
+        let sleep = |_| {
+            sleeps.set(sleeps.get() + 1);
+            if 2 == sleeps.get() {
+                let event = UpdateEvent {
+                    outcome: UpdateOutcome::Failed,
+                    at: SystemTime::now(),
+                };
+                store.0.borrow_mut().insert(sid(5), event);
+            }
+            ready(())
+        };
+        let event = block_on(wait_for_event(&store, sid(5), since, 10, sleep)).unwrap();
+        assert_eq!(UpdateOutcome::Failed, event.outcome);
+        assert_eq!(2, sleeps.get());
+        assert!(event_message(Some(event)).starts_with("event: failed\ndata: {"));
+    }
+
+    #[test]
+    fn test_wait_for_event_timeout() {
+        let store = MapEvents::default();
+        let sleeps = Cell::new(0);
+        let sleep = |_| {
+            sleeps.set(sleeps.get() + 1);
+            ready(())
+        };
+        assert_eq!(
+            None,
+            block_on(wait_for_event(&store, sid(5), SystemTime::now(), 3, sleep))
+        );
+        assert_eq!(2, sleeps.get());
+        assert_eq!("event: timeout\ndata: null\n\n", event_message(None));
+    }
+}
@@ -0,0 +1,186 @@
+//! Reddit flair text generation from champion masteries.
+
+use riven::consts::Champion;
+use serde_with::{serde_as, TimestampMilliSeconds};
+use web_time::SystemTime;
+use worker::{query, D1Database};
+
+use crate::error::{batch_results, CmError, DecodeContext};
+use crate::ids::SummonerId;
+use crate::profile::validate_bgskinid;
+use crate::with::{ChampionAs, WebSystemTime};
+
+/// A summoner's mastery for one champion, as stored in `summoner_champion_mastery`.
+#[serde_as]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ChampionMastery {
+    /// Champion. Numeric ID, though string keys are accepted too, see [`ChampionAs`].
+    #[serde_as(as = "ChampionAs")]
+    pub champ_id: Champion,
+    /// Mastery points.
+    pub points: u64,
+    /// Mastery level.
+    pub level: u64,
+    /// Last time the champion was played, `None` if never.
+    #[serde_as(as = "Option<WebSystemTime<TimestampMilliSeconds<i64>>>")]
+    #[serde(default)]
+    pub last_play_time: Option<SystemTime>,
+    /// Mastery tokens earned towards the next level, if known.
+    #[serde(default)]
+    pub tokens_earned: Option<u64>,
+}
+
+/// Builds the flair text, e.g. `"Zed 1.2M | Mastery 350"`.
+///
+/// The featured champion is the champion of `bgskinid` if valid (see [`validate_bgskinid`]),
+/// otherwise the champion with the most points. The total is the sum of all mastery levels.
+/// Returns an empty string if there are no masteries and no `bgskinid`.
+pub fn build_flair(masteries: &[ChampionMastery], bgskinid: Option<u64>) -> String {
+    let featured = bgskinid
+        .and_then(|bgskinid| validate_bgskinid(bgskinid).ok())
+        .map(|(champion, _skin_idx)| champion)
+        .or_else(|| {
+            masteries
+                .iter()
+                .max_by_key(|mastery| mastery.points)
+                .map(|mastery| mastery.champ_id)
+        });
+    let Some(featured) = featured else {
+        return String::new();
+    };
+    let name = featured.name().unwrap_or("?");
+    let total_level = masteries.iter().map(|mastery| mastery.level).sum::<u64>();
+
+    match masteries
+        .iter()
+        .find(|mastery| featured == mastery.champ_id)
+    {
+        Some(mastery) => format!(
+            "{} {} | Mastery {}",
+            name,
+            abbreviate(mastery.points),
+            total_level
+        ),
+        None => format!("{} | Mastery {}", name, total_level),
+    }
+}
+
+/// Removes masteries with fewer than `min_points`, so trivial masteries do not affect flairs.
+pub fn retain_min_points(masteries: &mut Vec<ChampionMastery>, min_points: u64) {
+    masteries.retain(|mastery| min_points <= mastery.points);
+}
+
+/// Loads the summoner's masteries and owner's `profile_bgskinid`, and builds the flair text.
+/// Masteries with fewer than `min_points` are ignored, see [`retain_min_points`].
+pub async fn load_flair(
+    db: &D1Database,
+    summoner_id: SummonerId,
+    min_points: u64,
+) -> Result<String, CmError> {
+    #[derive(serde::Deserialize)]
+    struct UserRow {
+        profile_bgskinid: Option<u64>,
+    }
+    let user_query = query!(
+        &db,
+        "SELECT u.profile_bgskinid
+        FROM user u
+        JOIN summoner s ON s.user_id = u.id
+        WHERE s.id = ?",
+        summoner_id,
+    )?;
+    let masteries_query = query!(
+        &db,
+        "SELECT champ_id, points, level
+        FROM summoner_champion_mastery
+        WHERE summoner_id = ?",
+        summoner_id,
+    )?;
+
+    let results = db.batch(vec![user_query, masteries_query]).await?;
+    let [user_result, masteries_result] = batch_results(&results)?;
+    let user: UserRow = user_result
+        .results()
+        .decode_context("load_flair user")?
+        .into_iter()
+        .next()
+        .ok_or_else(|| {
+            CmError::NotFound(format!("Summoner with ID {} does not exist.", summoner_id))
+        })?;
+    let mut masteries: Vec<ChampionMastery> = masteries_result
+        .results()
+        .decode_context("load_flair masteries")?;
+    retain_min_points(&mut masteries, min_points);
+    Ok(build_flair(&masteries, user.profile_bgskinid))
+}
+
+/// Abbreviates `n`, e.g. `1234` to `"1.2k"` and `1234567` to `"1.2M"`.
+pub fn abbreviate(n: u64) -> String {
+    match n {
+        0..=999 => n.to_string(),
+        1_000..=999_999 => format!("{:.1}k", n as f64 / 1e3),
+        _ => format!("{:.1}M", n as f64 / 1e6),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mastery(champ_id: Champion, points: u64, level: u64) -> ChampionMastery {
+        ChampionMastery {
+            champ_id,
+            points,
+            level,
+            last_play_time: None,
+            tokens_earned: None,
+        }
+    }
+
+    #[test]
+    fn test_abbreviate() {
+        assert_eq!("0", abbreviate(0));
+        assert_eq!("999", abbreviate(999));
+        assert_eq!("1.0k", abbreviate(1_000));
+        assert_eq!("1.2k", abbreviate(1_234));
+        assert_eq!("1.2M", abbreviate(1_234_567));
+    }
+
+    #[test]
+    fn test_build_flair() {
+        let masteries = [
+            mastery(Champion::ANNIE, 20_000, 5),
+            mastery(Champion::ZED, 1_234_567, 7),
+        ];
+        assert_eq!("Zed 1.2M | Mastery 12", build_flair(&masteries, None));
+        assert_eq!(
+            "Annie 20.0k | Mastery 12",
+            build_flair(&masteries, Some(1000))
+        );
+        assert_eq!("Lux | Mastery 12", build_flair(&masteries, Some(99008)));
+        // Unknown champion, falls back to top points.
+        assert_eq!(
+            "Zed 1.2M | Mastery 12",
+            build_flair(&masteries, Some(9999000))
+        );
+        assert_eq!("", build_flair(&[], None));
+    }
+
+    #[test]
+    fn test_retain_min_points() {
+        let mut masteries = vec![
+            mastery(Champion::ANNIE, 999, 1),
+            mastery(Champion::LUX, 1_000, 2),
+            mastery(Champion::ZED, 1_234_567, 7),
+        ];
+        retain_min_points(&mut masteries, 1_000);
+        assert_eq!(
+            vec![Champion::LUX, Champion::ZED],
+            masteries.iter().map(|m| m.champ_id).collect::<Vec<_>>()
+        );
+        assert_eq!("Zed 1.2M | Mastery 9", build_flair(&masteries, None));
+
+        retain_min_points(&mut masteries, 0);
+        assert_eq!(2, masteries.len());
+    }
+}
@@ -0,0 +1,118 @@
+//! Typed PK IDs, so summoner and user IDs cannot be mixed up.
+//!
+//! Both are (de)serialized as plain integers, so they can be used in `Path` extractors, D1 query
+//! arguments, and D1 rows directly. Neither can be zero, so e.g. `Path<SummonerId>` rejects
+//! `/summoner/0` with `400 Bad Request` before the handler runs.
+
+use std::fmt;
+use std::num::{NonZeroU64, ParseIntError};
+use std::str::FromStr;
+
+/// Summoner PK ID, `summoner.id`. Never zero.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct SummonerId(NonZeroU64);
+impl SummonerId {
+    /// Wraps `id`, `None` if zero.
+    pub const fn new(id: u64) -> Option<Self> {
+        match NonZeroU64::new(id) {
+            Some(id) => Some(Self(id)),
+            None => None,
+        }
+    }
+
+    /// The ID as a plain integer.
+    pub fn get(self) -> u64 {
+        self.0.get()
+    }
+}
+impl From<NonZeroU64> for SummonerId {
+    fn from(id: NonZeroU64) -> Self {
+        Self(id)
+    }
+}
+impl fmt::Display for SummonerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl FromStr for SummonerId {
+    type Err = ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+/// User PK ID, `user.id`. Never zero.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct UserId(NonZeroU64);
+impl UserId {
+    /// Wraps `id`, `None` if zero.
+    pub const fn new(id: u64) -> Option<Self> {
+        match NonZeroU64::new(id) {
+            Some(id) => Some(Self(id)),
+            None => None,
+        }
+    }
+
+    /// The ID as a plain integer.
+    pub fn get(self) -> u64 {
+        self.0.get()
+    }
+}
+impl From<NonZeroU64> for UserId {
+    fn from(id: NonZeroU64) -> Self {
+        Self(id)
+    }
+}
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl FromStr for UserId {
+    type Err = ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ids_serde() {
+        let sid: SummonerId = serde_json::from_str("12").unwrap();
+        assert_eq!(12, sid.get());
+        assert_eq!("12", serde_json::to_string(&sid).unwrap());
+        assert!(serde_json::from_str::<SummonerId>("0").is_err());
+
+        let user_id: UserId = serde_json::from_str("5").unwrap();
+        assert_eq!(5, user_id.get());
+        assert_eq!("5", serde_json::to_string(&user_id).unwrap());
+        assert!(serde_json::from_str::<UserId>("0").is_err());
+    }
+
+    #[test]
+    fn test_ids_new() {
+        assert_eq!(Some(12), SummonerId::new(12).map(SummonerId::get));
+        assert_eq!(None, SummonerId::new(0));
+        assert_eq!(Some(5), UserId::new(5).map(UserId::get));
+        assert_eq!(None, UserId::new(0));
+    }
+
+    #[test]
+    fn test_ids_from_str() {
+        assert_eq!(12, "12".parse::<SummonerId>().unwrap().get());
+        assert!("0".parse::<SummonerId>().is_err());
+        assert_eq!("5", "5".parse::<UserId>().unwrap().to_string());
+        assert!("0".parse::<UserId>().is_err());
+        assert!("-1".parse::<SummonerId>().is_err());
+    }
+}
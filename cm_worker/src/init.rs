@@ -1,23 +1,30 @@
 //! Helper utilities.
 
+use std::collections::HashMap;
+use std::fmt;
+use std::num::{NonZeroU32, NonZeroU8, NonZeroUsize};
+use std::str::FromStr;
 use std::sync::{Once, OnceLock};
 
 use cm_macro::FromRefStatic;
-use hmac::Hmac;
+use hmac::{Hmac, Mac};
 use riven::reqwest::Client;
 use riven::RiotApi;
 use secrecy::{ExposeSecret, SecretString};
-use sha2::Sha512;
 use url::Url;
 use web_sys::console;
+use web_time::Duration;
+use worker::kv::KvStore;
 use worker::{console_error, console_log, D1Database, Env, Error, Queue, Result};
 
-use crate::auth::OauthHelper;
+use crate::auth::{JwtKey, JwtKeys, OauthHelper, OauthProvider, SessionTtls};
+use crate::crypt::TokenCipher;
+use crate::rate_limit::RateLimitConfig;
 use crate::webjob::WebjobConfig;
 
 /// Initialize [`log`] logging into Cloudflare's [`console`] logging system, if not already
-/// initialized.
-pub fn init_logging() {
+/// initialized. The max level is read from the `LOG_LEVEL` env var, default `Info`.
+pub fn init_logging(env: &Env) {
     static ONCE: Once = Once::new();
     ONCE.call_once(|| {
         {
@@ -31,8 +38,8 @@ pub fn init_logging() {
             struct ConsoleLog;
             static LOG: ConsoleLog = ConsoleLog;
             impl log::Log for ConsoleLog {
-                fn enabled(&self, _metadata: &log::Metadata) -> bool {
-                    true // TODO
+                fn enabled(&self, metadata: &log::Metadata) -> bool {
+                    metadata.level() <= log::max_level()
                 }
 
                 fn log(&self, record: &log::Record) {
@@ -43,23 +50,36 @@ pub fn init_logging() {
                         log::Level::Debug => console::debug_1,
                         log::Level::Trace => console::trace_1,
                     };
-                    (method)(
-                        &format!(
+                    let message = match crate::request_id::current() {
+                        Some(request_id) => format!(
+                            "[{} {} {}] {}",
+                            record.level(),
+                            record.module_path().unwrap_or("?"),
+                            request_id,
+                            record.args()
+                        ),
+                        None => format!(
                             "[{} {}] {}",
                             record.level(),
                             record.module_path().unwrap_or("?"),
                             record.args()
-                        )
-                        .into(),
-                    );
+                        ),
+                    };
+                    (method)(&message.into());
                 }
 
                 fn flush(&self) {}
             }
+            let level = envvar_parsed_opt::<log::LevelFilter>(env, "LOG_LEVEL")
+                .unwrap_or_else(|e| {
+                    console_error!("Invalid `LOG_LEVEL`, using `Info`: {}", e);
+                    None
+                })
+                .unwrap_or(log::LevelFilter::Info);
             log::set_logger(&LOG).unwrap();
-            log::set_max_level(log::LevelFilter::Trace); // TODO
+            log::set_max_level(level);
 
-            log::info!("logger set");
+            log::info!("logger set, level: {}", level);
         }
     });
 }
@@ -73,107 +93,585 @@ pub struct AppStateOwned {
     pub db: D1Database,
     /// Webjob queue.
     pub webjob_queue: Queue,
+    /// Webjob dead-letter queue, for tasks which failed too many times.
+    pub webjob_dead_letter_queue: WebjobDeadLetterQueue,
     /// Riot API client.
     pub riot_api: RiotApi,
     /// General/Reddit API client.
     pub reqwest_client: Client,
-    /// Reddit Oauth helper.
-    pub reddit_oauth: RedditOauthHelper,
-    /// RSO Oauth helper.
-    pub rso_oauth: RsoOauthHelper,
-    /// HMAC for signing JWTs.
-    pub jwt_hmac: Hmac<Sha512>,
+    /// Timeout for [`Self::reqwest_client`] requests, see [`crate::outbound`].
+    pub http_timeout: HttpTimeout,
+    /// Oauth helpers, for each [`OauthProvider`].
+    pub oauth_helpers: OauthHelpers,
+    /// HMAC keys for signing and verifying JWTs.
+    pub jwt_keys: JwtKeys,
+    /// Allowed clock skew when validating JWT times.
+    pub jwt_clock_skew: JwtClockSkew,
+    /// Expected audience of session JWTs.
+    pub jwt_audience: JwtAudience,
+    /// Session token lifetimes.
+    pub session_ttls: SessionTtls,
+    /// Cipher for encrypting oauth tokens stored in the DB.
+    pub token_cipher: TokenCipher,
     /// Origin (with trailing slash) for `cm_pages` static site.
     pub cm_pages_origin: CmPagesOrigin,
+    /// If `GET /` redirects to [`Self::cm_pages_origin`] permanently (`308`) rather than
+    /// temporarily (`307`).
+    pub pages_redirect_permanent: PagesRedirectPermanent,
     /// See [`crate::webjob::Task::SummonerBulkUpdate`].
     pub webjob_config: WebjobConfig,
+    /// Per-user limit on `POST /summoner/:sid/update`.
+    pub update_rate_limit: UpdateRateLimit,
+    /// Champions with fewer mastery points are left out of flairs and public profiles, see
+    /// [`crate::flair::retain_min_points`].
+    pub flair_min_points: FlairMinPoints,
+    /// Data Dragon version, for champion image URLs. See [`crate::ddragon`].
+    pub ddragon_version: DdragonVersion,
+    /// Bearer token for `/admin` routes, which are disabled if unset. See [`crate::auth::Admin`].
+    pub admin_token: AdminToken,
+    /// Optional KV store, for caching. See [`crate::cache`]. Bound from `BINDING_KV`, `None` if
+    /// unbound (e.g. local dev), so handlers extract `State<&'static Option<KvStore>>`.
+    pub kv: Option<KvStore>,
 }
 
 /// Get the AppState, initializing it if needed.
 pub fn get_appstate(env: &Env) -> worker::Result<AppState> {
     static ONCE: OnceLock<AppStateOwned> = OnceLock::new();
     ONCE.get_or_try_init(|| {
+        validate_config(env).map_err(|problems| {
+            Error::RustError(format!("Invalid config:\n{}", problems.join("\n")))
+        })?;
+
         let db = env.d1("BINDING_D1_DB").unwrap();
         let webjob_queue = env.queue("BINDING_QUEUE_WEBJOB").unwrap();
+        let kv = env.kv("BINDING_KV").ok();
+        let webjob_dead_letter_queue =
+            WebjobDeadLetterQueue(env.queue("BINDING_QUEUE_WEBJOB_DEAD_LETTER").unwrap());
         let riot_api = RiotApi::new(env.secret("RGAPI_KEY").unwrap().to_string());
+        let http_timeout = HttpTimeout(
+            envvar_secs(env, "HTTP_TIMEOUT_SECS")?.unwrap_or(crate::outbound::DEFAULT_TIMEOUT),
+        );
+        let subreddit = reddit_subreddit(|name| envvar(env, name).ok())
+            .ok_or_else(|| Error::RustError("Missing `REDDIT_SUBREDDIT`.".to_owned()))?;
         let reqwest_client = {
-            let user_agent = format!(
-                "cmflairs:{client_id}:{version} (by /u/{reddit_user})",
-                client_id = secret(env, "REDDIT_CLIENT_ID")?.expose_secret(),
-                version = option_env!("GIT_HASH").unwrap_or("localdev"),
-                reddit_user = secret(env, "REDDIT_OWNER_USERNAME")?.expose_secret(),
+            let user_agent = crate::reddit::user_agent(
+                secret(env, "REDDIT_CLIENT_ID")?.expose_secret(),
+                crate::GIT_HASH,
+                secret(env, "REDDIT_OWNER_USERNAME")?.expose_secret(),
+                &subreddit,
             );
             log::info!(
                 "Initializing reqwest client with user agent: {:?}",
                 user_agent
             );
-            Client::builder()
-                .user_agent(user_agent)
+            let builder = Client::builder().user_agent(user_agent);
+            // Not supported on `wasm32`, see `crate::outbound`.
+            #[cfg(not(target_arch = "wasm32"))]
+            let builder = builder
+                .timeout(http_timeout.0)
+                .connect_timeout(crate::outbound::CONNECT_TIMEOUT);
+            builder
                 .build()
                 .map_err(|e| format!("Failed to build reqwest client: {}", e))?
         };
-        let reddit_oauth = RedditOauthHelper(OauthHelper {
-            client_id: envvar(env, "REDDIT_CLIENT_ID")?,
-            client_secret: secret(env, "REDDIT_CLIENT_SECRET")?,
-            provider_authorize_url: envvar(env, "REDDIT_PROVIDER_AUTHORIZE_URL")?,
-            provider_token_url: envvar(env, "REDDIT_PROVIDER_TOKEN_URL")?,
-            callback_url: envvar(env, "REDDIT_CALLBACK_URL")?,
-        });
-        let rso_oauth = RsoOauthHelper(OauthHelper {
-            client_id: envvar(env, "RSO_CLIENT_ID")?,
-            client_secret: secret(env, "RSO_CLIENT_SECRET")?,
-            provider_authorize_url: envvar(env, "RSO_PROVIDER_AUTHORIZE_URL")?,
-            provider_token_url: envvar(env, "RSO_PROVIDER_TOKEN_URL")?,
-            callback_url: envvar(env, "RSO_CALLBACK_URL")?,
-        });
-        let jwt_hmac = {
-            let secret = secret(env, "HMAC_SECRET")?;
-            let secret = base64::decode_config(secret.expose_secret(), base64::URL_SAFE_NO_PAD)
-                .map_err(|e| format!("Failed to decode `HMAC_SECRET`: {}", e))?;
-            if secret.len() < 32 {
-                return Result::Err(Error::RustError(format!(
-                    "`HMAC_SECRET` is too short, len: {}",
-                    secret.len(),
-                )));
+        let oauth_helpers = OauthHelpers(
+            OauthProvider::ALL
+                .into_iter()
+                .map(|provider| Ok((provider, oauth_helper(env, provider)?)))
+                .collect::<Result<_>>()?,
+        );
+        let jwt_keys = JwtKeys {
+            primary: jwt_key(secret(env, "HMAC_SECRET")?.expose_secret(), "HMAC_SECRET")?,
+            previous: env
+                .secret("HMAC_SECRET_PREVIOUS")
+                .map(|v| v.to_string())
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(|secret| jwt_key(secret, "HMAC_SECRET_PREVIOUS"))
+                .collect::<Result<_>>()?,
+        };
+        let jwt_clock_skew = JwtClockSkew(
+            envvar_parsed_opt::<u64>(env, "JWT_CLOCK_SKEW_SECS")?
+                .map_or(Duration::from_secs(10), Duration::from_secs),
+        );
+        let jwt_audience = JwtAudience(envvar(env, "JWT_AUDIENCE")?);
+        let session_ttls = {
+            let default = SessionTtls::default();
+            SessionTtls {
+                anonymous: envvar_secs(env, "SESSION_TTL_ANON_SECS")?.unwrap_or(default.anonymous),
+                transition: envvar_secs(env, "SESSION_TTL_TRANSITION_SECS")?
+                    .unwrap_or(default.transition),
+                signed_in: envvar_secs(env, "SESSION_TTL_SIGNED_IN_SECS")?
+                    .unwrap_or(default.signed_in),
+                nbf_backdate: envvar_secs(env, "JWT_NBF_BACKDATE_SECS")?
+                    .unwrap_or(default.nbf_backdate),
             }
-            hmac::Mac::new_from_slice(&secret)
-                .map_err(|e| format!("Failed to create hmac: {}", e))?
+        };
+        let token_cipher = {
+            let secret = secret(env, "OAUTH_TOKEN_SECRET")?;
+            let secret = base64::decode_config(secret.expose_secret(), base64::URL_SAFE_NO_PAD)
+                .map_err(|e| format!("Failed to decode `OAUTH_TOKEN_SECRET`: {}", e))?;
+            TokenCipher::new(&secret).map_err(|e| format!("Invalid `OAUTH_TOKEN_SECRET`: {}", e))?
         };
         let cm_pages_origin = CmPagesOrigin(
             Url::parse(&envvar(env, "PAGES_ORIGIN")?)
                 .map_err(|e| format!("Invalid url in `PAGES_ORIGIN`: {}", e))?,
         );
+        let pages_redirect_permanent = PagesRedirectPermanent(
+            envvar_parsed_opt(env, "PAGES_REDIRECT_PERMANENT")?.unwrap_or(false),
+        );
         let webjob_config = WebjobConfig {
-            bulk_update_batch_size: envvar(env, "WEBJOB_BULK_UPDATE_BATCH_SIZE")?
-                .parse()
-                .map_err(|e| Error::RustError(format!("Env var `WEBJOB_BULK_UPDATE_BATCH_SIZE` should be a positive integer string: {}", e)))?,
+            bulk_update_batch_size: envvar_parsed(env, "WEBJOB_BULK_UPDATE_BATCH_SIZE")?,
+            update_cooldown: envvar_parsed_opt::<u64>(env, "WEBJOB_UPDATE_COOLDOWN_SECS")?
+                .map_or(Duration::from_secs(60), Duration::from_secs),
+            max_attempts: envvar_parsed(env, "WEBJOB_MAX_ATTEMPTS")?,
+            batch_chunk_size: envvar_parsed_opt::<NonZeroUsize>(env, "WEBJOB_D1_BATCH_CHUNK_SIZE")?
+                .map_or(50, NonZeroUsize::get),
+            match_history_count: envvar_parsed_opt(env, "WEBJOB_MATCH_HISTORY_COUNT")?
+                .unwrap_or(NonZeroU8::new(20).unwrap()),
+            rate_limit_max_retries: envvar_parsed_opt(env, "WEBJOB_RATE_LIMIT_MAX_RETRIES")?
+                .unwrap_or(1),
+            record_history: envvar_parsed_opt(env, "WEBJOB_RECORD_HISTORY")?.unwrap_or(false),
+            flair_subreddit: subreddit,
+            flair_template_id: envvar(env, "REDDIT_FLAIR_TEMPLATE_ID")
+                .ok()
+                .filter(|id| !id.is_empty()),
+            mastery_cache_ttl: envvar_secs(env, "WEBJOB_MASTERY_CACHE_TTL_SECS")?,
+            concurrency: envvar_parsed_opt::<NonZeroUsize>(env, "WEBJOB_CONCURRENCY")?,
+            send_attempts: envvar_parsed_opt::<NonZeroU32>(env, "WEBJOB_SEND_ATTEMPTS")?
+                .map_or(3, NonZeroU32::get),
+            top_champs: envvar_parsed_opt(env, "WEBJOB_TOP_CHAMPS")?,
         };
+        let update_rate_limit = UpdateRateLimit(RateLimitConfig {
+            max_requests: envvar_parsed_opt(env, "UPDATE_RATE_LIMIT_MAX")?.unwrap_or(10),
+            window: envvar_secs(env, "UPDATE_RATE_LIMIT_WINDOW_SECS")?
+                .unwrap_or(Duration::from_secs(60)),
+        });
+        let flair_min_points =
+            FlairMinPoints(envvar_parsed_opt(env, "FLAIR_MIN_POINTS")?.unwrap_or(0));
+        let ddragon_version = DdragonVersion(envvar(env, "DDRAGON_VERSION")?);
+        let admin_token = AdminToken(secret(env, "ADMIN_TOKEN").ok());
         Ok(AppStateOwned {
             db,
             webjob_queue,
+            webjob_dead_letter_queue,
             riot_api,
             reqwest_client,
-            reddit_oauth,
-            rso_oauth,
-            jwt_hmac,
+            http_timeout,
+            oauth_helpers,
+            jwt_keys,
+            jwt_clock_skew,
+            jwt_audience,
+            session_ttls,
+            token_cipher,
             cm_pages_origin,
+            pages_redirect_permanent,
             webjob_config,
+            update_rate_limit,
+            flair_min_points,
+            ddragon_version,
+            admin_token,
+            kv,
         })
     })
 }
 
+/// Checks that all required env vars and secrets are present and well-formed. Returns every problem
+/// found, so a misconfigured deployment fails at startup listing exactly what to fix.
+pub fn validate_config(env: &Env) -> std::result::Result<(), Vec<String>> {
+    validate_config_with(
+        |name| env.var(name).ok().map(|v| v.to_string()),
+        |name| env.secret(name).ok().map(|v| v.to_string()),
+    )
+}
+
+/// [`validate_config`], looking up env vars with `var` and secrets with `secret`.
+fn validate_config_with(
+    var: impl Fn(&str) -> Option<String>,
+    secret: impl Fn(&str) -> Option<String>,
+) -> std::result::Result<(), Vec<String>> {
+    type Check = fn(&str) -> std::result::Result<(), String>;
+    let mut problems = Vec::new();
+    let mut require = |name: &str, value: Option<String>, check: Check| match value {
+        None => problems.push(format!("Missing `{}`.", name)),
+        Some(value) => {
+            if let Err(e) = check(&value) {
+                problems.push(format!("Invalid `{}`: {}", name, e));
+            }
+        }
+    };
+
+    require("RGAPI_KEY", secret("RGAPI_KEY"), check_rgapi_key);
+    require(
+        "REDDIT_OWNER_USERNAME",
+        secret("REDDIT_OWNER_USERNAME"),
+        check_non_empty,
+    );
+    require("HMAC_SECRET", secret("HMAC_SECRET"), |v| {
+        check_base64_len(v, |len| 32 <= len, "at least 32")
+    });
+    if let Some(previous) = secret("HMAC_SECRET_PREVIOUS") {
+        // Optional, whitespace-separated.
+        require("HMAC_SECRET_PREVIOUS", Some(previous), |v| {
+            v.split_whitespace()
+                .try_for_each(|v| check_base64_len(v, |len| 32 <= len, "at least 32"))
+        });
+    }
+    require("OAUTH_TOKEN_SECRET", secret("OAUTH_TOKEN_SECRET"), |v| {
+        check_base64_len(v, |len| 32 == len, "exactly 32")
+    });
+    for provider in OauthProvider::ALL {
+        let name = |suffix: &str| format!("{}_{}", provider.env_prefix(), suffix);
+        require(&name("CLIENT_ID"), var(&name("CLIENT_ID")), check_non_empty);
+        require(
+            &name("CLIENT_SECRET"),
+            secret(&name("CLIENT_SECRET")),
+            check_non_empty,
+        );
+        for suffix in [
+            "PROVIDER_AUTHORIZE_URL",
+            "PROVIDER_TOKEN_URL",
+            "CALLBACK_URL",
+        ] {
+            require(&name(suffix), var(&name(suffix)), check_url);
+        }
+        require(&name("SCOPES"), var(&name("SCOPES")), |_| Ok(()));
+    }
+    require("PAGES_ORIGIN", var("PAGES_ORIGIN"), check_url);
+    require("JWT_AUDIENCE", var("JWT_AUDIENCE"), check_url);
+    for name in ["WEBJOB_BULK_UPDATE_BATCH_SIZE", "WEBJOB_MAX_ATTEMPTS"] {
+        require(name, var(name), |v| match v.parse::<u32>() {
+            Ok(1..) => Ok(()),
+            _ => Err("should be a positive integer.".to_owned()),
+        });
+    }
+    require("REDDIT_SUBREDDIT", reddit_subreddit(&var), check_subreddit);
+    require("DDRAGON_VERSION", var("DDRAGON_VERSION"), check_non_empty);
+
+    problems.is_empty().then_some(()).ok_or(problems)
+}
+
+/// Gets `REDDIT_SUBREDDIT` via `var`, falling back to its old name `REDDIT_FLAIR_SUBREDDIT` so
+/// existing deployments keep working.
+// TODO: remove the fallback once deployments have renamed the var.
+fn reddit_subreddit(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    var("REDDIT_SUBREDDIT").or_else(|| {
+        let subreddit = var("REDDIT_FLAIR_SUBREDDIT")?;
+        log::warn!("`REDDIT_FLAIR_SUBREDDIT` is deprecated, rename it to `REDDIT_SUBREDDIT`.");
+        Some(subreddit)
+    })
+}
+
+/// Checks that `value` is not blank.
+fn check_non_empty(value: &str) -> std::result::Result<(), String> {
+    (!value.trim().is_empty())
+        .then_some(())
+        .ok_or_else(|| "should not be empty.".to_owned())
+}
+
+/// Checks that `value` is a subreddit name (no "/r/"): 3 to 21 letters, digits, or underscores.
+fn check_subreddit(value: &str) -> std::result::Result<(), String> {
+    (3..=21)
+        .contains(&value.len())
+        .then_some(())
+        .filter(|()| {
+            value
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b'_' == b)
+        })
+        .ok_or_else(|| "should be a subreddit name, without \"/r/\".".to_owned())
+}
+
+/// Checks that `value` looks like a Riot API key.
+fn check_rgapi_key(value: &str) -> std::result::Result<(), String> {
+    value
+        .starts_with("RGAPI-")
+        .then_some(())
+        .ok_or_else(|| "should start with `RGAPI-`.".to_owned())
+}
+
+/// Checks that `value` is a valid URL.
+fn check_url(value: &str) -> std::result::Result<(), String> {
+    Url::parse(value).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Checks that `value` is URL-safe base64 decoding to an acceptable number of bytes.
+fn check_base64_len(
+    value: &str,
+    len_ok: impl Fn(usize) -> bool,
+    expected: &str,
+) -> std::result::Result<(), String> {
+    let bytes = base64::decode_config(value, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| format!("should be URL-safe base64: {}", e))?;
+    len_ok(bytes.len())
+        .then_some(())
+        .ok_or_else(|| format!("should decode to {} bytes, len: {}", expected, bytes.len()))
+}
+
+/// Oauth helpers by provider. [`get_appstate`] creates a helper for every [`OauthProvider`].
+pub struct OauthHelpers(pub HashMap<OauthProvider, OauthHelper>);
+impl OauthHelpers {
+    /// Gets the helper for `provider`.
+    pub fn get(&self, provider: OauthProvider) -> &OauthHelper {
+        &self.0[&provider]
+    }
+}
 /// Wraper to distinguish Axum states.
-pub struct RedditOauthHelper(pub OauthHelper);
+pub struct CmPagesOrigin(pub Url);
 /// Wraper to distinguish Axum states.
-pub struct RsoOauthHelper(pub OauthHelper);
+pub struct PagesRedirectPermanent(pub bool);
 /// Wraper to distinguish Axum states.
-pub struct CmPagesOrigin(pub Url);
+pub struct WebjobDeadLetterQueue(pub Queue);
+/// Wraper to distinguish Axum states.
+pub struct JwtClockSkew(pub Duration);
+/// Wraper to distinguish Axum states.
+pub struct JwtAudience(pub String);
+/// Wraper to distinguish Axum states.
+pub struct UpdateRateLimit(pub RateLimitConfig);
+/// Wraper to distinguish Axum states.
+pub struct DdragonVersion(pub String);
+/// Wraper to distinguish Axum states.
+pub struct FlairMinPoints(pub u64);
+/// Wraper to distinguish Axum states.
+pub struct AdminToken(pub Option<SecretString>);
+/// Wraper to distinguish Axum states.
+pub struct HttpTimeout(pub Duration);
+
+/// Creates a [`JwtKey`] from URL-safe base64 `secret`, read from the `name` secret.
+fn jwt_key(secret: &str, name: &str) -> Result<JwtKey> {
+    let secret = base64::decode_config(secret, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| format!("Failed to decode `{}`: {}", name, e))?;
+    if secret.len() < 32 {
+        return Result::Err(Error::RustError(format!(
+            "`{}` is too short, len: {}",
+            name,
+            secret.len(),
+        )));
+    }
+    let hmac =
+        Hmac::new_from_slice(&secret).map_err(|e| format!("Failed to create hmac: {}", e))?;
+    Ok(JwtKey::new(hmac))
+}
+
+/// Creates the [`OauthHelper`] for `provider` from its `{PREFIX}_*` env vars and secrets.
+fn oauth_helper(env: &Env, provider: OauthProvider) -> Result<OauthHelper> {
+    let prefix = provider.env_prefix();
+    let oauth_helper = OauthHelper::builder()
+        .client_id(envvar(env, &format!("{}_CLIENT_ID", prefix))?)
+        .client_secret(secret(env, &format!("{}_CLIENT_SECRET", prefix))?)
+        .provider_authorize_url(envvar(env, &format!("{}_PROVIDER_AUTHORIZE_URL", prefix))?)
+        .provider_token_url(envvar(env, &format!("{}_PROVIDER_TOKEN_URL", prefix))?)
+        .callback_url(envvar(env, &format!("{}_CALLBACK_URL", prefix))?)
+        .scopes(envvar_list(env, &format!("{}_SCOPES", prefix))?)
+        .extra_params(provider.extra_params())
+        .build()
+        .map_err(|e| format!("Invalid `{}_*` oauth config: {}", prefix, e))?;
+    Ok(oauth_helper)
+}
 
 /// Get an env var.
 pub fn envvar(env: &Env, name: &str) -> Result<String> {
     env.var(name).map(|v| v.to_string())
 }
+/// Get an env var parsed as `T`, see [`parse_envvar`].
+pub fn envvar_parsed<T>(env: &Env, name: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    parse_envvar(name, &envvar(env, name)?)
+}
+/// Parses `value` of env var `name` as `T`, with an error naming the var and `T`.
+pub fn parse_envvar<T>(name: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|e| {
+        Error::RustError(format!(
+            "Env var `{}` should parse as `{}`: {}",
+            name,
+            std::any::type_name::<T>(),
+            e
+        ))
+    })
+}
+/// Get an optional env var parsed as `T`, `None` if unset. See [`parse_envvar`].
+pub fn envvar_parsed_opt<T>(env: &Env, name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    envvar(env, name)
+        .ok()
+        .map(|value| parse_envvar(name, &value))
+        .transpose()
+}
+/// Get an optional env var as a [`Duration`] in whole seconds.
+pub fn envvar_secs(env: &Env, name: &str) -> Result<Option<Duration>> {
+    Ok(envvar_parsed_opt::<u64>(env, name)?.map(Duration::from_secs))
+}
+/// Get a whitespace-separated env var as a list.
+pub fn envvar_list(env: &Env, name: &str) -> Result<Vec<String>> {
+    envvar(env, name).map(|v| v.split_whitespace().map(ToOwned::to_owned).collect())
+}
 /// Get an env secret.
 pub fn secret(env: &Env, name: &str) -> Result<SecretString> {
     env.secret(name).map(|v| v.to_string().into())
 }
+
+/// [`AppState`] for tests, with default config and placeholder (unusable) JS bindings, so only
+/// routes which are rejected before touching the DB or queues can be called natively.
+#[cfg(test)]
+pub(crate) fn test_app_state() -> AppState {
+    use wasm_bindgen::{JsCast, JsValue};
+
+    Box::leak(Box::new(AppStateOwned {
+        db: JsValue::UNDEFINED.unchecked_into(),
+        webjob_queue: JsValue::UNDEFINED.unchecked_into(),
+        webjob_dead_letter_queue: WebjobDeadLetterQueue(JsValue::UNDEFINED.unchecked_into()),
+        riot_api: RiotApi::new("RGAPI-00000000-0000-0000-0000-000000000000"),
+        reqwest_client: Client::new(),
+        http_timeout: HttpTimeout(crate::outbound::DEFAULT_TIMEOUT),
+        oauth_helpers: OauthHelpers(HashMap::new()),
+        jwt_keys: JwtKeys {
+            primary: JwtKey::new(Hmac::new_from_slice(&[7; 32]).unwrap()),
+            previous: Vec::new(),
+        },
+        jwt_clock_skew: JwtClockSkew(Duration::from_secs(10)),
+        jwt_audience: JwtAudience("http://localhost:8787".to_owned()),
+        session_ttls: SessionTtls::default(),
+        token_cipher: TokenCipher::new(&[7; 32]).unwrap(),
+        cm_pages_origin: CmPagesOrigin(Url::parse("http://localhost:5173/").unwrap()),
+        pages_redirect_permanent: PagesRedirectPermanent(false),
+        webjob_config: crate::webjob::test_webjob_config(),
+        update_rate_limit: UpdateRateLimit(RateLimitConfig {
+            max_requests: 10,
+            window: Duration::from_secs(60),
+        }),
+        flair_min_points: FlairMinPoints(0),
+        ddragon_version: DdragonVersion("14.20.1".to_owned()),
+        admin_token: AdminToken(None),
+        kv: None,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn valid_config() -> HashMap<&'static str, String> {
+        let key = base64::encode_config([7; 32], base64::URL_SAFE_NO_PAD);
+        [
+            ("RGAPI_KEY", "RGAPI-00000000-0000-0000-0000-000000000000"),
+            ("REDDIT_OWNER_USERNAME", "LugnutsK"),
+            ("HMAC_SECRET", key.as_str()),
+            ("OAUTH_TOKEN_SECRET", key.as_str()),
+            ("REDDIT_CLIENT_ID", "id"),
+            ("REDDIT_CLIENT_SECRET", "secret"),
+            (
+                "REDDIT_PROVIDER_AUTHORIZE_URL",
+                "https://www.reddit.com/api/v1/authorize",
+            ),
+            (
+                "REDDIT_PROVIDER_TOKEN_URL",
+                "https://www.reddit.com/api/v1/access_token",
+            ),
+            ("REDDIT_CALLBACK_URL", "http://localhost/signin-reddit"),
+            ("REDDIT_SCOPES", "identity"),
+            ("RSO_CLIENT_ID", "id"),
+            ("RSO_CLIENT_SECRET", "secret"),
+            (
+                "RSO_PROVIDER_AUTHORIZE_URL",
+                "https://auth.riotgames.com/authorize",
+            ),
+            ("RSO_PROVIDER_TOKEN_URL", "https://auth.riotgames.com/token"),
+            ("RSO_CALLBACK_URL", "http://localhost/signin-rso"),
+            ("RSO_SCOPES", "openid"),
+            ("PAGES_ORIGIN", "http://localhost:5173"),
+            ("JWT_AUDIENCE", "http://localhost:8787"),
+            ("WEBJOB_BULK_UPDATE_BATCH_SIZE", "20"),
+            ("WEBJOB_MAX_ATTEMPTS", "3"),
+            ("REDDIT_SUBREDDIT", "championmains"),
+            ("DDRAGON_VERSION", "14.20.1"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name, value.to_owned()))
+        .collect()
+    }
+
+    fn validate(config: &HashMap<&str, String>) -> std::result::Result<(), Vec<String>> {
+        let lookup = |name: &str| config.get(name).cloned();
+        validate_config_with(lookup, lookup)
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert_eq!(Ok(()), validate(&valid_config()));
+    }
+
+    #[test]
+    fn test_validate_config_problems() {
+        let mut config = valid_config();
+        config.remove("RGAPI_KEY");
+        config.remove("RSO_CLIENT_SECRET");
+        config.insert("HMAC_SECRET", "c2hvcnQ".to_owned());
+        config.insert("PAGES_ORIGIN", "not a url".to_owned());
+        config.insert("WEBJOB_MAX_ATTEMPTS", "0".to_owned());
+        config.insert("REDDIT_SUBREDDIT", "/r/championmains".to_owned());
+        let problems = validate(&config).unwrap_err();
+        assert_eq!(6, problems.len(), "{:#?}", problems);
+        assert_eq!("Missing `RGAPI_KEY`.", problems[0]);
+        assert!(problems[1].starts_with("Invalid `HMAC_SECRET`"));
+        assert_eq!("Missing `RSO_CLIENT_SECRET`.", problems[2]);
+        assert!(problems[3].starts_with("Invalid `PAGES_ORIGIN`"));
+        assert!(problems[4].starts_with("Invalid `WEBJOB_MAX_ATTEMPTS`"));
+        assert!(problems[5].starts_with("Invalid `REDDIT_SUBREDDIT`"));
+    }
+
+    #[test]
+    fn test_validate_config_deprecated_subreddit() {
+        let mut config = valid_config();
+        let subreddit = config.remove("REDDIT_SUBREDDIT").unwrap();
+        config.insert("REDDIT_FLAIR_SUBREDDIT", subreddit);
+        assert_eq!(Ok(()), validate(&config));
+
+        // The new name takes precedence.
+        config.insert("REDDIT_SUBREDDIT", "/r/championmains".to_owned());
+        assert!(validate(&config).is_err());
+
+        config.remove("REDDIT_SUBREDDIT");
+        config.remove("REDDIT_FLAIR_SUBREDDIT");
+        assert_eq!(
+            vec!["Missing `REDDIT_SUBREDDIT`.".to_owned()],
+            validate(&config).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_envvar() {
+        assert_eq!(
+            20,
+            parse_envvar::<u32>("WEBJOB_BULK_UPDATE_BATCH_SIZE", "20").unwrap()
+        );
+        assert!(parse_envvar::<bool>("WEBJOB_RECORD_HISTORY", "true").unwrap());
+
+        let Err(Error::RustError(msg)) = parse_envvar::<u32>("WEBJOB_BULK_UPDATE_BATCH_SIZE", "-1")
+        else {
+            panic!("Expected parse error.");
+        };
+        assert_eq!(
+            "Env var `WEBJOB_BULK_UPDATE_BATCH_SIZE` should parse as `u32`: invalid digit found in string",
+            msg
+        );
+        let Err(Error::RustError(msg)) = parse_envvar::<bool>("WEBJOB_RECORD_HISTORY", "yes")
+        else {
+            panic!("Expected parse error.");
+        };
+        assert!(
+            msg.starts_with("Env var `WEBJOB_RECORD_HISTORY` should parse as `bool`: "),
+            "{}",
+            msg
+        );
+    }
+}
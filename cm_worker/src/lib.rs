@@ -4,81 +4,182 @@
 //! Cloudflare worker.
 
 use std::future::{ready, Ready};
-use std::num::NonZeroU64;
 
-use auth::{AuthError, OauthCallbackQueryResponse, SessionStateSignedIn, SessionStateTransition};
+use auth::{
+    store_oauth_tokens, Admin, AuthError, OauthCallbackQueryResponse, OauthProvider,
+    SessionStateSignedIn,
+};
 pub use axum;
 use axum::extract::{Path, Query, State};
-use axum::response::Redirect;
+use axum::response::{IntoResponse, Redirect, Response};
 use axum::{routing, Json};
+use axum_extra::headers::{ETag, IfNoneMatch};
+use axum_extra::TypedHeader;
 use cm_macro::local_async;
-use futures::future::join_all;
-use hmac::Hmac;
-use http::header::AUTHORIZATION;
+use futures::StreamExt;
+use http::header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, SET_COOKIE};
 use http::status::StatusCode;
-use http::HeaderValue;
-use init::{CmPagesOrigin, RedditOauthHelper, RsoOauthHelper};
-use riven::consts::{Champion, PlatformRoute, RegionalRoute};
+use http::{HeaderMap, HeaderName, HeaderValue, Method};
+use init::{
+    CmPagesOrigin, DdragonVersion, FlairMinPoints, HttpTimeout, JwtAudience, JwtClockSkew,
+    OauthHelpers, PagesRedirectPermanent, UpdateRateLimit,
+};
+use riven::consts::PlatformRoute;
 use riven::reqwest::Client;
+use riven::RiotApi;
+use serde::de::IgnoredAny;
 use serde::Serialize;
 use serde_with::de::DeserializeAsWrap;
-use serde_with::{serde_as, Same};
-use sha2::Sha512;
+use serde_with::ser::SerializeAsWrap;
+use serde_with::{Same, TimestampMilliSeconds};
 use tower::Service;
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::{CorsLayer, MaxAge};
+use url::Url;
 use web_time::{Duration, SystemTime};
+use worker::kv::KvStore;
 use worker::{
     event, query, Context, D1Database, Env, Error, MessageBatch, MessageExt, Queue, Result,
+    ScheduleContext, ScheduledEvent,
 };
 
-use crate::auth::{create_session_state_token, SessionState};
+use crate::auth::{
+    create_session_state_token, revoke_session_state_token, JwtKeys, JwtSessionState, SessionState,
+    SessionTtls,
+};
+use crate::crypt::TokenCipher;
 use crate::error::CmError;
-use crate::webjob::Task;
-use crate::with::IgnoreKeys;
+use crate::ids::{SummonerId, UserId};
+use crate::outbound::with_timeout;
+use crate::profile::ChampsQuery;
+use crate::request_id::{RequestIdLayer, X_REQUEST_ID};
+use crate::webjob::{enqueue_tasks, send_task, MessageOutcome, Task, WebjobConfig};
+use crate::with::{Base36, IgnoreKeys, WebSystemTime};
 
 pub mod auth;
 pub mod base36;
+pub mod cache;
+pub mod crypt;
+pub mod ddragon;
 pub mod init;
+pub mod migrations;
 pub mod reddit;
 #[macro_use]
 pub mod local_future;
+#[macro_use]
+pub mod checked_query;
 pub mod error;
+pub mod events;
+pub mod flair;
+pub mod ids;
+pub mod outbound;
+pub mod profile;
+pub mod query_row;
+pub mod rate_limit;
+pub mod request_id;
+pub mod retry;
 pub mod webjob;
 pub mod with;
 
-/// Local region.
-pub const ROUTE: RegionalRoute = RegionalRoute::AMERICAS;
+/// Git hash of this build, or `"localdev"`.
+pub const GIT_HASH: &str = match option_env!("GIT_HASH") {
+    Some(git_hash) => git_hash,
+    None => "localdev",
+};
 
 /// Cloudflare queue handler.
 #[event(queue)]
 pub async fn queue(
-    message_batch: MessageBatch<webjob::Task>,
+    message_batch: MessageBatch<serde_json::Value>,
     env: Env,
     _ctx: Context,
 ) -> Result<()> {
-    init::init_logging();
+    init::init_logging(&env);
     let app_state = init::get_appstate(&env)?;
 
-    let futures = message_batch.messages()?.into_iter().map(|msg| {
-        log::info!("Handling webjob task: `{:?}`.", msg.body());
-        webjob::handle(
-            &app_state.db,
-            &app_state.riot_api,
-            &app_state.webjob_config,
-            msg,
-        )
+    let messages = message_batch.messages()?;
+    let futures = messages.iter().map(|msg| async move {
+        let result = match webjob::decode_task(msg.body()) {
+            Ok(task) => {
+                log::info!("Handling webjob task: `{:?}`.", task);
+                webjob::handle(app_state, &task).await
+            }
+            Err(error) => Err(error),
+        };
+        (msg, result)
     });
-    let results = join_all(futures).await;
-    let errors = results
-        .into_iter()
-        .filter_map(|result| result.map(|msg| msg.ack()).err())
-        .collect::<Vec<_>>();
+    let results = webjob::run_concurrent(futures, app_state.webjob_config.concurrency).await;
+
+    // Each message is acked or retried individually, so one failure does not re-deliver the rest.
+    let max_attempts = app_state.webjob_config.max_attempts;
+    let mut failures = 0;
+    for (msg, result) in results {
+        let outcome = webjob::message_outcome(&result, msg.attempts(), max_attempts);
+        let error = result.err().map(|e| e.to_string()).unwrap_or_default();
+        match outcome {
+            MessageOutcome::Ack => msg.ack(),
+            MessageOutcome::Retry => {
+                failures += 1;
+                log::warn!(
+                    "Retrying webjob task `{}` after {} attempts. Error: {}",
+                    msg.body(),
+                    msg.attempts(),
+                    error
+                );
+                msg.retry();
+            }
+            MessageOutcome::DeadLetter => {
+                failures += 1;
+                log::error!(
+                    "Dead-lettering webjob task `{}` after {} attempts. Error: {}",
+                    msg.body(),
+                    msg.attempts(),
+                    error
+                );
+                match app_state.webjob_dead_letter_queue.0.send(msg.body()).await {
+                    Ok(()) => msg.ack(),
+                    Err(dlq_error) => {
+                        log::error!("Failed to dead-letter, retrying instead: {}", dlq_error);
+                        msg.retry();
+                    }
+                }
+            }
+        }
+    }
+
+    log::info!(
+        "Handling webjob tasks complete. {} of {} failed.",
+        failures,
+        messages.len()
+    );
+    Ok(())
+}
+
+/// Cloudflare scheduled (cron) handler.
+#[event(scheduled)]
+pub async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    init::init_logging(&env);
+    let app_state = match init::get_appstate(&env) {
+        Ok(app_state) => app_state,
+        Err(e) => {
+            log::error!("Failed to initialize app state: {}", e);
+            return;
+        }
+    };
 
-    log::info!("Handling webjob task complete. Errors: {:?}", errors);
-    errors
-        .is_empty()
-        .then_some(())
-        .ok_or(Error::RustError(format!("{:?}", errors)))
+    let failed = enqueue_tasks(
+        &app_state.webjob_queue,
+        [Task::SummonerBulkUpdate, Task::OauthTokenRefresh],
+    )
+    .await;
+    if !failed.is_empty() {
+        log::error!("Failed to enqueue cron tasks: {:?}", failed);
+    }
+    if let Err(e) =
+        auth::delete_expired_revoked_nonces(&app_state.db, app_state.jwt_clock_skew.0).await
+    {
+        log::error!("Failed to delete expired revoked nonces: {}", e);
+    }
 }
 
 /// Cloudflare fetch request handler.
@@ -88,76 +189,365 @@ pub async fn fetch(
     env: Env,
     _ctx: Context,
 ) -> Result<http::Response<axum::body::Body>> {
-    init::init_logging();
+    init::init_logging(&env);
     let app_state = init::get_appstate(&env)?;
 
-    let router = axum::Router::new();
-    let mut app = router
+    let mut app = routes()
+        // Inside CORS, so preflight responses skip compression.
+        .layer(compression_layer())
+        .layer(cors_layer(&app_state.cm_pages_origin.0))
+        .layer(RequestIdLayer)
+        .with_state(app_state);
+
+    Ok(call_or_500(&mut app, req).await)
+}
+
+/// The route table, without layers or state.
+pub fn routes() -> axum::Router<init::AppState> {
+    axum::Router::new()
         .route("/", routing::get(get_index))
+        .route("/health", routing::get(get_health))
+        .route("/admin/metrics", routing::get(get_admin_metrics))
+        .route("/admin/migrations", routing::post(post_admin_migrations))
+        .route(
+            "/admin/flairs/recompute",
+            routing::post(post_admin_flairs_recompute),
+        )
+        .route(
+            "/admin/summoner/:sid/update",
+            routing::post(post_admin_summoner_update),
+        )
         .route("/signin/anonymous", routing::get(get_signin_anonymous))
         .route("/signin/upgrade", routing::get(get_signin_upgrade))
+        .route("/signin/:provider", routing::get(get_signin_provider))
+        // Typed routes, kept for existing links.
         .route(
             "/signin/reddit",
-            routing::get(
-                |State(RedditOauthHelper(oauth)): State<&'static _>,
-                 Query(query_state): Query<QueryState>| {
-                    ready(Redirect::temporary(
-                        oauth.make_signin_link(&query_state.state).as_str(),
-                    ))
-                },
-            ),
+            routing::get(|oauth_helpers, session_ttls, query| {
+                get_signin_provider(
+                    oauth_helpers,
+                    session_ttls,
+                    Path(OauthProvider::Reddit),
+                    query,
+                )
+            }),
         )
         .route(
             "/signin/rso",
-            routing::get(
-                |State(RsoOauthHelper(oauth)): State<&'static _>,
-                 Query(query_state): Query<QueryState>| {
-                    ready(Redirect::temporary(
-                        oauth.make_signin_link(&query_state.state).as_str(),
-                    ))
-                },
-            ),
+            routing::get(|oauth_helpers, session_ttls, query| {
+                get_signin_provider(oauth_helpers, session_ttls, Path(OauthProvider::Rso), query)
+            }),
         )
         .route("/signin-reddit", routing::get(get_signin_reddit))
-        .route("/user/me", routing::get(get_user_me))
-        .route("/summoner/:sid/update", routing::post(post_summoner_update))
-        .layer(
-            CorsLayer::new()
-                .allow_origin(
-                    HeaderValue::from_str(
-                        app_state.cm_pages_origin.0.as_str().trim_end_matches('/'),
-                    )
-                    .unwrap(),
-                )
-                .allow_headers([AUTHORIZATION])
-                .max_age(MaxAge::exact(Duration::from_secs(3600))),
+        .route("/signin-rso", routing::get(get_signin_rso))
+        .route("/signout", routing::post(post_signout))
+        .route("/user/me", routing::get(get_user_me).patch(patch_user_me))
+        .route("/user/me/link-rso", routing::post(post_user_me_link_rso))
+        .route("/user/me/stats", routing::get(get_user_me_stats))
+        .route("/profile/:reddit_user_name", routing::get(get_profile))
+        .route("/search", routing::get(get_search))
+        .route(
+            "/summoner/:sid",
+            routing::get(get_summoner).delete(delete_summoner),
         )
-        .with_state(app_state);
+        .route("/summoner/:sid/update", routing::post(post_summoner_update))
+        .route("/summoner/:sid/events", routing::get(get_summoner_events))
+        .route("/summoner/:sid/history", routing::get(get_summoner_history))
+        .route("/summoner/:sid/flair", routing::get(get_summoner_flair))
+}
+
+/// Allows the `cm_pages` origin `pages_origin` to call the API, including with the `cm_session`
+/// cookie.
+pub fn cors_layer(pages_origin: &Url) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(HeaderValue::from_str(pages_origin.as_str().trim_end_matches('/')).unwrap())
+        .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+        .allow_headers([
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            IF_NONE_MATCH,
+            X_REQUEST_ID.clone(),
+        ])
+        .expose_headers([ETAG, X_REQUEST_ID.clone()])
+        // For the `cm_session` cookie.
+        .allow_credentials(true)
+        .max_age(MaxAge::exact(Duration::from_secs(3600)))
+}
 
-    Ok(app.call(req).await.unwrap())
+/// Compresses responses with gzip or brotli, as negotiated via `Accept-Encoding`.
+pub fn compression_layer() -> CompressionLayer {
+    CompressionLayer::new().gzip(true).br(true)
+}
+
+/// Calls `service`, converting any error into a 500 response instead of panicking.
+pub async fn call_or_500<S, Req>(service: &mut S, req: Req) -> http::Response<axum::body::Body>
+where
+    S: Service<Req, Response = http::Response<axum::body::Body>>,
+    S::Error: std::fmt::Display,
+{
+    match service.call(req).await {
+        Ok(response) => response,
+        Err(e) => {
+            log::error!("Service error: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error.").into_response()
+        }
+    }
 }
 
 #[axum::debug_handler(state = init::AppState)]
-fn get_index(State(CmPagesOrigin(url)): State<&'static CmPagesOrigin>) -> Ready<Redirect> {
-    ready(Redirect::temporary(url.as_str()))
+fn get_index(
+    State(CmPagesOrigin(url)): State<&'static CmPagesOrigin>,
+    State(PagesRedirectPermanent(permanent)): State<&'static PagesRedirectPermanent>,
+) -> Ready<Response> {
+    ready(index_redirect(url.as_str(), *permanent))
+}
+
+/// How long browsers and crawlers may cache the `GET /` redirect.
+const INDEX_REDIRECT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Redirect to the `cm_pages` origin `url`, `308` if `permanent` otherwise `307`. Cached for
+/// [`INDEX_REDIRECT_MAX_AGE`] either way, so a changed origin is eventually picked up.
+fn index_redirect(url: &str, permanent: bool) -> Response {
+    let redirect = if permanent {
+        Redirect::permanent(url)
+    } else {
+        Redirect::temporary(url)
+    };
+    let cache_control = format!("public, max-age={}", INDEX_REDIRECT_MAX_AGE.as_secs());
+    (
+        [(
+            CACHE_CONTROL,
+            HeaderValue::from_str(&cache_control).unwrap(),
+        )],
+        redirect,
+    )
+        .into_response()
 }
 
+/// `GET /health`
+///
+/// Always 200 if the worker is up, the body reports if D1 is reachable.
 #[axum::debug_handler(state = init::AppState)]
-fn get_signin_anonymous(State(jwt_hmac): State<&'static Hmac<Sha512>>) -> Ready<Json<String>> {
+#[local_async]
+pub async fn get_health(State(db): State<&'static D1Database>) -> Json<impl Serialize> {
+    #[derive(Serialize)]
+    struct Health {
+        db: &'static str,
+        version: &'static str,
+    }
+    let db_status = match db.prepare("SELECT 1").first::<IgnoredAny>(None).await {
+        Ok(_) => "ok",
+        Err(e) => {
+            log::error!("Health check D1 query failed: {}", e);
+            "error"
+        }
+    };
+    Json(Health {
+        db: db_status,
+        version: GIT_HASH,
+    })
+}
+
+/// Summoners not updated for this long are counted as stale by `GET /admin/metrics`.
+pub const METRICS_STALE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// `GET /admin/metrics`
+///
+/// Counts for operators. Each count is a single indexed scan, so this is cheap enough to scrape.
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn get_admin_metrics(
+    _admin: Admin,
+    State(db): State<&'static D1Database>,
+) -> std::result::Result<Json<impl Serialize>, CmError> {
+    #[derive(Serialize)]
+    struct Metrics {
+        users: u64,
+        summoners: u64,
+        /// Summoners not successfully updated within [`METRICS_STALE_AGE`], including never.
+        stale_summoners: u64,
+        /// Summoners with a pending [`Task::SummonerUpdate`], estimates the queue depth.
+        pending_updates: u64,
+    }
+    type MetricsWith = (Same, Same, Same, Same);
+    let (users, summoners, stale_summoners, pending_updates) = checked_query!(
+        &db,
+        MetricsWith,
+        "SELECT
+            (SELECT COUNT(*) FROM user),
+            (SELECT COUNT(*) FROM summoner),
+            (SELECT COUNT(*) FROM summoner WHERE last_success IS NULL OR last_success < ?),
+            (SELECT COUNT(*) FROM summoner WHERE pending_update IS NOT NULL)",
+        <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(
+            &(SystemTime::now() - METRICS_STALE_AGE)
+        ),
+    )?
+    .first::<DeserializeAsWrap<(u64, u64, u64, u64), IgnoreKeys<MetricsWith>>>(None)
+    .await?
+    .map(DeserializeAsWrap::into_inner)
+    .ok_or_else(|| CmError::InternalServerError("Metrics query returned no row.".to_owned()))?;
+    Ok(Json(Metrics {
+        users,
+        summoners,
+        stale_summoners,
+        pending_updates,
+    }))
+}
+
+/// `POST /admin/migrations`
+///
+/// Applies pending [`migrations::MIGRATIONS`], returning the newly applied versions.
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn post_admin_migrations(
+    _admin: Admin,
+    State(db): State<&'static D1Database>,
+) -> std::result::Result<Json<impl Serialize>, CmError> {
+    #[derive(Serialize)]
+    struct Applied {
+        applied: Vec<u32>,
+    }
+    let applied = migrations::apply_migrations(db).await?;
+    Ok(Json(Applied { applied }))
+}
+
+/// Helper to parse `?assign=...`.
+#[derive(serde::Deserialize)]
+pub struct RecomputeFlairsQuery {
+    #[serde(default)]
+    assign: bool,
+}
+
+/// `POST /admin/flairs/recompute`
+///
+/// Enqueues a [`Task::RecomputeFlairAll`], which reassigns all flairs if `?assign=true`.
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn post_admin_flairs_recompute(
+    _admin: Admin,
+    State(webjob_queue): State<&'static Queue>,
+    State(webjob_config): State<&'static WebjobConfig>,
+    Query(RecomputeFlairsQuery { assign }): Query<RecomputeFlairsQuery>,
+) -> std::result::Result<StatusCode, CmError> {
+    send_task(
+        webjob_queue,
+        Task::RecomputeFlairAll { assign },
+        webjob_config.send_attempts,
+    )
+    .await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Helper to parse `?dry_run=...`.
+#[derive(serde::Deserialize)]
+pub struct AdminUpdateQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// `POST /admin/summoner/:sid/update`
+///
+/// Force-updates the summoner right away, returning the [`webjob::SummonerChanges`] written. Same
+/// as a [`Task::SummonerRefresh`], see [`webjob::summoner_update_and_publish`]. With
+/// `?dry_run=true`, fetches from Riot but skips all writes and events, returning the would-be
+/// changes. Useful for validating the Riot integration without mutating state.
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn post_admin_summoner_update(
+    _admin: Admin,
+    State(db): State<&'static D1Database>,
+    State(rgapi): State<&'static RiotApi>,
+    State(kv): State<&'static Option<KvStore>>,
+    State(webjob_queue): State<&'static Queue>,
+    State(webjob_config): State<&'static WebjobConfig>,
+    Path(sid): Path<SummonerId>,
+    Query(AdminUpdateQuery { dry_run }): Query<AdminUpdateQuery>,
+) -> std::result::Result<Json<impl Serialize>, CmError> {
+    let kv = kv.as_ref();
+    let changes = if dry_run {
+        webjob::summoner_update(db, rgapi, kv, webjob_config, sid, true, true).await?
+    } else {
+        webjob::summoner_update_and_publish(db, rgapi, kv, webjob_queue, webjob_config, sid, true)
+            .await?
+    };
+    Ok(Json(changes))
+}
+
+#[axum::debug_handler(state = init::AppState)]
+fn get_signin_anonymous(
+    State(jwt_keys): State<&'static JwtKeys>,
+    State(session_ttls): State<&'static SessionTtls>,
+    State(JwtAudience(audience)): State<&'static JwtAudience>,
+) -> Ready<Json<String>> {
     ready(Json(
-        create_session_state_token(jwt_hmac, SessionState::Anonymous).unwrap(),
+        create_session_state_token(jwt_keys, session_ttls, audience, SessionState::Anonymous)
+            .unwrap(),
     ))
 }
 
+/// `GET /signin/upgrade?state=...`
+///
+/// Exchanges a transition token for a signed-in token. `state` must be the anonymous token the
+/// sign-in started with, see [`auth::check_transition_state`].
+/// Each transition token can only be exchanged once, see [`auth::consume_transition_token`]. The
+/// signed-in token is also set as the session cookie.
 #[axum::debug_handler(state = init::AppState)]
+#[local_async]
 async fn get_signin_upgrade(
-    State(jwt_hmac): State<&'static Hmac<Sha512>>,
-    SessionStateTransition { user_id }: SessionStateTransition,
-) -> std::result::Result<Json<String>, AuthError> {
-    let token = create_session_state_token(jwt_hmac, SessionState::SignedIn { user_id })?;
-    Ok(Json(token))
+    State(db): State<&'static D1Database>,
+    State(jwt_keys): State<&'static JwtKeys>,
+    State(session_ttls): State<&'static SessionTtls>,
+    State(JwtClockSkew(skew)): State<&'static JwtClockSkew>,
+    State(JwtAudience(audience)): State<&'static JwtAudience>,
+    claims: JwtSessionState,
+    Query(query_state): Query<QueryState>,
+) -> std::result::Result<(SetCookie, Json<String>), AuthError> {
+    let SessionState::Transition {
+        user_id,
+        state_nonce,
+    } = claims.session_state()
+    else {
+        return Err(AuthError::Unauthorized(
+            "Session state must by transition.".to_owned(),
+        ));
+    };
+    auth::check_transition_state(jwt_keys, *skew, audience, &state_nonce, &query_state.state)?;
+    auth::consume_transition_token(db, &claims).await?;
+    let token = create_session_state_token(
+        jwt_keys,
+        session_ttls,
+        audience,
+        SessionState::SignedIn { user_id },
+    )?;
+    let cookie = auth::session_cookie(&token, session_ttls.signed_in)?;
+    Ok(([(SET_COOKIE, cookie)], Json(token)))
+}
+
+/// `GET /signin/:provider`
+///
+/// Redirects to the provider's authorization endpoint. The `state` is also set as the
+/// [`auth::SIGNIN_STATE_COOKIE`], see [`auth::verify_callback_state`].
+#[axum::debug_handler(state = init::AppState)]
+fn get_signin_provider(
+    State(oauth_helpers): State<&'static OauthHelpers>,
+    State(session_ttls): State<&'static SessionTtls>,
+    Path(provider): Path<OauthProvider>,
+    Query(query_state): Query<QueryState>,
+) -> Ready<std::result::Result<(SetCookie, Redirect), AuthError>> {
+    let oauth = oauth_helpers.get(provider);
+    ready(
+        auth::signin_state_cookie(&query_state.state, session_ttls.anonymous).map(|cookie| {
+            (
+                [(SET_COOKIE, cookie)],
+                Redirect::temporary(oauth.make_signin_link(&query_state.state).as_str()),
+            )
+        }),
+    )
 }
 
+/// `Set-Cookie` response header, see [`auth::session_cookie`].
+type SetCookie = [(HeaderName, HeaderValue); 1];
+
 /// Helper to parse `?state=...`.
 #[derive(serde::Deserialize)]
 pub struct QueryState {
@@ -168,34 +558,168 @@ pub struct QueryState {
 #[axum::debug_handler(state = init::AppState)]
 #[local_async]
 pub async fn get_signin_reddit(
-    State(RedditOauthHelper(oauth)): State<&'static RedditOauthHelper>,
+    State(oauth_helpers): State<&'static OauthHelpers>,
     State(reqwest_client): State<&'static Client>,
+    State(HttpTimeout(http_timeout)): State<&'static HttpTimeout>,
     State(db): State<&'static D1Database>,
-    State(jwt_hmac): State<&'static Hmac<Sha512>>,
+    State(jwt_keys): State<&'static JwtKeys>,
+    State(JwtClockSkew(skew)): State<&'static JwtClockSkew>,
+    State(JwtAudience(audience)): State<&'static JwtAudience>,
+    State(session_ttls): State<&'static SessionTtls>,
+    State(token_cipher): State<&'static TokenCipher>,
     State(CmPagesOrigin(pages_origin)): State<&'static CmPagesOrigin>,
+    headers: HeaderMap,
     Query(callback_data): Query<OauthCallbackQueryResponse>,
-) -> std::result::Result<Redirect, AuthError> {
-    let tokens = oauth
-        .handle_callback(reqwest_client, jwt_hmac, &callback_data)
-        .await?;
-    log::info!("Reddit tokens: {:#?}", tokens);
-    let reddit_me = reddit::get_me(reqwest_client, &tokens.access_token)
-        .await
-        .map_err(|_| AuthError::UpstreamError)?;
-    log::info!("Reddit me: {:#?}", reddit_me);
+) -> std::result::Result<(SetCookie, Redirect), AuthError> {
+    let state_nonce = auth::verify_callback_state(
+        db,
+        jwt_keys,
+        *skew,
+        audience,
+        auth::signin_state(&headers).as_deref(),
+        &callback_data.state,
+    )
+    .await?;
+    let tokens = with_timeout(
+        *http_timeout,
+        oauth_helpers
+            .get(OauthProvider::Reddit)
+            .handle_callback(reqwest_client, &callback_data),
+    )
+    .await??;
+    log::debug!("Reddit tokens: {:#?}", tokens);
+    let reddit_me = with_timeout(
+        *http_timeout,
+        reddit::get_me(reqwest_client, &tokens.access_token),
+    )
+    .await?
+    .map_err(|_| AuthError::UpstreamError)?;
 
-    let user_id = create_or_get_db_user(db, &reddit_me)
-        .await
-        .map_err(|e| AuthError::TokenCreation(e.to_string()))?;
-    let user_signin_token =
-        create_session_state_token(jwt_hmac, SessionState::Transition { user_id })?;
+    let user_id = create_or_get_db_user(db, &reddit_me).await?;
+    store_oauth_tokens(
+        db,
+        token_cipher,
+        user_id,
+        OauthProvider::Reddit.as_str(),
+        &tokens,
+    )
+    .await
+    .map_err(|e| AuthError::TokenCreation(e.to_string()))?;
+    let user_signin_token = create_session_state_token(
+        jwt_keys,
+        session_ttls,
+        audience,
+        SessionState::Transition {
+            user_id,
+            state_nonce,
+        },
+    )?;
+
+    let cookie = auth::session_cookie(&user_signin_token, session_ttls.transition)?;
 
     let mut url = pages_origin.clone();
     url.query_pairs_mut().extend_pairs([
         ("token", &user_signin_token),
         ("state", &callback_data.state),
     ]);
-    Ok(Redirect::temporary(url.as_str()))
+    Ok(([(SET_COOKIE, cookie)], Redirect::temporary(url.as_str())))
+}
+
+/// `GET /signin-rso`
+///
+/// Signs in the user who owns the RSO account's summoner. If the RSO account is not yet associated
+/// with any user, redirects with `error=rso_account_not_linked` and an `rso_token` for
+/// [`post_user_me_link_rso`] instead of a token.
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn get_signin_rso(
+    State(oauth_helpers): State<&'static OauthHelpers>,
+    State(reqwest_client): State<&'static Client>,
+    State(HttpTimeout(http_timeout)): State<&'static HttpTimeout>,
+    State(db): State<&'static D1Database>,
+    State(jwt_keys): State<&'static JwtKeys>,
+    State(JwtClockSkew(skew)): State<&'static JwtClockSkew>,
+    State(JwtAudience(audience)): State<&'static JwtAudience>,
+    State(session_ttls): State<&'static SessionTtls>,
+    State(token_cipher): State<&'static TokenCipher>,
+    State(CmPagesOrigin(pages_origin)): State<&'static CmPagesOrigin>,
+    headers: HeaderMap,
+    Query(callback_data): Query<OauthCallbackQueryResponse>,
+) -> std::result::Result<Response, AuthError> {
+    let state_nonce = auth::verify_callback_state(
+        db,
+        jwt_keys,
+        *skew,
+        audience,
+        auth::signin_state(&headers).as_deref(),
+        &callback_data.state,
+    )
+    .await?;
+    let oauth_helper = oauth_helpers.get(OauthProvider::Rso);
+    let tokens = with_timeout(
+        *http_timeout,
+        oauth_helper.handle_callback(reqwest_client, &callback_data),
+    )
+    .await??;
+    let id_token = tokens.id_token.as_deref().ok_or(AuthError::InvalidToken)?;
+    let identity = auth::parse_rso_id_token(id_token, &oauth_helper.client_id)?;
+
+    let mut url = pages_origin.clone();
+    let user_id = get_summoner_user_id(db, &identity.sub)
+        .await
+        .map_err(|e| AuthError::Internal(e.to_string()))?;
+    let Some(user_id) = user_id else {
+        log::info!("RSO account not linked to any user: {}", identity.sub);
+        // Lets a signed-in user link this RSO account, see `POST /user/me/link-rso`.
+        let rso_link_token =
+            auth::create_rso_link_token(jwt_keys, session_ttls, audience, &identity)?;
+        url.query_pairs_mut().extend_pairs([
+            ("error", "rso_account_not_linked"),
+            ("rso_token", &rso_link_token),
+            ("state", &callback_data.state),
+        ]);
+        return Ok(Redirect::temporary(url.as_str()).into_response());
+    };
+
+    store_oauth_tokens(
+        db,
+        token_cipher,
+        user_id,
+        OauthProvider::Rso.as_str(),
+        &tokens,
+    )
+    .await
+    .map_err(|e| AuthError::TokenCreation(e.to_string()))?;
+    let user_signin_token = create_session_state_token(
+        jwt_keys,
+        session_ttls,
+        audience,
+        SessionState::Transition {
+            user_id,
+            state_nonce,
+        },
+    )?;
+    let cookie = auth::session_cookie(&user_signin_token, session_ttls.transition)?;
+
+    url.query_pairs_mut().extend_pairs([
+        ("token", &user_signin_token),
+        ("state", &callback_data.state),
+    ]);
+    Ok(([(SET_COOKIE, cookie)], Redirect::temporary(url.as_str())).into_response())
+}
+
+/// `POST /signout`
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn post_signout(
+    State(db): State<&'static D1Database>,
+    claims: JwtSessionState,
+) -> std::result::Result<StatusCode, CmError> {
+    let SessionState::SignedIn { .. } = claims.session_state() else {
+        return Err(CmError::Forbidden("Must be signed in.".to_owned()));
+    };
+    revoke_session_state_token(db, &claims).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
 /// `GET /user/me`
@@ -203,112 +727,638 @@ pub async fn get_signin_reddit(
 #[local_async]
 pub async fn get_user_me(
     State(db): State<&'static D1Database>,
+    State(DdragonVersion(ddragon_version)): State<&'static DdragonVersion>,
     SessionStateSignedIn { user_id }: SessionStateSignedIn,
+    Query(champs_query): Query<ChampsQuery>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+) -> std::result::Result<Response, CmError> {
+    let mut user = profile::load_profile(db, user_id).await?;
+    user.set_image_urls(ddragon_version);
+    let etag: ETag = user
+        .etag(&champs_query)
+        .parse()
+        .map_err(|_| CmError::InternalServerError("Failed to create ETag.".to_owned()))?;
+    // Before the `If-None-Match` check, so an invalid `champs` query is a 400 even if cached.
+    champs_query.apply(&mut user.champs)?;
+    if let Some(TypedHeader(if_none_match)) = if_none_match {
+        if !if_none_match.precondition_passes(&etag) {
+            return Ok((StatusCode::NOT_MODIFIED, TypedHeader(etag)).into_response());
+        }
+    }
+    Ok((TypedHeader(etag), Json(user)).into_response())
+}
+
+/// Helper to parse the `PATCH /user/me` body. Absent fields are left unchanged.
+#[derive(serde::Deserialize)]
+pub struct UserSettings {
+    profile_is_public: Option<bool>,
+    profile_bgskinid: Option<u64>,
+}
+
+/// `PATCH /user/me`
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn patch_user_me(
+    State(db): State<&'static D1Database>,
+    State(DdragonVersion(ddragon_version)): State<&'static DdragonVersion>,
+    SessionStateSignedIn { user_id }: SessionStateSignedIn,
+    Json(settings): Json<UserSettings>,
 ) -> std::result::Result<Json<impl Serialize>, CmError> {
-    #[serde_as]
-    #[derive(serde::Serialize, serde::Deserialize)]
-    struct User {
-        reddit_user_name: String,
-        #[serde_as(as = "serde_with::BoolFromInt")]
-        profile_is_public: bool,
-        profile_bgskinid: Option<u64>,
-        #[serde(skip_deserializing)]
-        summoners: Vec<Summoner>,
-        #[serde(skip_deserializing)]
-        champs: Vec<Champ>,
-    }
-    let user_query = query!(
-        &db,
-        "SELECT reddit_user_name, profile_is_public, profile_bgskinid
-        FROM user
-        WHERE id = ?",
+    profile::update_profile_settings(
+        db,
         user_id,
-    )?;
-    #[serde_as]
-    #[derive(serde::Serialize, serde::Deserialize)]
-    struct Summoner {
-        id: u64,
-        puuid: String,
-        #[serde_as(as = "serde_with::DisplayFromStr")]
-        platform: PlatformRoute,
-        game_name: String,
-        tag_line: String,
-        #[serde_as(as = "Option<crate::with::WebSystemTime<serde_with::TimestampSeconds<i64>>>")]
-        last_update: Option<SystemTime>,
-    }
-    let summoners_query = query!(
-        &db,
-        "SELECT id, puuid, platform, game_name, tag_line, last_update
-        FROM summoner
-        WHERE user_id = ?",
-        user_id,
-    )?;
-    #[serde_as]
-    #[derive(serde::Serialize, serde::Deserialize)]
-    struct Champ {
-        champ_id: Champion,
-        total_points: u64,
-        max_level: u64,
-        #[serde(skip_deserializing)]
-        name: Option<&'static str>,
-    }
-    let champs_query = query!(
-        &db,
-        "SELECT champ_id, SUM(points) AS total_points, MAX(level) AS max_level
-        FROM summoner_champion_mastery cm
-        JOIN summoner s ON s.id = cm.summoner_id
-        WHERE s.user_id = ?
-        GROUP BY champ_id
-        ORDER BY total_points DESC",
-        user_id,
-    )?;
+        settings.profile_is_public,
+        settings.profile_bgskinid,
+    )
+    .await?;
+    let mut user = profile::load_profile(db, user_id).await?;
+    user.set_image_urls(ddragon_version);
+    Ok(Json(user))
+}
 
-    let [user_result, summoners_result, champs_result] = &db
-        .batch(vec![user_query, summoners_query, champs_query])
-        .await?[..]
-    else {
-        unreachable!();
+/// `GET /user/me/stats`
+///
+/// Aggregate mastery numbers across the user's summoners, see [`profile::UserStats`].
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn get_user_me_stats(
+    State(db): State<&'static D1Database>,
+    SessionStateSignedIn { user_id }: SessionStateSignedIn,
+) -> std::result::Result<Json<profile::UserStats>, CmError> {
+    let stats = profile::load_user_stats(db, user_id).await?;
+    Ok(Json(stats))
+}
+
+/// Body of `POST /user/me/link-rso`.
+#[serde_with::serde_as]
+#[derive(serde::Deserialize)]
+pub struct LinkRsoBody {
+    /// The `rso_token` from `GET /signin-rso`, see [`auth::JwtRsoLink`].
+    token: String,
+    /// Platform of the summoner, only needed if the token has no
+    /// [`cpid`](auth::JwtRsoLink::cpid) and the platform cannot be inferred, see
+    /// [`webjob::infer_platform`].
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    platform: Option<PlatformRoute>,
+}
+
+/// `POST /user/me/link-rso`
+///
+/// Adds the summoner of the RSO account proven by [`LinkRsoBody::token`] to the signed-in user,
+/// and enqueues its update. Responds with the summoner's ID. 409 if the summoner already belongs
+/// to a different user. Each link token can only be used once.
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn post_user_me_link_rso(
+    State(db): State<&'static D1Database>,
+    State(riot_api): State<&'static RiotApi>,
+    State(webjob_queue): State<&'static Queue>,
+    State(webjob_config): State<&'static WebjobConfig>,
+    State(jwt_keys): State<&'static JwtKeys>,
+    State(JwtClockSkew(skew)): State<&'static JwtClockSkew>,
+    State(JwtAudience(audience)): State<&'static JwtAudience>,
+    SessionStateSignedIn { user_id }: SessionStateSignedIn,
+    Json(body): Json<LinkRsoBody>,
+) -> std::result::Result<Json<SummonerId>, CmError> {
+    let claims = auth::decode_rso_link_token(jwt_keys, *skew, audience, &body.token)?;
+    let platform = match rso_link_platform(claims.cpid.as_deref(), body.platform)? {
+        Some(platform) => platform,
+        None => webjob::infer_platform(riot_api, &claims.puuid)
+            .await
+            .ok_or_else(|| {
+                CmError::BadRequest(
+                    "Missing `platform`, failed to infer it from the account.".to_owned(),
+                )
+            })?,
     };
+    let account = riot_api
+        .account_v1()
+        .get_by_puuid(webjob::account_route_for(platform), &claims.puuid)
+        .await
+        .map_err(|e| CmError::InternalServerError(format!("Failed to get RSO account: {}", e)))?;
+    // Consumed last, so the token is not burned by a bad request.
+    auth::consume_rso_link_token(db, &claims).await?;
 
-    let mut user: User = user_result.results()?.into_iter().next().ok_or_else(|| {
-        CmError::InternalServerError(format!(
-            "User with ID {} does not exist. This should not happen - invalid session.",
-            user_id
-        ))
+    // Only takes effect if the summoner is new or already belongs to the user.
+    let summoner_id = query!(
+        &db,
+        "INSERT INTO summoner(user_id, puuid, game_name, tag_line, platform)
+        VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT(puuid) DO UPDATE SET
+            game_name = excluded.game_name,
+            tag_line = excluded.tag_line,
+            platform = excluded.platform
+        WHERE user_id = excluded.user_id
+        RETURNING id",
+        user_id,
+        &claims.puuid,
+        account.game_name.as_deref().unwrap_or_default(),
+        account.tag_line.as_deref().unwrap_or_default(),
+        platform.to_string(),
+    )?
+    .first::<DeserializeAsWrap<(SummonerId,), IgnoreKeys<(Same,)>>>(None)
+    .await?
+    .map(|summoner_id| summoner_id.into_inner().0)
+    .ok_or_else(|| {
+        CmError::Conflict("Summoner is already linked to a different user.".to_owned())
     })?;
-    user.summoners = summoners_result.results()?;
-    user.champs = champs_result.results()?;
-    // Add `name` to each champ
-    for champ in user.champs.iter_mut() {
-        champ.name = champ.champ_id.name();
+
+    send_task(
+        webjob_queue,
+        Task::SummonerUpdate(summoner_id),
+        webjob_config.send_attempts,
+    )
+    .await?;
+    Ok(Json(summoner_id))
+}
+
+/// The platform to link an RSO account's summoner on: the id_token's `cpid` if present, otherwise
+/// the `platform` given in the request. `None` if neither, then the platform is inferred.
+fn rso_link_platform(
+    cpid: Option<&str>,
+    platform: Option<PlatformRoute>,
+) -> std::result::Result<Option<PlatformRoute>, CmError> {
+    match cpid {
+        Some(cpid) => cpid
+            .parse()
+            .map(Some)
+            .map_err(|_| CmError::BadRequest(format!("Unknown RSO platform: {}", cpid))),
+        None => Ok(platform),
     }
+}
+
+/// `GET /profile/:reddit_user_name`
+///
+/// Private and nonexistent profiles both return 404, so they are indistinguishable. Champions
+/// below [`FlairMinPoints`] are left out, unlike `GET /user/me`.
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn get_profile(
+    State(db): State<&'static D1Database>,
+    State(FlairMinPoints(min_points)): State<&'static FlairMinPoints>,
+    State(DdragonVersion(ddragon_version)): State<&'static DdragonVersion>,
+    Path(reddit_user_name): Path<String>,
+) -> std::result::Result<Json<impl Serialize>, CmError> {
+    let user_id = profile::get_public_user_id(db, &reddit_user_name)
+        .await?
+        .ok_or_else(|| CmError::NotFound("Profile not found.".to_owned()))?;
+    let mut user = profile::load_profile(db, user_id).await?;
+    user.retain_min_points(*min_points);
+    user.set_image_urls(ddragon_version);
     Ok(Json(user))
 }
 
+/// Helper to parse `?q=...&after=...`.
+#[serde_with::serde_as]
+#[derive(serde::Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    #[serde_as(as = "Option<Base36>")]
+    #[serde(default)]
+    after: Option<u64>,
+}
+
+/// `GET /search?q=...&after=...`
+///
+/// A page of public profiles whose Reddit username starts with `q`, see
+/// [`profile::search_profiles`]. `after` is the previous page's `next` cursor.
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn get_search(
+    State(db): State<&'static D1Database>,
+    Query(search_query): Query<SearchQuery>,
+) -> std::result::Result<Json<impl Serialize>, CmError> {
+    let results = profile::search_profiles(db, &search_query.q, search_query.after).await?;
+    Ok(Json(results))
+}
+
+/// Helper to parse `?force=...`.
+#[derive(serde::Deserialize)]
+pub struct UpdateQuery {
+    #[serde(default)]
+    force: bool,
+}
+
 /// `POST /summoner/:sid/update`
+///
+/// Always 202, but only enqueues a [`Task::SummonerUpdate`] if one is not already pending, see
+/// [`should_enqueue_update`]. With `?force=true`, enqueues a [`Task::SummonerRefresh`] instead,
+/// which bypasses the update cooldown and the mastery cache. Forced updates are limited to once
+/// per [`FORCED_UPDATE_INTERVAL`] per summoner, see [`check_forced_update`]. All requests count
+/// towards the user's [`UpdateRateLimit`].
 #[axum::debug_handler(state = init::AppState)]
 #[local_async]
 pub async fn post_summoner_update(
+    State(db): State<&'static D1Database>,
     State(webjob_queue): State<&'static Queue>,
-    Path(sid): Path<u64>,
+    State(webjob_config): State<&'static WebjobConfig>,
+    Path(sid): Path<SummonerId>,
+    State(UpdateRateLimit(update_rate_limit)): State<&'static UpdateRateLimit>,
+    Query(update_query): Query<UpdateQuery>,
     SessionStateSignedIn { user_id }: SessionStateSignedIn,
 ) -> std::result::Result<StatusCode, CmError> {
-    // TODO(mingwei): validate that summoner belongs to user?
-    // TODO(mingwei): validate that summoner hasn't been updated recently?
-    let _ = user_id;
-    webjob_queue.send(Task::SummonerUpdate(sid)).await?;
-    Ok(StatusCode::NO_CONTENT)
+    rate_limit::hit(db, update_rate_limit, user_id, "summoner_update").await?;
+    type SummonerVals = (
+        UserId,
+        Option<SystemTime>,
+        Option<SystemTime>,
+        Option<SystemTime>,
+    );
+    type SummonerWith = (
+        Same,
+        Option<WebSystemTime<TimestampMilliSeconds<i64>>>,
+        Option<WebSystemTime<TimestampMilliSeconds<i64>>>,
+        Option<WebSystemTime<TimestampMilliSeconds<i64>>>,
+    );
+    let summoner = checked_query!(
+        &db,
+        SummonerWith,
+        "SELECT user_id, last_success, pending_update, last_forced_update FROM summoner WHERE id = ?",
+        sid,
+    )?
+    .first::<DeserializeAsWrap<SummonerVals, IgnoreKeys<SummonerWith>>>(None)
+    .await?
+    .map(DeserializeAsWrap::into_inner);
+    let now = SystemTime::now();
+    type TimeWith = WebSystemTime<TimestampMilliSeconds<i64>>;
+
+    if update_query.force {
+        check_forced_update(
+            user_id,
+            summoner.map(|(owner_id, _, _, last_forced_update)| (owner_id, last_forced_update)),
+            now,
+        )?;
+        // Claim the forced update only if still allowed, in case of a concurrent request.
+        let claimed = query!(
+            &db,
+            "UPDATE summoner SET last_forced_update = ?, pending_update = ?
+            WHERE id = ? AND (last_forced_update IS NULL OR last_forced_update <= ?)
+            RETURNING id",
+            <SerializeAsWrap<_, TimeWith>>::new(&now),
+            <SerializeAsWrap<_, TimeWith>>::new(&now),
+            sid,
+            <SerializeAsWrap<_, TimeWith>>::new(&(now - FORCED_UPDATE_INTERVAL)),
+        )?
+        .first::<IgnoredAny>(None)
+        .await?;
+        if claimed.is_none() {
+            return Err(CmError::TooManyRequests(
+                "Summoner was force-updated too recently.".to_owned(),
+            ));
+        }
+        let sent = send_task(
+            webjob_queue,
+            Task::SummonerRefresh(sid),
+            webjob_config.send_attempts,
+        )
+        .await;
+        if let Err(e) = sent {
+            // Restore the previous markers, so the failed request does not block a retry.
+            let (pending_update, last_forced_update) = summoner
+                .map(|(_, _, pending_update, last_forced_update)| {
+                    (pending_update, last_forced_update)
+                })
+                .unwrap_or_default();
+            release_update_claim(db, sid, now, pending_update, Some(last_forced_update)).await;
+            return Err(e.into());
+        }
+        return Ok(StatusCode::ACCEPTED);
+    }
+
+    let cooldown = webjob_config.update_cooldown;
+    check_summoner_update(
+        user_id,
+        summoner.map(|(owner_id, last_update, _, _)| (owner_id, last_update)),
+        now,
+        cooldown,
+    )?;
+    let pending_update = summoner.and_then(|(_, _, pending_update, _)| pending_update);
+    if !should_enqueue_update(pending_update, now, cooldown) {
+        log::info!("Summoner {} update already pending.", sid);
+        return Ok(StatusCode::ACCEPTED);
+    }
+
+    // Set the marker only if it is still unset or stale, in case of a concurrent request.
+    let claimed = query!(
+        &db,
+        "UPDATE summoner SET pending_update = ?
+        WHERE id = ? AND (pending_update IS NULL OR pending_update <= ?)
+        RETURNING id",
+        <SerializeAsWrap<_, TimeWith>>::new(&now),
+        sid,
+        <SerializeAsWrap<_, TimeWith>>::new(&(now - cooldown)),
+    )?
+    .first::<IgnoredAny>(None)
+    .await?;
+    if claimed.is_some() {
+        let sent = send_task(
+            webjob_queue,
+            Task::SummonerUpdate(sid),
+            webjob_config.send_attempts,
+        )
+        .await;
+        if let Err(e) = sent {
+            // Restore the previous marker, so the failed request does not block a retry.
+            release_update_claim(db, sid, now, pending_update, None).await;
+            return Err(e.into());
+        }
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Undoes a claim made at `claimed_at` by [`post_summoner_update`] after failing to send its task,
+/// restoring the previous `pending_update`, and `last_forced_update` if `Some`. Does nothing if
+/// another request has claimed the summoner since. Failures are logged and otherwise ignored.
+async fn release_update_claim(
+    db: &D1Database,
+    sid: SummonerId,
+    claimed_at: SystemTime,
+    pending_update: Option<SystemTime>,
+    last_forced_update: Option<Option<SystemTime>>,
+) {
+    type TimeWith = WebSystemTime<TimestampMilliSeconds<i64>>;
+    let query = match last_forced_update {
+        Some(last_forced_update) => query!(
+            &db,
+            "UPDATE summoner SET pending_update = ?, last_forced_update = ?
+            WHERE id = ? AND last_forced_update = ?",
+            <SerializeAsWrap<_, Option<TimeWith>>>::new(&pending_update),
+            <SerializeAsWrap<_, Option<TimeWith>>>::new(&last_forced_update),
+            sid,
+            <SerializeAsWrap<_, TimeWith>>::new(&claimed_at),
+        ),
+        None => query!(
+            &db,
+            "UPDATE summoner SET pending_update = ? WHERE id = ? AND pending_update = ?",
+            <SerializeAsWrap<_, Option<TimeWith>>>::new(&pending_update),
+            sid,
+            <SerializeAsWrap<_, TimeWith>>::new(&claimed_at),
+        ),
+    };
+    let result = async {
+        let result = query?.run().await?;
+        match result.error() {
+            Some(error) => Err(worker::Error::RustError(error)),
+            None => Ok(()),
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        log::error!("Failed to release summoner {} update claim: {}", sid, e);
+    }
+}
+
+/// `GET /summoner/:sid`
+///
+/// The summoner with its own champion masteries, see [`profile::SummonerChamps`].
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn get_summoner(
+    State(db): State<&'static D1Database>,
+    Path(sid): Path<SummonerId>,
+    SessionStateSignedIn { user_id }: SessionStateSignedIn,
+) -> std::result::Result<Json<impl Serialize>, CmError> {
+    let summoner = profile::load_summoner(db, sid).await?;
+    let summoner = check_summoner_access(user_id, sid, summoner)?;
+    Ok(Json(summoner))
+}
+
+/// `DELETE /summoner/:sid`
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn delete_summoner(
+    State(db): State<&'static D1Database>,
+    State(webjob_queue): State<&'static Queue>,
+    State(webjob_config): State<&'static WebjobConfig>,
+    Path(sid): Path<SummonerId>,
+    SessionStateSignedIn { user_id }: SessionStateSignedIn,
+) -> std::result::Result<StatusCode, CmError> {
+    check_summoner_owner(db, user_id, sid).await?;
+    send_task(
+        webjob_queue,
+        Task::SummonerDelete(sid),
+        webjob_config.send_attempts,
+    )
+    .await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Helper to parse `?since=...`, in milliseconds since epoch.
+#[serde_with::serde_as]
+#[derive(serde::Deserialize)]
+pub struct EventsQuery {
+    #[serde_as(as = "Option<WebSystemTime<TimestampMilliSeconds<i64>>>")]
+    #[serde(default)]
+    since: Option<SystemTime>,
+}
+
+/// `GET /summoner/:sid/events?since=...`
+///
+/// Server-sent events stream with a single `completed` or `failed` event once the summoner's next
+/// update finishes, or `timeout` after [`events::STREAM_TIMEOUT`]. Updates finished before `since`
+/// (default now) are ignored, so clients should pass the time they requested the update. See
+/// [`events`].
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn get_summoner_events(
+    State(db): State<&'static D1Database>,
+    Path(sid): Path<SummonerId>,
+    Query(events_query): Query<EventsQuery>,
+    SessionStateSignedIn { user_id }: SessionStateSignedIn,
+) -> std::result::Result<Response, CmError> {
+    check_summoner_owner(db, user_id, sid).await?;
+    let since = events_query.since.unwrap_or_else(SystemTime::now);
+    let max_polls = (events::STREAM_TIMEOUT.as_secs() / events::POLL_INTERVAL.as_secs()) as u32;
+    // Comment line so the response headers are flushed right away.
+    let connected = futures::stream::once(ready(": connected\n\n".to_owned()));
+    let event = futures::stream::once(local_future!(async move {
+        let event = events::wait_for_event(db, sid, since, max_polls, worker::Delay::from).await;
+        events::event_message(event)
+    }));
+    let body = axum::body::Body::from_stream(
+        connected
+            .chain(event)
+            .map(Ok::<_, std::convert::Infallible>),
+    );
+    Ok((
+        [
+            (CONTENT_TYPE, HeaderValue::from_static("text/event-stream")),
+            (CACHE_CONTROL, HeaderValue::from_static("no-cache")),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// `GET /summoner/:sid/history`
+///
+/// Mastery points over time per champion. Empty unless `WEBJOB_RECORD_HISTORY` is enabled.
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn get_summoner_history(
+    State(db): State<&'static D1Database>,
+    Path(sid): Path<SummonerId>,
+    SessionStateSignedIn { user_id }: SessionStateSignedIn,
+) -> std::result::Result<Json<impl Serialize>, CmError> {
+    check_summoner_owner(db, user_id, sid).await?;
+    let history = profile::load_mastery_history(db, sid).await?;
+    Ok(Json(history))
+}
+
+/// `GET /summoner/:sid/flair`
+///
+/// Flair text computed from the summoner's masteries, see [`flair::build_flair`].
+#[axum::debug_handler(state = init::AppState)]
+#[local_async]
+pub async fn get_summoner_flair(
+    State(db): State<&'static D1Database>,
+    State(FlairMinPoints(min_points)): State<&'static FlairMinPoints>,
+    Path(sid): Path<SummonerId>,
+    SessionStateSignedIn { user_id }: SessionStateSignedIn,
+) -> std::result::Result<Json<impl Serialize>, CmError> {
+    check_summoner_owner(db, user_id, sid).await?;
+    let flair = flair::load_flair(db, sid, *min_points).await?;
+    Ok(Json(flair))
+}
+
+/// Checks that the summoner exists and belongs to `user_id`, otherwise [`CmError::Forbidden`].
+async fn check_summoner_owner(
+    db: &D1Database,
+    user_id: UserId,
+    sid: SummonerId,
+) -> std::result::Result<(), CmError> {
+    let owner_id = query!(&db, "SELECT user_id FROM summoner WHERE id = ?", sid)?
+        .first::<DeserializeAsWrap<(UserId,), IgnoreKeys<(Same,)>>>(None)
+        .await?
+        .map(|wrap| wrap.into_inner().0);
+    if Some(user_id) != owner_id {
+        return Err(CmError::Forbidden(
+            "Summoner does not belong to user.".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the summoner, given as `(owner_id, summoner)`, exists and belongs to `user_id`. Unlike
+/// [`check_summoner_owner`], nonexistent summoners are [`CmError::NotFound`].
+fn check_summoner_access<T>(
+    user_id: UserId,
+    sid: SummonerId,
+    summoner: Option<(UserId, T)>,
+) -> std::result::Result<T, CmError> {
+    match summoner {
+        None => Err(CmError::NotFound(format!(
+            "Summoner with ID {} does not exist.",
+            sid
+        ))),
+        Some((owner_id, _)) if owner_id != user_id => Err(CmError::Forbidden(
+            "Summoner does not belong to user.".to_owned(),
+        )),
+        Some((_, summoner)) => Ok(summoner),
+    }
+}
+
+/// Checks that `user_id` may update the summoner, given the summoner's `(user_id, last_success)`
+/// if it exists. Nonexistent summoners are treated the same as summoners owned by other users.
+fn check_summoner_update(
+    user_id: UserId,
+    summoner: Option<(UserId, Option<SystemTime>)>,
+    now: SystemTime,
+    cooldown: Duration,
+) -> std::result::Result<(), CmError> {
+    let last_update = match summoner {
+        Some((owner_id, last_update)) if owner_id == user_id => last_update,
+        _ => {
+            return Err(CmError::Forbidden(
+                "Summoner does not belong to user.".to_owned(),
+            ))
+        }
+    };
+    if webjob::within_cooldown(last_update, now, cooldown) {
+        return Err(CmError::TooManyRequests(
+            "Summoner was updated too recently.".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Minimum time between forced updates of a summoner, see [`post_summoner_update`].
+pub const FORCED_UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Checks that `user_id` may force an update of the summoner, given the summoner's
+/// `(user_id, last_forced_update)` if it exists. Unlike [`check_summoner_update`], ignores the
+/// regular update cooldown and instead allows one forced update per [`FORCED_UPDATE_INTERVAL`].
+fn check_forced_update(
+    user_id: UserId,
+    summoner: Option<(UserId, Option<SystemTime>)>,
+    now: SystemTime,
+) -> std::result::Result<(), CmError> {
+    check_summoner_update(user_id, summoner, now, FORCED_UPDATE_INTERVAL)
+}
+
+/// If a new [`Task::SummonerUpdate`] should be enqueued given the summoner's `pending_update`
+/// marker. Markers older than `cooldown` are left over from a lost task, so are ignored.
+fn should_enqueue_update(
+    pending_update: Option<SystemTime>,
+    now: SystemTime,
+    cooldown: Duration,
+) -> bool {
+    !webjob::within_cooldown(pending_update, now, cooldown)
+}
+
+/// Error from [`create_or_get_db_user`].
+#[derive(Debug)]
+pub enum CreateUserError {
+    /// The Reddit account can still change its name, so it cannot be identified by name yet.
+    EditableName(String),
+    /// Failed to insert or get the user row.
+    Db(Error),
+    /// The user row had an invalid (zero) ID.
+    InvalidId(u64),
+}
+impl std::fmt::Display for CreateUserError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EditableName(name) => {
+                write!(f, "Cannot add new user with editable name: /u/{}.", name)
+            }
+            Self::Db(e) => write!(f, "Failed to get or insert user: {}", e),
+            Self::InvalidId(id) => write!(f, "Invalid user ID: {}", id),
+        }
+    }
+}
+impl From<Error> for CreateUserError {
+    fn from(value: Error) -> Self {
+        Self::Db(value)
+    }
+}
+impl From<CreateUserError> for AuthError {
+    fn from(value: CreateUserError) -> Self {
+        match value {
+            CreateUserError::EditableName(_) => AuthError::Unauthorized(value.to_string()),
+            CreateUserError::Db(_) | CreateUserError::InvalidId(_) => {
+                AuthError::Internal(value.to_string())
+            }
+        }
+    }
 }
 
-// TODO: update return Result type.
-/// Create or gets a DB user from the Reddit user.
-pub async fn create_or_get_db_user(db: &D1Database, reddit_me: &reddit::Me) -> Result<NonZeroU64> {
+/// Checks that a user can be created for the Reddit account, see
+/// [`CreateUserError::EditableName`].
+fn check_reddit_me(reddit_me: &reddit::Me) -> std::result::Result<(), CreateUserError> {
     if reddit_me.can_edit_name {
-        return Result::Err(Error::RustError(format!(
-            "Cannot add new user with editable name: /u/{}.",
-            reddit_me.name
-        )));
+        return Err(CreateUserError::EditableName(reddit_me.name.clone()));
     }
+    Ok(())
+}
+
+/// Creates the user for the Reddit account if needed, returning the user's ID.
+pub async fn create_or_get_db_user(
+    db: &D1Database,
+    reddit_me: &reddit::Me,
+) -> std::result::Result<UserId, CreateUserError> {
+    check_reddit_me(reddit_me)?;
 
     let query = query!(
         &db,
@@ -321,6 +1371,366 @@ pub async fn create_or_get_db_user(db: &D1Database, reddit_me: &reddit::Me) -> R
     let id: DeserializeAsWrap<(u64,), IgnoreKeys<(Same,)>> = query
         .first(None)
         .await?
-        .ok_or("Failed to get or insert user")?;
-    Ok(id.into_inner().0.try_into().unwrap())
+        .ok_or_else(|| Error::RustError("Failed to get or insert user".to_owned()))?;
+    let id = id.into_inner().0;
+    UserId::new(id).ok_or(CreateUserError::InvalidId(id))
+}
+
+/// Gets the ID of the user who owns the summoner with the given PUUID, if any.
+pub async fn get_summoner_user_id(db: &D1Database, puuid: &str) -> Result<Option<UserId>> {
+    let query = query!(&db, "SELECT user_id FROM summoner WHERE puuid = ?", puuid)?;
+    let user_id: Option<DeserializeAsWrap<(UserId,), IgnoreKeys<(Same,)>>> =
+        query.first(None).await?;
+    Ok(user_id.map(|user_id| user_id.into_inner().0))
+}
+
+#[cfg(test)]
+mod test {
+    use std::task::{Context, Poll};
+
+    use super::*;
+
+    /// Service which always fails.
+    struct FailingService;
+    impl Service<()> for FailingService {
+        type Response = http::Response<axum::body::Body>;
+        type Error = String;
+        type Future = Ready<std::result::Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::result::Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: ()) -> Self::Future {
+            ready(Err("failed".to_owned()))
+        }
+    }
+
+    fn compressed_router() -> axum::Router {
+        axum::Router::new()
+            .route("/", routing::get(|| ready(Json(vec!["Zyra"; 1000]))))
+            .layer(compression_layer())
+    }
+
+    #[test]
+    fn test_compression_gzip() {
+        let req = http::Request::builder()
+            .header(http::header::ACCEPT_ENCODING, "gzip")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = futures::executor::block_on(call_or_500(&mut compressed_router(), req));
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("gzip", response.headers()[http::header::CONTENT_ENCODING]);
+    }
+
+    #[test]
+    fn test_compression_none() {
+        let req = http::Request::new(axum::body::Body::empty());
+        let response = futures::executor::block_on(call_or_500(&mut compressed_router(), req));
+        assert_eq!(StatusCode::OK, response.status());
+        assert!(response
+            .headers()
+            .get(http::header::CONTENT_ENCODING)
+            .is_none());
+    }
+
+    /// Sends a CORS preflight from the `cm_pages` origin for `method` to `uri`.
+    fn preflight(uri: &str, method: Method) -> http::Response<axum::body::Body> {
+        let app_state = init::test_app_state();
+        let mut app = routes()
+            .layer(cors_layer(&app_state.cm_pages_origin.0))
+            .with_state(app_state);
+        let req = http::Request::builder()
+            .method(Method::OPTIONS)
+            .uri(uri)
+            .header(http::header::ORIGIN, "http://localhost:5173")
+            .header(http::header::ACCESS_CONTROL_REQUEST_METHOD, method.as_str())
+            .header(http::header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        futures::executor::block_on(call_or_500(&mut app, req))
+    }
+
+    #[test]
+    fn test_cors_preflight_patch() {
+        let response = preflight("/user/me", Method::PATCH);
+        assert_eq!(StatusCode::OK, response.status());
+        let headers = response.headers();
+        assert_eq!(
+            "http://localhost:5173",
+            headers[http::header::ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        assert!(headers[http::header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap()
+            .contains("PATCH"));
+        assert!(headers[http::header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap()
+            .contains("content-type"));
+    }
+
+    #[test]
+    fn test_cors_preflight_delete_summoner() {
+        let response = preflight("/summoner/5", Method::DELETE);
+        assert_eq!(StatusCode::OK, response.status());
+        assert!(
+            response.headers()[http::header::ACCESS_CONTROL_ALLOW_METHODS]
+                .to_str()
+                .unwrap()
+                .contains("DELETE")
+        );
+        assert_eq!(
+            "true",
+            response.headers()[http::header::ACCESS_CONTROL_ALLOW_CREDENTIALS]
+        );
+    }
+
+    #[test]
+    fn test_index_redirect() {
+        let url = "http://localhost:5173/";
+        for (permanent, status) in [
+            (false, StatusCode::TEMPORARY_REDIRECT),
+            (true, StatusCode::PERMANENT_REDIRECT),
+        ] {
+            let response = index_redirect(url, permanent);
+            assert_eq!(status, response.status());
+            assert_eq!(url, response.headers()[http::header::LOCATION]);
+            assert_eq!("public, max-age=3600", response.headers()[CACHE_CONTROL]);
+        }
+    }
+
+    #[test]
+    fn test_call_or_500() {
+        let response = futures::executor::block_on(call_or_500(&mut FailingService, ()));
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+    }
+
+    fn uid(id: u64) -> UserId {
+        UserId::new(id).unwrap()
+    }
+
+    fn sid(id: u64) -> SummonerId {
+        SummonerId::new(id).unwrap()
+    }
+
+    #[test]
+    fn test_summoner_zero_id() {
+        let mut app = routes().with_state(init::test_app_state());
+        let mut call = |req: http::Request<axum::body::Body>| {
+            futures::executor::block_on(call_or_500(&mut app, req)).status()
+        };
+
+        // Rejected by the `Path` extractor, before the session extractor (which needs the JS
+        // runtime) or the handler could run, so nothing is enqueued.
+        let req = http::Request::post("/summoner/0/update")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, call(req));
+        let req = http::Request::get("/summoner/0/flair")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, call(req));
+    }
+
+    #[test]
+    fn test_check_summoner_update() {
+        let user_id = uid(5);
+        let now = SystemTime::now();
+        let cooldown = Duration::from_secs(60);
+
+        assert!(check_summoner_update(user_id, Some((user_id, None)), now, cooldown).is_ok());
+        assert!(check_summoner_update(
+            user_id,
+            Some((user_id, Some(now - Duration::from_secs(90)))),
+            now,
+            cooldown
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_check_summoner_update_foreign() {
+        let user_id = uid(5);
+        let now = SystemTime::now();
+        let cooldown = Duration::from_secs(60);
+
+        assert!(matches!(
+            check_summoner_update(user_id, Some((uid(6), None)), now, cooldown),
+            Err(CmError::Forbidden(_))
+        ));
+        assert!(matches!(
+            check_summoner_update(user_id, None, now, cooldown),
+            Err(CmError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn test_search_query_after() {
+        let uri = "/search?q=lug".parse().unwrap();
+        let Query(search_query) = Query::<SearchQuery>::try_from_uri(&uri).unwrap();
+        assert_eq!(None, search_query.after);
+
+        let uri = "/search?q=lug&after=k".parse().unwrap();
+        let Query(search_query) = Query::<SearchQuery>::try_from_uri(&uri).unwrap();
+        assert_eq!(Some(20), search_query.after);
+
+        let uri = "/search?q=lug&after=k!".parse().unwrap();
+        assert!(Query::<SearchQuery>::try_from_uri(&uri).is_err());
+    }
+
+    #[test]
+    fn test_check_summoner_access() {
+        let user_id = uid(5);
+
+        assert!(matches!(
+            check_summoner_access(user_id, sid(1), Some((user_id, "ok"))),
+            Ok("ok")
+        ));
+        assert!(matches!(
+            check_summoner_access(user_id, sid(1), Some((uid(6), "ok"))),
+            Err(CmError::Forbidden(_))
+        ));
+        assert!(matches!(
+            check_summoner_access::<()>(user_id, sid(1), None),
+            Err(CmError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_should_enqueue_update() {
+        let now = SystemTime::now();
+        let cooldown = Duration::from_secs(60);
+
+        assert!(should_enqueue_update(None, now, cooldown));
+        // Double click, already pending.
+        assert!(!should_enqueue_update(
+            Some(now - Duration::from_secs(1)),
+            now,
+            cooldown
+        ));
+        // Stale marker.
+        assert!(should_enqueue_update(
+            Some(now - Duration::from_secs(90)),
+            now,
+            cooldown
+        ));
+    }
+
+    #[test]
+    fn test_check_summoner_update_too_soon() {
+        let user_id = uid(5);
+        let now = SystemTime::now();
+        let cooldown = Duration::from_secs(60);
+
+        assert!(matches!(
+            check_summoner_update(
+                user_id,
+                Some((user_id, Some(now - Duration::from_secs(30)))),
+                now,
+                cooldown
+            ),
+            Err(CmError::TooManyRequests(_))
+        ));
+    }
+    #[test]
+    fn test_check_forced_update() {
+        let user_id = uid(5);
+        let now = SystemTime::now();
+        let cooldown = Duration::from_secs(600);
+        let last_update = Some(now - Duration::from_secs(30));
+
+        // Normal updates respect the cooldown, forced updates do not.
+        assert!(matches!(
+            check_summoner_update(user_id, Some((user_id, last_update)), now, cooldown),
+            Err(CmError::TooManyRequests(_))
+        ));
+        assert!(check_forced_update(user_id, Some((user_id, None)), now).is_ok());
+        assert!(check_forced_update(
+            user_id,
+            Some((user_id, Some(now - FORCED_UPDATE_INTERVAL))),
+            now
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_check_forced_update_limited() {
+        let user_id = uid(5);
+        let now = SystemTime::now();
+
+        assert!(matches!(
+            check_forced_update(
+                user_id,
+                Some((user_id, Some(now - Duration::from_secs(30)))),
+                now
+            ),
+            Err(CmError::TooManyRequests(_))
+        ));
+        assert!(matches!(
+            check_forced_update(user_id, Some((uid(6), None)), now),
+            Err(CmError::Forbidden(_))
+        ));
+        assert!(matches!(
+            check_forced_update(user_id, None, now),
+            Err(CmError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn test_rso_link_platform() {
+        assert_eq!(
+            Some(PlatformRoute::NA1),
+            rso_link_platform(Some("NA1"), None).unwrap()
+        );
+        // `cpid` from the verified token takes precedence.
+        assert_eq!(
+            Some(PlatformRoute::NA1),
+            rso_link_platform(Some("NA1"), Some(PlatformRoute::EUW1)).unwrap()
+        );
+        assert_eq!(
+            Some(PlatformRoute::EUW1),
+            rso_link_platform(None, Some(PlatformRoute::EUW1)).unwrap()
+        );
+        // Left to `webjob::infer_platform`.
+        assert_eq!(None, rso_link_platform(None, None).unwrap());
+        assert!(matches!(
+            rso_link_platform(Some("XX9"), None),
+            Err(CmError::BadRequest(_))
+        ));
+    }
+
+    fn reddit_me(can_edit_name: bool) -> reddit::Me {
+        reddit::Me {
+            id: 5,
+            name: "LugnutsK".to_owned(),
+            can_edit_name,
+            icon_img: String::new(),
+            created_utc: SystemTime::UNIX_EPOCH,
+            total_karma: 0,
+        }
+    }
+
+    #[test]
+    fn test_check_reddit_me_editable_name() {
+        assert!(check_reddit_me(&reddit_me(false)).is_ok());
+
+        let err = check_reddit_me(&reddit_me(true)).unwrap_err();
+        assert!(matches!(&err, CreateUserError::EditableName(name) if "LugnutsK" == name));
+        assert!(matches!(
+            AuthError::from(err),
+            AuthError::Unauthorized(msg) if msg.contains("/u/LugnutsK")
+        ));
+    }
+
+    #[test]
+    fn test_create_user_error_internal() {
+        assert!(matches!(
+            AuthError::from(CreateUserError::InvalidId(0)),
+            AuthError::Internal(_)
+        ));
+    }
 }
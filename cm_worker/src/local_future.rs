@@ -19,8 +19,9 @@ impl<T> LocalFuture<T>
 where
     T: 'static,
 {
-    /// Wraps the future.
+    /// Wraps the future. The [`crate::request_id::current`] request ID is carried over.
     pub fn spawn(future: impl Future<Output = T> + 'static) -> Self {
+        let future = crate::request_id::scope(crate::request_id::current(), future);
         let (send, recv) = oneshot::channel();
         wasm_bindgen_futures::spawn_local(async move {
             let out = future.await;
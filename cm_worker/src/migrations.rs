@@ -0,0 +1,237 @@
+//! Embedded schema migrations, applied in order by `POST /admin/migrations`.
+//!
+//! Applied versions are tracked in the `_migrations` table. Migrations already applied by
+//! `wrangler d1 migrations apply`, which are tracked in its `d1_migrations` table, count as applied
+//! too, so switching a database over never re-runs them.
+
+use serde_with::de::DeserializeAsWrap;
+use serde_with::ser::SerializeAsWrap;
+use serde_with::{Same, TimestampMilliSeconds};
+use web_time::SystemTime;
+use worker::{query, D1Database, Error};
+
+use crate::with::{IgnoreKeys, WebSystemTime};
+
+/// An embedded migration file.
+#[derive(Clone, Copy, Debug)]
+pub struct Migration {
+    /// Migration number, the file name prefix.
+    pub version: u32,
+    /// File name, e.g. `0001_create.sql`.
+    pub name: &'static str,
+    /// SQL statements, separated by `;`.
+    pub sql: &'static str,
+}
+
+/// Embeds `migrations/{name}`.
+macro_rules! migration {
+    ($version:literal, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!("../../migrations/", $name)),
+        }
+    };
+}
+
+/// All migrations, in order. Must be kept in sync with the `migrations/` directory.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_create.sql"),
+    migration!(2, "0002_user_oauth_token.sql"),
+    migration!(3, "0003_revoked_nonce.sql"),
+    migration!(4, "0004_summoner_profile.sql"),
+    migration!(5, "0005_summoner_league.sql"),
+    migration!(6, "0006_summoner_match.sql"),
+    migration!(7, "0007_summoner_champion_mastery_history.sql"),
+    migration!(8, "0008_summoner_pending_update.sql"),
+    migration!(9, "0009_summoner_champion_mastery_play_time.sql"),
+    migration!(10, "0010_summoner_last_forced_update.sql"),
+    migration!(11, "0011_user_rate_limit.sql"),
+    migration!(12, "0012_summoner_update_indexes.sql"),
+    migration!(13, "0013_summoner_last_attempt.sql"),
+    migration!(14, "0014_user_oauth_token_refresh_error.sql"),
+    migration!(15, "0015_summoner_update_event.sql"),
+];
+
+/// Splits migration `sql` into statements, dropping `--` comments.
+pub fn split_statements(sql: &str) -> Vec<String> {
+    let sql = sql
+        .lines()
+        .map(|line| line.split_once("--").map_or(line, |(code, _comment)| code))
+        .collect::<Vec<_>>()
+        .join("\n");
+    sql.split(';')
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Migration version of a `d1_migrations` file name, e.g. `1` for `0001_create.sql`.
+fn wrangler_version(name: &str) -> Option<u32> {
+    name.split_once('_')?.0.parse().ok()
+}
+
+/// Storage for migrations and their applied versions.
+#[allow(async_fn_in_trait)]
+pub trait MigrationStore {
+    /// Versions of all applied migrations.
+    async fn applied_versions(&self) -> worker::Result<Vec<u32>>;
+    /// Applies the migration and records its version, atomically.
+    async fn apply(&self, migration: &Migration) -> worker::Result<()>;
+}
+impl MigrationStore for D1Database {
+    async fn applied_versions(&self) -> worker::Result<Vec<u32>> {
+        type Row = DeserializeAsWrap<(u32,), IgnoreKeys<(Same,)>>;
+        type NameRow = DeserializeAsWrap<(String,), IgnoreKeys<(Same,)>>;
+
+        let create = self.prepare(
+            "CREATE TABLE IF NOT EXISTS _migrations (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at INTEGER NOT NULL
+            )",
+        );
+        if let Some(error) = create.run().await?.error() {
+            return Err(Error::RustError(error));
+        }
+        let mut versions = self
+            .prepare("SELECT version FROM _migrations")
+            .all()
+            .await?
+            .results::<Row>()?
+            .into_iter()
+            .map(|row| row.into_inner().0)
+            .collect::<Vec<_>>();
+
+        let wrangler_table = self
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'd1_migrations'")
+            .first::<serde::de::IgnoredAny>(None)
+            .await?;
+        if wrangler_table.is_some() {
+            let names = self
+                .prepare("SELECT name FROM d1_migrations")
+                .all()
+                .await?
+                .results::<NameRow>()?;
+            versions.extend(
+                names
+                    .into_iter()
+                    .filter_map(|row| wrangler_version(&row.into_inner().0)),
+            );
+        }
+        Ok(versions)
+    }
+
+    async fn apply(&self, migration: &Migration) -> worker::Result<()> {
+        let mut statements = split_statements(migration.sql)
+            .iter()
+            .map(|statement| self.prepare(statement))
+            .collect::<Vec<_>>();
+        statements.push(query!(
+            self,
+            "INSERT INTO _migrations(version, name, applied_at) VALUES (?, ?, ?)",
+            migration.version,
+            migration.name,
+            <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(
+                &SystemTime::now()
+            ),
+        )?);
+        // D1 runs a batch as a single transaction.
+        let errors = self
+            .batch(statements)
+            .await?
+            .into_iter()
+            .filter_map(|result| result.error())
+            .collect::<Vec<_>>();
+        errors
+            .is_empty()
+            .then_some(())
+            .ok_or(Error::RustError(format!(
+                "Migration `{}` failed: {:?}",
+                migration.name, errors
+            )))
+    }
+}
+
+/// Applies each of `migrations` not yet applied to `store`, in order. Returns the newly applied
+/// versions, so applying again is a no-op returning none.
+pub async fn apply_migrations_with(
+    store: &impl MigrationStore,
+    migrations: &[Migration],
+) -> worker::Result<Vec<u32>> {
+    let applied = store.applied_versions().await?;
+    let mut newly_applied = Vec::new();
+    for migration in migrations {
+        if applied.contains(&migration.version) {
+            continue;
+        }
+        log::info!("Applying migration `{}`.", migration.name);
+        store.apply(migration).await?;
+        newly_applied.push(migration.version);
+    }
+    Ok(newly_applied)
+}
+
+/// Applies all pending [`MIGRATIONS`] to `db`. See [`apply_migrations_with`].
+pub async fn apply_migrations(db: &D1Database) -> worker::Result<Vec<u32>> {
+    apply_migrations_with(db, MIGRATIONS).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// In-memory store, records applied versions.
+    #[derive(Default)]
+    struct VecStore(RefCell<Vec<u32>>);
+    impl MigrationStore for VecStore {
+        async fn applied_versions(&self) -> worker::Result<Vec<u32>> {
+            Ok(self.0.borrow().clone())
+        }
+
+        async fn apply(&self, migration: &Migration) -> worker::Result<()> {
+            self.0.borrow_mut().push(migration.version);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_migrations_ordered() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(i as u32 + 1, migration.version);
+            assert_eq!(Some(migration.version), wrangler_version(migration.name));
+            assert!(!split_statements(migration.sql).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_split_statements() {
+        let sql = "-- Header; not a statement.\nALTER TABLE a ADD COLUMN b INTEGER; -- Trailing.\n\nCREATE TABLE c (\n    -- Column.\n    d TEXT\n);\n";
+        assert_eq!(
+            vec![
+                "ALTER TABLE a ADD COLUMN b INTEGER",
+                "CREATE TABLE c (\n    \n    d TEXT\n)"
+            ],
+            split_statements(sql)
+        );
+    }
+
+    #[test]
+    fn test_apply_migrations_idempotent() {
+        let store = VecStore::default();
+        store.0.borrow_mut().push(1);
+
+        let applied = block_on(apply_migrations_with(&store, MIGRATIONS)).unwrap();
+        assert_eq!((2..=MIGRATIONS.len() as u32).collect::<Vec<_>>(), applied);
+        assert_eq!(MIGRATIONS.len(), store.0.borrow().len());
+
+        let applied = block_on(apply_migrations_with(&store, MIGRATIONS)).unwrap();
+        assert_eq!(Vec::<u32>::new(), applied);
+        assert_eq!(MIGRATIONS.len(), store.0.borrow().len());
+    }
+}
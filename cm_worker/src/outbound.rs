@@ -0,0 +1,82 @@
+//! Timeouts for outgoing HTTP requests (Reddit and oauth providers).
+//!
+//! On `wasm32`, `reqwest` sends requests with the JS `fetch` API and its `ClientBuilder` has no
+//! `timeout` or `connect_timeout`, so those only apply to native builds. Instead, requests are
+//! raced against a [`worker::Delay`] with [`with_timeout`]. Dropping the `fetch` future abandons
+//! the request. `fetch` has no separate connect phase, so the overall timeout bounds connecting
+//! too. Riot API calls go through `riven`'s own client and are not covered.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::pin;
+
+use futures::future::{select, Either};
+use web_time::Duration;
+
+/// Default for `HTTP_TIMEOUT_SECS`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connect timeout, native builds only.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request did not complete within its timeout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimedOut(pub Duration);
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request timed out after {:?}.", self.0)
+    }
+}
+
+/// Runs `future`, giving up with [`TimedOut`] if it does not complete within `timeout`. Uses
+/// [`worker::Delay`] to wait.
+pub async fn with_timeout<F>(timeout: Duration, future: F) -> Result<F::Output, TimedOut>
+where
+    F: Future,
+{
+    with_timeout_sleep(timeout, worker::Delay::from, future).await
+}
+
+/// Same as [`with_timeout`], but uses `sleep` to wait.
+pub async fn with_timeout_sleep<F, S, SleepFut>(
+    timeout: Duration,
+    sleep: S,
+    future: F,
+) -> Result<F::Output, TimedOut>
+where
+    F: Future,
+    S: FnOnce(Duration) -> SleepFut,
+    SleepFut: Future<Output = ()>,
+{
+    match select(pin!(future), pin!(sleep(timeout))).await {
+        Either::Left((output, _sleep)) => Ok(output),
+        Either::Right(((), _future)) => Err(TimedOut(timeout)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::{pending, ready};
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_with_timeout_slow() {
+        // Endpoint which never responds.
+        let slow = pending::<Result<&str, ()>>();
+        assert_eq!(
+            Err(TimedOut(DEFAULT_TIMEOUT)),
+            block_on(with_timeout_sleep(DEFAULT_TIMEOUT, |_| ready(()), slow))
+        );
+    }
+
+    #[test]
+    fn test_with_timeout_fast() {
+        let fast = ready(Ok::<_, ()>("body"));
+        assert_eq!(
+            Ok(Ok("body")),
+            block_on(with_timeout_sleep(DEFAULT_TIMEOUT, |_| pending(), fast))
+        );
+    }
+}
@@ -0,0 +1,940 @@
+//! User profiles, as returned by `GET /user/me` and `GET /profile/:reddit_user_name`.
+
+use riven::consts::{Champion, PlatformRoute};
+use serde_with::de::DeserializeAsWrap;
+use serde_with::{serde_as, DefaultOnNull, Same, TimestampMilliSeconds};
+use sha2::{Digest, Sha256};
+use web_time::SystemTime;
+use worker::{query, D1Database};
+
+use crate::ddragon;
+use crate::error::{batch_results, CmError, DecodeContext};
+use crate::ids::{SummonerId, UserId};
+use crate::with::{Base36, IgnoreKeys, LenientU64, WebSystemTime};
+
+/// A user with their summoners and champion masteries.
+#[serde_as]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct User {
+    /// Reddit username (no "/u/").
+    pub reddit_user_name: String,
+    /// If the profile is visible at `GET /profile/:reddit_user_name`.
+    #[serde_as(as = "serde_with::BoolFromInt")]
+    pub profile_is_public: bool,
+    /// Profile background skin, `champID * 1000 + skinIdx`.
+    pub profile_bgskinid: Option<u64>,
+    /// The user's summoners.
+    #[serde(skip_deserializing)]
+    pub summoners: Vec<Summoner>,
+    /// Champion masteries, summed across all the user's summoners.
+    #[serde(skip_deserializing)]
+    pub champs: Vec<Champ>,
+    /// Splash art of [`Self::profile_bgskinid`], see [`Self::set_image_urls`].
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub profile_bg_url: Option<String>,
+}
+
+impl User {
+    /// Removes [`Self::champs`] with fewer than `min_points` total points, for public views. See
+    /// [`crate::flair::retain_min_points`].
+    pub fn retain_min_points(&mut self, min_points: u64) {
+        self.champs.retain(|champ| min_points <= champ.total_points);
+    }
+
+    /// Sets [`Self::profile_bg_url`] and each [`Champ::square_url`], for Data Dragon `version`.
+    pub fn set_image_urls(&mut self, version: &str) {
+        self.profile_bg_url = self.profile_bgskinid.and_then(ddragon::champion_splash_url);
+        for champ in self.champs.iter_mut() {
+            champ.square_url = Some(ddragon::champion_square_url(champ.champ_id, version));
+        }
+    }
+
+    /// Weak ETag value for this profile as returned with `champs_query`. Changes whenever any
+    /// summoner is added, removed, or updated (see `last_update`), or the settings change.
+    ///
+    /// A SHA-256 of the inputs' JSON, so it is stable across builds and isolates.
+    pub fn etag(&self, champs_query: &ChampsQuery) -> String {
+        let summoners = self
+            .summoners
+            .iter()
+            .map(|summoner| {
+                let last_update = summoner.last_update.and_then(|last_update| {
+                    last_update.duration_since(SystemTime::UNIX_EPOCH).ok()
+                });
+                (
+                    summoner.id,
+                    last_update.map(|since| since.as_millis() as u64),
+                )
+            })
+            .collect::<Vec<_>>();
+        let input = (
+            self.profile_is_public,
+            self.profile_bgskinid,
+            &self.profile_bg_url,
+            // Changes with the Data Dragon version.
+            self.champs
+                .first()
+                .and_then(|champ| champ.square_url.as_deref()),
+            summoners,
+            champs_query,
+        );
+        let digest = Sha256::digest(serde_json::to_vec(&input).unwrap());
+        let hex = digest[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        format!("W/\"{}\"", hex)
+    }
+}
+
+/// A summoner belonging to a [`User`].
+#[serde_as]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Summoner {
+    /// PK ID.
+    pub id: SummonerId,
+    /// Riot PUUID.
+    pub puuid: String,
+    /// Platform.
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub platform: PlatformRoute,
+    /// Riot ID game name.
+    pub game_name: String,
+    /// Riot ID tag line.
+    pub tag_line: String,
+    /// Last time the summoner was successfully updated (`last_success`), `None` if never (new
+    /// summoners).
+    #[serde_as(as = "Option<WebSystemTime<TimestampMilliSeconds<i64>>>")]
+    pub last_update: Option<SystemTime>,
+    /// Summoner-v4 profile icon.
+    pub profile_icon_id: Option<i32>,
+    /// Summoner-v4 level.
+    pub summoner_level: Option<u64>,
+    /// Solo queue tier, e.g. `"GOLD"`.
+    pub solo_tier: Option<String>,
+    /// Solo queue division, e.g. `"IV"`.
+    pub solo_rank: Option<String>,
+    /// Solo queue LP.
+    pub solo_league_points: Option<i32>,
+}
+
+/// A single [`Summoner`] with its own champion masteries, as returned by `GET /summoner/:sid`.
+#[derive(serde::Serialize)]
+pub struct SummonerChamps {
+    /// The summoner.
+    #[serde(flatten)]
+    pub summoner: Summoner,
+    /// Champion masteries of only this summoner.
+    pub champs: Vec<Champ>,
+}
+
+/// A champion mastery, summed across a [`User`]'s summoners.
+#[serde_as]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Champ {
+    /// Champion.
+    pub champ_id: Champion,
+    /// Total mastery points.
+    #[serde_as(as = "LenientU64")]
+    pub total_points: u64,
+    /// Highest mastery level.
+    #[serde_as(as = "LenientU64")]
+    pub max_level: u64,
+    /// Last time the champion was played on any summoner, `None` if never.
+    #[serde_as(as = "Option<WebSystemTime<TimestampMilliSeconds<i64>>>")]
+    #[serde(default)]
+    pub last_play_time: Option<SystemTime>,
+    /// Most mastery tokens earned on any summoner, if known.
+    #[serde(default)]
+    pub tokens_earned: Option<u64>,
+    /// Champion display name, see [`champ_display`].
+    #[serde(skip_deserializing)]
+    pub name: &'static str,
+    /// Champion key, e.g. `"MonkeyKing"` for Wukong, for building image URLs. See [`champ_display`].
+    #[serde(skip_deserializing)]
+    pub key: &'static str,
+    /// Square icon URL, see [`User::set_image_urls`].
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub square_url: Option<String>,
+}
+
+/// Returns the champion's display name and key, e.g. `("Wukong", "MonkeyKing")`, or `"Unknown"`
+/// for both if the champion is not known.
+pub fn champ_display(champ: Champion) -> (&'static str, &'static str) {
+    (
+        champ.name().unwrap_or("Unknown"),
+        champ.identifier().unwrap_or("Unknown"),
+    )
+}
+
+/// A snapshot of a champion mastery, see [`ChampHistory`].
+#[serde_as]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct MasterySnapshot {
+    /// When the snapshot was taken.
+    #[serde_as(as = "crate::with::WebSystemTime<serde_with::TimestampMilliSeconds<i64>>")]
+    pub captured_at: SystemTime,
+    /// Mastery points.
+    pub points: u64,
+    /// Mastery level.
+    pub level: u64,
+}
+
+/// A summoner's mastery points over time for one champion.
+#[derive(serde::Serialize)]
+pub struct ChampHistory {
+    /// Champion.
+    pub champ_id: Champion,
+    /// Champion display name.
+    pub name: Option<&'static str>,
+    /// Snapshots, oldest first.
+    pub snapshots: Vec<MasterySnapshot>,
+}
+
+/// Loads the summoner's recorded mastery history, see `WEBJOB_RECORD_HISTORY`.
+pub async fn load_mastery_history(
+    db: &D1Database,
+    summoner_id: SummonerId,
+) -> Result<Vec<ChampHistory>, CmError> {
+    type RowVals = (Champion, u64, u64, SystemTime);
+    type RowWith = (Same, Same, Same, WebSystemTime<TimestampMilliSeconds<i64>>);
+    let rows = checked_query!(
+        &db,
+        RowWith,
+        "SELECT champ_id, points, level, captured_at
+        FROM summoner_champion_mastery_history
+        WHERE summoner_id = ?
+        ORDER BY champ_id, captured_at",
+        summoner_id,
+    )?
+    .all()
+    .await?
+    .results::<DeserializeAsWrap<RowVals, IgnoreKeys<RowWith>>>()?;
+
+    let mut history: Vec<ChampHistory> = Vec::new();
+    for row in rows {
+        let (champ_id, points, level, captured_at) = row.into_inner();
+        let snapshot = MasterySnapshot {
+            captured_at,
+            points,
+            level,
+        };
+        match history.last_mut() {
+            Some(champ_history) if champ_id == champ_history.champ_id => {
+                champ_history.snapshots.push(snapshot)
+            }
+            _ => history.push(ChampHistory {
+                champ_id,
+                name: champ_id.name(),
+                snapshots: vec![snapshot],
+            }),
+        }
+    }
+    Ok(history)
+}
+
+/// Maximum `?limit=` for [`ChampsQuery`].
+pub const MAX_CHAMPS_LIMIT: i64 = 500;
+
+/// Sort order for [`User::champs`].
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChampsSort {
+    /// Total mastery points, descending.
+    #[default]
+    Points,
+    /// Highest mastery level, descending. Ties broken by points.
+    Level,
+    /// Champion name, ascending.
+    Name,
+}
+
+/// Pagination and sorting for [`User::champs`], from `?limit=&offset=&sort=`.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct ChampsQuery {
+    /// Maximum number of champs, unlimited if absent.
+    pub limit: Option<i64>,
+    /// Number of champs to skip.
+    pub offset: Option<i64>,
+    /// Sort order.
+    #[serde(default)]
+    pub sort: ChampsSort,
+}
+impl ChampsQuery {
+    /// Sorts and paginates `champs`.
+    pub fn apply(&self, champs: &mut Vec<Champ>) -> Result<(), CmError> {
+        let offset = match self.offset {
+            Some(offset) => usize::try_from(offset).map_err(|_| {
+                CmError::BadRequest(format!(
+                    "Invalid `offset`, must be non-negative: {}",
+                    offset
+                ))
+            })?,
+            None => 0,
+        };
+        let limit = match self.limit {
+            Some(limit @ 0..=MAX_CHAMPS_LIMIT) => limit as usize,
+            Some(limit) => {
+                return Err(CmError::BadRequest(format!(
+                    "Invalid `limit`, must be between 0 and {}: {}",
+                    MAX_CHAMPS_LIMIT, limit
+                )))
+            }
+            None => usize::MAX,
+        };
+
+        match self.sort {
+            ChampsSort::Points => champs.sort_by(|a, b| b.total_points.cmp(&a.total_points)),
+            ChampsSort::Level => champs
+                .sort_by(|a, b| (b.max_level, b.total_points).cmp(&(a.max_level, a.total_points))),
+            ChampsSort::Name => champs.sort_by_key(|champ| champ.name),
+        }
+        champs.drain(..offset.min(champs.len()));
+        champs.truncate(limit);
+        Ok(())
+    }
+}
+
+/// Loads the user's profile, with their summoners and champion masteries.
+pub async fn load_profile(db: &D1Database, user_id: UserId) -> Result<User, CmError> {
+    let user_query = query!(
+        &db,
+        "SELECT reddit_user_name, profile_is_public, profile_bgskinid
+        FROM user
+        WHERE id = ?",
+        user_id,
+    )?;
+    let summoners_query = query!(
+        &db,
+        "SELECT id, puuid, platform, game_name, tag_line, last_success AS last_update,
+            profile_icon_id, summoner_level, solo_tier, solo_rank, solo_league_points
+        FROM summoner
+        WHERE user_id = ?",
+        user_id,
+    )?;
+    let champs_query = query!(
+        &db,
+        "SELECT champ_id, SUM(points) AS total_points, MAX(level) AS max_level,
+            MAX(last_play_time) AS last_play_time, MAX(tokens_earned) AS tokens_earned
+        FROM summoner_champion_mastery cm
+        JOIN summoner s ON s.id = cm.summoner_id
+        WHERE s.user_id = ?
+        GROUP BY champ_id
+        ORDER BY total_points DESC",
+        user_id,
+    )?;
+
+    let results = db
+        .batch(vec![user_query, summoners_query, champs_query])
+        .await?;
+    let [user_result, summoners_result, champs_result] = batch_results(&results)?;
+
+    let mut user: User = user_result
+        .results()
+        .decode_context("load_profile user")?
+        .into_iter()
+        .next()
+        .ok_or_else(|| CmError::NotFound(format!("User with ID {} does not exist.", user_id)))?;
+    user.summoners = summoners_result
+        .results()
+        .decode_context("load_profile summoners")?;
+    user.champs = champs_result
+        .results()
+        .decode_context("load_profile champs")?;
+    // Add `name` and `key` to each champ
+    for champ in user.champs.iter_mut() {
+        (champ.name, champ.key) = champ_display(champ.champ_id);
+    }
+    Ok(user)
+}
+
+/// Loads the summoner with its champion masteries, along with the ID of the user who owns it.
+/// `None` if the summoner does not exist.
+pub async fn load_summoner(
+    db: &D1Database,
+    summoner_id: SummonerId,
+) -> Result<Option<(UserId, SummonerChamps)>, CmError> {
+    #[derive(serde::Deserialize)]
+    struct OwnerRow {
+        user_id: UserId,
+    }
+    let summoner_query = query!(
+        &db,
+        "SELECT user_id, id, puuid, platform, game_name, tag_line, last_success AS last_update,
+            profile_icon_id, summoner_level, solo_tier, solo_rank, solo_league_points
+        FROM summoner
+        WHERE id = ?",
+        summoner_id,
+    )?;
+    let champs_query = query!(
+        &db,
+        "SELECT champ_id, SUM(points) AS total_points, MAX(level) AS max_level,
+            MAX(last_play_time) AS last_play_time, MAX(tokens_earned) AS tokens_earned
+        FROM summoner_champion_mastery
+        WHERE summoner_id = ?
+        GROUP BY champ_id
+        ORDER BY total_points DESC",
+        summoner_id,
+    )?;
+
+    let results = db.batch(vec![summoner_query, champs_query]).await?;
+    let [summoner_result, champs_result] = batch_results(&results)?;
+
+    let Some(owner) = summoner_result
+        .results::<OwnerRow>()
+        .decode_context("load_summoner owner")?
+        .into_iter()
+        .next()
+    else {
+        return Ok(None);
+    };
+    let Some(summoner) = summoner_result
+        .results::<Summoner>()
+        .decode_context("load_summoner summoner")?
+        .into_iter()
+        .next()
+    else {
+        return Ok(None);
+    };
+    let mut champs: Vec<Champ> = champs_result
+        .results()
+        .decode_context("load_summoner champs")?;
+    for champ in champs.iter_mut() {
+        (champ.name, champ.key) = champ_display(champ.champ_id);
+    }
+    Ok(Some((owner.user_id, SummonerChamps { summoner, champs })))
+}
+
+/// Gets the ID of the user with the given Reddit username, only if their profile is public.
+pub async fn get_public_user_id(
+    db: &D1Database,
+    reddit_user_name: &str,
+) -> Result<Option<UserId>, CmError> {
+    #[derive(serde::Deserialize)]
+    struct Row {
+        id: UserId,
+    }
+    let row = query!(
+        &db,
+        "SELECT id FROM user WHERE reddit_user_name = ? AND profile_is_public = 1",
+        reddit_user_name,
+    )?
+    .first::<Row>(None)
+    .await?;
+    Ok(row.map(|row| row.id))
+}
+
+/// Aggregate champion mastery numbers across all of a user's summoners, see [`load_user_stats`].
+/// `NULL` aggregates deserialize as zero.
+#[serde_as]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct UserStats {
+    /// Total mastery points.
+    #[serde_as(as = "DefaultOnNull<LenientU64>")]
+    pub total_points: u64,
+    /// Number of champions at mastery level 7 or higher on any summoner.
+    #[serde_as(as = "DefaultOnNull<LenientU64>")]
+    pub level_7_champs: u64,
+    /// Most total points on a single champion.
+    #[serde_as(as = "DefaultOnNull<LenientU64>")]
+    pub max_champ_points: u64,
+}
+
+/// Aggregates [`UserStats`] over the per-champion totals, as in [`load_profile`]. Aggregates always
+/// return one row, `COALESCE` turns `NULL`s into zeros for users without masteries.
+const USER_STATS_SQL: &str = "SELECT COALESCE(SUM(total_points), 0) AS total_points,
+        COUNT(CASE WHEN 7 <= max_level THEN 1 END) AS level_7_champs,
+        COALESCE(MAX(total_points), 0) AS max_champ_points
+    FROM (
+        SELECT SUM(cm.points) AS total_points, MAX(cm.level) AS max_level
+        FROM summoner_champion_mastery cm
+        JOIN summoner s ON s.id = cm.summoner_id
+        WHERE s.user_id = ?
+        GROUP BY cm.champ_id
+    )";
+
+/// Loads the user's [`UserStats`], all zeros if the user has no summoners.
+pub async fn load_user_stats(db: &D1Database, user_id: UserId) -> Result<UserStats, CmError> {
+    let stats = query!(&db, USER_STATS_SQL, user_id)?
+        .first::<UserStats>(None)
+        .await?
+        .unwrap_or_default();
+    Ok(stats)
+}
+
+/// Maximum number of results per page from [`search_profiles`].
+pub const MAX_SEARCH_RESULTS: u32 = 20;
+
+/// Public profiles matching a prefix with IDs after the cursor, sorted by ID. Selects one more
+/// than a page to tell if there is a next page, see [`search_page`].
+const SEARCH_PROFILES_SQL: &str = "SELECT u.id, u.reddit_user_name, (
+        SELECT cm.champ_id
+        FROM summoner_champion_mastery cm
+        JOIN summoner s ON s.id = cm.summoner_id
+        WHERE s.user_id = u.id
+        GROUP BY cm.champ_id
+        ORDER BY SUM(cm.points) DESC
+        LIMIT 1
+    ) AS top_champion
+    FROM user u
+    WHERE u.profile_is_public = 1 AND u.reddit_user_name LIKE ? ESCAPE '\\' AND u.id > ?
+    ORDER BY u.id
+    LIMIT ?";
+
+/// A public profile found by [`search_profiles`].
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SearchResult {
+    /// User ID, only used for the [`SearchPage::next`] cursor.
+    #[serde(skip_serializing)]
+    pub id: UserId,
+    /// Reddit username (no "/u/").
+    pub reddit_user_name: String,
+    /// Champion with the most total points, if any.
+    pub top_champion: Option<Champion>,
+}
+
+/// A page of [`SearchResult`]s, see [`search_profiles`].
+#[serde_as]
+#[derive(serde::Serialize)]
+pub struct SearchPage {
+    /// Up to [`MAX_SEARCH_RESULTS`] results, sorted by user ID.
+    pub results: Vec<SearchResult>,
+    /// Opaque cursor for the next page, to pass as `?after=...`. `None` if this is the last page.
+    #[serde_as(as = "Option<Base36>")]
+    pub next: Option<u64>,
+}
+
+/// Builds a [`SearchPage`] from up to `MAX_SEARCH_RESULTS + 1` results. The extra result, if any,
+/// only signals that there is a next page.
+fn search_page(mut results: Vec<SearchResult>) -> SearchPage {
+    let more = (MAX_SEARCH_RESULTS as usize) < results.len();
+    results.truncate(MAX_SEARCH_RESULTS as usize);
+    let next = more
+        .then(|| results.last().map(|result| result.id.get()))
+        .flatten();
+    SearchPage { results, next }
+}
+
+/// Escapes `%`, `_`, and `\` in `s` for use in a `LIKE ... ESCAPE '\'` pattern.
+pub fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Builds the `LIKE` prefix pattern for search query `q`, see [`escape_like`]. Errors if `q` is
+/// blank, which would match every profile.
+fn search_pattern(q: &str) -> Result<String, CmError> {
+    let q = q.trim();
+    if q.is_empty() {
+        return Err(CmError::BadRequest("Empty search query `q`.".to_owned()));
+    }
+    Ok(format!("{}%", escape_like(q)))
+}
+
+/// Searches public profiles by Reddit username prefix (case-insensitive for ASCII). Pages by user
+/// ID: `after` is the previous page's [`SearchPage::next`] cursor, or `None` for the first page.
+pub async fn search_profiles(
+    db: &D1Database,
+    q: &str,
+    after: Option<u64>,
+) -> Result<SearchPage, CmError> {
+    let pattern = search_pattern(q)?;
+    let results = query!(
+        &db,
+        SEARCH_PROFILES_SQL,
+        pattern,
+        after.unwrap_or(0),
+        MAX_SEARCH_RESULTS + 1,
+    )?
+    .all()
+    .await?
+    .results()?;
+    Ok(search_page(results))
+}
+
+/// Exclusive upper bound on the skin index of a `profile_bgskinid`. No champion has anywhere
+/// near this many skins.
+pub const MAX_SKIN_IDX: u16 = 100;
+
+/// Splits `bgskinid` (`champID * 1000 + skinIdx`) into its [`Champion`] and skin index, checking
+/// that the champion is known and the skin index is less than [`MAX_SKIN_IDX`].
+pub fn validate_bgskinid(bgskinid: u64) -> Result<(Champion, u16), String> {
+    let champion = i16::try_from(bgskinid / 1000)
+        .ok()
+        .map(Champion::from)
+        .filter(|champion| champion.name().is_some())
+        .ok_or_else(|| format!("unknown champion: {}", bgskinid))?;
+    let skin_idx = (bgskinid % 1000) as u16;
+    if MAX_SKIN_IDX <= skin_idx {
+        return Err(format!("skin index out of range: {}", bgskinid));
+    }
+    Ok((champion, skin_idx))
+}
+
+/// Updates the user's profile settings. `None` values are left unchanged.
+pub async fn update_profile_settings(
+    db: &D1Database,
+    user_id: UserId,
+    profile_is_public: Option<bool>,
+    profile_bgskinid: Option<u64>,
+) -> Result<(), CmError> {
+    if let Some(bgskinid) = profile_bgskinid {
+        validate_bgskinid(bgskinid)
+            .map_err(|e| CmError::BadRequest(format!("Invalid `profile_bgskinid`, {}", e)))?;
+    }
+    let result = query!(
+        &db,
+        "UPDATE user SET
+            profile_is_public = COALESCE(?, profile_is_public),
+            profile_bgskinid = COALESCE(?, profile_bgskinid)
+        WHERE id = ?",
+        profile_is_public.map(u8::from),
+        profile_bgskinid,
+        user_id,
+    )?
+    .run()
+    .await?;
+    if let Some(error) = result.error() {
+        return Err(CmError::InternalServerError(error));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn champ(champ_id: Champion, total_points: u64, max_level: u64) -> Champ {
+        let (name, key) = champ_display(champ_id);
+        Champ {
+            champ_id,
+            total_points,
+            max_level,
+            last_play_time: None,
+            tokens_earned: None,
+            name,
+            key,
+            square_url: None,
+        }
+    }
+
+    #[test]
+    fn test_champ_display() {
+        assert_eq!(("Wukong", "MonkeyKing"), champ_display(Champion::WUKONG));
+        assert_eq!(("Zed", "Zed"), champ_display(Champion::ZED));
+        assert_eq!(("Unknown", "Unknown"), champ_display(Champion::from(9999)));
+    }
+
+    #[test]
+    fn test_champ_deserialize_never_played() {
+        let champ: Champ = serde_json::from_value(serde_json::json!({
+            "champ_id": 143,
+            "total_points": 1_000,
+            "max_level": 2,
+            "last_play_time": null,
+            "tokens_earned": null,
+        }))
+        .unwrap();
+        assert_eq!(None, champ.last_play_time);
+        assert_eq!(None, champ.tokens_earned);
+
+        let champ: Champ = serde_json::from_value(serde_json::json!({
+            "champ_id": 143,
+            "total_points": 1_000,
+            "max_level": 2,
+            "last_play_time": 1_700_000_000_000_i64,
+            "tokens_earned": 1,
+        }))
+        .unwrap();
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH + web_time::Duration::from_millis(1_700_000_000_000)),
+            champ.last_play_time
+        );
+        assert_eq!(Some(1), champ.tokens_earned);
+        let json = serde_json::to_value(&champ).unwrap();
+        assert_eq!(1_700_000_000_000_i64, json["last_play_time"]);
+    }
+
+    #[test]
+    fn test_summoner_deserialize_never_updated() {
+        let row = |last_update| {
+            serde_json::json!({
+                "id": 5,
+                "puuid": "puuid",
+                "platform": "NA1",
+                "game_name": "Name",
+                "tag_line": "NA1",
+                "last_update": last_update,
+                "profile_icon_id": null,
+                "summoner_level": null,
+                "solo_tier": null,
+                "solo_rank": null,
+                "solo_league_points": null,
+            })
+        };
+        let summoner: Summoner = serde_json::from_value(row(serde_json::Value::Null)).unwrap();
+        assert_eq!(None, summoner.last_update);
+
+        // Stored in milliseconds, same as written by `webjob::summoner_update`.
+        let summoner: Summoner = serde_json::from_value(row(1_700_000_000_000_i64.into())).unwrap();
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH + web_time::Duration::from_millis(1_700_000_000_000)),
+            summoner.last_update
+        );
+    }
+
+    #[test]
+    fn test_champs_query_apply() {
+        let champs = || {
+            vec![
+                champ(Champion::ZED, 300, 5),
+                champ(Champion::ANNIE, 200, 7),
+                champ(Champion::LUX, 100, 7),
+            ]
+        };
+        let ids = |champs: Vec<Champ>| champs.into_iter().map(|c| c.champ_id).collect::<Vec<_>>();
+
+        let mut points = champs();
+        ChampsQuery::default().apply(&mut points).unwrap();
+        assert_eq!(
+            vec![Champion::ZED, Champion::ANNIE, Champion::LUX],
+            ids(points)
+        );
+
+        let mut level = champs();
+        let query = ChampsQuery {
+            limit: Some(1),
+            offset: Some(1),
+            sort: ChampsSort::Level,
+        };
+        query.apply(&mut level).unwrap();
+        assert_eq!(vec![Champion::LUX], ids(level));
+
+        let mut name = champs();
+        let query = ChampsQuery {
+            sort: ChampsSort::Name,
+            ..Default::default()
+        };
+        query.apply(&mut name).unwrap();
+        assert_eq!(
+            vec![Champion::ANNIE, Champion::LUX, Champion::ZED],
+            ids(name)
+        );
+    }
+
+    #[test]
+    fn test_champs_query_bounds() {
+        let query = |limit, offset| ChampsQuery {
+            limit,
+            offset,
+            sort: ChampsSort::Points,
+        };
+        assert!(query(Some(-1), None).apply(&mut Vec::new()).is_err());
+        assert!(query(Some(MAX_CHAMPS_LIMIT + 1), None)
+            .apply(&mut Vec::new())
+            .is_err());
+        assert!(query(None, Some(-1)).apply(&mut Vec::new()).is_err());
+        assert!(query(Some(MAX_CHAMPS_LIMIT), Some(1000))
+            .apply(&mut Vec::new())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_user_etag() {
+        let mut user = User {
+            reddit_user_name: "LugnutsK".to_owned(),
+            profile_is_public: true,
+            profile_bgskinid: None,
+            summoners: vec![Summoner {
+                id: SummonerId::new(1).unwrap(),
+                puuid: "my-puuid".to_owned(),
+                platform: PlatformRoute::NA1,
+                game_name: "LugnutsK".to_owned(),
+                tag_line: "000".to_owned(),
+                last_update: None,
+                profile_icon_id: None,
+                summoner_level: None,
+                solo_tier: None,
+                solo_rank: None,
+                solo_league_points: None,
+            }],
+            champs: Vec::new(),
+            profile_bg_url: None,
+        };
+        let query = ChampsQuery::default();
+        let etag = user.etag(&query);
+        // Stable across builds, unlike `DefaultHasher`.
+        assert_eq!("W/\"3231c149ff1c427d6b15da39589f26b5\"", etag);
+        assert_eq!(etag, user.etag(&query));
+
+        user.summoners[0].last_update = Some(SystemTime::now());
+        let updated_etag = user.etag(&query);
+        assert_ne!(etag, updated_etag);
+
+        let limit_query = ChampsQuery {
+            limit: Some(10),
+            ..Default::default()
+        };
+        assert_ne!(updated_etag, user.etag(&limit_query));
+    }
+
+    #[test]
+    fn test_user_retain_min_points() {
+        let mut user = User {
+            reddit_user_name: "LugnutsK".to_owned(),
+            profile_is_public: true,
+            profile_bgskinid: None,
+            summoners: Vec::new(),
+            champs: vec![
+                champ(Champion::ZED, 1_000, 5),
+                champ(Champion::ANNIE, 999, 1),
+            ],
+            profile_bg_url: None,
+        };
+        user.retain_min_points(1_000);
+        assert_eq!(1, user.champs.len());
+        assert_eq!(Champion::ZED, user.champs[0].champ_id);
+    }
+
+    #[test]
+    fn test_escape_like() {
+        assert_eq!("LugnutsK", escape_like("LugnutsK"));
+        assert_eq!("a\\_b", escape_like("a_b"));
+        assert_eq!("100\\%", escape_like("100%"));
+        assert_eq!("a\\\\b", escape_like("a\\b"));
+    }
+
+    #[test]
+    fn test_user_stats_deserialize_defaults_zero() {
+        // User without masteries, aggregates over no rows.
+        let stats: UserStats = serde_json::from_value(serde_json::json!({
+            "total_points": null,
+            "level_7_champs": 0,
+            "max_champ_points": null,
+        }))
+        .unwrap();
+        assert_eq!(UserStats::default(), stats);
+
+        // D1 may return aggregates as floats or strings.
+        let stats: UserStats = serde_json::from_value(serde_json::json!({
+            "total_points": 1_234_567.0,
+            "level_7_champs": "2",
+            "max_champ_points": 1_000_000,
+        }))
+        .unwrap();
+        assert_eq!(
+            UserStats {
+                total_points: 1_234_567,
+                level_7_champs: 2,
+                max_champ_points: 1_000_000,
+            },
+            stats
+        );
+        assert_eq!(
+            serde_json::json!({
+                "total_points": 1_234_567,
+                "level_7_champs": 2,
+                "max_champ_points": 1_000_000,
+            }),
+            serde_json::to_value(stats).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_search_pattern() {
+        assert_eq!("Lugnuts%", search_pattern("  Lugnuts ").unwrap());
+        // Wildcards are matched literally, not as a pattern.
+        assert_eq!("\\%\\_%", search_pattern("%_").unwrap());
+        assert!(matches!(search_pattern(""), Err(CmError::BadRequest(_))));
+        assert!(matches!(search_pattern("   "), Err(CmError::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_search_result_row() {
+        let result: SearchResult = serde_json::from_value(serde_json::json!({
+            "id": 5,
+            "reddit_user_name": "LugnutsK",
+            "top_champion": null,
+        }))
+        .unwrap();
+        assert_eq!(UserId::new(5), Some(result.id));
+        assert_eq!(None, result.top_champion);
+
+        let result: SearchResult = serde_json::from_value(serde_json::json!({
+            "id": 6,
+            "reddit_user_name": "LugnutsK",
+            "top_champion": 143,
+        }))
+        .unwrap();
+        assert_eq!(Some(Champion::ZYRA), result.top_champion);
+        // The ID is only for the cursor, not exposed.
+        assert_eq!(
+            serde_json::json!({ "reddit_user_name": "LugnutsK", "top_champion": 143 }),
+            serde_json::to_value(&result).unwrap()
+        );
+    }
+
+    fn search_results(ids: impl IntoIterator<Item = u64>) -> Vec<SearchResult> {
+        ids.into_iter()
+            .map(|id| SearchResult {
+                id: UserId::new(id).unwrap(),
+                reddit_user_name: format!("user{}", id),
+                top_champion: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_search_page_first() {
+        let page = search_page(search_results(1..=21));
+        assert_eq!(20, page.results.len());
+        assert_eq!(Some(20), page.next);
+        let json = serde_json::to_value(&page).unwrap();
+        assert_eq!("k", json["next"]);
+        assert_eq!(None, json["results"][0].get("id"));
+    }
+
+    #[test]
+    fn test_search_page_next() {
+        // Results after the first page's cursor.
+        let page = search_page(search_results(21..=41));
+        assert_eq!(
+            UserId::new(21),
+            page.results.first().map(|result| result.id)
+        );
+        assert_eq!(Some(40), page.next);
+    }
+
+    #[test]
+    fn test_search_page_end() {
+        let page = search_page(search_results(41..=60));
+        assert_eq!(20, page.results.len());
+        assert_eq!(None, page.next);
+        assert_eq!(
+            serde_json::Value::Null,
+            serde_json::to_value(&page).unwrap()["next"]
+        );
+
+        let page = search_page(Vec::new());
+        assert!(page.results.is_empty());
+        assert_eq!(None, page.next);
+    }
+
+    #[test]
+    fn test_validate_bgskinid() {
+        assert_eq!(Ok((Champion::LUX, 8)), validate_bgskinid(99008));
+        assert_eq!(Ok((Champion::ANNIE, 0)), validate_bgskinid(1000));
+        // Unknown champion.
+        assert!(validate_bgskinid(9999000).is_err());
+        assert!(validate_bgskinid(u64::MAX).is_err());
+        // Skin index out of range.
+        assert!(validate_bgskinid(99100).is_err());
+        assert!(validate_bgskinid(99999).is_err());
+    }
+}
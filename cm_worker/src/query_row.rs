@@ -0,0 +1,94 @@
+//! [`QueryRow`], structs deserialized from D1 rows by column position.
+
+/// Derive macro for [`QueryRow`](trait@QueryRow).
+///
+/// ```ignore
+/// #[derive(QueryRow)]
+/// struct SummonerRow {
+///     puuid: String,
+///     #[query_row(with = "DisplayFromStr")]
+///     platform: PlatformRoute,
+///     #[query_row(with = "Option<WebSystemTime<TimestampMilliSeconds<i64>>>")]
+///     last_update: Option<SystemTime>,
+/// }
+/// let query = checked_query!(
+///     &db,
+///     SummonerRow,
+///     "SELECT puuid, platform, last_update FROM summoner WHERE id = ?",
+///     summoner_id,
+/// )?;
+/// let row: Option<SummonerRow> = query.first_row().await?;
+/// ```
+pub use cm_macro::QueryRow;
+use serde::de::DeserializeOwned;
+use worker::D1PreparedStatement;
+
+use crate::checked_query::ColumnCount;
+
+/// Re-exports for [`QueryRow`](macro@QueryRow) generated code.
+#[doc(hidden)]
+pub mod __private {
+    pub use serde;
+    pub use serde_with;
+}
+
+/// A struct deserialized from a D1 row by column position, see [`QueryRow`](macro@QueryRow).
+///
+/// Field order must match the `SELECT` column order, which [`checked_query!`] checks the length
+/// of.
+pub trait QueryRow: DeserializeOwned + ColumnCount {}
+
+/// [`QueryRow`] methods for [`D1PreparedStatement`].
+#[allow(async_fn_in_trait)]
+pub trait QueryRowExt {
+    /// Runs the query, returning the first row, or `None` if there are no rows.
+    async fn first_row<T: QueryRow>(&self) -> worker::Result<Option<T>>;
+    /// Runs the query, returning all rows.
+    async fn all_rows<T: QueryRow>(&self) -> worker::Result<Vec<T>>;
+}
+impl QueryRowExt for D1PreparedStatement {
+    async fn first_row<T: QueryRow>(&self) -> worker::Result<Option<T>> {
+        self.first(None).await
+    }
+
+    async fn all_rows<T: QueryRow>(&self) -> worker::Result<Vec<T>> {
+        self.all().await?.results()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_with::TimestampMilliSeconds;
+    use web_time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::with::WebSystemTime;
+
+    #[derive(Debug, PartialEq, Eq, QueryRow)]
+    struct Row {
+        id: u64,
+        name: String,
+        #[query_row(with = "Option<WebSystemTime<TimestampMilliSeconds<i64>>>")]
+        last_update: Option<SystemTime>,
+    }
+
+    #[test]
+    fn test_query_row() {
+        assert_eq!(3, <Row as ColumnCount>::COLUMNS);
+
+        let row: Row = serde_json::from_str(
+            r#"{ "id": 5, "reddit_user_name": "LugnutsK", "last_update": 1700000000000 }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Row {
+                id: 5,
+                name: "LugnutsK".to_owned(),
+                last_update: Some(
+                    SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_000)
+                ),
+            },
+            row
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Per-user fixed-window rate limiting, counted in D1.
+
+use serde_with::de::DeserializeAsWrap;
+use serde_with::ser::SerializeAsWrap;
+use serde_with::{Same, TimestampMilliSeconds};
+use web_time::{Duration, SystemTime};
+use worker::{query, D1Database};
+
+use crate::error::CmError;
+use crate::ids::UserId;
+use crate::with::{IgnoreKeys, WebSystemTime};
+
+/// Allows up to [`Self::max_requests`] per [`Self::window`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// Max requests allowed in each window.
+    pub max_requests: u32,
+    /// Window length.
+    pub window: Duration,
+}
+
+/// Start of the window containing `now`. Windows are aligned to the epoch.
+pub fn window_start(now: SystemTime, window: Duration) -> SystemTime {
+    let since_epoch = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let window_millis = window.as_millis().max(1);
+    let offset = since_epoch.as_millis() % window_millis;
+    now - Duration::from_millis(offset as u64)
+}
+
+/// Checks the `count`th request in the window starting at `window_start`. If over the limit,
+/// returns how long until the window ends, rounded up to whole seconds for `Retry-After`.
+pub fn check(
+    config: &RateLimitConfig,
+    count: u32,
+    window_start: SystemTime,
+    now: SystemTime,
+) -> Result<(), Duration> {
+    if count <= config.max_requests {
+        return Ok(());
+    }
+    let remaining = (window_start + config.window)
+        .duration_since(now)
+        .unwrap_or_default();
+    let secs = remaining.as_secs() + u64::from(0 < remaining.subsec_nanos());
+    Err(Duration::from_secs(secs.max(1)))
+}
+
+/// Counts a request by `user_id` for `action`, returning [`CmError::RateLimited`] if over the
+/// limit. Requests over the limit are still counted.
+pub async fn hit(
+    db: &D1Database,
+    config: &RateLimitConfig,
+    user_id: UserId,
+    action: &str,
+) -> Result<(), CmError> {
+    type TimeWith = WebSystemTime<TimestampMilliSeconds<i64>>;
+    let now = SystemTime::now();
+    let window_start = window_start(now, config.window);
+    // Restarts the count if the stored window has passed.
+    let count = query!(
+        &db,
+        "INSERT INTO user_rate_limit(user_id, action, window_start, count) VALUES (?, ?, ?, 1)
+        ON CONFLICT(user_id, action) DO UPDATE SET
+            count = CASE WHEN window_start = excluded.window_start THEN count + 1 ELSE 1 END,
+            window_start = excluded.window_start
+        RETURNING count",
+        user_id,
+        action,
+        <SerializeAsWrap<_, TimeWith>>::new(&window_start),
+    )?
+    .first::<DeserializeAsWrap<(u32,), IgnoreKeys<(Same,)>>>(None)
+    .await?
+    .map_or(1, |count| count.into_inner().0);
+    check(config, count, window_start, now).map_err(CmError::RateLimited)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: RateLimitConfig = RateLimitConfig {
+        max_requests: 10,
+        window: Duration::from_secs(60),
+    };
+
+    #[test]
+    fn test_window_start() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(60 * 1000);
+        assert_eq!(start, window_start(start, CONFIG.window));
+        assert_eq!(
+            start,
+            window_start(start + Duration::from_millis(59_999), CONFIG.window)
+        );
+        assert_eq!(
+            start + CONFIG.window,
+            window_start(start + CONFIG.window, CONFIG.window)
+        );
+    }
+
+    #[test]
+    fn test_check_boundary() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(60 * 1000);
+        let now = start + Duration::from_millis(15_500);
+        assert_eq!(Ok(()), check(&CONFIG, 1, start, now));
+        assert_eq!(Ok(()), check(&CONFIG, 10, start, now));
+        // Rounded up from 44.5 seconds.
+        assert_eq!(Err(Duration::from_secs(45)), check(&CONFIG, 11, start, now));
+    }
+
+    #[test]
+    fn test_check_end_of_window() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(60 * 1000);
+        // Never tells the client to retry immediately.
+        assert_eq!(
+            Err(Duration::from_secs(1)),
+            check(&CONFIG, 11, start, start + CONFIG.window)
+        );
+    }
+}
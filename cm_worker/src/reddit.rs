@@ -1,6 +1,19 @@
 //! Reddit API access.
 use riven::reqwest::Client;
-use serde_with::serde_as;
+use serde_with::{serde_as, TimestampSecondsWithFrac};
+use web_time::SystemTime;
+
+use crate::with::WebSystemTime;
+
+/// User agent for Reddit API calls, in Reddit's required
+/// `<platform>:<app ID>:<version string> (by /u/<reddit username>)` format. The `subreddit` is
+/// appended inside the parentheses so deployments for different subreddits can be told apart.
+pub fn user_agent(client_id: &str, version: &str, owner: &str, subreddit: &str) -> String {
+    format!(
+        "cmflairs:{}:{} (by /u/{} for /r/{})",
+        client_id, version, owner, subreddit
+    )
+}
 
 /// GET `/api/v1/me`
 #[serde_as]
@@ -13,7 +26,14 @@ pub struct Me {
     pub name: String,
     /// If this is a new user that can edit their name.
     pub can_edit_name: bool,
-    // Many other fields.
+    /// Avatar image URL.
+    pub icon_img: String,
+    /// Account creation time.
+    #[serde_as(as = "WebSystemTime<TimestampSecondsWithFrac<f64>>")]
+    pub created_utc: SystemTime,
+    /// Total (link, comment, award) karma.
+    pub total_karma: i64,
+    // Many other fields, ignored.
 }
 
 /// GET `/api/v1/me`.
@@ -28,3 +48,104 @@ pub async fn get_me(client: &Client, access_token: &str) -> riven::reqwest::Resu
         .await?;
     Ok(reddit_me)
 }
+
+/// Response body of Reddit API calls made with `api_type=json`.
+#[derive(Debug, serde::Deserialize)]
+pub struct JsonResponse {
+    /// Wrapper.
+    pub json: JsonErrors,
+}
+/// See [`JsonResponse`].
+#[derive(Debug, serde::Deserialize)]
+pub struct JsonErrors {
+    /// Errors, each usually `[code, message, field]`. Empty on success.
+    #[serde(default)]
+    pub errors: Vec<serde_json::Value>,
+}
+
+/// POST `/r/{subreddit}/api/selectflair`.
+///
+/// Sets `name`'s flair to `text`, using the flair template `flair_template_id` if given. Returns
+/// any errors reported in the response body.
+pub async fn select_flair(
+    client: &Client,
+    access_token: &str,
+    subreddit: &str,
+    name: &str,
+    flair_template_id: Option<&str>,
+    text: &str,
+) -> riven::reqwest::Result<Vec<serde_json::Value>> {
+    let mut form = vec![("api_type", "json"), ("name", name), ("text", text)];
+    if let Some(flair_template_id) = flair_template_id {
+        form.push(("flair_template_id", flair_template_id));
+    }
+    let response: JsonResponse = client
+        .post(format!(
+            "https://oauth.reddit.com/r/{}/api/selectflair",
+            subreddit
+        ))
+        .bearer_auth(access_token)
+        .form(&form)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())?
+        .json()
+        .await?;
+    Ok(response.json.errors)
+}
+
+#[cfg(test)]
+mod test {
+    use web_time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn test_user_agent() {
+        let user_agent = user_agent("Bmf2qtPKIBSAtw", "0123abc", "LugnutsK", "championmains");
+        assert_eq!(
+            "cmflairs:Bmf2qtPKIBSAtw:0123abc (by /u/LugnutsK for /r/championmains)",
+            user_agent
+        );
+        // `<platform>:<app ID>:<version string> (by /u/<reddit username>...)`.
+        let (id, by) = user_agent.split_once(' ').unwrap();
+        assert_eq!(3, id.split(':').filter(|part| !part.is_empty()).count());
+        assert!(by.starts_with("(by /u/LugnutsK") && by.ends_with(')'));
+        assert!(riven::reqwest::header::HeaderValue::from_str(&user_agent).is_ok());
+    }
+
+    #[test]
+    fn test_me_deserialize() {
+        let json = r#"{
+            "is_employee": false,
+            "icon_img": "https://styles.redditmedia.com/t5_abc/styles/profileIcon_xyz.png?width=256&amp;height=256",
+            "pref_show_snoovatar": false,
+            "name": "LugnutsK",
+            "created": 1391213316.0,
+            "created_utc": 1391184516.0,
+            "link_karma": 2049,
+            "comment_karma": 10512,
+            "total_karma": 12750,
+            "awarder_karma": 68,
+            "awardee_karma": 121,
+            "has_verified_email": true,
+            "id": "f3hna",
+            "over_18": true,
+            "can_edit_name": false,
+            "subreddit": {
+                "display_name": "u_LugnutsK",
+                "public_description": ""
+            },
+            "features": {
+                "chat": true
+            }
+        }"#;
+        let me: Me = serde_json::from_str(json).unwrap();
+        assert_eq!(25357078, me.id);
+        assert_eq!("LugnutsK", me.name);
+        assert!(!me.can_edit_name);
+        assert!(me.icon_img.starts_with("https://styles.redditmedia.com/"));
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(1391184516), me.created_utc);
+        assert_eq!(12750, me.total_karma);
+    }
+}
@@ -0,0 +1,175 @@
+//! Request IDs, for correlating log lines with requests.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http::{HeaderName, HeaderValue, Request, Response};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use tower::{Layer, Service};
+
+/// Request ID header, read from requests and echoed in responses.
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Maximum length of an inbound `x-request-id` to honor.
+const MAX_LEN: usize = 128;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// The request ID of the currently running [`Scoped`] future, if any.
+pub fn current() -> Option<Arc<str>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Runs `f` with `request_id` set as [`current`].
+pub fn with<R>(request_id: Option<Arc<str>>, f: impl FnOnce() -> R) -> R {
+    let prev = CURRENT.with(|current| current.replace(request_id));
+    let out = f();
+    CURRENT.with(|current| *current.borrow_mut() = prev);
+    out
+}
+
+/// Runs `future` with `request_id` set as [`current`] whenever it is polled.
+pub fn scope<F: Future>(request_id: Option<Arc<str>>, future: F) -> Scoped<F> {
+    Scoped {
+        request_id,
+        future: Box::pin(future),
+    }
+}
+
+/// Future returned by [`scope`].
+pub struct Scoped<F> {
+    request_id: Option<Arc<str>>,
+    future: Pin<Box<F>>,
+}
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        with(this.request_id.clone(), || this.future.as_mut().poll(cx))
+    }
+}
+
+/// [`Layer`] which assigns each request an ID, honoring an inbound [`X_REQUEST_ID`] header.
+/// The ID is [`current`] while the request is handled and is echoed in the response.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestIdLayer;
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService(inner)
+    }
+}
+
+/// [`Service`] created by [`RequestIdLayer`].
+#[derive(Clone, Debug)]
+pub struct RequestIdService<S>(S);
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RequestIdService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = RequestIdFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let request_id: Arc<str> = req
+            .headers()
+            .get(&X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty() && value.len() <= MAX_LEN)
+            .map_or_else(|| format!("{:016x}", OsRng.next_u64()).into(), Into::into);
+        let future = with(Some(request_id.clone()), || self.0.call(req));
+        let future = scope(Some(request_id.clone()), future);
+        RequestIdFuture { request_id, future }
+    }
+}
+
+/// Future returned by [`RequestIdService`].
+pub struct RequestIdFuture<F> {
+    request_id: Arc<str>,
+    future: Scoped<F>,
+}
+impl<F, ResBody, E> Future for RequestIdFuture<F>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        Pin::new(&mut this.future).poll(cx).map_ok(|mut response| {
+            if let Ok(value) = HeaderValue::from_str(&this.request_id) {
+                response.headers_mut().insert(X_REQUEST_ID.clone(), value);
+            }
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::future::{ready, Ready};
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// Service which responds with [`current`] as the body.
+    #[derive(Clone)]
+    struct CurrentService;
+    impl Service<Request<()>> for CurrentService {
+        type Response = Response<Option<String>>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: Request<()>) -> Self::Future {
+            ready(Ok(Response::new(current().map(|id| id.to_string()))))
+        }
+    }
+
+    #[test]
+    fn test_scope() {
+        assert_eq!(None, current());
+        let id = block_on(scope(Some("abc".into()), async { current() }));
+        assert_eq!(Some("abc".into()), id);
+        assert_eq!(None, current());
+    }
+
+    #[test]
+    fn test_inbound_request_id() {
+        let mut service = RequestIdLayer.layer(CurrentService);
+        let req = Request::builder()
+            .header(&X_REQUEST_ID, "my-request-id")
+            .body(())
+            .unwrap();
+        let response = block_on(service.call(req)).unwrap();
+        assert_eq!("my-request-id", response.headers()[&X_REQUEST_ID]);
+        assert_eq!(Some("my-request-id"), response.body().as_deref());
+    }
+
+    #[test]
+    fn test_generated_request_id() {
+        let mut service = RequestIdLayer.layer(CurrentService);
+        let response = block_on(service.call(Request::new(()))).unwrap();
+        let request_id = response.headers()[&X_REQUEST_ID].to_str().unwrap();
+        assert_eq!(16, request_id.len());
+        assert_eq!(Some(request_id), response.body().as_deref());
+    }
+}
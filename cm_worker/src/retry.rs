@@ -0,0 +1,196 @@
+//! Retrying rate-limited (429) Riot API calls.
+
+use std::future::Future;
+
+use riven::reqwest::header::RETRY_AFTER;
+use riven::reqwest::StatusCode;
+use riven::RiotApiError;
+use web_time::Duration;
+
+/// Delay to use if a 429 response has no valid `Retry-After` header.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Errors which may indicate rate limiting.
+pub trait RateLimitError {
+    /// If rate limited, returns the suggested delay before retrying.
+    fn retry_after(&self) -> Option<Duration>;
+}
+impl RateLimitError for RiotApiError {
+    fn retry_after(&self) -> Option<Duration> {
+        if Some(StatusCode::TOO_MANY_REQUESTS) != self.status_code() {
+            return None;
+        }
+        let retry_after = self
+            .response()
+            .and_then(|response| response.headers().get(RETRY_AFTER))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+        Some(retry_after)
+    }
+}
+
+/// Calls `f`, retrying up to `max_retries` times if rate limited, after waiting the suggested
+/// delay. Uses [`worker::Delay`] to wait.
+pub async fn retry_rate_limited<T, E, F, Fut>(max_retries: u32, f: F) -> Result<T, E>
+where
+    E: RateLimitError,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_rate_limited_with(max_retries, worker::Delay::from, f).await
+}
+
+/// Same as [`retry_rate_limited`], but uses `sleep` to wait.
+pub async fn retry_rate_limited_with<T, E, F, Fut, S, SleepFut>(
+    max_retries: u32,
+    sleep: S,
+    mut f: F,
+) -> Result<T, E>
+where
+    E: RateLimitError,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    S: Fn(Duration) -> SleepFut,
+    SleepFut: Future<Output = ()>,
+{
+    let mut retries = 0;
+    loop {
+        let result = f().await;
+        match result.as_ref().err().and_then(RateLimitError::retry_after) {
+            Some(retry_after) if retries < max_retries => {
+                retries += 1;
+                log::warn!(
+                    "Rate limited, retrying after {:?} (retry {} of {}).",
+                    retry_after,
+                    retries,
+                    max_retries
+                );
+                sleep(retry_after).await;
+            }
+            _ => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::future::ready;
+
+    use futures::executor::block_on;
+    use riven::consts::PlatformRoute;
+    use riven::{RiotApi, RiotApiConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Mock error, `Some(retry_after)` if rate limited.
+    #[derive(Debug, PartialEq)]
+    struct MockError(Option<Duration>);
+    impl RateLimitError for MockError {
+        fn retry_after(&self) -> Option<Duration> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_retry_rate_limited_once() {
+        let calls = Cell::new(0);
+        let sleeps = RefCell::new(Vec::new());
+        let result = block_on(retry_rate_limited_with(
+            1,
+            |duration| {
+                sleeps.borrow_mut().push(duration);
+                ready(())
+            },
+            || {
+                calls.set(calls.get() + 1);
+                ready(match calls.get() {
+                    1 => Err(MockError(Some(Duration::from_secs(3)))),
+                    _ => Ok("success"),
+                })
+            },
+        ));
+        assert_eq!(Ok("success"), result);
+        assert_eq!(2, calls.get());
+        assert_eq!(vec![Duration::from_secs(3)], *sleeps.borrow());
+    }
+
+    #[test]
+    fn test_retry_rate_limited_exhausted() {
+        let calls = Cell::new(0);
+        let result: Result<(), _> = block_on(retry_rate_limited_with(
+            2,
+            |_| ready(()),
+            || {
+                calls.set(calls.get() + 1);
+                ready(Err(MockError(Some(Duration::from_secs(1)))))
+            },
+        ));
+        assert_eq!(Err(MockError(Some(Duration::from_secs(1)))), result);
+        assert_eq!(3, calls.get());
+    }
+
+    #[test]
+    fn test_retry_rate_limited_other_error() {
+        let calls = Cell::new(0);
+        let result: Result<(), _> = block_on(retry_rate_limited_with(
+            2,
+            |_| ready(()),
+            || {
+                calls.set(calls.get() + 1);
+                ready(Err(MockError(None)))
+            },
+        ));
+        assert_eq!(Err(MockError(None)), result);
+        assert_eq!(1, calls.get());
+    }
+
+    /// Gets a real [`RiotApiError`] by calling the Riot API against a local server which replies
+    /// with the raw HTTP `response`.
+    fn riot_api_error(response: &'static str) -> RiotApiError {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let config = RiotApiConfig::with_key("RGAPI-00000000-0000-0000-0000-000000000000")
+                .set_base_url(format!("http://{}", listener.local_addr().unwrap()))
+                .set_retries(0);
+            let rgapi = RiotApi::new(config);
+            let server = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await.unwrap();
+                stream.write_all(response.as_bytes()).await.unwrap();
+            };
+            let request = rgapi.lol_status_v4().get_platform_data(PlatformRoute::NA1);
+            let ((), result) = futures::join!(server, request);
+            result.unwrap_err()
+        })
+    }
+
+    #[test]
+    fn test_riot_api_error_retry_after() {
+        let error = riot_api_error(
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 7\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert_eq!(Some(StatusCode::TOO_MANY_REQUESTS), error.status_code());
+        assert_eq!(Some(Duration::from_secs(7)), error.retry_after());
+    }
+
+    #[test]
+    fn test_riot_api_error_retry_after_missing() {
+        let error = riot_api_error("HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(Some(DEFAULT_RETRY_AFTER), error.retry_after());
+    }
+
+    #[test]
+    fn test_riot_api_error_not_rate_limited() {
+        let error = riot_api_error("HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(None, error.retry_after());
+    }
+}
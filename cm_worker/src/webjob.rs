@@ -1,229 +1,2806 @@
 //! Background "webjob" task handling.
 
-use futures::future::{join, join_all};
-use riven::consts::PlatformRoute;
-use riven::models::champion_mastery_v4::ChampionMastery;
-use riven::RiotApi;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::num::{NonZeroU32, NonZeroU8, NonZeroUsize};
+
+use futures::future::{join5, join_all};
+use futures::stream::{self, StreamExt};
+use riven::consts::{Champion, Division, PlatformRoute, QueueType, RegionalRoute, Tier};
+use riven::reqwest::StatusCode;
+use riven::{RiotApi, RiotApiError};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
 use serde_with::de::DeserializeAsWrap;
 use serde_with::ser::SerializeAsWrap;
 use serde_with::{DisplayFromStr, Same, TimestampMilliSeconds};
 use web_time::{Duration, SystemTime};
-use worker::{query, D1Database, Error, Message, Result};
+use worker::kv::KvStore;
+use worker::{query, D1Database, D1PreparedStatement, Error, Queue, Result};
 
+use crate::auth::{store_oauth_tokens, AuthError, OauthProvider, OauthTokenResponse};
+use crate::cache::Cache;
+use crate::events::UpdateOutcome;
+use crate::flair::ChampionMastery;
+use crate::ids::{SummonerId, UserId};
+use crate::init::{AppState, AppStateOwned, FlairMinPoints, HttpTimeout};
+use crate::outbound::with_timeout;
+use crate::query_row::{QueryRow, QueryRowExt};
+use crate::retry::{retry_rate_limited, RateLimitError};
 use crate::with::{IgnoreKeys, WebSystemTime};
+use crate::{cache, events, reddit};
+
+/// Prefix of error messages for tasks which will never succeed, which are dead-lettered
+/// immediately instead of retried. See [`is_permanent_error`].
+pub const PERMANENT_ERROR_PREFIX: &str = "Permanent error: ";
+
+/// If `error` is a permanent error, see [`PERMANENT_ERROR_PREFIX`].
+pub fn is_permanent_error(error: &Error) -> bool {
+    matches!(error, Error::RustError(msg) if msg.starts_with(PERMANENT_ERROR_PREFIX))
+}
+
+/// What to do with a queue message after handling it, see [`message_outcome`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageOutcome {
+    /// Succeeded, ack.
+    Ack,
+    /// Failed, retry later.
+    Retry,
+    /// Failed permanently or too many times, send to the dead-letter queue.
+    DeadLetter,
+}
+
+/// Runs `futures` with at most `concurrency` running at once, or all at once if `None`. Outputs
+/// are in completion order.
+pub async fn run_concurrent<F>(
+    futures: impl IntoIterator<Item = F>,
+    concurrency: Option<NonZeroUsize>,
+) -> Vec<F::Output>
+where
+    F: Future,
+{
+    let futures = futures.into_iter().collect::<Vec<_>>();
+    let limit = concurrency.map_or(futures.len(), NonZeroUsize::get).max(1);
+    stream::iter(futures)
+        .buffer_unordered(limit)
+        .collect()
+        .await
+}
+
+/// Decides what to do with a message given its handling `result` and number of `attempts` so far.
+pub fn message_outcome(result: &Result<()>, attempts: u32, max_attempts: u32) -> MessageOutcome {
+    match result {
+        Ok(()) => MessageOutcome::Ack,
+        Err(error) if is_permanent_error(error) || max_attempts <= attempts => {
+            MessageOutcome::DeadLetter
+        }
+        Err(_) => MessageOutcome::Retry,
+    }
+}
+
+/// How long before expiry [`Task::OauthTokenRefresh`] refreshes an access token.
+const TOKEN_REFRESH_WINDOW: Duration = Duration::from_secs(15 * 60);
 
 /// Webjob configuration settings, set up in [`crate::init`].
 pub struct WebjobConfig {
     /// See [`Task::SummonerBulkUpdate`].
     pub bulk_update_batch_size: u32,
+    /// Minimum time between updates of the same summoner.
+    pub update_cooldown: Duration,
+    /// Number of attempts before a failing task is sent to the dead-letter queue.
+    pub max_attempts: u32,
+    /// Maximum number of statements per D1 batch.
+    pub batch_chunk_size: usize,
+    /// See [`Task::SummonerMatchHistory`].
+    pub match_history_count: NonZeroU8,
+    /// Number of times to retry a rate-limited Riot API call.
+    pub rate_limit_max_retries: u32,
+    /// If [`Task::SummonerUpdate`] records mastery snapshots into
+    /// `summoner_champion_mastery_history`.
+    pub record_history: bool,
+    /// Subreddit (no "/r/") to assign flairs in, see [`Task::AssignFlair`]. From
+    /// `REDDIT_SUBREDDIT`, which is also in the Reddit user agent.
+    pub flair_subreddit: String,
+    /// Flair template ID to assign flairs with, if any.
+    pub flair_template_id: Option<String>,
+    /// How long to cache champion mastery responses in KV, if at all. See [`crate::cache`].
+    pub mastery_cache_ttl: Option<Duration>,
+    /// Maximum number of queue messages handled at once, see [`run_concurrent`]. Unlimited if
+    /// `None`.
+    pub concurrency: Option<NonZeroUsize>,
+    /// Number of attempts to send a task from a request handler, see [`send_task`]. Positive.
+    pub send_attempts: u32,
+    /// Only fetch and store each summoner's top champion masteries, by points. All if `None`.
+    /// See [`fetch_champion_masteries`].
+    pub top_champs: Option<NonZeroU32>,
 }
 
-/// Enum of the possible tasks for the RiotApi web job.
+/// If `last_update` is within `cooldown` of `now`. Never-updated summoners (`None`) are not.
+pub fn within_cooldown(
+    last_update: Option<SystemTime>,
+    now: SystemTime,
+    cooldown: Duration,
+) -> bool {
+    last_update
+        .and_then(|last_update| now.duration_since(last_update).ok())
+        .map_or(false, |dur| dur < cooldown)
+}
+
+/// Current [`TaskMessage::version`]. Bump when [`Task`]'s serialized form changes incompatibly.
+pub const TASK_VERSION: u32 = 1;
+
+/// A [`Task`] as sent through the webjob queue, e.g.
+/// `{"version":1,"task":{"type":"SummonerUpdate","data":5}}`. See [`decode_task`].
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct TaskMessage {
+    /// Schema version, see [`TASK_VERSION`].
+    pub version: u32,
+    /// The task.
+    pub task: Task,
+}
+impl TaskMessage {
+    /// Wraps `task` with the current [`TASK_VERSION`].
+    pub fn new(task: Task) -> Self {
+        Self {
+            version: TASK_VERSION,
+            task,
+        }
+    }
+}
+
+/// Maximum messages per [`Queue::send_batch`], a Cloudflare Queues limit.
+pub const MAX_SEND_BATCH: usize = 100;
+
+/// Queue which [`TaskMessage`]s are sent to, implemented by [`Queue`]. Allows testing without a
+/// queue binding.
+#[allow(async_fn_in_trait)]
+pub trait TaskQueue {
+    /// Sends all the `messages`, or none of them if this errors.
+    async fn send_messages(&self, messages: Vec<TaskMessage>) -> Result<()>;
+}
+impl TaskQueue for Queue {
+    async fn send_messages(&self, messages: Vec<TaskMessage>) -> Result<()> {
+        self.send_batch(messages).await
+    }
+}
+
+/// Delay before retrying a failed [`send_task`], doubled after each failed attempt.
+pub const SEND_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Sends `task`, retrying failures for up to `attempts` attempts in total, so a transient queue
+/// error does not fail the request. Uses [`worker::Delay`] to wait.
+pub async fn send_task(queue: &impl TaskQueue, task: Task, attempts: u32) -> Result<()> {
+    send_task_with(queue, task, attempts, worker::Delay::from).await
+}
+
+/// Same as [`send_task`], but uses `sleep` to wait.
+pub async fn send_task_with<S, SleepFut>(
+    queue: &impl TaskQueue,
+    task: Task,
+    attempts: u32,
+    sleep: S,
+) -> Result<()>
+where
+    S: Fn(Duration) -> SleepFut,
+    SleepFut: Future<Output = ()>,
+{
+    let mut delay = SEND_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match queue
+            .send_messages(vec![TaskMessage::new(task.clone())])
+            .await
+        {
+            Err(e) if attempt < attempts => {
+                log::warn!(
+                    "Failed to send task {:?}, retrying after {:?} (attempt {} of {}): {}",
+                    task,
+                    delay,
+                    attempt,
+                    attempts,
+                    e
+                );
+                sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Enqueues `tasks` in batches of at most [`MAX_SEND_BATCH`]. Each batch is sent or fails as a
+/// whole, so returns the tasks of any failed batches, which are not enqueued. Errors are logged.
+pub async fn enqueue_tasks(
+    queue: &impl TaskQueue,
+    tasks: impl IntoIterator<Item = Task>,
+) -> Vec<Task> {
+    let mut failed = Vec::new();
+    for chunk in into_chunks(tasks.into_iter().collect(), MAX_SEND_BATCH) {
+        let messages = chunk.iter().cloned().map(TaskMessage::new).collect();
+        if let Err(e) = queue.send_messages(messages).await {
+            log::error!("Failed to enqueue batch of {} tasks: {}", chunk.len(), e);
+            failed.extend(chunk);
+        }
+    }
+    failed
+}
+
+/// Decodes a queue message body into a [`Task`]. Messages with a different version or an unknown
+/// task, e.g. from an older or newer deploy, are rejected with a permanent error.
+pub fn decode_task(body: &serde_json::Value) -> Result<Task> {
+    let version = body.get("version").and_then(serde_json::Value::as_u64);
+    if Some(u64::from(TASK_VERSION)) != version {
+        return Err(Error::RustError(format!(
+            "{}Unsupported task message version {:?}: {}",
+            PERMANENT_ERROR_PREFIX, version, body
+        )));
+    }
+    TaskMessage::deserialize(body)
+        .map(|message| message.task)
+        .map_err(|e| {
+            Error::RustError(format!(
+                "{}Failed to decode task message {}: {}",
+                PERMANENT_ERROR_PREFIX, body, e
+            ))
+        })
+}
+
+/// Enum of the possible tasks for the RiotApi web job.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", content = "data")]
 pub enum Task {
     /// Update the summoner with the given PK ID.
-    SummonerUpdate(u64),
+    SummonerUpdate(SummonerId),
+    /// Same as [`Task::SummonerUpdate`], but bypasses the update cooldown and the cached champion
+    /// mastery response.
+    SummonerRefresh(SummonerId),
     /// Update a batch of summoners. Amount determined by `WEBJOB_BULK_UPDATE_BATCH_SIZE`.
     SummonerBulkUpdate,
+    /// Delete the summoner with the given PK ID, along with its masteries.
+    SummonerDelete(SummonerId),
+    /// Refresh a batch of stored oauth access tokens which are expiring soon. Amount determined by
+    /// `WEBJOB_BULK_UPDATE_BATCH_SIZE`.
+    OauthTokenRefresh,
+    /// Store recent matches for the summoner with the given PK ID. Amount determined by
+    /// `WEBJOB_MATCH_HISTORY_COUNT`. Enqueued after each successful update, see
+    /// [`summoner_update_and_history`].
+    SummonerMatchHistory(SummonerId),
+    /// Assign the owning user's flair on [`WebjobConfig::flair_subreddit`], computed from the
+    /// summoner with the given PK ID.
+    AssignFlair(SummonerId),
+    /// Recompute the flairs of all users, e.g. after flair formatting changes. If `assign`,
+    /// enqueues an [`Task::AssignFlair`] for each user, otherwise only logs the flairs. Visits the
+    /// first batch of users, then continues with [`Task::RecomputeFlairPage`].
+    RecomputeFlairAll {
+        /// If flairs are reassigned.
+        assign: bool,
+    },
+    /// Recompute the flairs of the next batch of users after the `after` user ID, then
+    /// re-enqueue itself with the last visited ID until all users are visited. Amount determined
+    /// by `WEBJOB_BULK_UPDATE_BATCH_SIZE`. See [`Task::RecomputeFlairAll`].
+    RecomputeFlairPage {
+        /// If flairs are reassigned.
+        assign: bool,
+        /// Cursor, the last visited user ID.
+        after: UserId,
+    },
 }
 
 /// Handle a `Task`.
-pub async fn handle(
-    db: &D1Database,
-    rgapi: &RiotApi,
-    webjob_config: &WebjobConfig,
-    msg: Message<Task>,
-) -> Result<Message<Task>> {
-    match msg.body() {
-        &Task::SummonerUpdate(summoner_id) => {
-            summoner_update(db, rgapi, summoner_id).await?;
-            Result::<Message<_>>::Ok(msg)
+pub async fn handle(app_state: AppState, task: &Task) -> Result<()> {
+    let AppStateOwned {
+        db,
+        riot_api: rgapi,
+        webjob_queue,
+        webjob_config,
+        kv,
+        ..
+    } = app_state;
+    let kv = kv.as_ref();
+    match task {
+        &Task::SummonerUpdate(summoner_id) | &Task::SummonerRefresh(summoner_id) => {
+            let force = matches!(task, Task::SummonerRefresh(_));
+            summoner_update_and_publish(
+                db,
+                rgapi,
+                kv,
+                webjob_queue,
+                webjob_config,
+                summoner_id,
+                force,
+            )
+            .await?;
         }
         Task::SummonerBulkUpdate => {
-            summoner_bulk_update(db, rgapi, webjob_config.bulk_update_batch_size).await?;
-            Result::<Message<_>>::Ok(msg)
+            summoner_bulk_update(db, rgapi, kv, webjob_queue, webjob_config).await?;
+        }
+        &Task::SummonerDelete(summoner_id) => {
+            summoner_delete(db, summoner_id).await?;
+        }
+        Task::OauthTokenRefresh => {
+            oauth_token_refresh(app_state, webjob_config.bulk_update_batch_size).await?;
+        }
+        &Task::SummonerMatchHistory(summoner_id) => {
+            summoner_match_history(db, rgapi, webjob_config, summoner_id).await?;
+        }
+        &Task::AssignFlair(summoner_id) => {
+            assign_flair(app_state, summoner_id).await?;
+        }
+        &Task::RecomputeFlairAll { assign } => {
+            recompute_flair_all(app_state, assign, None).await?;
+        }
+        &Task::RecomputeFlairPage { assign, after } => {
+            recompute_flair_all(app_state, assign, Some(after)).await?;
         }
     }
+    Ok(())
 }
 
 type Wrap<T, U> = DeserializeAsWrap<T, IgnoreKeys<U>>;
 
+/// Runs [`summoner_update_and_history`], then clears the summoner's `pending_update` and
+/// publishes the outcome for `GET /summoner/:sid/events`, whether or not the update succeeded.
+/// Used by [`Task::SummonerUpdate`] and the admin update route, so both have the same effects.
+pub async fn summoner_update_and_publish(
+    db: &D1Database,
+    rgapi: &RiotApi,
+    kv: Option<&KvStore>,
+    webjob_queue: &Queue,
+    webjob_config: &WebjobConfig,
+    summoner_id: SummonerId,
+    force: bool,
+) -> Result<Option<SummonerChanges>> {
+    let result = summoner_update_and_history(
+        db,
+        rgapi,
+        kv,
+        webjob_queue,
+        webjob_config,
+        summoner_id,
+        force,
+    )
+    .await;
+    clear_pending_update(db, summoner_id).await?;
+    let outcome = if result.is_ok() {
+        UpdateOutcome::Completed
+    } else {
+        UpdateOutcome::Failed
+    };
+    if let Err(e) = events::publish(db, summoner_id, outcome).await {
+        log::warn!("Failed to publish summoner {} event: {}", summoner_id, e);
+    }
+    result
+}
+
 /// Handle [`Task::SummonerBulkUpdate`].
-pub async fn summoner_bulk_update(db: &D1Database, rgapi: &RiotApi, batch_size: u32) -> Result<()> {
-    Ok(())
-    // type SummonerValus = (u64, String, PlatformRoute);
-    // type SummonerSerde = (Same, Same, DisplayFromStr);
-    // let query = query!(
-    //     &db,
-    //     "SELECT id, puuid, platform FROM summoner ORDER BY last_update ASC LIMIT ?",
-    //     batch_size,
-    // )?;
-    // let summoner_to_update = query
-    //     .all()
-    //     .await?
-    //     .results()?
-    //     .into_iter()
-    //     .map(<Wrap<SummonerValus, SummonerSerde>>::into_inner)
-    //     .collect::<Vec<_>>();
-
-    // let champ_scores_list =
-    //     summoner_to_update
-    //         .into_iter()
-    //         .map(|(id, puuid, platform)| async move {
-    //             let champion_masteries = rgapi
-    //                 .champion_mastery_v4()
-    //                 .get_all_champion_masteries_by_puuid(platform, &puuid)
-    //                 .await
-    //                 .map_err(|e| {
-    //                     Error::RustError(format!(
-    //                         "Failed to get summoner with PUUID {}: {}",
-    //                         puuid, e
-    //                     ))
-    //                 })?;
-    //             let champ_scores = champion_masteries
-    //                 .into_iter()
-    //                 .map(
-    //                     |ChampionMastery {
-    //                          champion_id,
-    //                          champion_points,
-    //                          champion_level,
-    //                          ..
-    //                      }| ChampScore {
-    //                         champion: champion_id,
-    //                         points: champion_points,
-    //                         level: champion_level,
-    //                     },
-    //                 )
-    //                 .collect::<Vec<_>>();
-    //             Result::Ok((id, champ_scores))
-    //         });
-
-    // let champ_scores_list = join_all(champ_scores_list).await;
-
-    // let now = SystemTime::now();
-    // let now = <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(&now);
-
-    // let mut errors = Vec::new();
-    // let updates = champ_scores_list
-    //     .into_iter()
-    //     .map(|result| {
-    //         let (id, champ_scores) = result?;
-    //         let update = query!(
-    //             &db,
-    //             "UPDATE summoner SET
-    //                 champ_scores = ?,
-    //                 last_update = ?
-    //             WHERE id = ?",
-    //             <SerializeAsWrap<_, JsonString>>::new(&champ_scores),
-    //             now,
-    //             id,
-    //         )?;
-    //         Ok(update)
-    //     })
-    //     .filter_map(|result| result.map_err(|err| errors.push(err)).ok())
-    //     .collect();
-
-    // if let Err(err) = db.batch(updates).await {
-    //     errors.push(err)
-    // }
-
-    // errors
-    //     .is_empty()
-    //     .then_some(())
-    //     .ok_or(Error::RustError(format!("{:?}", errors)))
-}
-
-/// Handle [`Task::UpdateSummoner`].
-pub async fn summoner_update(db: &D1Database, rgapi: &RiotApi, summoner_id: u64) -> Result<bool> {
-    type SummonerVals = (String, PlatformRoute, SystemTime);
-    type SummonerWith = (
-        Same,
-        DisplayFromStr,
-        WebSystemTime<TimestampMilliSeconds<i64>>,
-    );
+///
+/// Updates the [`WebjobConfig::bulk_update_batch_size`] least-recently-attempted summoners,
+/// so summoners which keep failing do not crowd out the rest.
+/// Failures are collected so one failing summoner does not prevent the others from updating.
+pub async fn summoner_bulk_update(
+    db: &D1Database,
+    rgapi: &RiotApi,
+    kv: Option<&KvStore>,
+    webjob_queue: &Queue,
+    webjob_config: &WebjobConfig,
+) -> Result<()> {
+    let query = query!(
+        &db,
+        "SELECT id, last_attempt FROM summoner
+        ORDER BY last_attempt ASC NULLS FIRST, id ASC
+        LIMIT ?",
+        webjob_config.bulk_update_batch_size,
+    )?;
+    let rows = query
+        .all()
+        .await?
+        .results::<Wrap<
+            (SummonerId, Option<SystemTime>),
+            (Same, Option<WebSystemTime<TimestampMilliSeconds<i64>>>),
+        >>()?
+        .into_iter()
+        .map(DeserializeAsWrap::into_inner)
+        .collect::<Vec<_>>();
+    let summoner_ids = select_stale(rows, webjob_config.bulk_update_batch_size);
+
+    update_each(summoner_ids, |summoner_id| {
+        summoner_update_and_history(
+            db,
+            rgapi,
+            kv,
+            webjob_queue,
+            webjob_config,
+            summoner_id,
+            false,
+        )
+    })
+    .await
+}
+
+/// Picks the `batch_size` least-recently-attempted summoners from `(id, last_attempt)` rows.
+/// Never-attempted summoners come first, ties are broken by ID. This is the same order and limit
+/// as the [`summoner_bulk_update`] query, kept here so the selection is testable natively.
+pub fn select_stale(
+    mut rows: Vec<(SummonerId, Option<SystemTime>)>,
+    batch_size: u32,
+) -> Vec<SummonerId> {
+    // `None` sorts before `Some`, like SQL `NULLS FIRST`.
+    rows.sort_by_key(|&(summoner_id, last_attempt)| (last_attempt, summoner_id));
+    rows.into_iter()
+        .take(batch_size as usize)
+        .map(|(summoner_id, _last_attempt)| summoner_id)
+        .collect()
+}
+
+/// Runs [`summoner_update`] (not as a dry run), then enqueues a [`Task::SummonerMatchHistory`] if
+/// the summoner was updated rather than skipped, so recent matches are stored along with each
+/// update. Failing to enqueue is only logged, as the update itself succeeded.
+pub async fn summoner_update_and_history(
+    store: &impl SummonerStore,
+    source: &impl SummonerSource,
+    cache: Option<&impl Cache>,
+    webjob_queue: &impl TaskQueue,
+    webjob_config: &WebjobConfig,
+    summoner_id: SummonerId,
+    force: bool,
+) -> Result<Option<SummonerChanges>> {
+    let changes = summoner_update(
+        store,
+        source,
+        cache,
+        webjob_config,
+        summoner_id,
+        force,
+        false,
+    )
+    .await?;
+    if changes.is_some() {
+        let task = Task::SummonerMatchHistory(summoner_id);
+        if let Err(e) = send_task(webjob_queue, task, webjob_config.send_attempts).await {
+            log::warn!(
+                "Failed to enqueue match history of summoner {}: {}",
+                summoner_id,
+                e
+            );
+        }
+    }
+    Ok(changes)
+}
+
+/// Runs `update` for each of `summoner_ids`. Failures are collected into one error rather than
+/// stopping at the first, see [`summoner_bulk_update`].
+pub async fn update_each<F, Fut, T>(summoner_ids: Vec<SummonerId>, update: F) -> Result<()>
+where
+    F: Fn(SummonerId) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let results = join_all(summoner_ids.iter().copied().map(update)).await;
+    let errors = summoner_ids
+        .into_iter()
+        .zip(results)
+        .filter_map(|(summoner_id, result)| {
+            result
+                .err()
+                .map(|e| format!("Failed to update summoner {}: {}", summoner_id, e))
+        })
+        .collect::<Vec<_>>();
+
+    errors
+        .is_empty()
+        .then_some(())
+        .ok_or(Error::RustError(format!("{:?}", errors)))
+}
+
+/// Handle [`Task::SummonerDelete`].
+///
+/// Idempotent, deleting an already-deleted summoner does nothing.
+pub async fn summoner_delete(db: &D1Database, summoner_id: SummonerId) -> Result<()> {
+    let deletes = vec![
+        query!(
+            &db,
+            "DELETE FROM summoner_champion_mastery WHERE summoner_id = ?",
+            summoner_id,
+        )?,
+        query!(
+            &db,
+            "DELETE FROM summoner_match WHERE summoner_id = ?",
+            summoner_id,
+        )?,
+        query!(
+            &db,
+            "DELETE FROM summoner_champion_mastery_history WHERE summoner_id = ?",
+            summoner_id,
+        )?,
+        query!(
+            &db,
+            "DELETE FROM summoner_update_event WHERE summoner_id = ?",
+            summoner_id,
+        )?,
+        query!(&db, "DELETE FROM summoner WHERE id = ?", summoner_id)?,
+    ];
+    let errors = db
+        .batch(deletes)
+        .await?
+        .into_iter()
+        .filter_map(|result| result.error())
+        .collect::<Vec<_>>();
+
+    errors
+        .is_empty()
+        .then_some(())
+        .ok_or(Error::RustError(format!("{:?}", errors)))
+}
+
+/// Handle [`Task::SummonerMatchHistory`].
+///
+/// Fetches the [`WebjobConfig::match_history_count`] most recent match IDs and stores each not yet
+/// stored match. Matches are fetched sequentially to respect rate limits. If still rate limited
+/// after [`WebjobConfig::rate_limit_max_retries`], the matches fetched so far are stored and the
+/// rest are left for next time.
+pub async fn summoner_match_history(
+    db: &D1Database,
+    rgapi: &RiotApi,
+    webjob_config: &WebjobConfig,
+    summoner_id: SummonerId,
+) -> Result<()> {
     let query = query!(
         &db,
-        "SELECT puuid, platform, last_update FROM summoner WHERE id = ?",
+        "SELECT puuid, platform FROM summoner WHERE id = ?",
         summoner_id,
     )?;
-    let (puuid, platform, last_update) = query
+    let (puuid, platform) = query
         .first(None)
         .await?
-        .map(<Wrap<SummonerVals, SummonerWith>>::into_inner)
+        .map(<Wrap<(String, PlatformRoute), (Same, DisplayFromStr)>>::into_inner)
         .ok_or_else(|| {
             Error::RustError(format!(
                 "Failed to find summoner with PK ID: {}",
                 summoner_id
             ))
         })?;
+    let route = regional_for(platform);
+
+    let query = query!(
+        &db,
+        "SELECT match_id FROM summoner_match WHERE summoner_id = ?",
+        summoner_id,
+    )?;
+    let stored_match_ids = query
+        .all()
+        .await?
+        .results::<Wrap<(String,), (Same,)>>()?
+        .into_iter()
+        .map(|wrap| wrap.into_inner().0)
+        .collect::<HashSet<_>>();
+
+    let max_retries = webjob_config.rate_limit_max_retries;
+    let match_ids = retry_rate_limited(max_retries, || {
+        rgapi.match_v5().get_match_ids_by_puuid(
+            route,
+            &puuid,
+            Some(webjob_config.match_history_count.get().into()),
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+    })
+    .await
+    .map_err(|e| {
+        Error::RustError(format!(
+            "Failed to get match IDs with PUUID {}: {}",
+            puuid, e
+        ))
+    })?;
 
-    if SystemTime::now()
-        .duration_since(last_update)
-        .map_or(false, |dur| dur < Duration::from_secs(60))
+    let mut match_inserts = Vec::new();
+    for match_id in match_ids
+        .iter()
+        .filter(|match_id| !stored_match_ids.contains(*match_id))
     {
-        log::info!("Skipping recently-updated summoner {}", summoner_id);
-        return Ok(false);
+        let get_match =
+            retry_rate_limited(max_retries, || rgapi.match_v5().get_match(route, match_id));
+        let match_info = match get_match.await {
+            Ok(Some(match_info)) => match_info.info,
+            Ok(None) => {
+                log::warn!("Match {} not found, skipping.", match_id);
+                continue;
+            }
+            Err(e) if Some(StatusCode::TOO_MANY_REQUESTS) == e.status_code() => {
+                log::warn!(
+                    "Rate limited on summoner {} match history, storing {} matches and stopping.",
+                    summoner_id,
+                    match_inserts.len()
+                );
+                break;
+            }
+            Err(e) => {
+                return Err(Error::RustError(format!(
+                    "Failed to get match {}: {}",
+                    match_id, e
+                )))
+            }
+        };
+        let Some(participant) = match_info
+            .participants
+            .iter()
+            .find(|participant| participant.puuid == puuid)
+        else {
+            log::warn!(
+                "Summoner {} not found in match {}, skipping.",
+                summoner_id,
+                match_id
+            );
+            continue;
+        };
+        match_inserts.push(query!(
+            &db,
+            "INSERT INTO summoner_match(summoner_id, match_id, champ_id, win, queue_id, game_start)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT DO NOTHING",
+            summoner_id,
+            match_id,
+            participant.champion_id,
+            u8::from(participant.win),
+            match_info.queue_id,
+            match_info.game_start_timestamp,
+        )?);
+    }
+
+    batch_chunked(db, match_inserts, webjob_config.batch_chunk_size).await
+}
+
+/// What [`assign_flair`] does with a stored access token, see [`token_action`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenAction {
+    /// Use the stored token as-is.
+    Use,
+    /// Refresh the token first.
+    Refresh,
+    /// The token has expired and cannot be refreshed.
+    Expired,
+}
+
+/// Refresh a token expiring at `expires_at` if it expires within [`TOKEN_REFRESH_WINDOW`] of `now`
+/// and `can_refresh`. Otherwise use it until it expires.
+pub fn token_action(expires_at: SystemTime, now: SystemTime, can_refresh: bool) -> TokenAction {
+    if now + TOKEN_REFRESH_WINDOW <= expires_at {
+        TokenAction::Use
+    } else if can_refresh {
+        TokenAction::Refresh
+    } else if now < expires_at {
+        TokenAction::Use
+    } else {
+        TokenAction::Expired
     }
+}
 
-    // TODO(mingwei): handle chaning riot IDs `username#tagline`.
+/// Handle [`Task::AssignFlair`].
+///
+/// Uses the user's stored Reddit access token, refreshing it first if it is about to expire (see
+/// [`token_action`]). If the token is expired and cannot be refreshed, or lacks permission to set
+/// the user's flair in the subreddit, returns a permanent error so the task is dead-lettered
+/// without retrying.
+pub async fn assign_flair(app_state: AppState, summoner_id: SummonerId) -> Result<()> {
+    let AppStateOwned {
+        db,
+        reqwest_client,
+        http_timeout: HttpTimeout(http_timeout),
+        token_cipher,
+        webjob_config,
+        flair_min_points: FlairMinPoints(min_points),
+        ..
+    } = app_state;
 
-    let update_summoner_time = query!(
+    #[serde_with::serde_as]
+    #[derive(serde::Deserialize)]
+    struct Row {
+        user_id: UserId,
+        reddit_user_name: String,
+        access_token: String,
+        refresh_token: Option<String>,
+        refresh_error: Option<String>,
+        #[serde_as(as = "WebSystemTime<TimestampMilliSeconds<i64>>")]
+        expires_at: SystemTime,
+    }
+    let row = query!(
         &db,
-        "UPDATE summoner SET last_update = ? WHERE id = ?",
-        <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(&SystemTime::now()),
+        "SELECT u.id AS user_id, u.reddit_user_name, t.access_token, t.refresh_token,
+            t.refresh_error, t.expires_at
+        FROM summoner s
+        JOIN user u ON u.id = s.user_id
+        JOIN user_oauth_token t ON t.user_id = u.id AND t.provider = 'reddit'
+        WHERE s.id = ?",
         summoner_id,
+    )?
+    .first::<Row>(None)
+    .await?;
+    let Some(row) = row else {
+        log::warn!(
+            "Summoner {} does not exist or its user has no Reddit token, skipping flair.",
+            summoner_id
+        );
+        return Ok(());
+    };
+    let refresh_token = row
+        .refresh_token
+        .as_deref()
+        .filter(|_| row.refresh_error.is_none());
+    let action = token_action(row.expires_at, SystemTime::now(), refresh_token.is_some());
+    let access_token = match (action, refresh_token) {
+        (TokenAction::Use, _) => token_cipher.decrypt(&row.access_token)?,
+        (TokenAction::Refresh, Some(refresh_token)) => {
+            let tokens = refresh_oauth_tokens(
+                app_state,
+                row.user_id,
+                OauthProvider::Reddit.as_str(),
+                refresh_token,
+            )
+            .await
+            .map_err(|e| {
+                Error::RustError(format!(
+                    "Failed to refresh Reddit token for /u/{}: {}",
+                    row.reddit_user_name, e
+                ))
+            })?
+            .ok_or_else(|| {
+                Error::RustError(format!(
+                    "{}Reddit refresh token for /u/{} was rejected.",
+                    PERMANENT_ERROR_PREFIX, row.reddit_user_name
+                ))
+            })?;
+            SecretString::new(tokens.access_token)
+        }
+        (TokenAction::Refresh, None) | (TokenAction::Expired, _) => {
+            return Err(Error::RustError(format!(
+                "{}Reddit token for /u/{} expired and cannot be refreshed.",
+                PERMANENT_ERROR_PREFIX, row.reddit_user_name
+            )));
+        }
+    };
+
+    let text = crate::flair::load_flair(db, summoner_id, *min_points)
+        .await
+        .map_err(|e| Error::RustError(format!("{:?}", e)))?;
+    let errors = with_timeout(
+        *http_timeout,
+        reddit::select_flair(
+            reqwest_client,
+            access_token.expose_secret(),
+            &webjob_config.flair_subreddit,
+            &row.reddit_user_name,
+            webjob_config.flair_template_id.as_deref(),
+            &text,
+        ),
+    )
+    .await
+    .map_err(|e| Error::RustError(format!("Failed to set flair: {}", e)))?
+    .map_err(|e| match e.status() {
+        Some(StatusCode::FORBIDDEN) => Error::RustError(format!(
+            "{}/u/{}'s Reddit token is not allowed to set their flair in r/{}: {}",
+            PERMANENT_ERROR_PREFIX, row.reddit_user_name, webjob_config.flair_subreddit, e
+        )),
+        _ => Error::RustError(format!("Failed to set flair: {}", e)),
+    })?;
+    if !errors.is_empty() {
+        return Err(Error::RustError(format!(
+            "Failed to set flair: {:?}",
+            errors
+        )));
+    }
+    log::info!(
+        "Set flair for /u/{} in r/{}: {:?}",
+        row.reddit_user_name,
+        webjob_config.flair_subreddit,
+        text
+    );
+    Ok(())
+}
+
+/// Runs one page of [`Task::RecomputeFlairPage`]. Calls `load_page(after, batch_size)` for the
+/// `(user_id, summoner_id)`s of up to `batch_size` users with IDs after `after` (from the first
+/// user if `None`), in ID order, then `visit`s them. Returns the cursor for the next page, or
+/// `None` once all users are visited.
+///
+/// The cursor travels in the task itself, so a failed page is retried (by the queue) from the same
+/// `after`, and concurrent runs do not interfere.
+pub async fn recompute_flair_page<P, PFut, V, VFut>(
+    after: Option<UserId>,
+    batch_size: u32,
+    load_page: P,
+    visit: V,
+) -> Result<Option<UserId>>
+where
+    P: FnOnce(Option<UserId>, u32) -> PFut,
+    PFut: Future<Output = Result<Vec<(UserId, SummonerId)>>>,
+    V: FnOnce(Vec<(UserId, SummonerId)>) -> VFut,
+    VFut: Future<Output = Result<()>>,
+{
+    let page = load_page(after, batch_size).await?;
+    let next = match page.last() {
+        Some(&(user_id, _)) if page.len() >= batch_size as usize => Some(user_id),
+        _ => None,
+    };
+    visit(page).await?;
+    Ok(next)
+}
+
+/// Handle [`Task::RecomputeFlairAll`] and [`Task::RecomputeFlairPage`], starting after the `after`
+/// user ID, or from the first user if `None`.
+///
+/// Each user's flair is computed from their first summoner. Users without summoners are skipped.
+pub async fn recompute_flair_all(
+    app_state: AppState,
+    assign: bool,
+    after: Option<UserId>,
+) -> Result<()> {
+    let AppStateOwned {
+        db,
+        webjob_queue,
+        webjob_config,
+        flair_min_points: FlairMinPoints(min_points),
+        ..
+    } = app_state;
+
+    let load_page = |after: Option<UserId>, limit: u32| async move {
+        let query = checked_query!(
+            &db,
+            (Same, Same),
+            "SELECT u.id, MIN(s.id)
+            FROM user u
+            JOIN summoner s ON s.user_id = u.id
+            WHERE u.id > ?
+            GROUP BY u.id
+            ORDER BY u.id
+            LIMIT ?",
+            after.map_or(0, UserId::get),
+            limit,
+        )?;
+        let page = query
+            .all()
+            .await?
+            .results::<Wrap<(UserId, SummonerId), (Same, Same)>>()?
+            .into_iter()
+            .map(DeserializeAsWrap::into_inner)
+            .collect::<Vec<_>>();
+        Ok(page)
+    };
+    let visit = |page: Vec<(UserId, SummonerId)>| async move {
+        if assign {
+            let tasks = page
+                .into_iter()
+                .map(|(_user_id, summoner_id)| Task::AssignFlair(summoner_id));
+            let failed = enqueue_tasks(webjob_queue, tasks).await;
+            if !failed.is_empty() {
+                return Err(Error::RustError(format!(
+                    "Failed to enqueue {} flair assignments.",
+                    failed.len()
+                )));
+            }
+        } else {
+            for (user_id, summoner_id) in page {
+                let text = crate::flair::load_flair(db, summoner_id, *min_points)
+                    .await
+                    .map_err(|e| Error::RustError(format!("{:?}", e)))?;
+                log::info!("Recomputed flair for user {}: {:?}", user_id, text);
+            }
+        }
+        Ok(())
+    };
+    let next = recompute_flair_page(
+        after,
+        webjob_config.bulk_update_batch_size,
+        load_page,
+        visit,
+    )
+    .await?;
+    if let Some(after) = next {
+        webjob_queue
+            .send(TaskMessage::new(Task::RecomputeFlairPage { assign, after }))
+            .await?;
+    } else {
+        log::info!("Recomputed flairs for all users.");
+    }
+    Ok(())
+}
+
+/// Handle [`Task::OauthTokenRefresh`].
+///
+/// Tokens the provider rejects for good (see [`crate::auth::OauthHelper::refresh_token`]) are
+/// marked with their `refresh_error` and skipped until the user signs in again.
+pub async fn oauth_token_refresh(app_state: AppState, batch_size: u32) -> Result<()> {
+    let db = &app_state.db;
+
+    type TokenVals = (UserId, String, String);
+    type TokenWith = (Same, Same, Same);
+    let query = checked_query!(
+        &db,
+        TokenWith,
+        "SELECT user_id, provider, refresh_token FROM user_oauth_token
+        WHERE refresh_token IS NOT NULL AND refresh_error IS NULL AND expires_at < ?
+        ORDER BY expires_at ASC LIMIT ?",
+        <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(
+            &(SystemTime::now() + TOKEN_REFRESH_WINDOW)
+        ),
+        batch_size,
     )?;
+    let tokens_to_refresh = query
+        .all()
+        .await?
+        .results::<Wrap<TokenVals, TokenWith>>()?
+        .into_iter()
+        .map(<Wrap<TokenVals, TokenWith>>::into_inner);
 
-    let get_champion_masteries = rgapi
-        .champion_mastery_v4()
-        .get_all_champion_masteries_by_puuid(platform, &puuid);
+    // Sequential, to go easy on the providers.
+    let mut errors = Vec::new();
+    for (user_id, provider, refresh_token) in tokens_to_refresh {
+        let result = refresh_oauth_tokens(app_state, user_id, &provider, &refresh_token).await;
+        if let Err(e) = result {
+            errors.push(format!(
+                "Failed to refresh {} token for user {}: {}",
+                provider, user_id, e
+            ));
+        }
+    }
+
+    errors
+        .is_empty()
+        .then_some(())
+        .ok_or(Error::RustError(format!("{:?}", errors)))
+}
 
-    let (update_summoner_time, get_champion_masteries) =
-        join(update_summoner_time.run(), get_champion_masteries).await;
-    if let Some(error) = update_summoner_time?.error() {
+/// Refreshes the user's `provider` tokens with the encrypted `refresh_token` and stores the new
+/// tokens. If the provider rejects the refresh token, records it with [`mark_refresh_error`] and
+/// returns `Ok(None)`.
+async fn refresh_oauth_tokens(
+    app_state: AppState,
+    user_id: UserId,
+    provider: &str,
+    refresh_token: &str,
+) -> std::result::Result<Option<OauthTokenResponse>, String> {
+    let AppStateOwned {
+        db,
+        reqwest_client,
+        http_timeout: HttpTimeout(http_timeout),
+        oauth_helpers,
+        token_cipher,
+        ..
+    } = app_state;
+
+    let oauth = oauth_helpers.get(provider.parse()?);
+    let refresh_token = token_cipher.decrypt(refresh_token)?;
+    let refresh = oauth.refresh_token(reqwest_client, refresh_token.expose_secret());
+    let tokens = match with_timeout(*http_timeout, refresh)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        Ok(tokens) => tokens,
+        Err(AuthError::Unauthorized(msg)) => {
+            log::warn!(
+                "{} refresh token for user {} was rejected, skipping until next sign-in: {}",
+                provider,
+                user_id,
+                msg
+            );
+            mark_refresh_error(db, user_id, provider, &msg)
+                .await
+                .map_err(|e| e.to_string())?;
+            return Ok(None);
+        }
+        Err(e) => return Err(format!("{:?}", e)),
+    };
+    store_oauth_tokens(db, token_cipher, user_id, provider, &tokens)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(Some(tokens))
+}
+
+/// Records that the user's `provider` refresh token was permanently rejected with `error`, so
+/// [`oauth_token_refresh`] skips it until [`store_oauth_tokens`] stores new tokens.
+pub async fn mark_refresh_error(
+    db: &D1Database,
+    user_id: UserId,
+    provider: &str,
+    error: &str,
+) -> Result<()> {
+    let result = query!(
+        &db,
+        "UPDATE user_oauth_token SET refresh_error = ? WHERE user_id = ? AND provider = ?",
+        error,
+        user_id,
+        provider,
+    )?
+    .run()
+    .await?;
+    if let Some(error) = result.error() {
+        return Err(Error::RustError(error));
+    }
+    Ok(())
+}
+
+/// Clears the summoner's `pending_update` marker set by `POST /summoner/:sid/update`.
+pub async fn clear_pending_update(db: &D1Database, summoner_id: SummonerId) -> Result<()> {
+    let result = query!(
+        &db,
+        "UPDATE summoner SET pending_update = NULL WHERE id = ?",
+        summoner_id,
+    )?
+    .run()
+    .await?;
+    if let Some(error) = result.error() {
         return Err(Error::RustError(error));
     }
-    let champion_masteries = get_champion_masteries.map_err(|e| {
+    Ok(())
+}
+
+/// Source of champion masteries, implemented by [`RiotApi`]. Allows testing without network access.
+#[allow(async_fn_in_trait)]
+pub trait ChampionMasterySource {
+    /// Error type, which may indicate rate limiting.
+    type Error: RateLimitError + std::fmt::Display;
+    /// Gets the summoner's `top` champion masteries by points, or all of them if `None`.
+    async fn champion_masteries(
+        &self,
+        platform: PlatformRoute,
+        puuid: &str,
+        top: Option<NonZeroU32>,
+    ) -> std::result::Result<Vec<ChampionMastery>, Self::Error>;
+}
+impl ChampionMasterySource for RiotApi {
+    type Error = RiotApiError;
+    async fn champion_masteries(
+        &self,
+        platform: PlatformRoute,
+        puuid: &str,
+        top: Option<NonZeroU32>,
+    ) -> std::result::Result<Vec<ChampionMastery>, Self::Error> {
+        let champion_mastery_v4 = self.champion_mastery_v4();
+        let masteries = match top {
+            None => {
+                champion_mastery_v4
+                    .get_all_champion_masteries_by_puuid(platform, puuid)
+                    .await?
+            }
+            Some(top) => {
+                let count = i32::try_from(top.get()).unwrap_or(i32::MAX);
+                champion_mastery_v4
+                    .get_top_champion_masteries_by_puuid(platform, puuid, Some(count))
+                    .await?
+            }
+        };
+        Ok(masteries
+            .into_iter()
+            .map(|mastery| ChampionMastery {
+                champ_id: mastery.champion_id,
+                points: mastery.champion_points.try_into().unwrap_or(0),
+                level: mastery.champion_level.try_into().unwrap_or(0),
+                // Zero if never played.
+                last_play_time: u64::try_from(mastery.last_play_time)
+                    .ok()
+                    .filter(|&millis| 0 < millis)
+                    .map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis)),
+                tokens_earned: mastery.tokens_earned.try_into().ok(),
+            })
+            .collect())
+    }
+}
+
+/// Source of the other Riot API data [`summoner_update`] needs, implemented by [`RiotApi`].
+#[allow(async_fn_in_trait)]
+pub trait SummonerSource: ChampionMasterySource {
+    /// Gets the summoner's `(profile_icon_id, summoner_level)`. `None` if summoner-v4 does not find
+    /// the summoner, e.g. after a region transfer.
+    async fn profile(
+        &self,
+        platform: PlatformRoute,
+        puuid: &str,
+    ) -> std::result::Result<Option<(i32, i64)>, Self::Error>;
+    /// Gets the summoner's solo queue `(tier, rank, league_points)`. `None` if unranked.
+    async fn solo_league(
+        &self,
+        platform: PlatformRoute,
+        puuid: &str,
+    ) -> std::result::Result<Option<(Option<Tier>, Option<Division>, i32)>, Self::Error>;
+    /// Gets the account's current `(game_name, tag_line)`, either of which may be missing.
+    async fn riot_id(
+        &self,
+        platform: PlatformRoute,
+        puuid: &str,
+    ) -> std::result::Result<(Option<String>, Option<String>), Self::Error>;
+}
+impl SummonerSource for RiotApi {
+    async fn profile(
+        &self,
+        platform: PlatformRoute,
+        puuid: &str,
+    ) -> std::result::Result<Option<(i32, i64)>, Self::Error> {
+        match self.summoner_v4().get_by_puuid(platform, puuid).await {
+            Ok(summoner) => Ok(Some((summoner.profile_icon_id, summoner.summoner_level))),
+            Err(e) if Some(StatusCode::NOT_FOUND) == e.status_code() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn solo_league(
+        &self,
+        platform: PlatformRoute,
+        puuid: &str,
+    ) -> std::result::Result<Option<(Option<Tier>, Option<Division>, i32)>, Self::Error> {
+        let league_entries = self
+            .league_v4()
+            .get_league_entries_by_puuid(platform, puuid)
+            .await?;
+        Ok(league_entries
+            .iter()
+            .find(|entry| matches!(entry.queue_type, QueueType::RANKED_SOLO_5x5))
+            .map(|entry| (entry.tier, entry.rank, entry.league_points)))
+    }
+
+    async fn riot_id(
+        &self,
+        platform: PlatformRoute,
+        puuid: &str,
+    ) -> std::result::Result<(Option<String>, Option<String>), Self::Error> {
+        let account = self
+            .account_v1()
+            .get_by_puuid(account_route_for(platform), puuid)
+            .await?;
+        Ok((account.game_name, account.tag_line))
+    }
+}
+
+/// Gets the summoner's champion masteries from `source`, retrying if rate limited. Cached in
+/// `cache` for [`WebjobConfig::mastery_cache_ttl`], unless `bypass_cache` is set.
+///
+/// Only the [`WebjobConfig::top_champs`] masteries with the most points are fetched, if set, which
+/// is part of the cache key. Champions which fall out of the top are deleted by
+/// [`summoner_update`].
+pub async fn fetch_champion_masteries(
+    source: &impl ChampionMasterySource,
+    cache: Option<&impl Cache>,
+    webjob_config: &WebjobConfig,
+    platform: PlatformRoute,
+    puuid: &str,
+    bypass_cache: bool,
+) -> Result<Vec<ChampionMastery>> {
+    let max_retries = webjob_config.rate_limit_max_retries;
+    cache::get_or_fetch(
+        cache.filter(|_| webjob_config.mastery_cache_ttl.is_some()),
+        &champion_masteries_cache_key(platform, puuid, webjob_config.top_champs),
+        webjob_config.mastery_cache_ttl.unwrap_or_default(),
+        bypass_cache,
+        || {
+            retry_rate_limited(max_retries, || {
+                source.champion_masteries(platform, puuid, webjob_config.top_champs)
+            })
+        },
+    )
+    .await
+    .map_err(|e| {
         Error::RustError(format!(
-            "Failed to get summoner with PUUID {}: {}",
+            "Failed to get champion masteries with PUUID {}: {}",
             puuid, e
         ))
+    })
+}
+
+/// Cache key of [`fetch_champion_masteries`]. Includes `top`, so changing
+/// [`WebjobConfig::top_champs`] does not reuse responses fetched with a different limit.
+fn champion_masteries_cache_key(
+    platform: PlatformRoute,
+    puuid: &str,
+    top: Option<NonZeroU32>,
+) -> String {
+    match top {
+        None => format!("champion_masteries:{}:{}", platform, puuid),
+        Some(top) => format!("champion_masteries:{}:{}:top{}", platform, puuid, top),
+    }
+}
+
+/// Changes made by [`summoner_update`], or that would be made in a dry run.
+#[derive(Debug, serde::Serialize)]
+pub struct SummonerChanges {
+    /// New `(profile_icon_id, summoner_level)`. `None` if summoner-v4 did not find the summoner,
+    /// in which case the old values are kept.
+    pub profile: Option<(i32, i64)>,
+    /// New `(game_name, tag_line)`, if the Riot ID changed.
+    pub riot_id: Option<(String, String)>,
+    /// New solo queue `(tier, rank, league_points)`. `None` if unranked.
+    pub solo_league: Option<(Option<Tier>, Option<Division>, i32)>,
+    /// Champion masteries to upsert, and to record in the history if
+    /// [`WebjobConfig::record_history`]. Only those which changed, see [`changed_masteries`].
+    pub champion_masteries: Vec<ChampionMastery>,
+    /// Stored champions which fell out of the [`WebjobConfig::top_champs`], to delete so they
+    /// do not linger in flairs and profiles. Always empty if `top_champs` is unset.
+    pub removed_champions: Vec<Champion>,
+}
+
+/// Filters `masteries` to those which differ from the `stored` mastery of the same champion in any
+/// field (including `last_play_time` and `tokens_earned`), or which are not stored yet. Unchanged
+/// masteries are skipped to save D1 writes.
+fn changed_masteries(
+    stored: &HashMap<Champion, ChampionMastery>,
+    masteries: Vec<ChampionMastery>,
+) -> Vec<ChampionMastery> {
+    masteries
+        .into_iter()
+        .filter(|mastery| stored.get(&mastery.champ_id) != Some(mastery))
+        .collect()
+}
+
+/// Runs `write`, unless `dry_run` in which case it is dropped without being polled.
+async fn write_unless_dry_run(
+    dry_run: bool,
+    write: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    if dry_run {
+        log::info!("Dry run, skipping DB writes.");
+        return Ok(());
+    }
+    write.await
+}
+
+/// Stamps `last_attempt` when [`summoner_update`] starts, whether or not it succeeds.
+const STAMP_ATTEMPT_SQL: &str = "UPDATE summoner SET last_attempt = ? WHERE id = ?";
+/// Stamps `last_success` once [`summoner_update`] succeeds, in the same batch as its writes.
+const STAMP_SUCCESS_SQL: &str = "UPDATE summoner SET last_success = ? WHERE id = ?";
+
+/// A SQL statement and its parameters, built without a [`D1Database`] so the writes of
+/// [`summoner_update`] can be inspected in tests. Prepared with [`Statement::prepare`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement {
+    /// SQL, with `?` placeholders.
+    pub sql: &'static str,
+    /// Parameters bound to the placeholders, in order.
+    pub params: Vec<serde_json::Value>,
+}
+impl Statement {
+    /// Creates a statement, failing if any of the `params` failed to serialize. See `statement!`.
+    fn new<const N: usize>(
+        sql: &'static str,
+        params: [serde_json::Result<serde_json::Value>; N],
+    ) -> Result<Self> {
+        let params = params
+            .into_iter()
+            .collect::<serde_json::Result<Vec<_>>>()
+            .map_err(|e| Error::RustError(format!("Failed to serialize parameter: {}", e)))?;
+        Ok(Self { sql, params })
+    }
+
+    /// Prepares and binds the statement, same as [`worker::query!`].
+    pub fn prepare(&self, db: &D1Database) -> Result<D1PreparedStatement> {
+        // D1 doesn't support undefined, same as `query!`.
+        let serializer =
+            worker::d1::serde_wasm_bindgen::Serializer::new().serialize_missing_as_null(true);
+        let bindings = self
+            .params
+            .iter()
+            .map(|param| {
+                serde::Serialize::serialize(param, &serializer)
+                    .map_err(|e| Error::Internal(e.into()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        db.prepare(self.sql).bind(&bindings)
+    }
+}
+
+/// Builds a [`Statement`] from `sql` and its parameters, like [`worker::query!`].
+macro_rules! statement {
+    ($sql:expr $(, $args:expr)* $(,)?) => {
+        Statement::new($sql, [$(serde_json::to_value(&$args)),*])
+    };
+}
+
+/// Summoner row read by [`summoner_update`].
+#[derive(Debug, QueryRow)]
+pub struct StoredSummoner {
+    /// Riot PUUID.
+    pub puuid: String,
+    /// Platform the summoner is on.
+    #[query_row(with = "DisplayFromStr")]
+    pub platform: PlatformRoute,
+    /// Stored Riot ID game name.
+    pub game_name: String,
+    /// Stored Riot ID tag line.
+    pub tag_line: String,
+    /// Last successful update, `None` if never.
+    #[query_row(with = "Option<WebSystemTime<TimestampMilliSeconds<i64>>>")]
+    pub last_success: Option<SystemTime>,
+}
+
+/// Storage used by [`summoner_update`], implemented by [`D1Database`]. Allows testing without D1.
+#[allow(async_fn_in_trait)]
+pub trait SummonerStore {
+    /// Gets the summoner, `None` if not found.
+    async fn summoner(&self, summoner_id: SummonerId) -> Result<Option<StoredSummoner>>;
+    /// Gets the summoner's stored champion masteries.
+    async fn champion_masteries(&self, summoner_id: SummonerId) -> Result<Vec<ChampionMastery>>;
+    /// Runs `statements` in order, in batches of at most `chunk_size`. See [`batch_chunked`].
+    async fn run(&self, statements: Vec<Statement>, chunk_size: usize) -> Result<()>;
+}
+impl SummonerStore for D1Database {
+    async fn summoner(&self, summoner_id: SummonerId) -> Result<Option<StoredSummoner>> {
+        checked_query!(
+            self,
+            StoredSummoner,
+            "SELECT puuid, platform, game_name, tag_line, last_success FROM summoner WHERE id = ?",
+            summoner_id,
+        )?
+        .first_row()
+        .await
+    }
+
+    async fn champion_masteries(&self, summoner_id: SummonerId) -> Result<Vec<ChampionMastery>> {
+        query!(
+            self,
+            "SELECT champ_id, points, level, last_play_time, tokens_earned
+            FROM summoner_champion_mastery
+            WHERE summoner_id = ?",
+            summoner_id,
+        )?
+        .all()
+        .await?
+        .results()
+    }
+
+    async fn run(&self, statements: Vec<Statement>, chunk_size: usize) -> Result<()> {
+        let statements = statements
+            .iter()
+            .map(|statement| statement.prepare(self))
+            .collect::<Result<Vec<_>>>()?;
+        batch_chunked(self, statements, chunk_size).await
+    }
+}
+
+/// Handle [`Task::SummonerUpdate`].
+///
+/// Returns `None` if the summoner was skipped due to [`WebjobConfig::update_cooldown`], otherwise
+/// the [`SummonerChanges`] written. Champion masteries are cached in `cache` for
+/// [`WebjobConfig::mastery_cache_ttl`]. If `force` is set, both the cooldown and the cache are
+/// bypassed. If `dry_run` is set, fetches from Riot but skips all DB writes and the cache.
+pub async fn summoner_update(
+    store: &impl SummonerStore,
+    source: &impl SummonerSource,
+    cache: Option<&impl Cache>,
+    webjob_config: &WebjobConfig,
+    summoner_id: SummonerId,
+    force: bool,
+    dry_run: bool,
+) -> Result<Option<SummonerChanges>> {
+    let StoredSummoner {
+        puuid,
+        platform,
+        game_name,
+        tag_line,
+        last_success,
+    } = store.summoner(summoner_id).await?.ok_or_else(|| {
+        Error::RustError(format!(
+            "Failed to find summoner with PK ID: {}",
+            summoner_id
+        ))
     })?;
 
-    let champ_updates = champion_masteries
+    // Based on the last success, so failed updates can be retried right away.
+    if !force
+        && within_cooldown(
+            last_success,
+            SystemTime::now(),
+            webjob_config.update_cooldown,
+        )
+    {
+        log::info!("Skipping recently-updated summoner {}", summoner_id);
+        return Ok(None);
+    }
+
+    let update_summoner_time = write_unless_dry_run(dry_run, async {
+        let statement = statement!(
+            STAMP_ATTEMPT_SQL,
+            <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(&SystemTime::now()),
+            summoner_id,
+        )?;
+        store
+            .run(vec![statement], webjob_config.batch_chunk_size)
+            .await
+    });
+
+    let max_retries = webjob_config.rate_limit_max_retries;
+    // Dry runs always fetch from Riot, and leave the cache untouched.
+    let cache = cache.filter(|_| !dry_run);
+    let get_champion_masteries =
+        fetch_champion_masteries(source, cache, webjob_config, platform, &puuid, force);
+    let get_profile = retry_rate_limited(max_retries, || source.profile(platform, &puuid));
+    let get_solo_league = retry_rate_limited(max_retries, || source.solo_league(platform, &puuid));
+    let get_riot_id = retry_rate_limited(max_retries, || source.riot_id(platform, &puuid));
+
+    let (update_summoner_time, get_champion_masteries, get_profile, get_solo_league, get_riot_id) =
+        join5(
+            update_summoner_time,
+            get_champion_masteries,
+            get_profile,
+            get_solo_league,
+            get_riot_id,
+        )
+        .await;
+    update_summoner_time?;
+    let champion_masteries = get_champion_masteries?;
+    let profile = get_profile.map_err(|e| {
+        Error::RustError(format!(
+            "Failed to get summoner-v4 with PUUID {}: {}",
+            puuid, e
+        ))
+    })?;
+    if profile.is_none() {
+        // Leave the old values intact.
+        log::warn!(
+            "Summoner-v4 not found for summoner {} with PUUID {}, skipping profile update.",
+            summoner_id,
+            puuid
+        );
+    }
+
+    let (new_game_name, new_tag_line) = get_riot_id.map_err(|e| {
+        Error::RustError(format!("Failed to get account with PUUID {}: {}", puuid, e))
+    })?;
+    let riot_id = riot_id_change(
+        (&game_name, &tag_line),
+        (new_game_name.as_deref(), new_tag_line.as_deref()),
+    )
+    .map(|(new_game_name, new_tag_line)| {
+        log::info!(
+            "Summoner {} renamed from `{}#{}` to `{}#{}`.",
+            summoner_id,
+            game_name,
+            tag_line,
+            new_game_name,
+            new_tag_line
+        );
+        (new_game_name.to_owned(), new_tag_line.to_owned())
+    });
+
+    let solo_league = get_solo_league.map_err(|e| {
+        Error::RustError(format!(
+            "Failed to get league entries with PUUID {}: {}",
+            puuid, e
+        ))
+    })?;
+
+    let stored = store
+        .champion_masteries(summoner_id)
+        .await?
         .into_iter()
+        .map(|mastery| (mastery.champ_id, mastery))
+        .collect::<HashMap<_, _>>();
+    let removed_champions = match webjob_config.top_champs {
+        Some(_) => {
+            let top = champion_masteries
+                .iter()
+                .map(|mastery| mastery.champ_id)
+                .collect::<HashSet<_>>();
+            stored
+                .keys()
+                .copied()
+                .filter(|champ_id| !top.contains(champ_id))
+                .collect()
+        }
+        // Fetched all champions, so none were removed.
+        None => Vec::new(),
+    };
+    let champion_masteries = changed_masteries(&stored, champion_masteries);
+
+    let changes = SummonerChanges {
+        profile,
+        riot_id,
+        solo_league,
+        champion_masteries,
+        removed_champions,
+    };
+    write_unless_dry_run(dry_run, async {
+        let statements = summoner_change_statements(webjob_config, summoner_id, &changes)?;
+        store.run(statements, webjob_config.batch_chunk_size).await
+    })
+    .await?;
+    Ok(Some(changes))
+}
+
+/// Statements writing the summoner's [`SummonerChanges`], see [`summoner_update`].
+fn summoner_change_statements(
+    webjob_config: &WebjobConfig,
+    summoner_id: SummonerId,
+    changes: &SummonerChanges,
+) -> Result<Vec<Statement>> {
+    let summoner_info_update = changes
+        .profile
+        .map(|(profile_icon_id, summoner_level)| {
+            statement!(
+                "UPDATE summoner SET profile_icon_id = ?, summoner_level = ? WHERE id = ?",
+                profile_icon_id,
+                summoner_level,
+                summoner_id,
+            )
+        })
+        .transpose()?;
+
+    let riot_id_update = changes
+        .riot_id
+        .as_ref()
+        .map(|(new_game_name, new_tag_line)| {
+            statement!(
+                "UPDATE summoner SET game_name = ?, tag_line = ? WHERE id = ?",
+                new_game_name,
+                new_tag_line,
+                summoner_id,
+            )
+        })
+        .transpose()?;
+
+    // Unranked summoners have no solo queue entry, store `NULL`s.
+    let (solo_tier, solo_rank, solo_league_points) = match changes.solo_league {
+        Some((tier, rank, league_points)) => (tier, rank, Some(league_points)),
+        None => (None, None, None),
+    };
+    let league_update = statement!(
+        "UPDATE summoner SET solo_tier = ?, solo_rank = ?, solo_league_points = ? WHERE id = ?",
+        solo_tier,
+        solo_rank,
+        solo_league_points,
+        summoner_id,
+    )?;
+
+    // Stamp `last_success` last, so it changes after masteries are refreshed (see
+    // `profile::User::etag`).
+    let restamp_update = statement!(
+        STAMP_SUCCESS_SQL,
+        <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(&SystemTime::now()),
+        summoner_id,
+    )?;
+
+    let captured_at = SystemTime::now();
+    let history_inserts = changes
+        .champion_masteries
+        .iter()
+        .filter(|_| webjob_config.record_history)
+        .map(|champion_mastery| {
+            statement!(
+                "INSERT INTO summoner_champion_mastery_history(
+                    summoner_id, champ_id, points, level, captured_at
+                )
+                VALUES (?, ?, ?, ?, ?)",
+                summoner_id,
+                champion_mastery.champ_id,
+                champion_mastery.points,
+                champion_mastery.level,
+                <SerializeAsWrap<_, WebSystemTime<TimestampMilliSeconds<i64>>>>::new(&captured_at),
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let champ_updates = changes
+        .champion_masteries
+        .iter()
         .map(
-            |ChampionMastery {
-                 champion_id,
-                 champion_points,
-                 champion_level,
-                 ..
+            |&ChampionMastery {
+                 champ_id,
+                 points,
+                 level,
+                 last_play_time,
+                 tokens_earned,
              }| {
-                query!(
-                    &db,
-                    "INSERT INTO summoner_champion_mastery(summoner_id, champ_id, points, level)
-                    VALUES (?, ?, ?, ?)
+                statement!(
+                    "INSERT INTO summoner_champion_mastery(
+                        summoner_id, champ_id, points, level, last_play_time, tokens_earned
+                    )
+                    VALUES (?, ?, ?, ?, ?, ?)
                     ON CONFLICT DO UPDATE SET
                         points = EXCLUDED.points,
-                        level = EXCLUDED.level",
+                        level = EXCLUDED.level,
+                        last_play_time = EXCLUDED.last_play_time,
+                        tokens_earned = EXCLUDED.tokens_earned",
                     summoner_id,
-                    champion_id,
-                    champion_points,
-                    champion_level
+                    champ_id,
+                    points,
+                    level,
+                    <SerializeAsWrap<_, Option<WebSystemTime<TimestampMilliSeconds<i64>>>>>::new(
+                        &last_play_time
+                    ),
+                    tokens_earned,
                 )
-                .unwrap()
             },
         )
-        .collect::<Vec<_>>();
-
-    let results = db.batch(champ_updates).await?;
-    let errors = results
+        .collect::<Result<Vec<_>>>()?;
+    let champ_deletes = changes
+        .removed_champions
+        .iter()
+        .map(|champ_id| {
+            statement!(
+                "DELETE FROM summoner_champion_mastery WHERE summoner_id = ? AND champ_id = ?",
+                summoner_id,
+                champ_id,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(champ_updates
         .into_iter()
-        .filter_map(|result| result.error())
-        .collect::<Vec<_>>();
+        .chain(champ_deletes)
+        .chain(history_inserts)
+        .chain(summoner_info_update)
+        .chain([league_update])
+        .chain(riot_id_update)
+        .chain([restamp_update])
+        .collect())
+}
 
-    if !errors.is_empty() {
-        return Err(Error::RustError(format!("{:?}", errors)));
+/// Returns the new `(game_name, tag_line)` if the account's Riot ID differs from the stored one.
+/// Ignores missing values from the account.
+fn riot_id_change<'a>(
+    (game_name, tag_line): (&str, &str),
+    account_riot_id: (Option<&'a str>, Option<&'a str>),
+) -> Option<(&'a str, &'a str)> {
+    match account_riot_id {
+        (Some(new_game_name), Some(new_tag_line))
+            if (game_name, tag_line) != (new_game_name, new_tag_line) =>
+        {
+            Some((new_game_name, new_tag_line))
+        }
+        _ => None,
+    }
+}
+
+/// Runs `statements` in sequential [`D1Database::batch`]es of at most `chunk_size` statements
+/// each, to stay under D1's per-batch statement limit. Errors are aggregated across all chunks.
+pub async fn batch_chunked(
+    db: &D1Database,
+    statements: Vec<D1PreparedStatement>,
+    chunk_size: usize,
+) -> Result<()> {
+    let mut errors = Vec::new();
+    for chunk in into_chunks(statements, chunk_size) {
+        match db.batch(chunk).await {
+            Ok(results) => errors.extend(results.into_iter().filter_map(|result| result.error())),
+            Err(error) => errors.push(error.to_string()),
+        }
+    }
+
+    errors
+        .is_empty()
+        .then_some(())
+        .ok_or(Error::RustError(format!("{:?}", errors)))
+}
+
+/// Splits `items` into consecutive chunks of at most `chunk_size` items.
+fn into_chunks<T>(items: Vec<T>, chunk_size: usize) -> Vec<Vec<T>> {
+    assert_ne!(0, chunk_size, "`chunk_size` must be positive.");
+    let mut chunks = Vec::new();
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        chunks.push(items.by_ref().take(chunk_size).collect());
+    }
+    chunks
+}
+
+/// Platforms searched by [`infer_platform`], all live (non-PBE) platforms.
+pub const INFER_PLATFORMS: [PlatformRoute; 17] = [
+    PlatformRoute::BR1,
+    PlatformRoute::EUN1,
+    PlatformRoute::EUW1,
+    PlatformRoute::JP1,
+    PlatformRoute::KR,
+    PlatformRoute::LA1,
+    PlatformRoute::LA2,
+    PlatformRoute::ME1,
+    PlatformRoute::NA1,
+    PlatformRoute::OC1,
+    PlatformRoute::PH2,
+    PlatformRoute::RU,
+    PlatformRoute::SG2,
+    PlatformRoute::TH2,
+    PlatformRoute::TR1,
+    PlatformRoute::TW2,
+    PlatformRoute::VN2,
+];
+
+/// Infers the account's platform by looking up its summoner-v4 summoner on each of
+/// [`INFER_PLATFORMS`]. `None` if a lookup fails, or if the summoner is found on no platform or
+/// on several (the account played on multiple shards), in which case the platform must be given
+/// explicitly.
+pub async fn infer_platform(source: &impl SummonerSource, puuid: &str) -> Option<PlatformRoute> {
+    let results = join_all(
+        INFER_PLATFORMS
+            .map(|platform| async move { (platform, source.profile(platform, puuid).await) }),
+    )
+    .await;
+    let mut found = Vec::new();
+    for (platform, result) in results {
+        match result {
+            Ok(Some(_profile)) => found.push(platform),
+            Ok(None) => {}
+            Err(e) => {
+                log::warn!("Failed to get summoner {} on {}: {}", puuid, platform, e);
+                return None;
+            }
+        }
+    }
+    match found[..] {
+        [platform] => Some(platform),
+        _ => {
+            log::info!("Cannot infer platform of {}, found on: {:?}", puuid, found);
+            None
+        }
+    }
+}
+
+/// Regional route for a summoner's match-v5 calls, based on their `platform`. For account-v1, use
+/// [`account_route_for`].
+pub fn regional_for(platform: PlatformRoute) -> RegionalRoute {
+    match platform {
+        PlatformRoute::BR1
+        | PlatformRoute::LA1
+        | PlatformRoute::LA2
+        | PlatformRoute::NA1
+        | PlatformRoute::PBE1 => RegionalRoute::AMERICAS,
+        PlatformRoute::EUN1
+        | PlatformRoute::EUW1
+        | PlatformRoute::ME1
+        | PlatformRoute::RU
+        | PlatformRoute::TR1 => RegionalRoute::EUROPE,
+        PlatformRoute::JP1 | PlatformRoute::KR => RegionalRoute::ASIA,
+        PlatformRoute::OC1
+        | PlatformRoute::PH2
+        | PlatformRoute::SG2
+        | PlatformRoute::TH2
+        | PlatformRoute::TW2
+        | PlatformRoute::VN2 => RegionalRoute::SEA,
+        // `PlatformRoute` is `#[non_exhaustive]`, see `test_regional_for_exhaustive`.
+        _ => platform.to_regional(),
+    }
+}
+
+/// Regional route for a summoner's account-v1 calls. Account-v1 is global but not served from
+/// [`RegionalRoute::SEA`], so those platforms use [`RegionalRoute::ASIA`].
+pub fn account_route_for(platform: PlatformRoute) -> RegionalRoute {
+    match regional_for(platform) {
+        RegionalRoute::SEA => RegionalRoute::ASIA,
+        route => route,
+    }
+}
+
+/// [`WebjobConfig`] for tests.
+#[cfg(test)]
+pub(crate) fn test_webjob_config() -> WebjobConfig {
+    WebjobConfig {
+        bulk_update_batch_size: 20,
+        update_cooldown: Duration::from_secs(60),
+        max_attempts: 3,
+        batch_chunk_size: 50,
+        match_history_count: NonZeroU8::new(20).unwrap(),
+        rate_limit_max_retries: 1,
+        record_history: false,
+        flair_subreddit: "championmains".to_owned(),
+        flair_template_id: None,
+        mastery_cache_ttl: None,
+        concurrency: None,
+        send_attempts: 3,
+        top_champs: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+    use crate::cache::MapCache;
+
+    /// Test double returning canned masteries, or an error if `None`. Like the Riot API, only
+    /// returns the `top` masteries by points if set. Also a [`SummonerSource`] for an unranked
+    /// summoner with Riot ID `riot_id`, on `platforms` (all if `None`).
+    struct CannedMasteries {
+        masteries: Option<Vec<ChampionMastery>>,
+        calls: Cell<u32>,
+        top: Cell<Option<NonZeroU32>>,
+        riot_id: (&'static str, &'static str),
+        platforms: Option<Vec<PlatformRoute>>,
+    }
+    impl CannedMasteries {
+        fn new(masteries: Option<Vec<ChampionMastery>>) -> Self {
+            Self {
+                masteries,
+                calls: Cell::new(0),
+                top: Cell::new(None),
+                riot_id: ("LugnutsK", "000"),
+                platforms: None,
+            }
+        }
+    }
+    impl ChampionMasterySource for CannedMasteries {
+        type Error = &'static str;
+        async fn champion_masteries(
+            &self,
+            _platform: PlatformRoute,
+            _puuid: &str,
+            top: Option<NonZeroU32>,
+        ) -> std::result::Result<Vec<ChampionMastery>, Self::Error> {
+            self.calls.set(self.calls.get() + 1);
+            self.top.set(top);
+            let mut masteries = self.masteries.clone().ok_or("Riot API 500")?;
+            if let Some(top) = top {
+                masteries.sort_by(|a, b| b.points.cmp(&a.points));
+                masteries.truncate(top.get() as usize);
+            }
+            Ok(masteries)
+        }
+    }
+    impl SummonerSource for CannedMasteries {
+        async fn profile(
+            &self,
+            platform: PlatformRoute,
+            _puuid: &str,
+        ) -> std::result::Result<Option<(i32, i64)>, Self::Error> {
+            let found = self
+                .platforms
+                .as_ref()
+                .map_or(true, |platforms| platforms.contains(&platform));
+            Ok(found.then_some((4568, 30)))
+        }
+
+        async fn solo_league(
+            &self,
+            _platform: PlatformRoute,
+            _puuid: &str,
+        ) -> std::result::Result<Option<(Option<Tier>, Option<Division>, i32)>, Self::Error>
+        {
+            Ok(None)
+        }
+
+        async fn riot_id(
+            &self,
+            _platform: PlatformRoute,
+            _puuid: &str,
+        ) -> std::result::Result<(Option<String>, Option<String>), Self::Error> {
+            let (game_name, tag_line) = self.riot_id;
+            Ok((Some(game_name.to_owned()), Some(tag_line.to_owned())))
+        }
+    }
+
+    /// In-memory [`SummonerStore`] for summoner `LugnutsK#000`, recording the statements run
+    /// instead of running them.
+    struct MemoryStore {
+        last_success: Option<SystemTime>,
+        masteries: Vec<ChampionMastery>,
+        runs: RefCell<Vec<Vec<Statement>>>,
+    }
+    impl MemoryStore {
+        fn new(masteries: Vec<ChampionMastery>) -> Self {
+            Self {
+                last_success: None,
+                masteries,
+                runs: RefCell::new(Vec::new()),
+            }
+        }
+
+        /// SQL of all statements run, in order.
+        fn sqls(&self) -> Vec<&'static str> {
+            self.runs
+                .borrow()
+                .iter()
+                .flatten()
+                .map(|statement| statement.sql)
+                .collect()
+        }
+    }
+    impl SummonerStore for MemoryStore {
+        async fn summoner(&self, summoner_id: SummonerId) -> Result<Option<StoredSummoner>> {
+            Ok((SUMMONER_ID == summoner_id).then(|| StoredSummoner {
+                puuid: "my-puuid".to_owned(),
+                platform: PlatformRoute::NA1,
+                game_name: "LugnutsK".to_owned(),
+                tag_line: "000".to_owned(),
+                last_success: self.last_success,
+            }))
+        }
+
+        async fn champion_masteries(
+            &self,
+            _summoner_id: SummonerId,
+        ) -> Result<Vec<ChampionMastery>> {
+            Ok(self.masteries.clone())
+        }
+
+        async fn run(&self, statements: Vec<Statement>, _chunk_size: usize) -> Result<()> {
+            self.runs.borrow_mut().push(statements);
+            Ok(())
+        }
+    }
+
+    const SUMMONER_ID: SummonerId = SummonerId::new(7).unwrap();
+
+    fn sid(id: u64) -> SummonerId {
+        SummonerId::new(id).unwrap()
+    }
+
+    fn uid(id: u64) -> UserId {
+        UserId::new(id).unwrap()
+    }
+
+    /// Runs [`summoner_update`] for [`SUMMONER_ID`] without a cache.
+    fn update(
+        store: &MemoryStore,
+        source: &CannedMasteries,
+        webjob_config: &WebjobConfig,
+        force: bool,
+        dry_run: bool,
+    ) -> Result<Option<SummonerChanges>> {
+        futures::executor::block_on(summoner_update(
+            store,
+            source,
+            None::<&KvStore>,
+            webjob_config,
+            SUMMONER_ID,
+            force,
+            dry_run,
+        ))
+    }
+
+    #[test]
+    fn test_summoner_update() {
+        let store = MemoryStore::new(vec![mastery(Champion::LUX, 1_000, 2)]);
+        let source = CannedMasteries::new(Some(vec![
+            mastery(Champion::LUX, 1_000, 2),
+            mastery(Champion::ZYRA, 123_456, 7),
+        ]));
+        let changes = update(&store, &source, &test_webjob_config(), false, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some((4568, 30)), changes.profile);
+        assert_eq!(None, changes.riot_id);
+        assert_eq!(None, changes.solo_league);
+        assert_eq!(
+            vec![mastery(Champion::ZYRA, 123_456, 7)],
+            changes.champion_masteries
+        );
+
+        // Attempt stamped first, then the changes, ending with the success stamp.
+        let runs = store.runs.borrow();
+        assert_eq!(2, runs.len());
+        assert_eq!(STAMP_ATTEMPT_SQL, runs[0][0].sql);
+        assert_eq!(serde_json::json!(SUMMONER_ID), runs[0][0].params[1]);
+        let upsert = &runs[1][0];
+        assert!(upsert
+            .sql
+            .starts_with("INSERT INTO summoner_champion_mastery("));
+        assert_eq!(
+            serde_json::json!([SUMMONER_ID, Champion::ZYRA, 123_456, 7, null, null]),
+            serde_json::Value::from(upsert.params.clone())
+        );
+        assert_eq!(STAMP_SUCCESS_SQL, runs[1].last().unwrap().sql);
+    }
+
+    #[test]
+    fn test_summoner_update_missing() {
+        let store = MemoryStore::new(Vec::new());
+        let source = CannedMasteries::new(Some(Vec::new()));
+        let result = futures::executor::block_on(summoner_update(
+            &store,
+            &source,
+            None::<&KvStore>,
+            &test_webjob_config(),
+            sid(8),
+            false,
+            false,
+        ));
+        assert!(result.is_err());
+        assert_eq!(0, source.calls.get());
+        assert!(store.sqls().is_empty());
+    }
+
+    impl RateLimitError for &'static str {
+        fn retry_after(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_fetch_champion_masteries() {
+        let masteries = vec![ChampionMastery {
+            champ_id: Champion::ZYRA,
+            points: 123_456,
+            level: 7,
+            last_play_time: None,
+            tokens_earned: Some(1),
+        }];
+        let source = CannedMasteries::new(Some(masteries.clone()));
+        let result = futures::executor::block_on(fetch_champion_masteries(
+            &source,
+            None::<&KvStore>,
+            &test_webjob_config(),
+            PlatformRoute::NA1,
+            "my-puuid",
+            false,
+        ));
+        assert_eq!(masteries, result.unwrap());
+        assert_eq!(1, source.calls.get());
+    }
+
+    #[test]
+    fn test_fetch_champion_masteries_top_champs() {
+        let masteries = vec![
+            mastery(Champion::LUX, 1_000, 2),
+            mastery(Champion::ZYRA, 123_456, 7),
+            mastery(Champion::ZED, 100, 1),
+            mastery(Champion::ANNIE, 5_000, 3),
+        ];
+        let source = CannedMasteries::new(Some(masteries.clone()));
+        let cache = MapCache::default();
+        let fetch = |top_champs| {
+            let webjob_config = WebjobConfig {
+                top_champs,
+                mastery_cache_ttl: Some(cache::MIN_TTL),
+                ..test_webjob_config()
+            };
+            futures::executor::block_on(fetch_champion_masteries(
+                &source,
+                Some(&cache),
+                &webjob_config,
+                PlatformRoute::NA1,
+                "my-puuid",
+                false,
+            ))
+            .unwrap()
+        };
+
+        let top_champs = NonZeroU32::new(2);
+        assert_eq!(
+            vec![
+                mastery(Champion::ZYRA, 123_456, 7),
+                mastery(Champion::ANNIE, 5_000, 3),
+            ],
+            fetch(top_champs)
+        );
+        assert_eq!(top_champs, source.top.get());
+        assert!(cache
+            .0
+            .borrow()
+            .contains_key("champion_masteries:NA1:my-puuid:top2"));
+
+        // The cached top list is not reused for all champions, or vice versa.
+        assert_eq!(masteries, fetch(None));
+        assert_eq!(2, source.calls.get());
+        assert_eq!(2, fetch(top_champs).len());
+        assert_eq!(2, source.calls.get());
+    }
+
+    #[test]
+    fn test_summoner_update_top_champs() {
+        let store = MemoryStore::new(vec![
+            mastery(Champion::ZYRA, 123_456, 7),
+            mastery(Champion::LUX, 1_000, 2),
+            mastery(Champion::ZED, 100, 1),
+        ]);
+        let source = CannedMasteries::new(Some(vec![
+            mastery(Champion::LUX, 1_000, 2),
+            mastery(Champion::ZYRA, 123_456, 7),
+            mastery(Champion::ZED, 100, 1),
+            mastery(Champion::ANNIE, 5_000, 3),
+        ]));
+        let webjob_config = WebjobConfig {
+            top_champs: NonZeroU32::new(2),
+            ..test_webjob_config()
+        };
+        let changes = update(&store, &source, &webjob_config, false, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            vec![mastery(Champion::ANNIE, 5_000, 3)],
+            changes.champion_masteries
+        );
+
+        // Only the top champions are left stored: Annie is upserted, Lux and Zed deleted.
+        let runs = store.runs.borrow();
+        let params_of = |sql_prefix: &str| {
+            let mut params = runs
+                .iter()
+                .flatten()
+                .filter(|statement| statement.sql.starts_with(sql_prefix))
+                .map(|statement| serde_json::Value::from(statement.params.clone()).to_string())
+                .collect::<Vec<_>>();
+            params.sort();
+            params
+        };
+        let upserted = params_of("INSERT INTO summoner_champion_mastery(");
+        assert_eq!(1, upserted.len());
+        assert!(upserted[0].starts_with(&format!(
+            "[{},{}",
+            SUMMONER_ID,
+            serde_json::json!(Champion::ANNIE)
+        )));
+        let mut expected_deletes = [Champion::LUX, Champion::ZED]
+            .map(|champ_id| serde_json::json!([SUMMONER_ID, champ_id]).to_string())
+            .to_vec();
+        expected_deletes.sort();
+        assert_eq!(
+            expected_deletes,
+            params_of("DELETE FROM summoner_champion_mastery")
+        );
+    }
+
+    #[test]
+    fn test_fetch_champion_masteries_error() {
+        let source = CannedMasteries::new(None);
+        let result = futures::executor::block_on(fetch_champion_masteries(
+            &source,
+            None::<&KvStore>,
+            &test_webjob_config(),
+            PlatformRoute::NA1,
+            "my-puuid",
+            false,
+        ));
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("my-puuid"), "{}", error);
+        assert!(error.contains("Riot API 500"), "{}", error);
+        // Not rate limited, so not retried.
+        assert_eq!(1, source.calls.get());
+    }
+
+    #[test]
+    fn test_message_outcome_mixed_batch() {
+        let failed = || Err(Error::RustError("Riot API 500".to_owned()));
+        let permanent = || {
+            Err(Error::RustError(format!(
+                "{}Forbidden",
+                PERMANENT_ERROR_PREFIX
+            )))
+        };
+        let batch = [
+            (Ok(()), 1),
+            (failed(), 1),
+            (Ok(()), 2),
+            (failed(), 3),
+            (permanent(), 1),
+        ];
+        let outcomes = batch
+            .iter()
+            .map(|(result, attempts)| message_outcome(result, *attempts, 3))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                MessageOutcome::Ack,
+                MessageOutcome::Retry,
+                MessageOutcome::Ack,
+                MessageOutcome::DeadLetter,
+                MessageOutcome::DeadLetter,
+            ],
+            outcomes
+        );
+    }
+
+    #[test]
+    fn test_task_message_roundtrip() {
+        let json = serde_json::to_value(TaskMessage::new(Task::SummonerUpdate(sid(5)))).unwrap();
+        assert_eq!(
+            serde_json::json!({ "version": 1, "task": { "type": "SummonerUpdate", "data": 5 } }),
+            json
+        );
+        assert_eq!(Task::SummonerUpdate(sid(5)), decode_task(&json).unwrap());
+
+        let json = serde_json::to_value(TaskMessage::new(Task::SummonerBulkUpdate)).unwrap();
+        assert!(matches!(decode_task(&json), Ok(Task::SummonerBulkUpdate)));
+    }
+
+    #[test]
+    fn test_decode_task_rejected() {
+        let rejected = |json: serde_json::Value| {
+            let error = decode_task(&json).unwrap_err();
+            assert!(is_permanent_error(&error), "{}", error);
+        };
+        // Old unversioned format.
+        rejected(serde_json::json!({ "SummonerUpdate": 5 }));
+        rejected(serde_json::json!("SummonerBulkUpdate"));
+        // Newer version.
+        rejected(
+            serde_json::json!({ "version": 2, "task": { "type": "SummonerUpdate", "data": 5 } }),
+        );
+        // Task variant added in a newer deploy.
+        rejected(serde_json::json!({ "version": 1, "task": { "type": "NewTask", "data": 5 } }));
+    }
+
+    #[test]
+    fn test_infer_platform() {
+        let infer = |platforms: Vec<PlatformRoute>| {
+            let mut source = CannedMasteries::new(Some(Vec::new()));
+            source.platforms = Some(platforms);
+            futures::executor::block_on(infer_platform(&source, "my-puuid"))
+        };
+        assert_eq!(Some(PlatformRoute::EUW1), infer(vec![PlatformRoute::EUW1]));
+        assert_eq!(Some(PlatformRoute::OC1), infer(vec![PlatformRoute::OC1]));
+        // Not found, or ambiguous.
+        assert_eq!(None, infer(Vec::new()));
+        assert_eq!(None, infer(vec![PlatformRoute::NA1, PlatformRoute::KR]));
+        // Only PBE is not searched.
+        assert_eq!(None, infer(vec![PlatformRoute::PBE1]));
+    }
+
+    #[test]
+    fn test_regional_for_exhaustive() {
+        let known = [
+            (PlatformRoute::BR1, RegionalRoute::AMERICAS),
+            (PlatformRoute::LA1, RegionalRoute::AMERICAS),
+            (PlatformRoute::LA2, RegionalRoute::AMERICAS),
+            (PlatformRoute::NA1, RegionalRoute::AMERICAS),
+            (PlatformRoute::PBE1, RegionalRoute::AMERICAS),
+            (PlatformRoute::EUN1, RegionalRoute::EUROPE),
+            (PlatformRoute::EUW1, RegionalRoute::EUROPE),
+            (PlatformRoute::ME1, RegionalRoute::EUROPE),
+            (PlatformRoute::RU, RegionalRoute::EUROPE),
+            (PlatformRoute::TR1, RegionalRoute::EUROPE),
+            (PlatformRoute::JP1, RegionalRoute::ASIA),
+            (PlatformRoute::KR, RegionalRoute::ASIA),
+            (PlatformRoute::OC1, RegionalRoute::SEA),
+            (PlatformRoute::PH2, RegionalRoute::SEA),
+            (PlatformRoute::SG2, RegionalRoute::SEA),
+            (PlatformRoute::TH2, RegionalRoute::SEA),
+            (PlatformRoute::TW2, RegionalRoute::SEA),
+            (PlatformRoute::VN2, RegionalRoute::SEA),
+        ];
+        for (platform, regional) in known {
+            assert_eq!(regional, regional_for(platform), "{:?}", platform);
+        }
+        // Every `PlatformRoute` variant, to catch new ones added to riven.
+        let all = (0..=u8::MAX)
+            .filter_map(|n| PlatformRoute::try_from(n).ok())
+            .collect::<HashSet<_>>();
+        let known = known
+            .into_iter()
+            .map(|(platform, _)| platform)
+            .collect::<HashSet<_>>();
+        assert_eq!(all, known, "`regional_for` is missing platforms.");
+    }
+
+    #[test]
+    fn test_account_route_for() {
+        assert_eq!(
+            RegionalRoute::AMERICAS,
+            account_route_for(PlatformRoute::NA1)
+        );
+        assert_eq!(RegionalRoute::ASIA, account_route_for(PlatformRoute::KR));
+        for platform in [PlatformRoute::OC1, PlatformRoute::VN2] {
+            assert_eq!(RegionalRoute::ASIA, account_route_for(platform));
+        }
+    }
+
+    #[test]
+    fn test_select_stale() {
+        let now = SystemTime::now();
+        let ago = |secs| Some(now - Duration::from_secs(secs));
+        let rows = vec![
+            (sid(1), ago(10)),
+            (sid(2), ago(300)),
+            (sid(3), None),
+            (sid(4), ago(60)),
+            (sid(5), ago(300)),
+            (sid(6), ago(5)),
+        ];
+        assert_eq!(
+            vec![sid(3), sid(2), sid(5), sid(4)],
+            select_stale(rows.clone(), 4)
+        );
+        assert_eq!(6, select_stale(rows.clone(), 20).len());
+        assert!(select_stale(rows, 0).is_empty());
+    }
+
+    #[test]
+    fn test_token_action() {
+        let now = SystemTime::now();
+        let fresh = now + TOKEN_REFRESH_WINDOW + Duration::from_secs(60);
+        let expiring = now + Duration::from_secs(60);
+        let expired = now - Duration::from_secs(60);
+        assert_eq!(TokenAction::Use, token_action(fresh, now, true));
+        assert_eq!(TokenAction::Use, token_action(fresh, now, false));
+        assert_eq!(TokenAction::Refresh, token_action(expiring, now, true));
+        assert_eq!(TokenAction::Use, token_action(expiring, now, false));
+        assert_eq!(TokenAction::Refresh, token_action(expired, now, true));
+        assert_eq!(TokenAction::Expired, token_action(expired, now, false));
+    }
+
+    #[test]
+    fn test_within_cooldown() {
+        let now = SystemTime::now();
+        let last_update = Some(now - Duration::from_secs(30));
+        assert!(within_cooldown(last_update, now, Duration::from_secs(60)));
+        assert!(!within_cooldown(last_update, now, Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_failed_update_keeps_success_cooldown() {
+        // Succeeded 90s ago, outside the 60s cooldown.
+        let mut store = MemoryStore::new(Vec::new());
+        store.last_success = Some(SystemTime::now() - Duration::from_secs(90));
+        let failing = CannedMasteries::new(None);
+        assert!(update(&store, &failing, &test_webjob_config(), false, false).is_err());
+        // Only the attempt is stamped, `last_success` is untouched.
+        assert_eq!(vec![STAMP_ATTEMPT_SQL], store.sqls());
+
+        // So the summoner can be retried right away, rather than waiting out a cooldown.
+        store.runs.borrow_mut().clear();
+        let source = CannedMasteries::new(Some(vec![mastery(Champion::ZYRA, 123_456, 7)]));
+        assert!(update(&store, &source, &test_webjob_config(), false, false)
+            .unwrap()
+            .is_some());
+        assert_eq!(Some(&STAMP_SUCCESS_SQL), store.sqls().last());
+
+        // Whereas a recent success is skipped, without stamping an attempt.
+        let mut store = MemoryStore::new(Vec::new());
+        store.last_success = Some(SystemTime::now() - Duration::from_secs(5));
+        assert!(update(&store, &source, &test_webjob_config(), false, false)
+            .unwrap()
+            .is_none());
+        assert!(store.sqls().is_empty());
+    }
+
+    #[test]
+    fn test_within_cooldown_never_updated() {
+        let now = SystemTime::now();
+        assert!(!within_cooldown(None, now, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_is_permanent_error() {
+        assert!(is_permanent_error(&Error::RustError(format!(
+            "{}Missing permission.",
+            PERMANENT_ERROR_PREFIX
+        ))));
+        assert!(!is_permanent_error(&Error::RustError(
+            "Failed to set flair.".to_owned()
+        )));
+    }
+
+    #[test]
+    fn test_summoner_update_riot_id_change() {
+        const RIOT_ID_SQL: &str = "UPDATE summoner SET game_name = ?, tag_line = ? WHERE id = ?";
+        let mut source = CannedMasteries::new(Some(Vec::new()));
+
+        // Unchanged.
+        let store = MemoryStore::new(Vec::new());
+        update(&store, &source, &test_webjob_config(), false, false).unwrap();
+        assert!(!store.sqls().contains(&RIOT_ID_SQL));
+
+        // New tag line.
+        source.riot_id = ("LugnutsK", "NA1");
+        let store = MemoryStore::new(Vec::new());
+        update(&store, &source, &test_webjob_config(), false, false).unwrap();
+        let runs = store.runs.borrow();
+        let rename = runs
+            .iter()
+            .flatten()
+            .find(|statement| RIOT_ID_SQL == statement.sql)
+            .expect("Riot ID not updated.");
+        assert_eq!(
+            vec![
+                serde_json::json!("LugnutsK"),
+                serde_json::json!("NA1"),
+                serde_json::json!(SUMMONER_ID),
+            ],
+            rename.params
+        );
+    }
+
+    #[test]
+    fn test_riot_id_change() {
+        assert_eq!(
+            Some(("LugnutsK", "NA1")),
+            riot_id_change(("LugnutsK", "000"), (Some("LugnutsK"), Some("NA1")))
+        );
+        assert_eq!(
+            None,
+            riot_id_change(("LugnutsK", "000"), (Some("LugnutsK"), Some("000")))
+        );
+        assert_eq!(
+            None,
+            riot_id_change(("LugnutsK", "000"), (None, Some("NA1")))
+        );
+    }
+
+    #[test]
+    fn test_into_chunks() {
+        let chunks = into_chunks((0..120).collect(), 50);
+        assert_eq!(
+            vec![
+                (0..50).collect::<Vec<_>>(),
+                (50..100).collect(),
+                (100..120).collect()
+            ],
+            chunks
+        );
+    }
+
+    #[test]
+    fn test_into_chunks_under_threshold() {
+        assert_eq!(vec![vec![1, 2, 3]], into_chunks(vec![1, 2, 3], 50));
+        assert_eq!(
+            vec![(0..50).collect::<Vec<_>>()],
+            into_chunks((0..50).collect(), 50)
+        );
+        assert!(into_chunks(Vec::<u32>::new(), 50).is_empty());
+    }
+
+    /// Test double failing the batches whose (zero-based) index is in `fail`.
+    #[derive(Default)]
+    struct MockQueue {
+        fail: Vec<usize>,
+        batches: RefCell<Vec<Vec<Task>>>,
+    }
+    impl TaskQueue for MockQueue {
+        async fn send_messages(&self, messages: Vec<TaskMessage>) -> Result<()> {
+            let mut batches = self.batches.borrow_mut();
+            let index = batches.len();
+            batches.push(messages.into_iter().map(|message| message.task).collect());
+            if self.fail.contains(&index) {
+                return Err(Error::RustError("Queue send failed".to_owned()));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_enqueue_tasks_mixed_batch() {
+        let queue = MockQueue {
+            fail: vec![1],
+            ..Default::default()
+        };
+        let tasks = (1..=250)
+            .map(|id| Task::SummonerUpdate(sid(id)))
+            .collect::<Vec<_>>();
+        let failed = futures::executor::block_on(enqueue_tasks(&queue, tasks.clone()));
+        assert_eq!(tasks[100..200], failed);
+
+        let batches = queue.batches.borrow();
+        assert_eq!(
+            vec![100, 100, 50],
+            batches.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!(tasks, batches.concat());
+    }
+
+    #[test]
+    fn test_enqueue_tasks_empty() {
+        let queue = MockQueue::default();
+        assert!(futures::executor::block_on(enqueue_tasks(&queue, [])).is_empty());
+        assert!(queue.batches.borrow().is_empty());
+    }
+
+    /// Returns `Pending` once, waking immediately.
+    #[derive(Default)]
+    struct YieldOnce(bool);
+    impl Future for YieldOnce {
+        type Output = ();
+        fn poll(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<()> {
+            if std::mem::replace(&mut self.0, true) {
+                std::task::Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_concurrent_limit() {
+        for (concurrency, expected_max) in [(Some(3), 3), (Some(1), 1), (None, 10)] {
+            let in_flight = Cell::new(0);
+            let max_in_flight = Cell::new(0);
+            let futures = (0..10).map(|i| {
+                let (in_flight, max_in_flight) = (&in_flight, &max_in_flight);
+                async move {
+                    in_flight.set(in_flight.get() + 1);
+                    max_in_flight.set(max_in_flight.get().max(in_flight.get()));
+                    YieldOnce::default().await;
+                    in_flight.set(in_flight.get() - 1);
+                    i
+                }
+            });
+            let concurrency = concurrency.and_then(NonZeroUsize::new);
+            let mut outputs = futures::executor::block_on(run_concurrent(futures, concurrency));
+            outputs.sort();
+            assert_eq!((0..10).collect::<Vec<_>>(), outputs);
+            assert_eq!(expected_max, max_in_flight.get(), "{:?}", concurrency);
+        }
+    }
+
+    #[test]
+    fn test_update_each_collects_failures() {
+        let updated = RefCell::new(Vec::new());
+        let result =
+            futures::executor::block_on(update_each(vec![sid(1), sid(2), sid(3)], |summoner_id| {
+                updated.borrow_mut().push(summoner_id);
+                std::future::ready(if sid(2) == summoner_id {
+                    Err(Error::RustError("Riot API unavailable.".to_owned()))
+                } else {
+                    Ok(())
+                })
+            }));
+        // The others are still updated.
+        assert_eq!(vec![sid(1), sid(2), sid(3)], *updated.borrow());
+        let message = match result {
+            Err(Error::RustError(message)) => message,
+            other => panic!("Expected an error: {:?}", other),
+        };
+        assert!(
+            message.contains("Failed to update summoner 2"),
+            "{}",
+            message
+        );
+        assert!(!message.contains("summoner 1"), "{}", message);
+        assert!(!message.contains("summoner 3"), "{}", message);
+
+        let result = futures::executor::block_on(update_each(vec![sid(1), sid(2), sid(3)], |_| {
+            std::future::ready(Ok(()))
+        }));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_recompute_flair_page_visits_all_once() {
+        // Users 1 through 25, each with summoner ID `100 + user_id`. User 7 has no summoners.
+        let users = (1..=25)
+            .filter(|&user_id| 7 != user_id)
+            .map(|user_id| (uid(user_id), sid(100 + user_id)))
+            .collect::<Vec<_>>();
+        let load_page = |after: Option<UserId>, limit: u32| {
+            let page = users
+                .iter()
+                .copied()
+                .filter(|&(user_id, _)| after < Some(user_id))
+                .take(limit as usize)
+                .collect::<Vec<_>>();
+            std::future::ready(Ok(page))
+        };
+        let visited = RefCell::new(Vec::new());
+        let run = |after| {
+            futures::executor::block_on(recompute_flair_page(after, 10, load_page, |page| {
+                visited.borrow_mut().extend(page);
+                std::future::ready(Ok(()))
+            }))
+            .unwrap()
+        };
+
+        let mut after = None;
+        let mut runs = 1;
+        while let Some(next) = run(after) {
+            after = Some(next);
+            runs += 1;
+            assert!(runs <= 10, "Too many runs.");
+        }
+        assert_eq!(3, runs);
+        assert_eq!(users, *visited.borrow());
+    }
+
+    #[test]
+    fn test_recompute_flair_page_retries_failed_page() {
+        let load_page = |after: Option<UserId>, limit: u32| {
+            let after = after.map_or(0, UserId::get);
+            std::future::ready(Ok((after + 1..=after + u64::from(limit))
+                .map(|id| (uid(id), sid(id)))
+                .collect()))
+        };
+        let result =
+            futures::executor::block_on(recompute_flair_page(Some(uid(5)), 5, load_page, |_| {
+                std::future::ready(Err(Error::RustError("Queue unavailable.".to_owned())))
+            }));
+        assert!(result.is_err());
+
+        // Redelivered with the same cursor.
+        let visited = RefCell::new(Vec::new());
+        let next =
+            futures::executor::block_on(recompute_flair_page(Some(uid(5)), 5, load_page, |page| {
+                visited
+                    .borrow_mut()
+                    .extend(page.into_iter().map(|(user_id, _)| user_id.get()));
+                std::future::ready(Ok(()))
+            }))
+            .unwrap();
+        assert_eq!(Some(uid(10)), next);
+        assert_eq!(vec![6, 7, 8, 9, 10], *visited.borrow());
+    }
+
+    #[test]
+    fn test_write_unless_dry_run() {
+        let writes = Cell::new(0);
+        let write = || async {
+            writes.set(writes.get() + 1);
+            Ok(())
+        };
+
+        futures::executor::block_on(write_unless_dry_run(true, write())).unwrap();
+        assert_eq!(0, writes.get(), "Dry run must not write.");
+
+        futures::executor::block_on(write_unless_dry_run(false, write())).unwrap();
+        assert_eq!(1, writes.get());
+    }
+
+    #[test]
+    fn test_summoner_update_dry_run() {
+        let store = MemoryStore::new(vec![mastery(Champion::LUX, 1_000, 2)]);
+        let mut source = CannedMasteries::new(Some(vec![
+            mastery(Champion::LUX, 1_500, 2),
+            mastery(Champion::ZYRA, 123_456, 7),
+        ]));
+        source.riot_id = ("LugnutsK", "NA1");
+        let changes = update(&store, &source, &test_webjob_config(), false, true)
+            .unwrap()
+            .unwrap();
+        // Would-be changes are still computed.
+        assert_eq!(Some((4568, 30)), changes.profile);
+        assert_eq!(
+            Some(("LugnutsK".to_owned(), "NA1".to_owned())),
+            changes.riot_id
+        );
+        assert_eq!(
+            vec![
+                mastery(Champion::LUX, 1_500, 2),
+                mastery(Champion::ZYRA, 123_456, 7),
+            ],
+            changes.champion_masteries
+        );
+        // But nothing is written, not even the attempt stamp.
+        assert!(store.sqls().is_empty(), "{:?}", store.sqls());
+    }
+
+    #[test]
+    fn test_summoner_update_and_history() {
+        let update_and_history = |store: &MemoryStore, queue: &MockQueue| {
+            futures::executor::block_on(summoner_update_and_history(
+                store,
+                &CannedMasteries::new(Some(vec![mastery(Champion::LUX, 1_500, 2)])),
+                None::<&KvStore>,
+                queue,
+                &test_webjob_config(),
+                SUMMONER_ID,
+                false,
+            ))
+        };
+
+        let mut store = MemoryStore::new(vec![mastery(Champion::LUX, 1_000, 2)]);
+        let queue = MockQueue::default();
+        assert!(update_and_history(&store, &queue).unwrap().is_some());
+        assert_eq!(
+            vec![vec![Task::SummonerMatchHistory(SUMMONER_ID)]],
+            *queue.batches.borrow()
+        );
+
+        // Skipped by the cooldown, no match history.
+        store.last_success = Some(SystemTime::now());
+        let queue = MockQueue::default();
+        assert!(update_and_history(&store, &queue).unwrap().is_none());
+        assert!(queue.batches.borrow().is_empty());
+    }
+
+    fn mastery(champ_id: Champion, points: u64, level: u64) -> ChampionMastery {
+        ChampionMastery {
+            champ_id,
+            points,
+            level,
+            last_play_time: None,
+            tokens_earned: None,
+        }
+    }
+
+    /// `masteries` keyed by champion, as loaded by [`SummonerStore::champion_masteries`].
+    fn stored(masteries: &[ChampionMastery]) -> HashMap<Champion, ChampionMastery> {
+        masteries
+            .iter()
+            .map(|&mastery| (mastery.champ_id, mastery))
+            .collect()
+    }
+
+    #[test]
+    fn test_changed_masteries_unchanged() {
+        let masteries = vec![
+            ChampionMastery {
+                last_play_time: Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000)),
+                tokens_earned: Some(2),
+                ..mastery(Champion::ZYRA, 123_456, 7)
+            },
+            mastery(Champion::LUX, 1_000, 2),
+        ];
+        let stored = stored(&masteries);
+        assert_eq!(
+            Vec::<ChampionMastery>::new(),
+            changed_masteries(&stored, masteries)
+        );
+    }
+
+    #[test]
+    fn test_changed_masteries_diff() {
+        let stored = stored(&[
+            mastery(Champion::ZYRA, 123_456, 7),
+            mastery(Champion::LUX, 1_000, 2),
+            mastery(Champion::ANNIE, 5_000, 3),
+            mastery(Champion::AHRI, 20_000, 5),
+            mastery(Champion::AKALI, 30_000, 5),
+        ]);
+        let played = ChampionMastery {
+            last_play_time: Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000)),
+            ..mastery(Champion::AHRI, 20_000, 5)
+        };
+        let token = ChampionMastery {
+            tokens_earned: Some(1),
+            ..mastery(Champion::AKALI, 30_000, 5)
+        };
+        let masteries = vec![
+            mastery(Champion::ZYRA, 123_456, 7),
+            // Points changed.
+            mastery(Champion::LUX, 1_500, 2),
+            // Level changed.
+            mastery(Champion::ANNIE, 5_000, 4),
+            // Only the last play time changed.
+            played,
+            // Only the tokens changed.
+            token,
+            // Not stored yet.
+            mastery(Champion::ZED, 100, 1),
+        ];
+        assert_eq!(
+            vec![
+                mastery(Champion::LUX, 1_500, 2),
+                mastery(Champion::ANNIE, 5_000, 4),
+                played,
+                token,
+                mastery(Champion::ZED, 100, 1),
+            ],
+            changed_masteries(&stored, masteries)
+        );
+    }
+
+    #[test]
+    fn test_send_task_retries_once() {
+        let queue = MockQueue {
+            fail: vec![0],
+            ..Default::default()
+        };
+        let sleeps = RefCell::new(Vec::new());
+        let result = futures::executor::block_on(send_task_with(
+            &queue,
+            Task::SummonerUpdate(sid(5)),
+            3,
+            |delay| {
+                sleeps.borrow_mut().push(delay);
+                std::future::ready(())
+            },
+        ));
+        assert!(result.is_ok());
+        assert_eq!(
+            vec![
+                vec![Task::SummonerUpdate(sid(5))],
+                vec![Task::SummonerUpdate(sid(5))]
+            ],
+            *queue.batches.borrow()
+        );
+        assert_eq!(vec![SEND_RETRY_DELAY], *sleeps.borrow());
+    }
+
+    #[test]
+    fn test_send_task_gives_up() {
+        let queue = MockQueue {
+            fail: vec![0, 1, 2],
+            ..Default::default()
+        };
+        let sleeps = RefCell::new(Vec::new());
+        let result = futures::executor::block_on(send_task_with(
+            &queue,
+            Task::SummonerUpdate(sid(5)),
+            3,
+            |delay| {
+                sleeps.borrow_mut().push(delay);
+                std::future::ready(())
+            },
+        ));
+        assert!(result.is_err());
+        assert_eq!(3, queue.batches.borrow().len());
+        assert_eq!(
+            vec![SEND_RETRY_DELAY, 2 * SEND_RETRY_DELAY],
+            *sleeps.borrow()
+        );
     }
-    return Ok(true);
 }
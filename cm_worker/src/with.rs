@@ -3,7 +3,11 @@
 use std::fmt;
 use std::marker::PhantomData;
 
-use serde::de::{Deserializer, Error as DeError, IgnoredAny, MapAccess, Visitor};
+use riven::consts::Champion;
+use serde::de::{
+    Deserialize, DeserializeSeed, Deserializer, Error as DeError, IgnoredAny, MapAccess, SeqAccess,
+    Unexpected, Visitor,
+};
 use serde_with::de::{DeserializeAs, DeserializeAsWrap};
 use serde_with::{Same, SerializeAs};
 
@@ -76,6 +80,104 @@ tuple_impl!(14 0 T0 As0 1 T1 As1 2 T2 As2 3 T3 As3 4 T4 As4 5 T5 As5 6 T6 As6 7
 tuple_impl!(15 0 T0 As0 1 T1 As1 2 T2 As2 3 T3 As3 4 T4 As4 5 T5 As5 6 T6 As6 7 T7 As7 8 T8 As8 9 T9 As9 10 T10 As10 11 T11 As11 12 T12 As12 13 T13 As13 14 T14 As14);
 tuple_impl!(16 0 T0 As0 1 T1 As1 2 T2 As2 3 T3 As3 4 T4 As4 5 T5 As5 6 T6 As6 7 T7 As7 8 T8 As8 9 T9 As9 10 T10 As10 11 T11 As11 12 T12 As12 13 T13 As13 14 T14 As14 15 T15 As15);
 
+/// Deserialize a struct (or anything else deserializable from a sequence) from a map, ignoring
+/// keys and using the values positionally.
+///
+/// Values are assigned to the struct's fields in declaration order, so the field order must match
+/// the order of the map's entries. For D1 rows, this is the column order of the `SELECT` list;
+/// the column names and field names are NOT compared. Extra trailing entries are ignored.
+///
+/// ```ignore
+/// #[derive(serde::Deserialize)]
+/// struct Row {
+///     id: u64,
+///     name: String,
+/// }
+/// // `SELECT id, reddit_user_name FROM user`
+/// let row: DeserializeAsWrap<Row, Positional> = query.first(None).await?.unwrap();
+/// ```
+pub struct Positional;
+impl<'de, T> DeserializeAs<'de, T> for Positional
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize_as<D>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        T::deserialize(MapAsSeqDeserializer(deserializer))
+    }
+}
+
+/// Deserializer which deserializes maps from the inner deserializer as sequences of values.
+struct MapAsSeqDeserializer<D>(D);
+impl<'de, D> Deserializer<'de> for MapAsSeqDeserializer<D>
+where
+    D: Deserializer<'de>,
+{
+    type Error = D::Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.0.deserialize_map(MapAsSeqVisitor(visitor))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+/// Visitor which visits a map as a sequence of its values.
+struct MapAsSeqVisitor<V>(V);
+impl<'de, V> Visitor<'de> for MapAsSeqVisitor<V>
+where
+    V: Visitor<'de>,
+{
+    type Value = V::Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.expecting(formatter)
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut seq = MapValuesSeqAccess(map);
+        let value = self.0.visit_seq(&mut seq)?;
+        // Drain any trailing entries, which some deserializers (e.g. `serde_json`) otherwise reject.
+        while let Some((IgnoredAny, IgnoredAny)) = seq.0.next_entry()? {}
+        Ok(value)
+    }
+}
+
+/// [`SeqAccess`] over the values of a [`MapAccess`].
+struct MapValuesSeqAccess<A>(A);
+impl<'de, A> SeqAccess<'de> for MapValuesSeqAccess<A>
+where
+    A: MapAccess<'de>,
+{
+    type Error = A::Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.0.next_key::<IgnoredAny>()? {
+            Some(IgnoredAny) => self.0.next_value_seed(seed).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.0.size_hint()
+    }
+}
+
 /// `serde_with` to convert from [`web_time::SystemTime`] to [`std::time::SystemTime`].
 pub struct WebSystemTime<T>(PhantomData<T>);
 impl<'de, T> DeserializeAs<'de, web_time::SystemTime> for WebSystemTime<T>
@@ -93,8 +195,8 @@ where
         }
         #[cfg(not(all(target_family = "wasm", target_os = "unknown")))]
         {
-            let _ = deserializer;
-            unimplemented!();
+            // `web_time::SystemTime` is `std::time::SystemTime`.
+            T::deserialize_as(deserializer)
         }
     }
 }
@@ -113,13 +215,174 @@ where
         }
         #[cfg(not(all(target_family = "wasm", target_os = "unknown")))]
         {
-            let _ = (source, serializer);
-            unimplemented!();
+            // `web_time::SystemTime` is `std::time::SystemTime`.
+            T::serialize_as(source, serializer)
+        }
+    }
+}
+
+/// `serde_with` for [`web_time::Duration`], analogous to [`WebSystemTime`].
+///
+/// [`web_time::Duration`] is a re-export of [`std::time::Duration`], so this just delegates to
+/// `T`. Use it anyway for symmetry with [`WebSystemTime`].
+pub struct WebDuration<T>(PhantomData<T>);
+impl<'de, T> DeserializeAs<'de, web_time::Duration> for WebDuration<T>
+where
+    T: DeserializeAs<'de, std::time::Duration>,
+{
+    fn deserialize_as<D>(deserializer: D) -> Result<web_time::Duration, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        T::deserialize_as(deserializer)
+    }
+}
+impl<T> SerializeAs<web_time::Duration> for WebDuration<T>
+where
+    T: SerializeAs<std::time::Duration>,
+{
+    fn serialize_as<S>(source: &web_time::Duration, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        T::serialize_as(source, serializer)
+    }
+}
+
+/// `serde_with` for a [`u64`] which may be sent as an integer, a float, or a string of either.
+///
+/// D1 may return integer columns, especially `SUM`/`MAX` aggregates, as floats or strings
+/// depending on the driver. Floats must be integral and in range. Serializes as a plain integer.
+pub struct LenientU64;
+impl<'de> DeserializeAs<'de, u64> for LenientU64 {
+    fn deserialize_as<D>(deserializer: D) -> Result<u64, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct LenientU64Visitor;
+        impl<'de> Visitor<'de> for LenientU64Visitor {
+            type Value = u64;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str("a non-negative integer, as a number or string")
+            }
+
+            fn visit_u64<E: DeError>(self, v: u64) -> Result<u64, E> {
+                Ok(v)
+            }
+
+            fn visit_i64<E: DeError>(self, v: i64) -> Result<u64, E> {
+                u64::try_from(v).map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))
+            }
+
+            fn visit_f64<E: DeError>(self, v: f64) -> Result<u64, E> {
+                // `u64::MAX as f64` rounds up to 2^64, which is out of range.
+                if 0.0 <= v && v < u64::MAX as f64 && 0.0 == v.fract() {
+                    Ok(v as u64)
+                } else {
+                    Err(E::invalid_value(Unexpected::Float(v), &self))
+                }
+            }
+
+            fn visit_str<E: DeError>(self, v: &str) -> Result<u64, E> {
+                let v = v.trim();
+                match v.parse::<u64>() {
+                    Ok(n) => Ok(n),
+                    Err(_) => v
+                        .parse::<f64>()
+                        .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+                        .and_then(|f| self.visit_f64(f)),
+                }
+            }
         }
+        deserializer.deserialize_any(LenientU64Visitor)
     }
 }
+impl SerializeAs<u64> for LenientU64 {
+    fn serialize_as<S>(source: &u64, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u64(*source)
+    }
+}
+
+/// Serializes a [`Champion`] as its numeric ID, e.g. `62`, see [`ChampionAs`].
+pub struct ChampionId;
+/// Serializes a [`Champion`] as its string key, e.g. `"MonkeyKing"`, see [`ChampionAs`]. Unknown
+/// champions have no key, so are serialized as their numeric ID.
+pub struct ChampionKey;
+
+/// `serde_with` for a [`Champion`], serialized as [`ChampionId`] (default) or [`ChampionKey`].
+///
+/// Deserializing accepts either representation regardless of `F`, including numeric IDs of
+/// unknown champions, so the stored format can change without a migration.
+pub struct ChampionAs<F = ChampionId>(PhantomData<F>);
+impl<'de, F> DeserializeAs<'de, Champion> for ChampionAs<F> {
+    fn deserialize_as<D>(deserializer: D) -> Result<Champion, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ChampionVisitor;
+        impl<'de> Visitor<'de> for ChampionVisitor {
+            type Value = Champion;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str("a champion numeric ID or string key")
+            }
+
+            fn visit_i64<E: DeError>(self, v: i64) -> Result<Champion, E> {
+                i16::try_from(v)
+                    .map(Champion::from)
+                    .map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))
+            }
+
+            fn visit_u64<E: DeError>(self, v: u64) -> Result<Champion, E> {
+                i16::try_from(v)
+                    .map(Champion::from)
+                    .map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_f64<E: DeError>(self, v: f64) -> Result<Champion, E> {
+                if f64::from(i16::MIN) <= v && v <= f64::from(i16::MAX) && 0.0 == v.fract() {
+                    Ok(Champion::from(v as i16))
+                } else {
+                    Err(E::invalid_value(Unexpected::Float(v), &self))
+                }
+            }
 
-/// Parse a String as Base36;
+            fn visit_str<E: DeError>(self, v: &str) -> Result<Champion, E> {
+                if let Ok(id) = v.parse::<i16>() {
+                    return Ok(Champion::from(id));
+                }
+                v.parse()
+                    .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+            }
+        }
+        deserializer.deserialize_any(ChampionVisitor)
+    }
+}
+impl SerializeAs<Champion> for ChampionAs<ChampionId> {
+    fn serialize_as<S>(source: &Champion, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_i16(i16::from(*source))
+    }
+}
+impl SerializeAs<Champion> for ChampionAs<ChampionKey> {
+    fn serialize_as<S>(source: &Champion, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match source.identifier() {
+            Some(key) => serializer.serialize_str(key),
+            None => serializer.serialize_i16(i16::from(*source)),
+        }
+    }
+}
+
+/// Parse a String as Base36, see [`base36`]. Non-alphanumeric characters are a deserialize error.
 pub struct Base36<T = Same>(PhantomData<T>);
 impl<'de, T> DeserializeAs<'de, u64> for Base36<T>
 where
@@ -130,8 +393,165 @@ where
         D: Deserializer<'de>,
     {
         let s = T::deserialize_as(deserializer)?;
-        let n = base36::decode(&s)
-            .map_err(|b| <D::Error>::custom(format!("Bad char for base36: {}", b)))?;
+        let n = base36::decode(&s).map_err(<D::Error>::custom)?;
         Ok(n)
     }
 }
+impl<T> SerializeAs<u64> for Base36<T>
+where
+    T: SerializeAs<String>,
+{
+    fn serialize_as<S>(source: &u64, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        T::serialize_as(&base36::encode(*source), serializer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_with::ser::SerializeAsWrap;
+
+    use super::*;
+
+    #[test]
+    fn test_positional() {
+        #[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+        struct Row {
+            id: u64,
+            name: String,
+            icon: Option<u32>,
+        }
+        let row: DeserializeAsWrap<Row, Positional> =
+            serde_json::from_str(r#"{ "x": 5, "y": "LugnutsK", "z": null }"#).unwrap();
+        assert_eq!(
+            Row {
+                id: 5,
+                name: "LugnutsK".to_owned(),
+                icon: None,
+            },
+            row.into_inner()
+        );
+    }
+
+    #[test]
+    fn test_web_duration() {
+        type With = WebDuration<serde_with::DurationSeconds<u64>>;
+        let duration = web_time::Duration::from_secs(3 * 60 * 60);
+        let json = serde_json::to_string(&SerializeAsWrap::<_, With>::new(&duration)).unwrap();
+        assert_eq!("10800", json);
+        let wrap: DeserializeAsWrap<web_time::Duration, With> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(duration, wrap.into_inner());
+    }
+
+    #[test]
+    fn test_positional_too_short() {
+        #[derive(Debug, serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Row {
+            id: u64,
+            name: String,
+        }
+        let result = serde_json::from_str::<DeserializeAsWrap<Row, Positional>>(r#"{ "x": 5 }"#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_positional_trailing() {
+        #[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+        struct Row {
+            id: u64,
+            name: String,
+        }
+        let row: DeserializeAsWrap<Row, Positional> =
+            serde_json::from_str(r#"{ "x": 5, "y": "LugnutsK", "z": [1, 2], "w": null }"#).unwrap();
+        assert_eq!(
+            Row {
+                id: 5,
+                name: "LugnutsK".to_owned(),
+            },
+            row.into_inner()
+        );
+    }
+
+    #[test]
+    fn test_lenient_u64() {
+        fn decode(json: &str) -> serde_json::Result<u64> {
+            serde_json::from_str::<DeserializeAsWrap<u64, LenientU64>>(json)
+                .map(DeserializeAsWrap::into_inner)
+        }
+        for json in [
+            "12345",
+            "12345.0",
+            r#""12345""#,
+            r#""12345.0""#,
+            r#"" 12345 ""#,
+        ] {
+            assert_eq!(12345, decode(json).unwrap(), "{}", json);
+        }
+        assert_eq!(u64::MAX, decode(&u64::MAX.to_string()).unwrap());
+        for json in [
+            "-1", "1.5", "-1.0", "1e20", r#""abc""#, r#""""#, "null", "true",
+        ] {
+            assert!(decode(json).is_err(), "{}", json);
+        }
+        let json = serde_json::to_string(&SerializeAsWrap::<_, LenientU64>::new(&5)).unwrap();
+        assert_eq!("5", json);
+    }
+
+    #[test]
+    fn test_base36() {
+        let n = serde_json::from_str::<DeserializeAsWrap<u64, Base36>>(r#""e69d6""#)
+            .unwrap()
+            .into_inner();
+        assert_eq!(23806698, n);
+        let json = serde_json::to_string(&SerializeAsWrap::<_, Base36>::new(&n)).unwrap();
+        assert_eq!(r#""e69d6""#, json);
+
+        let err = serde_json::from_str::<DeserializeAsWrap<u64, Base36>>(r#""e6!d6""#)
+            .map(DeserializeAsWrap::into_inner)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("Bad char for base36: !"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_champion_as() {
+        fn decode(json: &str) -> serde_json::Result<Champion> {
+            serde_json::from_str::<DeserializeAsWrap<Champion, ChampionAs>>(json)
+                .map(DeserializeAsWrap::into_inner)
+        }
+        fn encode<F>(champion: Champion) -> String
+        where
+            ChampionAs<F>: SerializeAs<Champion>,
+        {
+            serde_json::to_string(&SerializeAsWrap::<_, ChampionAs<F>>::new(&champion)).unwrap()
+        }
+
+        // Numeric ID.
+        assert_eq!("62", encode::<ChampionId>(Champion::MONKEY_KING));
+        assert_eq!(Champion::MONKEY_KING, decode("62").unwrap());
+        assert_eq!(Champion::MONKEY_KING, decode("62.0").unwrap());
+        assert_eq!(Champion::MONKEY_KING, decode(r#""62""#).unwrap());
+        // String key.
+        assert_eq!(
+            r#""MonkeyKing""#,
+            encode::<ChampionKey>(Champion::MONKEY_KING)
+        );
+        assert_eq!(Champion::MONKEY_KING, decode(r#""MonkeyKing""#).unwrap());
+        // Unknown champion, round-trips as its numeric ID either way.
+        let unknown = Champion::from(9999);
+        assert_eq!("9999", encode::<ChampionId>(unknown));
+        assert_eq!("9999", encode::<ChampionKey>(unknown));
+        assert_eq!(unknown, decode("9999").unwrap());
+
+        for json in ["40000", "1.5", r#""NotAChampion""#, "null"] {
+            assert!(decode(json).is_err(), "{}", json);
+        }
+    }
+}
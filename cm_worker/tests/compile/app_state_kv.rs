@@ -0,0 +1,11 @@
+//! Handlers can extract the optional KV store from `AppState`, see `cm_worker::cache`.
+
+use cm_worker::axum::extract::FromRef;
+use cm_worker::init::AppState;
+use worker::kv::KvStore;
+
+fn assert_from_ref<T: FromRef<AppState>>() {}
+
+fn main() {
+    assert_from_ref::<&'static Option<KvStore>>();
+}
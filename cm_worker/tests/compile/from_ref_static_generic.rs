@@ -0,0 +1,26 @@
+use cm_macro::FromRefStatic;
+use cm_worker::axum::extract::FromRef;
+
+#[derive(FromRefStatic)]
+pub struct GenericState<'a, T>
+where
+    T: 'static,
+{
+    pub count: u32,
+    pub name: &'a str,
+    pub items: Vec<T>,
+}
+
+fn main() {
+    let state: &'static GenericState<'static, String> = Box::leak(Box::new(GenericState {
+        count: 5,
+        name: "cmflairs",
+        items: vec!["a".to_owned()],
+    }));
+    let count: &'static u32 = FromRef::from_ref(&state);
+    let name: &'static &'static str = FromRef::from_ref(&state);
+    let items: &'static Vec<String> = FromRef::from_ref(&state);
+    assert_eq!(5, *count);
+    assert_eq!("cmflairs", *name);
+    assert_eq!(1, items.len());
+}
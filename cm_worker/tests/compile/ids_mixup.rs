@@ -0,0 +1,10 @@
+use cm_worker::ids::{SummonerId, UserId};
+
+fn summoner_url(sid: SummonerId) -> String {
+    format!("/summoner/{}", sid)
+}
+
+fn main() {
+    let user_id = UserId::new(5).unwrap();
+    summoner_url(user_id);
+}
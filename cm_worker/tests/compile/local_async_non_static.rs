@@ -0,0 +1,15 @@
+use cm_macro::local_async;
+
+#[local_async]
+async fn name_len(name: &str) -> usize {
+    name.len()
+}
+
+struct Greeter<'a>(&'a str);
+
+#[local_async]
+async fn greeting(greeter: Greeter<'_>) -> String {
+    format!("Hello {}", greeter.0)
+}
+
+fn main() {}
@@ -0,0 +1,33 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::rc::Rc;
+
+use cm_macro::local_async;
+
+#[local_async]
+async fn explicit(x: u32) -> Result<u32, Box<dyn Error + Send + Sync>> {
+    // Non-`Send` value held across an await.
+    let rc = Rc::new(x);
+    std::future::ready(()).await;
+    if 0 == *rc {
+        return Err("zero".into());
+    }
+    let y: u32 = "5".parse()?;
+    Ok(*rc + y)
+}
+
+#[local_async]
+async fn opaque(name: &'static str) -> impl Display {
+    format!("Hello {}", name)
+}
+
+#[local_async]
+async fn unit(_name: String) {}
+
+fn assert_send<T: Send>(_: T) {}
+
+fn main() {
+    assert_send(explicit(5));
+    assert_send(opaque("cmflairs"));
+    assert_send(unit("cmflairs".to_owned()));
+}
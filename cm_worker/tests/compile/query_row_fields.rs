@@ -0,0 +1,20 @@
+use cm_worker::checked_query::ColumnCount;
+use cm_worker::query_row::QueryRow;
+use serde_with::DisplayFromStr;
+
+#[derive(QueryRow)]
+pub struct SummonerRow {
+    pub id: u64,
+    #[query_row(with = "DisplayFromStr")]
+    pub platform: riven::consts::PlatformRoute,
+    pub game_name: Option<String>,
+}
+
+fn main() {
+    assert_eq!(3, <SummonerRow as ColumnCount>::COLUMNS);
+    let row: SummonerRow =
+        serde_json::from_str(r#"{ "id": 5, "platform": "NA1", "game_name": null }"#).unwrap();
+    assert_eq!(5, row.id);
+    assert_eq!(riven::consts::PlatformRoute::NA1, row.platform);
+    assert_eq!(None, row.game_name);
+}
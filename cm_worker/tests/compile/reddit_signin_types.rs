@@ -0,0 +1,15 @@
+//! The Reddit signin flow uses the shared `reqwest_client` from `AppState` for `reddit::get_me`.
+
+use cm_worker::auth::OauthTokenResponse;
+use cm_worker::init::AppState;
+use cm_worker::{create_or_get_db_user, reddit};
+
+#[allow(dead_code)]
+async fn signin(app_state: AppState, tokens: &OauthTokenResponse) {
+    let reddit_me: reddit::Me = reddit::get_me(&app_state.reqwest_client, &tokens.access_token)
+        .await
+        .unwrap();
+    let _user_id = create_or_get_db_user(&app_state.db, &reddit_me).await;
+}
+
+fn main() {}
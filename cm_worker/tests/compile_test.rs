@@ -0,0 +1,11 @@
+#[test]
+fn test_compile() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/compile/app_state_kv.rs");
+    t.pass("tests/compile/from_ref_static_generic.rs");
+    t.pass("tests/compile/local_async_return_types.rs");
+    t.pass("tests/compile/query_row_fields.rs");
+    t.pass("tests/compile/reddit_signin_types.rs");
+    t.compile_fail("tests/compile/ids_mixup.rs");
+    t.compile_fail("tests/compile/local_async_non_static.rs");
+}
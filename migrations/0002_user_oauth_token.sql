@@ -0,0 +1,15 @@
+-- Migration number: 0002 	 2026-10-16T17:02:11.512Z
+CREATE TABLE IF NOT EXISTS user_oauth_token (
+    user_id INTEGER NOT NULL,
+    provider TEXT NOT NULL,
+    -- Encrypted, see `crypt::TokenCipher`.
+    access_token TEXT NOT NULL,
+    -- Encrypted, see `crypt::TokenCipher`.
+    refresh_token TEXT,
+    -- Milliseconds since epoch.
+    expires_at INTEGER NOT NULL,
+    PRIMARY KEY(user_id, provider),
+    FOREIGN KEY(user_id) REFERENCES user(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_oauth_token__expires_at ON user_oauth_token(expires_at);
@@ -0,0 +1,9 @@
+-- Migration number: 0003 	 2026-10-16T18:40:27.204Z
+CREATE TABLE IF NOT EXISTS revoked_nonce (
+    -- Base64 nonce of the revoked session token.
+    nonce TEXT PRIMARY KEY,
+    -- Milliseconds since epoch, `exp` of the revoked session token.
+    exp INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_revoked_nonce__exp ON revoked_nonce(exp);
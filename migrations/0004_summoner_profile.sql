@@ -0,0 +1,4 @@
+-- Migration number: 0004 	 2026-10-16T19:12:53.871Z
+ALTER TABLE summoner ADD COLUMN profile_icon_id INTEGER;
+
+ALTER TABLE summoner ADD COLUMN summoner_level INTEGER;
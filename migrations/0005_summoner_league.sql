@@ -0,0 +1,6 @@
+-- Migration number: 0005 	 2026-10-16T19:48:05.330Z
+ALTER TABLE summoner ADD COLUMN solo_tier TEXT;
+
+ALTER TABLE summoner ADD COLUMN solo_rank TEXT;
+
+ALTER TABLE summoner ADD COLUMN solo_league_points INTEGER;
@@ -0,0 +1,12 @@
+-- Migration number: 0006 	 2026-10-16T21:12:40.907Z
+CREATE TABLE IF NOT EXISTS summoner_match (
+    summoner_id INTEGER NOT NULL,
+    match_id TEXT NOT NULL,
+    champ_id INTEGER NOT NULL,
+    win INTEGER NOT NULL,
+    queue_id INTEGER NOT NULL,
+    -- Milliseconds since epoch.
+    game_start INTEGER NOT NULL,
+    PRIMARY KEY(summoner_id, match_id),
+    FOREIGN KEY(summoner_id) REFERENCES summoner(id)
+);
@@ -0,0 +1,13 @@
+-- Migration number: 0007 	 2026-10-16T22:31:08.114Z
+CREATE TABLE IF NOT EXISTS summoner_champion_mastery_history (
+    id INTEGER PRIMARY KEY,
+    summoner_id INTEGER NOT NULL,
+    champ_id INTEGER NOT NULL,
+    points INTEGER NOT NULL,
+    level INTEGER NOT NULL,
+    -- Milliseconds since epoch.
+    captured_at INTEGER NOT NULL,
+    FOREIGN KEY(summoner_id) REFERENCES summoner(id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_summoner_champion_mastery_history__summoner_id ON summoner_champion_mastery_history(summoner_id, champ_id, captured_at);
@@ -0,0 +1,3 @@
+-- Migration number: 0008 	 2026-10-16T23:12:41.502Z
+-- Milliseconds since epoch when a `SummonerUpdate` task was enqueued, cleared when it completes.
+ALTER TABLE summoner ADD COLUMN pending_update INTEGER;
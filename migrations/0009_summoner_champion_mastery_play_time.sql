@@ -0,0 +1,5 @@
+-- Migration number: 0009 	 2026-10-16T23:48:19.736Z
+-- Milliseconds since epoch, `NULL` if never played.
+ALTER TABLE summoner_champion_mastery ADD COLUMN last_play_time INTEGER;
+
+ALTER TABLE summoner_champion_mastery ADD COLUMN tokens_earned INTEGER;
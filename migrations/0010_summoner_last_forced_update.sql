@@ -0,0 +1,3 @@
+-- Migration number: 0010 	 2026-10-17T00:21:52.048Z
+-- Milliseconds since epoch of the last forced update, see `POST /summoner/:sid/update?force=true`.
+ALTER TABLE summoner ADD COLUMN last_forced_update INTEGER;
@@ -0,0 +1,13 @@
+-- Migration number: 0011 	 2026-10-17T01:05:13.317Z
+-- Fixed-window request counters per user, see `cm_worker/src/rate_limit.rs`.
+CREATE TABLE IF NOT EXISTS user_rate_limit (
+    user_id INTEGER NOT NULL,
+    -- Name of the rate-limited action, e.g. `summoner_update`.
+    action TEXT NOT NULL,
+    -- Milliseconds since epoch, start of the current window.
+    window_start INTEGER NOT NULL,
+    -- Requests made in the current window.
+    count INTEGER NOT NULL,
+    PRIMARY KEY(user_id, action),
+    FOREIGN KEY(user_id) REFERENCES user(id)
+);
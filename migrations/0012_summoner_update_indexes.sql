@@ -0,0 +1,7 @@
+-- Migration number: 0012 	 2026-10-17T02:14:36.905Z
+-- Indexes for `GET /admin/metrics` counts, and `SummonerBulkUpdate` ordering by `last_update`.
+CREATE INDEX IF NOT EXISTS idx_summoner__last_update ON summoner(last_update);
+
+CREATE INDEX IF NOT EXISTS idx_summoner__pending_update ON summoner(pending_update)
+WHERE
+    pending_update IS NOT NULL;
@@ -0,0 +1,16 @@
+-- Migration number: 0013 	 2026-10-17T03:02:18.611Z
+-- Splits `last_update` into `last_success`, set only once an update succeeds, and `last_attempt`,
+-- set when an update starts. Cooldowns use `last_success`, so a failed update does not delay the
+-- retry. `SummonerBulkUpdate` orders by `last_attempt`, so failing summoners do not crowd out the
+-- rest.
+ALTER TABLE summoner RENAME COLUMN last_update TO last_success;
+
+ALTER TABLE summoner ADD COLUMN last_attempt INTEGER;
+
+UPDATE summoner SET last_attempt = last_success;
+
+DROP INDEX IF EXISTS idx_summoner__last_update;
+
+CREATE INDEX IF NOT EXISTS idx_summoner__last_success ON summoner(last_success);
+
+CREATE INDEX IF NOT EXISTS idx_summoner__last_attempt ON summoner(last_attempt);
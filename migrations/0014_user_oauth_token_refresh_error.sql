@@ -0,0 +1,5 @@
+-- Migration number: 0014 	 2026-10-17T09:41:52.204Z
+-- Set when the provider permanently rejects a refresh token (e.g. the grant was revoked), so
+-- `OauthTokenRefresh` skips the row instead of retrying every tick. Cleared when the user signs in
+-- again and new tokens are stored.
+ALTER TABLE user_oauth_token ADD COLUMN refresh_error TEXT;
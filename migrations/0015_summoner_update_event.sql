@@ -0,0 +1,11 @@
+-- Migration number: 0015 	 2026-10-18T02:14:37.905Z
+-- Latest update outcome per summoner, polled by `GET /summoner/:sid/events`. Previously stored in
+-- KV, whose cached negative lookups delayed events by up to a minute.
+CREATE TABLE IF NOT EXISTS summoner_update_event (
+    summoner_id INTEGER PRIMARY KEY NOT NULL,
+    -- `completed` or `failed`.
+    outcome TEXT NOT NULL,
+    -- Milliseconds since epoch.
+    at INTEGER NOT NULL,
+    FOREIGN KEY(summoner_id) REFERENCES summoner(id)
+);
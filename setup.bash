@@ -6,6 +6,9 @@ which wrangler || npm i -g wrangler
 QUEUE='dev-webjob'
 $(wrangler queues list | grep -q "$QUEUE") || wrangler queues create "$QUEUE"
 
+DLQUEUE='dev-webjob-dead-letter'
+$(wrangler queues list | grep -q "$DLQUEUE") || wrangler queues create "$DLQUEUE"
+
 D1DB='dev-db'
 $(wrangler d1 list | grep -q "$D1DB") || wrangler d1 create "$D1DB"
 echo '❗ UPDATE YOUR `wrangler.toml` IF THERE IS A NEW `database_id` ABOVE ❗'